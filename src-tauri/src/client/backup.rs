@@ -0,0 +1,72 @@
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::shared::{
+    db::ExportedEntry,
+    json::{self, JsonLimits},
+};
+use crate::Error;
+
+use super::intent::SealedSecret;
+
+/// Revision of the backup format `write` produces. `read` turns newer ones away.
+pub const BACKUP_VERSION: u32 = 1;
+
+/// A backup as stored: the contents sealed under a key stretched from the backup
+/// passphrase with Argon2id, and the format revision in the clear so it can be
+/// checked before anything is decrypted.
+#[derive(Serialize, Deserialize)]
+struct BackupFile {
+    version: u32,
+    contents: SealedSecret,
+}
+
+/// What a profile is rebuilt from: its identity keys and the entries of every one of
+/// its databases, by file name.
+#[derive(Serialize, Deserialize)]
+pub struct BackupContents {
+    pub private_key_pem: String,
+    pub ed25519_key_pem: Option<String>,
+    pub databases: Vec<(String, Vec<ExportedEntry>)>,
+}
+
+// a backup holds whole databases, well past what's accepted from peers
+fn limits() -> JsonLimits {
+    JsonLimits {
+        max_array_len: usize::MAX,
+        ..JsonLimits::default()
+    }
+}
+
+/// Seals `contents` under `passphrase` into the file at `path`. It's written aside
+/// and renamed over, so an export cut short never leaves half a backup behind.
+pub fn write(path: &Path, contents: &BackupContents, passphrase: &[u8]) -> Result<(), Error> {
+    let plaintext = Zeroizing::new(serde_json::to_vec(contents)?);
+    let file = BackupFile {
+        version: BACKUP_VERSION,
+        contents: SealedSecret::seal(&plaintext, passphrase)?,
+    };
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, serde_json::to_vec(&file)?)?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Opens the backup at `path`. Fails with `InvalidPassphrase` if it wasn't sealed
+/// under `passphrase`.
+pub fn read(path: &Path, passphrase: &[u8]) -> Result<BackupContents, Error> {
+    let file: BackupFile = json::from_reader(fs::File::open(path)?, &limits())?;
+    if file.version > BACKUP_VERSION {
+        Err(format!(
+            "Backup format {} is newer than this version supports ({})",
+            file.version, BACKUP_VERSION
+        ))?;
+    }
+    let plaintext = file
+        .contents
+        .open(passphrase)?
+        .ok_or(Error::InvalidPassphrase)?;
+    json::from_reader(plaintext.as_slice(), &limits())
+}
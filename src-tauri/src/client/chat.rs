@@ -0,0 +1,569 @@
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use rsa::pkcs1v15::Signature;
+
+use crate::shared::{
+    pki,
+    rpc::Request,
+    rpc_models::{
+        self, AckStatus, ChatAccept, ChatCustomizationParams, ChatInvite, ChatMessagePayload,
+        EncryptionType, PayloadType,
+    },
+    ski,
+};
+use crate::Error;
+
+use super::{
+    db::OutboxEntry,
+    models::{
+        Chat, ChatEvent, ChatId, Message, MessageId, MessageStatus, OutgoingMessage,
+        Reconciliation, ServerId, ServerModel, User, UserId,
+    },
+    Client, CHAT_CUSTOMIZED_EVENT, MESSAGE_ACKED_EVENT, MESSAGE_RECEIVED_EVENT,
+    MESSAGE_RECONCILED_EVENT,
+};
+
+/// How many times a message in the outbox is tried before it is marked failed.
+pub const MAX_SEND_ATTEMPTS: u32 = 5;
+
+impl Client {
+    /// Our own entry among the connected server's users, added by `register`.
+    fn own_user(&self, server: &ServerModel) -> Result<Option<(UserId, User)>, Error> {
+        let fingerprint = pki::fingerprint(&self.private_key.to_public_key())?;
+        for id in server.user_ids() {
+            let user = self.db.get_user(id)?;
+            if user.fingerprint().as_ref() == Some(&fingerprint) {
+                return Ok(Some((id.clone(), user)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn connected_server(&self) -> Result<(ServerId, ServerModel), Error> {
+        let server_id = self.server_id.clone().ok_or("Server not found")?;
+        let server = self.db.get_server(&server_id)?;
+        Ok((server_id, server))
+    }
+
+    /// The server the chat was started or joined on, so messages can be queued for it
+    /// while it's unreachable. Chats no server lists go to the connected one.
+    fn chat_server(&self, chat_id: &ChatId) -> Result<(ServerId, ServerModel), Error> {
+        match self.db.server_of_chat(chat_id)? {
+            Some(found) => Ok(found),
+            None => self.connected_server(),
+        }
+    }
+
+    /// Invites `username` on the connected server to an end-to-end encrypted chat. The
+    /// chat key travels under their RSA key, so the server never learns it. Nothing can
+    /// be sent until they accept.
+    pub async fn create_chat(&mut self, username: &str, name: &str) -> Result<ChatId, Error> {
+        let (_, server) = self.connected_server()?;
+        let (_, me) = self
+            .own_user(&server)?
+            .ok_or("Register with the server before starting chats")?;
+        let lookup = self.get_user_key(username).await?;
+        let their_key = pki::pub_key_from_str(&lookup.pub_key)?;
+        let key = ski::gen_key();
+        let chat = Chat::invite(vec![lookup.user_id], name.to_string(), key.clone());
+        let chat_id = self.db.save_chat(chat)?;
+        let mut invite = ChatInvite {
+            chat_id: chat_id.to_string(),
+            name: name.to_string(),
+            from: me.username().to_string(),
+            from_key: me.pub_key().to_string(),
+            encrypted_key: pki::encrypt_message(&their_key, &key)?,
+            signature: vec![],
+        };
+        invite.signature = pki::sign_message(&self.private_key, &invite.signed_data());
+        let sent = self
+            .forward(
+                vec![pki::fingerprint(&their_key)?],
+                PayloadType::ChatInvite,
+                EncryptionType::RsaOaep,
+                serde_json::to_vec(&invite)?,
+            )
+            .await;
+        if let Err(e) = sent {
+            self.db.chat_db.delete_entry(chat_id.as_str())?;
+            return Err(e);
+        }
+        // reloaded, the user lookup may have changed the server entry
+        let (server_id, mut server) = self.connected_server()?;
+        server.add_chat(chat_id.clone());
+        self.db.server_db.update_entry(server_id.as_str(), server)?;
+        Ok(chat_id)
+    }
+
+    /// Joins the chat `invite`, pushed by `server_id`, offers, after checking the
+    /// inviter's key against the one that server has registered for them, and tells
+    /// them we accepted. Goes over the session the invite came in on as it is.
+    pub async fn accept_chat(
+        &self,
+        server_id: &ServerId,
+        invite: ChatInvite,
+    ) -> Result<ChatId, Error> {
+        let from_key = pki::pub_key_from_str(&invite.from_key)?;
+        let signature = Signature::try_from(invite.signature.as_slice())?;
+        if !pki::verify_signature(&from_key, &invite.signed_data(), &signature) {
+            Err(Error::Auth(String::from("Invalid chat invite signature")))?;
+        }
+        let lookup = self.get_server_user_key(server_id, &invite.from).await?;
+        if pki::pub_key_from_str(&lookup.pub_key)? != from_key {
+            Err(format!(
+                "Chat invite key doesn't match the server's key for {}",
+                invite.from
+            ))?;
+        }
+        let key = pki::decrypt_message(&self.private_key, &invite.encrypted_key)?;
+        if key.len() != 32 {
+            Err("Chat keys must be 32 bytes")?;
+        }
+        let chat_id = ChatId::from(invite.chat_id);
+        if self.db.chat_db.contains(chat_id.as_str())? {
+            Err(format!("Chat {} already exists", chat_id))?;
+        }
+        let accept = ChatAccept {
+            chat_id: chat_id.to_string(),
+            proof: ski::seal_gcm(chat_id.as_str().as_bytes(), &key)?,
+        };
+        let chat = Chat::new(vec![lookup.user_id], invite.name, key, Default::default());
+        // same id as on the inviter's side, so payloads can name the chat
+        self.db.chat_db.update_entry(chat_id.as_str(), chat)?;
+        let mut server = self.db.get_server(server_id)?;
+        server.add_chat(chat_id.clone());
+        self.db.server_db.update_entry(server_id.as_str(), server)?;
+        for payload in self.db.take_stashed(&chat_id)? {
+            if let Err(e) = self.receive_chat_message(server_id, payload, None).await {
+                eprintln!("Error: a message stashed for chat {}: {}", chat_id, e);
+            }
+        }
+        self.forward_to(
+            server_id,
+            vec![pki::fingerprint(&from_key)?],
+            PayloadType::ChatAccept,
+            EncryptionType::AesGcm,
+            serde_json::to_vec(&accept)?,
+        )
+        .await?;
+        Ok(chat_id)
+    }
+
+    /// A page of a chat's history, newest first, see `ClientDatabase::chat_messages`.
+    /// History only lives on this device; the server forgets messages once delivered.
+    pub fn chat_messages(
+        &self,
+        chat_id: &str,
+        before: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(MessageId, Message)>, Error> {
+        let before = before.map(MessageId::from);
+        self.db
+            .chat_messages(&ChatId::from(chat_id), before.as_ref(), limit)
+    }
+
+    /// Seals `text` under the chat key and sends it to the other participants. The
+    /// server only relays the ciphertext.
+    pub async fn send_chat_message(
+        &mut self,
+        chat_id: &str,
+        text: &str,
+    ) -> Result<MessageId, Error> {
+        let chat_id = ChatId::from(chat_id);
+        let (server_id, server) = self.connected_server()?;
+        let (recipients, payload) = self.seal_chat_payload(&chat_id, &server, text.as_bytes())?;
+        let receipt = self
+            .forward(
+                recipients,
+                PayloadType::ChatMessage,
+                EncryptionType::AesGcm,
+                serde_json::to_vec(&payload)?,
+            )
+            .await?;
+        let sender_id = self.own_user(&server)?.map(|(id, _)| id);
+        let mut message = Message::new(server_id, sender_id, chat_id, text.to_string());
+        message.set_server_message_id(receipt.and_then(|receipt| receipt.message_id));
+        self.db.add_message(message)
+    }
+
+    /// Gives the chat a new color and emoji, signed with our key, and sends it to the
+    /// other participants under the chat key.
+    pub async fn customize_chat(
+        &mut self,
+        chat_id: &str,
+        color: &str,
+        emoji: &str,
+    ) -> Result<(), Error> {
+        let chat_id = ChatId::from(chat_id);
+        let (_, server) = self.connected_server()?;
+        let params = self
+            .db
+            .customize_chat(&chat_id, color, emoji, &self.private_key)?;
+        let customization = serde_json::to_vec(&params.customization)?;
+        let (recipients, payload) = self.seal_chat_payload(&chat_id, &server, &customization)?;
+        self.forward(
+            recipients,
+            PayloadType::ChatCustomization,
+            EncryptionType::AesGcm,
+            serde_json::to_vec(&payload)?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Stores `text` as a pending message of the chat and queues it in the outbox, without
+    /// waiting for the server, so the UI can show it right away. `flush_outbox` sends it
+    /// later and reports how it settled. The chat's server doesn't have to be reachable,
+    /// the message waits for it in the outbox until it is.
+    pub fn send_message(&mut self, chat_id: &str, text: &str) -> Result<OutgoingMessage, Error> {
+        let chat_id = ChatId::from(chat_id);
+        let (server_id, server) = self.chat_server(&chat_id)?;
+        let (recipients, payload) = self.seal_chat_payload(&chat_id, &server, text.as_bytes())?;
+        let sender_id = self.own_user(&server)?.map(|(id, _)| id);
+        let message = Message::outgoing(server_id, sender_id, chat_id.clone(), text.to_string());
+        let timestamp = message.timestamp();
+        let entry = OutboxEntry {
+            recipients,
+            payload: serde_json::to_vec(&payload)?,
+            attempts: 0,
+            queued_at: timestamp,
+        };
+        let message_id = self.db.add_outgoing_message(message, &entry)?;
+        Ok(OutgoingMessage {
+            message_id,
+            chat_id,
+            status: MessageStatus::Pending,
+            timestamp,
+        })
+    }
+
+    /// Sends what waits in the outbox for the connected server, oldest first, emitting a
+    /// `MESSAGE_RECONCILED_EVENT` for every message that settles. A message the server
+    /// refuses, or that fails `MAX_SEND_ATTEMPTS` times, is marked failed. Any other
+    /// failure stops the flush, so later messages never overtake an earlier one. Losing
+    /// the connection doesn't count as a failed attempt; the session is brought back and
+    /// the flush carries on, or the messages go out once the server is back, see
+    /// `reconnect`.
+    pub async fn flush_outbox(&mut self) -> Result<Vec<Reconciliation>, Error> {
+        match self.server_id.clone() {
+            Some(server_id) => self.flush_server_outbox(&server_id).await,
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Like `flush_pending`, over the session with `server_id` as it is, so the client
+    /// only has to be shared. Messages still waiting when the session turns out to be
+    /// lost stay in the outbox for the next one.
+    pub async fn flush_session_outbox(
+        &self,
+        server_id: &str,
+    ) -> Result<Vec<Reconciliation>, Error> {
+        let mut settled = vec![];
+        if self.flushing_outbox.swap(true, Ordering::SeqCst) {
+            return Ok(settled);
+        }
+        let flushed = self.send_outbox(&ServerId::from(server_id), &mut settled).await;
+        self.flushing_outbox.store(false, Ordering::SeqCst);
+        flushed.map(|_| settled)
+    }
+
+    /// Like `flush_outbox`, for `server_id`'s messages, and returns how many the server
+    /// took. Each one leaves the outbox only once the server has it.
+    pub async fn flush_pending(&mut self, server_id: &str) -> Result<usize, Error> {
+        let settled = self.flush_server_outbox(&ServerId::from(server_id)).await?;
+        Ok(settled
+            .iter()
+            .filter(|reconciliation| reconciliation.error.is_none())
+            .count())
+    }
+
+    async fn flush_server_outbox(
+        &mut self,
+        server_id: &ServerId,
+    ) -> Result<Vec<Reconciliation>, Error> {
+        let mut settled = vec![];
+        if self.flushing_outbox.swap(true, Ordering::SeqCst) {
+            // a reconnect during the flush already running, which carries on by itself
+            return Ok(settled);
+        }
+        let flushed = self.send_outbox_recovering(server_id, &mut settled).await;
+        self.flushing_outbox.store(false, Ordering::SeqCst);
+        flushed.map(|_| settled)
+    }
+
+    /// `send_outbox`, bringing the session back whenever it's lost on the way. A server
+    /// that can't be reached leaves the rest waiting.
+    async fn send_outbox_recovering(
+        &mut self,
+        server_id: &ServerId,
+        settled: &mut Vec<Reconciliation>,
+    ) -> Result<(), Error> {
+        if self.outbox_of(server_id)?.is_empty() {
+            return Ok(());
+        }
+        if self.ensure_connected(server_id.as_str()).await.is_err() {
+            return Ok(());
+        }
+        while let Some(e) = self.send_outbox(server_id, settled).await? {
+            if self.recover_session(server_id.as_str(), e).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// What waits in the outbox for `server_id`, oldest first.
+    fn outbox_of(&self, server_id: &ServerId) -> Result<Vec<(MessageId, OutboxEntry)>, Error> {
+        let mut entries = vec![];
+        for (message_id, entry) in self.db.outbox()? {
+            if self.db.get_message(&message_id)?.server_id() == server_id {
+                entries.push((message_id, entry));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Sends `server_id`'s messages from the outbox over the session as it is. Returns
+    /// the error that showed the session to be lost, if that's what stopped it.
+    async fn send_outbox(
+        &self,
+        server_id: &ServerId,
+        settled: &mut Vec<Reconciliation>,
+    ) -> Result<Option<Error>, Error> {
+        for (message_id, mut entry) in self.outbox_of(server_id)? {
+            let sent = self
+                .forward_to(
+                    server_id,
+                    entry.recipients.clone(),
+                    PayloadType::ChatMessage,
+                    EncryptionType::AesGcm,
+                    entry.payload.clone(),
+                )
+                .await;
+            let (relayed_at, server_message_id, error) = match sent {
+                Ok(Some(receipt)) => (Some(receipt.relayed_at), receipt.message_id, None),
+                Ok(None) => (Some(SystemTime::now()), None, None),
+                // the session is gone, the messages wait for the next one
+                Err(e) if self.session_lost(server_id.as_str(), &e) => return Ok(Some(e)),
+                Err(e) => {
+                    entry.attempts += 1;
+                    let refused = matches!(e, Error::Rpc { .. } | Error::MessageTooLarge { .. });
+                    if !refused && entry.attempts < MAX_SEND_ATTEMPTS {
+                        self.db.update_outbox_entry(&message_id, &entry)?;
+                        break;
+                    }
+                    (None, None, Some(e.to_string()))
+                }
+            };
+            let message = self
+                .db
+                .settle_outgoing(&message_id, relayed_at, server_message_id)?;
+            let reconciliation = Reconciliation {
+                message_id,
+                chat_id: message.chat_id().clone(),
+                status: message.status(),
+                timestamp: message.timestamp(),
+                relayed_at: message.relayed_at(),
+                error,
+            };
+            self.emit(MESSAGE_RECONCILED_EVENT, serde_json::json!(reconciliation));
+            settled.push(reconciliation);
+        }
+        Ok(None)
+    }
+
+    /// Tells the sender of a message we received how far we got with it, through the
+    /// server that relayed it. Messages from servers that don't hand out ids can't be
+    /// acknowledged.
+    pub async fn ack_message(
+        &mut self,
+        server_id: &str,
+        message_id: &MessageId,
+        status: AckStatus,
+    ) -> Result<(), Error> {
+        let request = self.ack_request(message_id, status)?;
+        self.send_sym_encrypted_request(server_id, request)
+            .await?
+            .into_result()?;
+        Ok(())
+    }
+
+    /// The request `ack_message` sends.
+    pub fn ack_request(&self, message_id: &MessageId, status: AckStatus) -> Result<Request, Error> {
+        let message = self.db.get_message(message_id)?;
+        let server_message_id = message
+            .server_message_id()
+            .ok_or("The server gave the message no id to acknowledge it by")?;
+        let params = rpc_models::AckParams {
+            message_id: server_message_id.to_string(),
+            status,
+        };
+        Ok(Request::new(rpc_models::ACK_MESSAGE.to_string(), serde_json::json!(params)))
+    }
+
+    /// Updates the status of the message of ours a recipient acknowledged, as pushed by
+    /// the server, and emits a `MESSAGE_ACKED_EVENT` for it. Returns the message, or
+    /// `None` if the acknowledgement changed nothing.
+    pub fn on_message_ack(&self, request: Request) -> Result<Option<MessageId>, Error> {
+        if request.method != rpc_models::ACK_MESSAGE {
+            Err("Not a message acknowledgement")?;
+        }
+        let ack: rpc_models::MessageAck = serde_json::from_value(request.params)?;
+        let (message_id, message) = match self.db.ack_message(&ack.message_id, ack.status)? {
+            Some(acked) => acked,
+            None => return Ok(None),
+        };
+        self.emit(
+            MESSAGE_ACKED_EVENT,
+            serde_json::json!({
+                "chat_id": message.chat_id(),
+                "message_id": message_id,
+                "status": message.status(),
+            }),
+        );
+        Ok(Some(message_id))
+    }
+
+    /// The recipients and sealed payload of `data` for the chat, which has to be one
+    /// that can be sent to, on `server`.
+    fn seal_chat_payload(
+        &self,
+        chat_id: &ChatId,
+        server: &ServerModel,
+        data: &[u8],
+    ) -> Result<(Vec<String>, ChatMessagePayload), Error> {
+        let chat = self.db.get_chat(chat_id)?;
+        if chat.shared_key().is_empty() {
+            Err(format!("Chat {} is local only, nothing can be sent to it", chat_id))?;
+        }
+        if chat.is_invite_pending() {
+            Err(format!("Chat {} hasn't been accepted yet", chat_id))?;
+        }
+        let mut recipients = vec![];
+        for user_id in chat.user_ids() {
+            let user = self.db.get_user(user_id)?;
+            recipients.push(user.fingerprint().ok_or("A participant's key isn't known")?);
+        }
+        let payload = ChatMessagePayload {
+            chat_id: chat_id.to_string(),
+            sender: pki::fingerprint(&self.private_key.to_public_key())?,
+            data: ski::seal_gcm(data, chat.shared_key())?,
+            log_head: server.log_head.clone(),
+        };
+        Ok((recipients, payload))
+    }
+
+    /// Handles a chat payload pushed by `server_id`: invites are accepted, acceptances
+    /// complete our invites, messages are decrypted and stored, and new looks applied.
+    /// Messages for chats we haven't joined are stashed until we do. Whatever has to be asked of the server
+    /// goes over the session the payload came in on, as it is.
+    pub async fn on_forwarded_message(
+        &self,
+        server_id: &str,
+        request: Request,
+    ) -> Result<ChatEvent, Error> {
+        if request.method != rpc_models::FORWARDED_MSG {
+            Err("Not a forwarded message")?;
+        }
+        let server_id = ServerId::from(server_id);
+        let params: rpc_models::ForwardedMessageParams = serde_json::from_value(request.params)?;
+        match params.payload_type {
+            PayloadType::ChatInvite => {
+                let invite: ChatInvite = serde_json::from_slice(&params.data)?;
+                Ok(ChatEvent::Joined(self.accept_chat(&server_id, invite).await?))
+            }
+            PayloadType::ChatAccept => {
+                let accept: ChatAccept = serde_json::from_slice(&params.data)?;
+                let chat_id = ChatId::from(accept.chat_id);
+                let mut chat = self.db.get_chat(&chat_id)?;
+                if !chat.is_invite_pending() {
+                    Err(format!("No pending invite for chat {}", chat_id))?;
+                }
+                if ski::open_gcm(&accept.proof, chat.shared_key())? != chat_id.as_str().as_bytes() {
+                    Err("Chat acceptance doesn't prove knowledge of the chat key")?;
+                }
+                chat.mark_accepted();
+                self.db.chat_db.update_entry(chat_id.as_str(), chat)?;
+                Ok(ChatEvent::Accepted(chat_id))
+            }
+            PayloadType::ChatMessage => {
+                let payload: ChatMessagePayload = serde_json::from_slice(&params.data)?;
+                let chat_id = ChatId::from(payload.chat_id.clone());
+                if !self.db.chat_db.contains(chat_id.as_str())? {
+                    self.db.stash_payload(payload)?;
+                    return Ok(ChatEvent::Stashed(chat_id));
+                }
+                let message_id = self
+                    .receive_chat_message(&server_id, payload, params.message_id)
+                    .await?;
+                Ok(ChatEvent::Message(message_id))
+            }
+            PayloadType::ChatCustomization => {
+                let payload: ChatMessagePayload = serde_json::from_slice(&params.data)?;
+                Ok(ChatEvent::Customized(self.receive_chat_customization(payload)?))
+            }
+            PayloadType::Opaque => Err("Not a chat payload")?,
+        }
+    }
+
+    /// Decrypts and stores a message `server_id` relayed from another participant of a
+    /// chat we're in, and tells the frontend about it. A key log head gossiped along
+    /// with it is checked against ours of that server.
+    async fn receive_chat_message(
+        &self,
+        server_id: &ServerId,
+        payload: ChatMessagePayload,
+        server_message_id: Option<String>,
+    ) -> Result<MessageId, Error> {
+        let chat_id = ChatId::from(payload.chat_id);
+        let chat = self.db.get_chat(&chat_id)?;
+        let sender_id = self.chat_sender(&chat, &payload.sender)?;
+        let text = String::from_utf8(ski::open_gcm(&payload.data, chat.shared_key())?)?;
+        let mut message = Message::new(server_id.clone(), Some(sender_id), chat_id.clone(), text);
+        message.set_server_message_id(server_message_id);
+        let id = self.db.add_message(message)?;
+        self.emit(
+            MESSAGE_RECEIVED_EVENT,
+            serde_json::json!({ "chat_id": chat_id, "message_id": id }),
+        );
+        if let Some(head) = payload.log_head {
+            // an equivocation is reported on its own, the message is fine either way
+            if let Err(e) = self.check_server_log_head(server_id, head).await {
+                eprintln!("Error: checking the gossiped key log head: {}", e);
+            }
+        }
+        Ok(id)
+    }
+
+    /// Applies the look another participant gave a chat, and tells the frontend about it
+    /// unless it's older than the chat's current one.
+    fn receive_chat_customization(&self, payload: ChatMessagePayload) -> Result<ChatId, Error> {
+        let chat_id = ChatId::from(payload.chat_id);
+        let chat = self.db.get_chat(&chat_id)?;
+        let sender_id = self.chat_sender(&chat, &payload.sender)?;
+        let opened = ski::open_gcm(&payload.data, chat.shared_key())?;
+        let params = ChatCustomizationParams {
+            chat_id: chat_id.to_string(),
+            customization: serde_json::from_slice(&opened)?,
+        };
+        let customization = params.customization.clone();
+        if self.db.apply_chat_customization(&sender_id, params)? {
+            self.emit(
+                CHAT_CUSTOMIZED_EVENT,
+                serde_json::json!({ "chat_id": chat_id, "customization": customization }),
+            );
+        }
+        Ok(chat_id)
+    }
+
+    /// The participant of `chat` whose key has the fingerprint `sender`.
+    fn chat_sender(&self, chat: &Chat, sender: &str) -> Result<UserId, Error> {
+        for user_id in chat.user_ids() {
+            if self.db.get_user(user_id)?.fingerprint().as_deref() == Some(sender) {
+                return Ok(user_id.clone());
+            }
+        }
+        Err("Sender is not a participant of the chat")?
+    }
+}
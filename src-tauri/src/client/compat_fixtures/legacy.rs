@@ -0,0 +1,393 @@
+//! Writers frozen at what earlier releases put on the wire and on disk. They spell the
+//! formats out by hand and only share the crypto primitives with the current code, so
+//! changing a current writer can't quietly change the fixtures along with it.
+
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use directories::ProjectDirs;
+use rand_core::OsRng;
+use rsa::pkcs1::EncodeRsaPublicKey;
+use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+use rsa::sha2::{Digest, Sha256};
+use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+use serde_json::json;
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey};
+
+use crate::shared::db::DbConfig;
+use crate::shared::rpc::{Handler, Request, Response, RpcError, RpcErrorCode};
+use crate::shared::{pki, ski};
+use crate::Error;
+
+// method names as the releases below sent them
+const START_SERVER_HANDSHAKE: &str = "start_server_handshake";
+const CLIENT_CHALLENGE_RESPONSE: &str = "client_challenge_response";
+const ENCRYPTED_REQUEST: &str = "encrypted_request";
+const PING: &str = "ping";
+const SUBSCRIBE: &str = "subscribe";
+// every capability protocol 5 knew; it refused the handshake on any other
+const V5_CAPABILITIES: [&str; 4] = ["Push", "UserDirectory", "KeyLog", "OfflineDelivery"];
+
+fn config_dir(loc: &str) -> Result<std::path::PathBuf, Error> {
+    let project_dirs =
+        ProjectDirs::from("com", "carapace", loc).ok_or("Could not find project directories")?;
+    Ok(project_dirs.config_dir().to_path_buf())
+}
+
+fn write_rsa_key_file(loc: &str, file: serde_json::Value) -> Result<(), Error> {
+    let dir = config_dir(loc)?;
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("private_key.pem"), file.to_string())?;
+    Ok(())
+}
+
+/// RSA key file from before the passkey went through a KDF: no salt, sealed with the
+/// raw passkey.
+pub fn write_unsalted_key_file(
+    loc: &str,
+    sk: &RsaPrivateKey,
+    passkey: &[u8],
+) -> Result<(), Error> {
+    let nonce = ski::nonce();
+    let pem = sk.to_pkcs8_pem(LineEnding::LF)?;
+    let file = json!({
+        "pem": ski::encrypt_gcm(pem.as_bytes(), passkey, &nonce)?,
+        "nonce": nonce,
+    });
+    write_rsa_key_file(loc, file)
+}
+
+/// RSA key file keyed by the passphrase through Argon2id, from before profiles had a
+/// master key.
+pub fn write_passphrase_key_file(
+    loc: &str,
+    sk: &RsaPrivateKey,
+    passphrase: &[u8],
+) -> Result<(), Error> {
+    let nonce = ski::nonce();
+    let salt = ski::salt();
+    let pem = sk.to_pkcs8_pem(LineEnding::LF)?;
+    let key = ski::derive_key(passphrase, &salt)?;
+    let file = json!({
+        "pem": ski::encrypt_gcm(pem.as_bytes(), &key, &nonce)?,
+        "nonce": nonce,
+        "salt": salt,
+    });
+    write_rsa_key_file(loc, file)
+}
+
+/// A message as the messages database held it before it had any optional fields.
+pub fn message(server_id: &str, chat_id: &str, text: &str) -> Result<Vec<u8>, Error> {
+    let sent = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?;
+    let message = json!({
+        "server_id": server_id,
+        "sender_id": null,
+        "chat_id": chat_id,
+        "message": text,
+        "timestamp": { "secs_since_epoch": sent.as_secs(), "nanos_since_epoch": 0 },
+    });
+    Ok(serde_json::to_vec(&message)?)
+}
+
+/// Stores `value` under `id` in the profile's entry database `db`, sealed under `key`.
+fn write_entry(loc: &str, db: &str, id: &str, value: &[u8], key: &[u8]) -> Result<(), Error> {
+    let store = DbConfig::default().open(config_dir(loc)?.join(db))?;
+    let nonce = ski::nonce();
+    let entry = json!({
+        "nonce": nonce,
+        "value": ski::encrypt_gcm(value, key, &nonce)?,
+    });
+    store.insert("entries", id.as_bytes(), entry.to_string().as_bytes())?;
+    store.flush()
+}
+
+/// An entry sealed with the raw passkey, from before entry databases had a salt.
+pub fn write_unsalted_entry(
+    loc: &str,
+    db: &str,
+    id: &str,
+    value: &[u8],
+    passkey: &[u8],
+) -> Result<(), Error> {
+    write_entry(loc, db, id, value, passkey)
+}
+
+/// An entry under the passphrase run through Argon2id with the database's salt, from
+/// before profiles had a master key. The salt is made on first use.
+pub fn write_passphrase_entry(
+    loc: &str,
+    db: &str,
+    id: &str,
+    value: &[u8],
+    passphrase: &[u8],
+) -> Result<(), Error> {
+    let salt = {
+        let store = DbConfig::default().open(config_dir(loc)?.join(db))?;
+        match store.get("kdf", b"salt")? {
+            Some(salt) => salt,
+            None => {
+                let salt = ski::salt();
+                store.insert("kdf", b"salt", &salt)?;
+                store.flush()?;
+                salt
+            }
+        }
+    };
+    write_entry(loc, db, id, value, &ski::derive_key(passphrase, &salt)?)
+}
+
+/// SHA-256 over `label` and `fields`, each prefixed with its length, as protocol 5
+/// hashed handshake transcripts.
+fn transcript(label: &[u8], fields: &[&[u8]]) -> Vec<u8> {
+    let mut hash = Sha256::new();
+    for field in std::iter::once(label).chain(fields.iter().copied()) {
+        hash.update((field.len() as u64).to_be_bytes());
+        hash.update(field);
+    }
+    hash.finalize().to_vec()
+}
+
+/// Parses `capabilities` the way protocol 5 did, into a closed set.
+fn v5_capabilities(capabilities: &serde_json::Value) -> Result<(), Error> {
+    for capability in capabilities.as_array().ok_or("Capabilities aren't a list")? {
+        if !V5_CAPABILITIES.iter().any(|known| capability == known) {
+            Err(format!("unknown variant {}", capability))?;
+        }
+    }
+    Ok(())
+}
+
+/// A server answering handshakes as protocol `version` did: 0 from before versioning,
+/// 2 with RSA-OAEP, 3 with ephemeral keys but before capabilities and message limits
+/// were advertised, 5 signing the transcript and refusing capabilities it doesn't
+/// know. It serves nothing past the handshake.
+#[derive(Clone)]
+pub struct LegacyServer {
+    key: RsaPrivateKey,
+    version: u32,
+    challenge: String,
+    session_key: Option<Vec<u8>>,
+}
+impl LegacyServer {
+    pub fn new(version: u32) -> Result<Self, Error> {
+        Ok(LegacyServer {
+            key: pki::gen_key()?,
+            version,
+            challenge: String::new(),
+            session_key: None,
+        })
+    }
+
+    fn challenge_response(&mut self, params: serde_json::Value) -> Result<serde_json::Value, Error> {
+        let server_challenge = params["server_challenge"]
+            .as_str()
+            .ok_or("No server challenge")?;
+        if self.version >= 5 {
+            v5_capabilities(&params["capabilities"])?;
+        }
+        let mut response = json!({
+            "pub_key": self.key.to_public_key(),
+            "signiture": pki::sign_message(&self.key, server_challenge.as_bytes()),
+        });
+        match self.version {
+            0 => {}
+            // the session key followed under the client's RSA key; it's left out since
+            // current clients stop at the version
+            2 => response["protocol_version"] = json!(2),
+            3 | 5 => {
+                let client_ephemeral: X25519PublicKey =
+                    serde_json::from_value(params["ephemeral_key"].clone())?;
+                let secret = EphemeralSecret::random_from_rng(OsRng);
+                let ephemeral_key = X25519PublicKey::from(&secret);
+                let mut signed = b"carapace ephemeral keys:".to_vec();
+                signed.extend_from_slice(server_challenge.as_bytes());
+                signed.extend_from_slice(client_ephemeral.as_bytes());
+                signed.extend_from_slice(ephemeral_key.as_bytes());
+                let shared_secret = secret.diffie_hellman(&client_ephemeral);
+                let (key, nonce) = ski::derive_session_key(shared_secret.as_bytes())?;
+                let key_confirmation = ski::encrypt_gcm(server_challenge.as_bytes(), &key, &nonce)?;
+                response["protocol_version"] = json!(self.version);
+                response["ephemeral_key"] = json!(ephemeral_key);
+                response["ephemeral_signature"] = json!(pki::sign_message(&self.key, &signed));
+                response["key_confirmation"] = json!(key_confirmation);
+                self.session_key = Some(key);
+                if self.version == 5 {
+                    let max_message_bytes: u64 = 64 * 1024;
+                    let capabilities = json!(["Push"]);
+                    let server_transcript = transcript(
+                        b"carapace server transcript",
+                        &[
+                            &self.client_transcript(&params)?,
+                            self.key.to_public_key().to_pkcs1_der()?.as_bytes(),
+                            b"Rsa2048",
+                            b"",
+                            &5u32.to_be_bytes(),
+                            ephemeral_key.as_bytes(),
+                            &key_confirmation,
+                            &max_message_bytes.to_be_bytes(),
+                            &serde_json::to_vec(&capabilities)?,
+                        ],
+                    );
+                    response["key_type"] = json!("Rsa2048");
+                    response["max_message_bytes"] = json!(max_message_bytes);
+                    response["capabilities"] = capabilities;
+                    response["transcript_signature"] =
+                        json!(pki::sign_message(&self.key, &server_transcript));
+                }
+            }
+            version => Err(format!("No frozen server for protocol version {}", version))?,
+        }
+        Ok(response)
+    }
+
+    /// The client's transcript of the handshake, from the fields it sent.
+    fn client_transcript(&self, params: &serde_json::Value) -> Result<Vec<u8>, Error> {
+        let pub_key: RsaPublicKey = serde_json::from_value(params["pub_key"].clone())?;
+        let ephemeral_key: X25519PublicKey =
+            serde_json::from_value(params["ephemeral_key"].clone())?;
+        let key_type = params["key_type"].as_str().ok_or("No key type")?;
+        if key_type != "Rsa2048" {
+            Err("This fixture only checks RSA signatures")?;
+        }
+        let protocol_version = params["protocol_version"].as_u64().ok_or("No version")? as u32;
+        Ok(transcript(
+            b"carapace client transcript",
+            &[
+                self.challenge.as_bytes(),
+                params["server_challenge"].as_str().ok_or("No server challenge")?.as_bytes(),
+                pub_key.to_pkcs1_der()?.as_bytes(),
+                key_type.as_bytes(),
+                b"",
+                ephemeral_key.as_bytes(),
+                &serde_json::to_vec(&params["capabilities"])?,
+                &protocol_version.to_be_bytes(),
+            ],
+        ))
+    }
+}
+impl LegacyServer {
+    /// Answers the one request a session at protocol 5 makes on connecting, `SUBSCRIBE`,
+    /// sealed under the session key like any other.
+    fn encrypted_request(&self, params: serde_json::Value) -> Result<serde_json::Value, Error> {
+        let key = self.session_key.as_ref().ok_or("No session")?;
+        let data: Vec<u8> = serde_json::from_value(params["data"].clone())?;
+        let request: serde_json::Value = serde_json::from_slice(&ski::open_gcm(&data, key)?)?;
+        let result = match request["method"].as_str() {
+            Some(SUBSCRIBE) if self.version >= 5 => json!({
+                "result": null,
+                "error": null,
+                "id": request["id"],
+            }),
+            _ => Err(Error::rpc(RpcErrorCode::MethodNotFound, "Invalid rpc method"))?,
+        };
+        Ok(json!(ski::seal_gcm(&serde_json::to_vec(&result)?, key)?))
+    }
+}
+impl Handler for LegacyServer {
+    async fn handle(&mut self, request: Request) -> Response {
+        let result = match request.method.as_str() {
+            START_SERVER_HANDSHAKE => {
+                self.challenge = uuid::Uuid::new_v4().to_string();
+                Ok(json!(self.challenge))
+            }
+            CLIENT_CHALLENGE_RESPONSE => self.challenge_response(request.params),
+            ENCRYPTED_REQUEST => self.encrypted_request(request.params),
+            _ => Err(Error::rpc(RpcErrorCode::MethodNotFound, "Invalid rpc method")),
+        };
+        match result {
+            Ok(result) => Response::new(result, None, request.id),
+            Err(e) => Response::new(
+                json!(null),
+                Some(RpcError {
+                    message: e.to_string(),
+                    code: e.rpc_code().unwrap_or(RpcErrorCode::InvalidRequest),
+                }),
+                request.id,
+            ),
+        }
+    }
+}
+
+/// A client at protocol `version`: 2 sends no ephemeral key, 3 predates capabilities,
+/// 5 signs the transcript and refuses capabilities it doesn't know.
+pub struct LegacyClient {
+    key: RsaPrivateKey,
+    version: u32,
+}
+impl LegacyClient {
+    pub fn new(version: u32) -> Result<Self, Error> {
+        Ok(LegacyClient {
+            key: pki::gen_key()?,
+            version,
+        })
+    }
+
+    pub fn pub_key(&self) -> RsaPublicKey {
+        self.key.to_public_key()
+    }
+
+    pub fn start_handshake() -> Request {
+        Request::new(START_SERVER_HANDSHAKE.to_string(), json!(null))
+    }
+
+    /// Answers the server's `challenge`. Ephemeral secrets are dropped, these clients
+    /// never read the session that follows.
+    pub fn challenge_response(&self, challenge: &str) -> Result<Request, Error> {
+        let mut params = json!({
+            "pub_key": self.key.to_public_key(),
+            "signiture": pki::sign_message(&self.key, challenge.as_bytes()),
+            "server_challenge": uuid::Uuid::new_v4().to_string(),
+        });
+        match self.version {
+            2 => {}
+            3 => {
+                let secret = EphemeralSecret::random_from_rng(OsRng);
+                params["key_type"] = json!("Rsa2048");
+                params["ephemeral_key"] = json!(X25519PublicKey::from(&secret));
+            }
+            5 => {
+                let ephemeral_key = X25519PublicKey::from(&EphemeralSecret::random_from_rng(OsRng));
+                let capabilities = json!(["Push"]);
+                let client_transcript = transcript(
+                    b"carapace client transcript",
+                    &[
+                        challenge.as_bytes(),
+                        params["server_challenge"].as_str().unwrap_or_default().as_bytes(),
+                        self.key.to_public_key().to_pkcs1_der()?.as_bytes(),
+                        b"Rsa2048",
+                        b"",
+                        ephemeral_key.as_bytes(),
+                        &serde_json::to_vec(&capabilities)?,
+                        &5u32.to_be_bytes(),
+                    ],
+                );
+                params["key_type"] = json!("Rsa2048");
+                params["ephemeral_key"] = json!(ephemeral_key);
+                params["capabilities"] = capabilities;
+                params["protocol_version"] = json!(5);
+                params["transcript_signature"] =
+                    json!(pki::sign_message(&self.key, &client_transcript));
+            }
+            version => Err(format!("No frozen client for protocol version {}", version))?,
+        }
+        Ok(Request::new(CLIENT_CHALLENGE_RESPONSE.to_string(), params))
+    }
+
+    /// Reads the server's answer to the challenge response as far as this version
+    /// parsed it before going on.
+    pub fn read_answer(&self, answer: &serde_json::Value) -> Result<(), Error> {
+        if self.version >= 5 {
+            v5_capabilities(&answer["capabilities"])?;
+        }
+        Ok(())
+    }
+
+    /// A ping encrypted for the server with PKCS#1 v1.5, as protocol 1 sent requests.
+    pub fn pkcs1v15_ping(server_pub_key: &RsaPublicKey) -> Result<Request, Error> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let ping = json!({ "method": PING, "params": null, "id": id }).to_string();
+        let data = server_pub_key.encrypt(&mut OsRng, Pkcs1v15Encrypt, ping.as_bytes())?;
+        let params = json!({ "enc_type": "RsaPkcs1v15", "data": data });
+        Ok(Request::new(ENCRYPTED_REQUEST.to_string(), params))
+    }
+}
@@ -0,0 +1,356 @@
+//! Compatibility matrix between this build and what earlier releases wrote: handshakes
+//! with peers at older protocol versions, and profiles left behind by older clients.
+//! Each cell states whether it should work or be refused, and the test fails with the
+//! whole matrix when any cell disagrees, so a deliberate format change shows exactly
+//! which combinations it breaks.
+
+mod legacy;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use async_std::sync::RwLock;
+use async_std::task;
+
+use crate::server::handler::ServerHandler;
+use crate::server::{start_server_with_handle, Server, ServerConfig};
+use crate::shared::pki;
+use crate::shared::rpc::{Handler, Response};
+use crate::shared::rpc_models::Capability;
+use crate::Error;
+
+use self::legacy::{LegacyClient, LegacyServer};
+use super::db::ClientDatabase;
+use super::models::{Message, ServerModel};
+use super::Client;
+
+const PASSPHRASE: &[u8] = b"example key1";
+// the file name older clients kept messages under
+const MESSAGES_DB: &str = "messages.db";
+
+#[derive(Clone, Copy)]
+enum Expected {
+    Works,
+    /// Refused with an error containing the text.
+    Refused(&'static str),
+}
+
+struct Cell {
+    axis: &'static str,
+    current: &'static str,
+    legacy: &'static str,
+    expected: Expected,
+    run: fn() -> Result<(), Error>,
+}
+
+/// Runs `cells` and lays them out one per line with what they did. Returns the table
+/// and whether every cell did what it was expected to.
+fn run_matrix(cells: &[Cell]) -> (String, bool) {
+    let mut table = format!(
+        "{:<10} {:<28} {:<36} {:<10} {}\n",
+        "axis", "current", "legacy", "expected", "got"
+    );
+    let mut passed = true;
+    for cell in cells {
+        let outcome = (cell.run)();
+        let ok = match (cell.expected, &outcome) {
+            (Expected::Works, Ok(())) => true,
+            (Expected::Refused(reason), Err(e)) => e.to_string().contains(reason),
+            _ => false,
+        };
+        passed &= ok;
+        let expected = match cell.expected {
+            Expected::Works => "works",
+            Expected::Refused(_) => "refused",
+        };
+        let got = match outcome {
+            Ok(()) => String::from("works"),
+            Err(e) => format!("refused: {}", e),
+        };
+        table.push_str(&format!(
+            "{:<10} {:<28} {:<36} {:<10} {}{}\n",
+            cell.axis,
+            cell.current,
+            cell.legacy,
+            expected,
+            if ok { "" } else { "BROKEN " },
+            got
+        ));
+    }
+    (table, passed)
+}
+
+fn server_model(port: u16) -> ServerModel {
+    ServerModel::new(
+        String::from("compat"),
+        vec![],
+        vec![],
+        IpAddr::V4([127, 0, 0, 1].into()),
+        port,
+    )
+}
+
+fn client_to_current_server() -> Result<(), Error> {
+    let loc = "client_compat_current_server";
+    let _ = std::fs::remove_dir_all(ClientDatabase::base_dir(loc));
+    let mut client = Client::with_location(loc, PASSPHRASE.to_vec(), None)?;
+    let server = Server::new(pki::gen_key()?, vec![client.private_key.to_public_key()], None);
+    let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+    let pinged = task::block_on(async {
+        let handle = start_server_with_handle(handler, String::from("127.0.0.1"), 0).await?;
+        let server_id = client.db.server_db.save_entry(server_model(handle.local_addr().port()))?;
+        let pinged = match client.server_connect(&server_id).await {
+            Ok(()) => client.server_ping(server_id.as_str()).await,
+            Err(e) => Err(e),
+        };
+        handle.shutdown(None).await?;
+        pinged
+    });
+    drop(client);
+    std::fs::remove_dir_all(ClientDatabase::base_dir(loc))?;
+    pinged
+}
+
+/// Connects a fresh client to a server at protocol `version`, and hands it to `check`
+/// once connected.
+fn client_to_legacy_server(
+    version: u32,
+    check: impl FnOnce(&Client) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let loc = format!("client_compat_server_v{}", version);
+    let _ = std::fs::remove_dir_all(ClientDatabase::base_dir(&loc));
+    let mut client = Client::with_location(&loc, PASSPHRASE.to_vec(), None)?;
+    let handler = LegacyServer::new(version)?;
+    let connected = task::block_on(async {
+        let handle = start_server_with_handle(handler, String::from("127.0.0.1"), 0).await?;
+        let server_id = client.db.server_db.save_entry(server_model(handle.local_addr().port()))?;
+        let connected = client.server_connect(&server_id).await;
+        handle.shutdown(None).await?;
+        connected
+    })
+    .and_then(|()| check(&client));
+    drop(client);
+    std::fs::remove_dir_all(ClientDatabase::base_dir(&loc))?;
+    connected
+}
+
+/// Runs a legacy client's handshake against a current server, returning the server's
+/// answer to the challenge response.
+fn legacy_client_handshake(
+    client: &LegacyClient,
+    handler: &mut ServerHandler,
+) -> Result<Response, Error> {
+    task::block_on(async {
+        let challenge = handler.handle(LegacyClient::start_handshake()).await;
+        let challenge: String = serde_json::from_value(challenge.into_result()?)?;
+        Ok(handler.handle(client.challenge_response(&challenge)?).await)
+    })
+}
+
+/// A current server that still answers clients which only sign the bare challenge, as
+/// one has to be configured to for them.
+fn legacy_server_config() -> Option<ServerConfig> {
+    Some(ServerConfig {
+        allow_legacy_handshake: true,
+        ..ServerConfig::default()
+    })
+}
+
+fn legacy_client_to_current_server(version: u32) -> Result<(), Error> {
+    legacy_client_to_server(version, legacy_server_config())
+}
+
+fn legacy_client_to_server(version: u32, config: Option<ServerConfig>) -> Result<(), Error> {
+    let client = LegacyClient::new(version)?;
+    let server = Server::new(pki::gen_key()?, vec![client.pub_key()], config);
+    let mut handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+    let answer = legacy_client_handshake(&client, &mut handler)?.into_result()?;
+    client.read_answer(&answer)
+}
+
+fn pkcs1v15_request_to_current_server() -> Result<(), Error> {
+    let client = LegacyClient::new(3)?;
+    let server_key = pki::gen_key()?;
+    let server_pub_key = server_key.to_public_key();
+    let server = Server::new(server_key, vec![client.pub_key()], legacy_server_config());
+    let mut handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+    legacy_client_handshake(&client, &mut handler)?.into_result()?;
+    let request = LegacyClient::pkcs1v15_ping(&server_pub_key)?;
+    task::block_on(handler.handle(request)).into_result()?;
+    Ok(())
+}
+
+/// Opens a profile some `write` left at `loc` and checks the key and message it wrote
+/// came through the migration.
+fn open_profile(
+    loc: &str,
+    write: impl FnOnce(&rsa::RsaPrivateKey) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let base = ClientDatabase::base_dir(loc);
+    let _ = std::fs::remove_dir_all(&base);
+    let key = pki::gen_key()?;
+    write(&key)?;
+    let opened = (|| -> Result<(), Error> {
+        let client = Client::with_location(loc, PASSPHRASE.to_vec(), None)?;
+        if client.private_key != key {
+            Err("Key file came back with a different key")?;
+        }
+        let message = client.db.message_db.get_entry::<Message>("legacy")?;
+        if message.message() != "hi" {
+            Err("Message came back changed")?;
+        }
+        Ok(())
+    })();
+    std::fs::remove_dir_all(&base)?;
+    opened
+}
+
+fn unsalted_profile() -> Result<(), Error> {
+    let loc = "client_compat_unsalted_profile";
+    open_profile(loc, |key| {
+        legacy::write_unsalted_key_file(loc, key, PASSPHRASE)?;
+        let message = legacy::message("server", "chat", "hi")?;
+        legacy::write_unsalted_entry(loc, MESSAGES_DB, "legacy", &message, PASSPHRASE)
+    })
+}
+
+fn passphrase_profile() -> Result<(), Error> {
+    let loc = "client_compat_passphrase_profile";
+    open_profile(loc, |key| {
+        legacy::write_passphrase_key_file(loc, key, PASSPHRASE)?;
+        let message = legacy::message("server", "chat", "hi")?;
+        legacy::write_passphrase_entry(loc, MESSAGES_DB, "legacy", &message, PASSPHRASE)
+    })
+}
+
+fn passphrase_profile_wrong_passphrase() -> Result<(), Error> {
+    let loc = "client_compat_wrong_passphrase";
+    let base = ClientDatabase::base_dir(loc);
+    let _ = std::fs::remove_dir_all(&base);
+    legacy::write_passphrase_key_file(loc, &pki::gen_key()?, PASSPHRASE)?;
+    let opened = Client::with_location(loc, b"wrong passphrase".to_vec(), None).map(|_| ());
+    std::fs::remove_dir_all(&base)?;
+    opened
+}
+
+const FORWARD_SECRECY: &str = "predates forward secret";
+
+fn cells() -> Vec<Cell> {
+    vec![
+        Cell {
+            axis: "wire",
+            current: "client v3",
+            legacy: "current server",
+            expected: Expected::Works,
+            run: client_to_current_server,
+        },
+        Cell {
+            axis: "wire",
+            current: "client v3",
+            legacy: "server v0, unversioned",
+            expected: Expected::Refused(FORWARD_SECRECY),
+            run: || client_to_legacy_server(0, |_| Ok(())),
+        },
+        Cell {
+            axis: "wire",
+            current: "client v3",
+            legacy: "server v2, RSA-OAEP session key",
+            expected: Expected::Refused(FORWARD_SECRECY),
+            run: || client_to_legacy_server(2, |_| Ok(())),
+        },
+        Cell {
+            axis: "wire",
+            current: "client v3",
+            legacy: "server v3, no capabilities",
+            expected: Expected::Works,
+            run: || {
+                client_to_legacy_server(3, |client| {
+                    let server_id = client.server_id.as_ref().ok_or("No server")?;
+                    let server = &client.connection_state(server_id.as_str())?.server;
+                    if !server.capabilities.is_empty() {
+                        Err("Server without capabilities was given some")?;
+                    }
+                    Ok(())
+                })
+            },
+        },
+        Cell {
+            axis: "wire",
+            current: "client v6",
+            legacy: "server v5, closed capabilities",
+            expected: Expected::Works,
+            run: || {
+                client_to_legacy_server(5, |client| {
+                    let server_id = client.server_id.as_ref().ok_or("No server")?;
+                    let state = client.connection_state(server_id.as_str())?;
+                    if state.server.capabilities.contains(&Capability::MsgPack) {
+                        Err("Server v5 was taken to frame sessions in MessagePack")?;
+                    }
+                    Ok(())
+                })
+            },
+        },
+        Cell {
+            axis: "wire",
+            current: "server v6",
+            legacy: "client v5, closed capabilities",
+            expected: Expected::Works,
+            run: || legacy_client_to_current_server(5),
+        },
+        Cell {
+            axis: "wire",
+            current: "server v3",
+            legacy: "client v2, no ephemeral key",
+            expected: Expected::Refused("forward secrecy"),
+            run: || legacy_client_to_current_server(2),
+        },
+        Cell {
+            axis: "wire",
+            current: "server v3",
+            legacy: "client v3, no capabilities",
+            expected: Expected::Works,
+            run: || legacy_client_to_current_server(3),
+        },
+        Cell {
+            axis: "wire",
+            current: "server v6, default config",
+            legacy: "client v3, bare challenge",
+            expected: Expected::Refused("doesn't cover the handshake"),
+            run: || legacy_client_to_server(3, None),
+        },
+        Cell {
+            axis: "wire",
+            current: "server v3",
+            legacy: "client v1, PKCS#1 v1.5 request",
+            expected: Expected::Refused("RSA-OAEP"),
+            run: pkcs1v15_request_to_current_server,
+        },
+        Cell {
+            axis: "data dir",
+            current: "open and migrate",
+            legacy: "profile without salts",
+            expected: Expected::Works,
+            run: unsalted_profile,
+        },
+        Cell {
+            axis: "data dir",
+            current: "open and migrate",
+            legacy: "profile keyed by passphrase",
+            expected: Expected::Works,
+            run: passphrase_profile,
+        },
+        Cell {
+            axis: "data dir",
+            current: "open and migrate",
+            legacy: "same, wrong passphrase",
+            expected: Expected::Refused("Wrong passphrase"),
+            run: passphrase_profile_wrong_passphrase,
+        },
+    ]
+}
+
+#[test]
+fn test_compat_matrix() {
+    let (table, passed) = run_matrix(&cells());
+    assert!(passed, "compatibility matrix broke:\n{}", table);
+}
@@ -0,0 +1,319 @@
+use std::{
+    collections::HashMap,
+    net::Shutdown,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_std::{
+    channel::{self, Sender},
+    net::TcpStream,
+    task,
+};
+
+use crate::shared::{
+    json,
+    rpc::{self, Codec, Frame, FrameWriter, Request, Response},
+    rpc_models,
+    ski::open_gcm,
+};
+use crate::Error;
+
+type Waiting = Arc<Mutex<HashMap<String, Sender<Response>>>>;
+type SessionKeys = Arc<Mutex<PushKeys>>;
+
+/// How many pushes are held back for a session key that isn't known yet.
+const MAX_HELD_PUSHES: usize = 16;
+
+/// Keys pushes may be sealed under, newest first.
+#[derive(Default)]
+struct PushKeys {
+    keys: Vec<Vec<u8>>,
+    // sealed under the key a rekey's answer is about to give us, the server switches
+    // before the answer is in
+    held: Vec<Request>,
+}
+
+/// An open session with a server. A background task reads every frame: responses go to
+/// whoever sent the matching request, requests pushed by the server to `pushes`.
+pub struct Connection {
+    stream: TcpStream,
+    writer: FrameWriter,
+    waiting: Waiting,
+    session_keys: SessionKeys,
+    pushes: Option<Sender<Request>>,
+    // set by the reader task once the stream is gone
+    closed: Arc<AtomicBool>,
+    last_frame: Arc<Mutex<Instant>>,
+}
+impl Connection {
+    /// Takes over `stream` once the session is established. Pushed requests are opened
+    /// with `session_key` if there is one, and dropped if there is nowhere to send them.
+    /// A frame over `max_frame_size` ends the connection. Requests are encoded with the
+    /// `codec` the handshake agreed on.
+    pub fn new(
+        stream: TcpStream,
+        session_key: Option<Vec<u8>>,
+        pushes: Option<Sender<Request>>,
+        max_frame_size: usize,
+        codec: Codec,
+    ) -> Self {
+        let waiting: Waiting = Arc::new(Mutex::new(HashMap::new()));
+        let session_keys: SessionKeys = Arc::new(Mutex::new(PushKeys {
+            keys: session_key.into_iter().collect(),
+            held: vec![],
+        }));
+        let closed = Arc::new(AtomicBool::new(false));
+        let last_frame = Arc::new(Mutex::new(Instant::now()));
+        task::spawn(read_frames(
+            stream.clone(),
+            waiting.clone(),
+            session_keys.clone(),
+            pushes.clone(),
+            closed.clone(),
+            last_frame.clone(),
+            max_frame_size,
+        ));
+        Connection {
+            writer: FrameWriter::with_codec(stream.clone(), codec),
+            stream,
+            waiting,
+            session_keys,
+            pushes,
+            closed,
+            last_frame,
+        }
+    }
+
+    /// Whether the server closed the stream, or it broke.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// How long ago the last frame came in, or the connection was opened if none has.
+    pub fn idle_for(&self) -> Duration {
+        self.last_frame.lock().unwrap().elapsed()
+    }
+
+    /// Opens pushes sealed under `key` from now on, and those held back for it. The
+    /// previous key is kept too, for pushes the server sealed before it switched.
+    pub fn add_session_key(&self, key: Vec<u8>) {
+        let opened: Vec<Request> = {
+            let mut session_keys = self.session_keys.lock().unwrap();
+            session_keys.keys.insert(0, key);
+            session_keys.keys.truncate(2);
+            let held = std::mem::take(&mut session_keys.held);
+            held.into_iter()
+                .filter_map(|request| open_push(request, &session_keys.keys[..1]).ok())
+                .collect()
+        };
+        if let Some(ref pushes) = self.pushes {
+            for request in opened {
+                let _ = pushes.try_send(request);
+            }
+        }
+    }
+
+    /// Sends `request` and waits for the response with the same id.
+    pub async fn call(
+        &self,
+        request: &Request,
+        timeout: Option<Duration>,
+    ) -> Result<Response, Error> {
+        let (respond, response) = channel::bounded(1);
+        self.waiting
+            .lock()
+            .unwrap()
+            .insert(request.id.clone(), respond);
+        let call = async {
+            self.writer.send(request).await?;
+            response
+                .recv()
+                .await
+                .map_err(|_| Error::from("Connection closed"))
+        };
+        let result = match timeout {
+            Some(timeout) => async_std::future::timeout(timeout, call)
+                .await
+                .unwrap_or_else(|e| Err(e.into())),
+            None => call.await,
+        };
+        self.waiting.lock().unwrap().remove(&request.id);
+        result
+    }
+}
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // ends the reader task
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+async fn read_frames(
+    mut stream: TcpStream,
+    waiting: Waiting,
+    session_keys: SessionKeys,
+    pushes: Option<Sender<Request>>,
+    closed: Arc<AtomicBool>,
+    last_frame: Arc<Mutex<Instant>>,
+    max_frame_size: usize,
+) {
+    loop {
+        let frame = match rpc::read_frame(&mut stream, max_frame_size).await {
+            Ok(Some(frame)) => frame,
+            _ => break,
+        };
+        *last_frame.lock().unwrap() = Instant::now();
+        match Codec::decode::<Frame>(&frame) {
+            Ok(Frame::Response(response)) => {
+                let respond = waiting.lock().unwrap().remove(response.id());
+                if let Some(respond) = respond {
+                    let _ = respond.try_send(response);
+                }
+            }
+            Ok(Frame::Request(request)) => {
+                let opened = {
+                    let mut session_keys = session_keys.lock().unwrap();
+                    match open_push(request.clone(), &session_keys.keys) {
+                        Err(_) if request.method == rpc_models::ENCRYPTED_REQUEST
+                            && session_keys.held.len() < MAX_HELD_PUSHES =>
+                        {
+                            session_keys.held.push(request);
+                            continue;
+                        }
+                        opened => opened,
+                    }
+                };
+                let request = match opened {
+                    Ok(request) => request,
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        continue;
+                    }
+                };
+                if let Some(ref pushes) = pushes {
+                    let _ = pushes.send(request).await;
+                }
+            }
+            Err(e) => eprintln!("Error: unreadable frame from server: {}", e),
+        }
+    }
+    closed.store(true, Ordering::SeqCst);
+    // callers still waiting see the connection close instead of hanging
+    waiting.lock().unwrap().clear();
+}
+
+/// Unwraps a push sealed under one of the session keys. Plaintext sessions have none.
+fn open_push(request: Request, session_keys: &[Vec<u8>]) -> Result<Request, Error> {
+    if session_keys.is_empty() {
+        return Ok(request);
+    }
+    if request.method != rpc_models::ENCRYPTED_REQUEST {
+        return Err("Pushed requests must be encrypted".into());
+    }
+    let params: rpc_models::EncryptedRequestParams =
+        serde_json::from_value(request.params)?;
+    let data = session_keys
+        .iter()
+        .find_map(|key| open_gcm(&params.data, key).ok())
+        .ok_or("Push isn't sealed under the session key")?;
+    json::from_slice(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::TcpListener;
+
+    use super::*;
+    use crate::shared::rpc::{read_frame, write_frame, MAX_FRAME_SIZE};
+    use crate::shared::ski;
+
+    /// Reads `count` requests, then pushes a request and answers them in reverse order,
+    /// echoing their params.
+    async fn spawn_reversing_server(count: usize) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut requests = vec![];
+            while requests.len() < count {
+                let frame = read_frame(&mut stream, MAX_FRAME_SIZE).await.unwrap().unwrap();
+                requests.push(serde_json::from_slice::<Request>(&frame).unwrap());
+            }
+            let push = Request::new("pushed".to_string(), serde_json::json!(null));
+            write_frame(&mut stream, &serde_json::to_vec(&push).unwrap()).await.unwrap();
+            for request in requests.into_iter().rev() {
+                let response = Response::new(request.params, None, request.id);
+                write_frame(&mut stream, &serde_json::to_vec(&response).unwrap()).await.unwrap();
+            }
+            // keeps the stream open until the client is done
+            let _ = read_frame(&mut stream, MAX_FRAME_SIZE).await;
+        });
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    #[test]
+    fn test_concurrent_calls() {
+        task::block_on(async {
+            let stream = spawn_reversing_server(4).await;
+            let (pushes, pushed) = channel::unbounded();
+            let connection =
+                Connection::new(stream, None, Some(pushes), MAX_FRAME_SIZE, Codec::Json);
+            let requests: Vec<Request> = (0..4)
+                .map(|i| Request::new(rpc_models::PING.to_string(), serde_json::json!(i)))
+                .collect();
+            let timeout = Some(Duration::from_secs(5));
+            let (a, b, c, d) = futures::join!(
+                connection.call(&requests[0], timeout),
+                connection.call(&requests[1], timeout),
+                connection.call(&requests[2], timeout),
+                connection.call(&requests[3], timeout),
+            );
+            for (i, response) in [a, b, c, d].into_iter().enumerate() {
+                let response = response.unwrap();
+                assert_eq!(response.id(), requests[i].id);
+                assert_eq!(response.result, serde_json::json!(i));
+            }
+            // the push in between went to the subscriber instead of a caller
+            assert_eq!(pushed.recv().await.unwrap().method, "pushed");
+            assert!(connection.waiting.lock().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_push_held_for_next_key() {
+        let (current, next) = (ski::gen_key(), ski::gen_key());
+        let sealed = |method: &str, key: &[u8]| {
+            let request = Request::new(method.to_string(), serde_json::json!(null));
+            let params = rpc_models::EncryptedRequestParams {
+                enc_type: rpc_models::EncryptionType::AesGcm,
+                data: ski::seal_gcm(&serde_json::to_vec(&request).unwrap(), key).unwrap(),
+            };
+            let push = Request::new(rpc_models::ENCRYPTED_REQUEST.to_string(), serde_json::json!(params));
+            serde_json::to_vec(&push).unwrap()
+        };
+        let (early, late) = (sealed("early", &next), sealed("late", &current));
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            task::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                // the server switched keys before the client learned the new one
+                write_frame(&mut stream, &early).await.unwrap();
+                write_frame(&mut stream, &late).await.unwrap();
+                let _ = read_frame(&mut stream, MAX_FRAME_SIZE).await;
+            });
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let (pushes, pushed) = channel::unbounded();
+            let connection =
+                Connection::new(stream, Some(current), Some(pushes), MAX_FRAME_SIZE, Codec::Json);
+            assert_eq!(pushed.recv().await.unwrap().method, "late");
+            assert!(pushed.is_empty());
+            connection.add_session_key(next);
+            assert_eq!(pushed.recv().await.unwrap().method, "early");
+        });
+    }
+}
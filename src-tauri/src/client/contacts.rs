@@ -0,0 +1,66 @@
+use rsa::pkcs8::EncodePublicKey;
+
+use crate::shared::pki::{self, get_line_ending};
+use crate::Error;
+
+use super::{
+    models::{User, UserId},
+    Client,
+};
+
+impl Client {
+    /// Saves `username` as a contact with the key in `pub_key_pem`, for when the key was
+    /// handed over out of band rather than looked up on a server. A key we already know
+    /// someone by is refused.
+    pub fn add_contact(&mut self, username: String, pub_key_pem: &str) -> Result<UserId, Error> {
+        let pub_key = pki::pub_key_from_str(pub_key_pem)?;
+        let fingerprint = pki::fingerprint(&pub_key)?;
+        let known = self
+            .db
+            .known_user_db
+            .find_entries(|user: &User| user.fingerprint().as_ref() == Some(&fingerprint))?;
+        if let Some((_, user)) = known.first() {
+            Err(format!("{} already has this key", user.username()))?;
+        }
+        // stored in our own PEM format so equal keys compare equal
+        let pub_key = pub_key.to_public_key_pem(get_line_ending())?;
+        self.db.save_user(User::new(username, pub_key))
+    }
+
+    /// The first contact named `username`. Users of different servers can share a
+    /// name, see `list_contacts` for all of them.
+    pub fn get_contact_by_username(
+        &self,
+        username: &str,
+    ) -> Result<Option<(UserId, User)>, Error> {
+        let found = self
+            .db
+            .known_user_db
+            .find_entries(|user: &User| user.username() == username)?;
+        Ok(found
+            .into_iter()
+            .next()
+            .map(|(id, user)| (UserId::from(id), user)))
+    }
+
+    /// Everyone we know, by name, leaving out ourselves.
+    pub fn list_contacts(&self) -> Result<Vec<(UserId, User)>, Error> {
+        let own = pki::fingerprint(&self.private_key.to_public_key())?;
+        let mut contacts: Vec<(UserId, User)> = self
+            .db
+            .known_user_db
+            .find_entries(|user: &User| user.fingerprint().as_ref() != Some(&own))?
+            .into_iter()
+            .map(|(id, user)| (UserId::from(id), user))
+            .collect();
+        contacts.sort_by(|(_, a), (_, b)| a.username().cmp(b.username()));
+        Ok(contacts)
+    }
+
+    /// Forgets a contact. The chats they were in stay, orphaned, and their messages
+    /// lose their sender.
+    pub fn remove_contact(&mut self, id: &UserId) -> Result<(), Error> {
+        self.db.get_user(id)?;
+        self.db.delete_contact(id)
+    }
+}
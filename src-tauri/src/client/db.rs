@@ -1,33 +1,1364 @@
-use crate::shared::{db::EntryDb, ski};
+use crate::client::export::{Transcript, TranscriptMessage};
+use crate::client::import::{ExternalArchive, ImportFormat, ImportReport, RecordError};
+use crate::client::models::{
+    Chat, ChatId, ChatPreview, Message, MessageId, ServerId, ServerModel, TrashedItem, User,
+    UserId,
+};
+use crate::shared::{
+    db::{DbConfig, EntryDb, ExportedEntry, StorageUsage},
+    kv::{self, Batch, DEFAULT_TREE},
+    models::ChatCustomization,
+    pki,
+    rpc_models::{
+        AckStatus, ChatCustomizationParams, ChatMessagePayload, DEFAULT_MAX_MESSAGE_BYTES,
+    },
+    ski,
+};
+use crate::Error;
 use directories::ProjectDirs;
+use rsa::RsaPrivateKey;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use sled::Db;
-use std::error::Error;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
 use uuid::Uuid;
+use zeroize::Zeroizing;
+
+const KNOWN_USERS_DB: &str = "known_users.db";
+const MESSAGES_DB: &str = "messages.db";
+pub const SERVER_DB: &str = "server.db";
+const CHATS_DB: &str = "chats.db";
+/// The entry databases a profile consists of, by file name.
+pub const ENTRY_DBS: [&str; 4] = [KNOWN_USERS_DB, MESSAGES_DB, SERVER_DB, CHATS_DB];
+const CHAT_PREVIEWS_TREE: &str = "chat_previews";
+const SYSTEM_CHAT_NAME: &str = "System";
+// deleted messages and chats, kept in the store they were deleted from
+const TRASH_TREE: &str = "trash";
+// messages waiting for the server, in the messages store so a message and its outbox
+// entry are written in one batch
+const OUTBOX_TREE: &str = "outbox";
+// messages their chat doesn't list yet, each written in the same batch as its message
+// and settled into the chat right after, or on open if a crash came in between
+const CHAT_LINKS_TREE: &str = "chat_links";
+// chats moved into or out of the trash, each written in the same batch as their
+// messages and settled into the chats store right after, or on open
+const CHAT_MOVES_TREE: &str = "chat_moves";
+// chat messages that arrived before we joined their chat
+const STASHED_TREE: &str = "stashed_payloads";
+// our ids of the messages a server handed out an id for, by that id, so its
+// acknowledgements can be matched up
+const SERVER_MESSAGE_IDS_TREE: &str = "server_message_ids";
+/// Most chat messages kept for chats we haven't joined. Their senders can't be checked
+/// until we do, so anyone could fill the stash otherwise.
+pub const MAX_STASHED_PAYLOADS: usize = 256;
+
+/// How long a deleted message or chat can be restored before it is purged.
+pub const DEFAULT_TRASH_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
 
 pub struct ClientDatabase {
     pub known_user_db: EntryDb,
     pub message_db: EntryDb,
     pub server_db: EntryDb,
     pub chat_db: EntryDb,
+    // previews live in a second tree of the messages store so they can be written in
+    // the same batch as messages; the lock serializes their read-modify-write
+    preview_lock: Mutex<()>,
+    // serializes settling chat links, which reads, extends and writes back chats
+    link_lock: Mutex<()>,
+    base: PathBuf,
+    message_limit: MessageLimit,
+    trash_retention: Duration,
+}
+
+/// What to do with an incoming message that is larger than the limit.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OversizePolicy {
+    Truncate,
+    Reject,
+}
+
+/// Size limit enforced on messages before they are persisted, independently of what
+/// the sender or server claim to enforce.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct MessageLimit {
+    pub max_bytes: usize,
+    pub policy: OversizePolicy,
+}
+impl Default for MessageLimit {
+    fn default() -> Self {
+        MessageLimit {
+            max_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            policy: OversizePolicy::Truncate,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientStorageUsage {
+    pub known_users: StorageUsage,
+    pub messages: StorageUsage,
+    pub servers: StorageUsage,
+    pub chats: StorageUsage,
+    /// The trash shares the message and chat stores, so its size is the bytes of its
+    /// entries, which are also part of theirs on disk.
+    #[serde(default)]
+    pub trash: StorageUsage,
+}
+
+/// A deleted entry. Its value is kept in the clear inside the trash entry, which is
+/// sealed like any other, so re-encrypting, exporting or importing the store carries
+/// it along.
+#[derive(Serialize, Deserialize)]
+struct TrashEntry {
+    item: TrashedItem,
+    deleted_at: SystemTime,
+    #[serde(default)]
+    value: serde_json::Value,
+    /// The entry as it was sealed when deleted, from trash entries that predate
+    /// `value`. Only opens while the store is still under the key it was deleted under.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    entry: Vec<u8>,
+}
+impl TrashEntry {
+    fn new(
+        item: TrashedItem,
+        deleted_at: SystemTime,
+        db: &EntryDb,
+        entry: &[u8],
+    ) -> Result<Self, Error> {
+        Ok(TrashEntry {
+            item,
+            deleted_at,
+            value: db.decrypt_value(entry)?,
+            entry: Vec::new(),
+        })
+    }
+
+    /// The entry sealed under `db`'s current key, to be put back.
+    fn restored(&self, db: &EntryDb) -> Result<Vec<u8>, Error> {
+        if self.entry.is_empty() {
+            db.encrypt_value(&self.value)
+        } else {
+            Ok(self.entry.clone())
+        }
+    }
+}
+
+/// A message in the outbox, with everything needed to send it again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    /// Fingerprints of the recipients' keys.
+    pub recipients: Vec<String>,
+    /// The sealed `ChatMessagePayload`, as forwarded.
+    pub payload: Vec<u8>,
+    pub attempts: u32,
+    pub queued_at: SystemTime,
+}
+
+/// A chat message kept until we join its chat.
+#[derive(Debug, Serialize, Deserialize)]
+struct StashedPayload {
+    payload: ChatMessagePayload,
+    received_at: SystemTime,
+}
+
+/// A chat whose messages were moved in or out of the trash, waiting for the chat itself
+/// to follow.
+#[derive(Serialize, Deserialize)]
+enum ChatMove {
+    Trash(TrashEntry),
+    Restore(TrashEntry),
+}
+
+/// A message waiting to be appended to its chat's message list.
+#[derive(Serialize, Deserialize)]
+struct ChatLink {
+    chat_id: ChatId,
+    linked_at: SystemTime,
+}
+
+/// A trashed message or chat as listed to the user or a backup.
+#[derive(Clone, Debug, Serialize)]
+pub struct TrashListing {
+    pub id: String,
+    pub item: TrashedItem,
+    pub deleted_at: SystemTime,
+}
+/// A reference from an entry in one tree to an id that no longer exists in another.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DanglingReference {
+    pub tree: &'static str,
+    pub entry_id: String,
+    pub field: &'static str,
+    pub target: String,
+}
+
+#[derive(Serialize)]
+pub struct ChatSummary {
+    pub id: ChatId,
+    pub chat: Chat,
+    pub preview: Option<ChatPreview>,
+}
+
+impl ClientStorageUsage {
+    pub fn size_on_disk(&self) -> u64 {
+        self.known_users.size_on_disk
+            + self.messages.size_on_disk
+            + self.servers.size_on_disk
+            + self.chats.size_on_disk
+    }
 }
 impl ClientDatabase {
-    pub fn new(key: &[u8]) -> Result<Self, Box<dyn Error>> {
-        let project_dirs = ProjectDirs::from("com", "carapace", "client")
+    pub fn new(master_key: &[u8]) -> Result<Self, Error> {
+        Self::with_location("client", master_key)
+    }
+
+    pub fn with_location(loc: &str, master_key: &[u8]) -> Result<Self, Error> {
+        Self::with_config(loc, master_key, DbConfig::default())
+    }
+
+    pub fn base_dir(loc: &str) -> PathBuf {
+        let project_dirs = ProjectDirs::from("com", "carapace", loc)
             .ok_or("Could not find project directories")
             .unwrap();
-        let base = project_dirs.config_dir();
-        let known_user_db = EntryDb::new(key, sled::open(base.join("known_users.db"))?);
-        let message_db = EntryDb::new(key, sled::open(base.join("messages.db"))?);
-        let server_db = EntryDb::new(key, sled::open(base.join("server.db"))?);
-        let chat_db = EntryDb::new(key, sled::open(base.join("chats.db"))?);
-        Ok(Self {
+        project_dirs.config_dir().to_path_buf()
+    }
+
+    /// Opens the profile's databases, each under its own key derived from `master_key`.
+    pub fn with_config(loc: &str, master_key: &[u8], config: DbConfig) -> Result<Self, Error> {
+        let base = Self::base_dir(loc);
+        let open = |name: &str| {
+            EntryDb::with_key(&Self::tree_key(master_key, name)?, config.open(base.join(name))?)
+        };
+        let known_user_db = open(KNOWN_USERS_DB)?;
+        let message_db = open(MESSAGES_DB)?;
+        let server_db = open(SERVER_DB)?;
+        let chat_db = open(CHATS_DB)?;
+        let db = Self {
             known_user_db,
             message_db,
             server_db,
             chat_db,
+            preview_lock: Mutex::new(()),
+            link_lock: Mutex::new(()),
+            base,
+            message_limit: MessageLimit::default(),
+            trash_retention: DEFAULT_TRASH_RETENTION,
+        };
+        db.settle_chat_links()?;
+        db.settle_chat_moves()?;
+        Ok(db)
+    }
+
+    /// Opens one of the `ENTRY_DBS` on its own, e.g. to repair it before the profile
+    /// is unlocked.
+    pub fn open_entry_db(loc: &str, name: &str, master_key: &[u8]) -> Result<EntryDb, Error> {
+        let store = DbConfig::default().open(Self::base_dir(loc).join(name))?;
+        EntryDb::with_key(&Self::tree_key(master_key, name)?, store)
+    }
+
+    /// Opens one of the `ENTRY_DBS` as profiles were keyed before they had a master
+    /// key, directly by the passphrase.
+    pub fn open_legacy_entry_db(
+        loc: &str,
+        name: &str,
+        passphrase: &[u8],
+    ) -> Result<EntryDb, Error> {
+        EntryDb::new(passphrase, DbConfig::default().open(Self::base_dir(loc).join(name))?)
+    }
+
+    /// The key one of the `ENTRY_DBS` is encrypted under. Each database gets its own, so
+    /// one leaking doesn't expose the others.
+    pub fn tree_key(master_key: &[u8], name: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+        ski::derive_subkey(master_key, format!("carapace entry db {}", name).as_bytes())
+    }
+
+    /// Moves one of the `ENTRY_DBS` from the passphrase it was keyed by over to its key
+    /// under `master_key`. Returns whether anything was done; a database already moved
+    /// is left alone, so an interrupted migration can simply be run again.
+    pub fn adopt_master_key(
+        loc: &str,
+        name: &str,
+        passphrase: &[u8],
+        master_key: &[u8],
+        config: &DbConfig,
+    ) -> Result<bool, Error> {
+        let store = config.open(Self::base_dir(loc).join(name))?;
+        if EntryDb::uses_data_key(&*store)? {
+            return Ok(false);
+        }
+        let mut db = EntryDb::new(passphrase, store)?;
+        db.set_key(&Self::tree_key(master_key, name)?)?;
+        Ok(true)
+    }
+
+    /// The profile's entry databases, named as in `ENTRY_DBS`.
+    pub fn entry_dbs(&self) -> [(&'static str, &EntryDb); 4] {
+        [
+            (KNOWN_USERS_DB, &self.known_user_db),
+            (MESSAGES_DB, &self.message_db),
+            (SERVER_DB, &self.server_db),
+            (CHATS_DB, &self.chat_db),
+        ]
+    }
+
+    pub fn entry_dbs_mut(&mut self) -> [(&'static str, &mut EntryDb); 4] {
+        [
+            (KNOWN_USERS_DB, &mut self.known_user_db),
+            (MESSAGES_DB, &mut self.message_db),
+            (SERVER_DB, &mut self.server_db),
+            (CHATS_DB, &mut self.chat_db),
+        ]
+    }
+
+    /// Re-encrypts every database under its key from `new_master_key`, calling
+    /// `checkpoint` with each one's name once it's done. Each database is rewritten in
+    /// one batch, so one that can't be decrypted in full is left as it was and stops
+    /// the rest.
+    pub fn reencrypt(
+        &mut self,
+        new_master_key: &[u8],
+        checkpoint: &mut dyn FnMut(&str) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        for (name, db) in self.entry_dbs_mut() {
+            db.set_key(&Self::tree_key(new_master_key, name)?)?;
+            checkpoint(name)?;
+        }
+        Ok(())
+    }
+
+    /// Every database's entries in the clear, by file name, see `EntryDb::export_entries`.
+    pub fn export_entries(&self) -> Result<Vec<(String, Vec<ExportedEntry>)>, Error> {
+        let mut exported = vec![];
+        for (name, db) in self.entry_dbs() {
+            exported.push((name.to_string(), db.export_entries()?));
+        }
+        Ok(exported)
+    }
+
+    /// Writes what `export_entries` gave, each database's entries in one batch. Entries
+    /// replace ones with the same id, see `EntryDb::import_entries`.
+    pub fn import_entries(
+        &self,
+        databases: Vec<(String, Vec<ExportedEntry>)>,
+    ) -> Result<(), Error> {
+        for (name, entries) in databases {
+            let (_, db) = self
+                .entry_dbs()
+                .into_iter()
+                .find(|(db_name, _)| *db_name == name)
+                .ok_or_else(|| format!("Unknown database {}", name))?;
+            db.import_entries(entries)?;
+        }
+        // links carried over from the other profile get settled like any left behind
+        self.settle_chat_links()
+    }
+
+    pub fn set_message_limit(&mut self, limit: MessageLimit) {
+        self.message_limit = limit;
+    }
+
+    pub fn set_trash_retention(&mut self, retention: Duration) {
+        self.trash_retention = retention;
+    }
+
+    fn enforce_message_limit(&self, message: &mut Message) -> Result<(), Error> {
+        let MessageLimit { max_bytes, policy } = self.message_limit;
+        if message.message().len() > max_bytes {
+            match policy {
+                OversizePolicy::Truncate => message.truncate(max_bytes),
+                OversizePolicy::Reject => Err(format!(
+                    "Message of {} bytes exceeds the limit of {} bytes",
+                    message.message().len(),
+                    max_bytes
+                ))?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn storage_usage(&self) -> Result<ClientStorageUsage, Error> {
+        let mut trash = StorageUsage::default();
+        for db in [&self.message_db, &self.chat_db] {
+            for (_, entry) in db.store().iter(TRASH_TREE)? {
+                trash.entries += 1;
+                trash.size_on_disk += entry.len() as u64;
+            }
+        }
+        Ok(ClientStorageUsage {
+            known_users: self.known_user_db.storage_usage()?,
+            messages: self.message_db.storage_usage()?,
+            servers: self.server_db.storage_usage()?,
+            chats: self.chat_db.storage_usage()?,
+            trash,
+        })
+    }
+
+    /// Removes the expired entries of every database, see `EntryDb::purge_expired`.
+    /// Returns how many were removed in all.
+    pub fn purge_expired_all(&self) -> Result<usize, Error> {
+        let mut purged = 0;
+        for db in [&self.known_user_db, &self.message_db, &self.server_db, &self.chat_db] {
+            purged += db.purge_expired()?;
+        }
+        Ok(purged)
+    }
+
+    pub fn get_user(&self, id: &UserId) -> Result<User, Error> {
+        self.known_user_db.get_entry(id.as_str())
+    }
+
+    pub fn save_user(&self, user: User) -> Result<UserId, Error> {
+        Ok(UserId::from(self.known_user_db.save_entry(user)?))
+    }
+
+    pub fn get_message(&self, id: &MessageId) -> Result<Message, Error> {
+        self.message_db.get_entry(id.as_str())
+    }
+
+    pub fn save_message(&self, mut message: Message) -> Result<MessageId, Error> {
+        self.enforce_message_limit(&mut message)?;
+        Ok(MessageId::from(self.message_db.save_entry(message)?))
+    }
+
+    /// Saves a message and appends it to its chat, refreshing the chat's preview in
+    /// the same batch.
+    pub fn add_message(&self, message: Message) -> Result<MessageId, Error> {
+        self.add_message_with(message, None)
+    }
+
+    /// Saves a message of ours along with its outbox entry, so it is either stored and
+    /// queued for sending or not at all, and appends it to its chat.
+    pub fn add_outgoing_message(
+        &self,
+        message: Message,
+        entry: &OutboxEntry,
+    ) -> Result<MessageId, Error> {
+        self.add_message_with(message, Some(entry))
+    }
+
+    fn add_message_with(
+        &self,
+        message: Message,
+        outbox: Option<&OutboxEntry>,
+    ) -> Result<MessageId, Error> {
+        let id = self.write_message(message, outbox)?;
+        self.settle_chat_links()?;
+        Ok(id)
+    }
+
+    /// Writes a message, its preview, outbox entry and chat link in one batch. The
+    /// chat itself is in another store, so it's extended by `settle_chat_links`.
+    fn write_message(
+        &self,
+        mut message: Message,
+        outbox: Option<&OutboxEntry>,
+    ) -> Result<MessageId, Error> {
+        self.enforce_message_limit(&mut message)?;
+        let id = MessageId::from(Uuid::new_v4().to_string());
+        let chat_id = message.chat_id().clone();
+        let preview = ChatPreview::new(id.clone(), &message);
+        let link = ChatLink {
+            chat_id: chat_id.clone(),
+            linked_at: SystemTime::now(),
+        };
+        let mut batch = Batch::default();
+        batch.insert(
+            DEFAULT_TREE,
+            id.as_str().as_bytes(),
+            self.message_db.encrypt_value(&message)?,
+        );
+        batch.insert(
+            CHAT_LINKS_TREE,
+            id.as_str().as_bytes(),
+            self.message_db.encrypt_value(&link)?,
+        );
+        if let Some(entry) = outbox {
+            batch.insert(
+                OUTBOX_TREE,
+                id.as_str().as_bytes(),
+                self.message_db.encrypt_value(entry)?,
+            );
+        }
+        self.index_server_message_id(&mut batch, &id, &message)?;
+        let _guard = self.preview_lock.lock().unwrap();
+        let replace = match self.chat_preview(&chat_id)? {
+            Some(current) => current.timestamp <= preview.timestamp,
+            None => true,
+        };
+        if replace {
+            batch.insert(
+                CHAT_PREVIEWS_TREE,
+                chat_id.as_str().as_bytes(),
+                self.message_db.encrypt_value(&preview)?,
+            );
+        }
+        self.message_db.store().apply_batch(batch)?;
+        Ok(id)
+    }
+
+    /// Appends the messages written since the last call to their chats' message lists,
+    /// oldest first. A message its chat already lists isn't added again, so settling
+    /// again after a crash part way through is harmless.
+    fn settle_chat_links(&self) -> Result<(), Error> {
+        let _guard = self.link_lock.lock().unwrap();
+        let mut links = vec![];
+        for (id, link) in self.message_db.store().iter(CHAT_LINKS_TREE)? {
+            let link: ChatLink = self.message_db.decrypt_value(&link)?;
+            links.push((MessageId::from(String::from_utf8(id)?), link));
+        }
+        if links.is_empty() {
+            return Ok(());
+        }
+        links.sort_by_key(|(_, link)| link.linked_at);
+        let mut chats: HashMap<ChatId, Chat> = HashMap::new();
+        let mut settled = Batch::default();
+        for (message_id, link) in links {
+            settled.remove(CHAT_LINKS_TREE, message_id.as_str().as_bytes());
+            if !chats.contains_key(&link.chat_id) {
+                // the chat was deleted, or never existed, so there's no list to extend
+                if !self.chat_db.contains(link.chat_id.as_str())? {
+                    continue;
+                }
+                chats.insert(link.chat_id.clone(), self.get_chat(&link.chat_id)?);
+            }
+            let chat = chats.get_mut(&link.chat_id).unwrap();
+            if !chat.message_ids().contains(&message_id) {
+                chat.push_message(message_id);
+            }
+        }
+        for (chat_id, chat) in chats {
+            self.chat_db.update_entry(chat_id.as_str(), chat)?;
+        }
+        self.message_db.store().apply_batch(settled)
+    }
+
+    /// Messages waiting for the server, oldest first.
+    pub fn outbox(&self) -> Result<Vec<(MessageId, OutboxEntry)>, Error> {
+        let mut entries = vec![];
+        for (id, entry) in self.message_db.store().iter(OUTBOX_TREE)? {
+            let entry: OutboxEntry = self.message_db.decrypt_value(&entry)?;
+            entries.push((MessageId::from(String::from_utf8(id)?), entry));
+        }
+        entries.sort_by_key(|(_, entry)| entry.queued_at);
+        Ok(entries)
+    }
+
+    pub fn update_outbox_entry(&self, id: &MessageId, entry: &OutboxEntry) -> Result<(), Error> {
+        self.message_db.store().insert(
+            OUTBOX_TREE,
+            id.as_str().as_bytes(),
+            &self.message_db.encrypt_value(entry)?,
+        )
+    }
+
+    /// Keeps a chat message for a chat we haven't joined, until `take_stashed` hands
+    /// it out once we have.
+    pub fn stash_payload(&self, payload: ChatMessagePayload) -> Result<(), Error> {
+        let store = self.message_db.store();
+        if store.len(STASHED_TREE)? >= MAX_STASHED_PAYLOADS {
+            Err("Too many messages for chats that weren't joined")?;
+        }
+        let stashed = StashedPayload {
+            payload,
+            received_at: SystemTime::now(),
+        };
+        store.insert(
+            STASHED_TREE,
+            Uuid::new_v4().to_string().as_bytes(),
+            &self.message_db.encrypt_value(&stashed)?,
+        )
+    }
+
+    /// Removes and returns the messages stashed for `chat_id`, oldest first.
+    pub fn take_stashed(&self, chat_id: &ChatId) -> Result<Vec<ChatMessagePayload>, Error> {
+        let mut stashed = vec![];
+        let mut batch = Batch::default();
+        for (id, entry) in self.message_db.store().iter(STASHED_TREE)? {
+            let entry: StashedPayload = self.message_db.decrypt_value(&entry)?;
+            if entry.payload.chat_id == chat_id.as_str() {
+                batch.remove(STASHED_TREE, &id);
+                stashed.push(entry);
+            }
+        }
+        if !batch.is_empty() {
+            self.message_db.store().apply_batch(batch)?;
+        }
+        stashed.sort_by_key(|entry| entry.received_at);
+        Ok(stashed.into_iter().map(|entry| entry.payload).collect())
+    }
+
+    /// Takes a message out of the outbox, as relayed at `relayed_at` or, without one, as
+    /// failed. A relayed message is ordered by the server's time from then on, unless
+    /// that would move it past a message shown next to it since it was sent; it then
+    /// keeps its local timestamp, so settling never reorders what the user has seen.
+    pub fn settle_outgoing(
+        &self,
+        id: &MessageId,
+        relayed_at: Option<SystemTime>,
+        server_message_id: Option<String>,
+    ) -> Result<Message, Error> {
+        let mut message = self.get_message(id)?;
+        message.set_server_message_id(server_message_id);
+        let chat_id = message.chat_id().clone();
+        match relayed_at {
+            Some(relayed_at) => {
+                let sent_at = message.timestamp();
+                let mut previous = None;
+                let mut next = None;
+                let others = self
+                    .message_db
+                    .find_entries(|other: &Message| other.chat_id() == &chat_id)?;
+                for (other_id, other) in others {
+                    if other_id == id.as_str() {
+                        continue;
+                    }
+                    let timestamp = other.timestamp();
+                    if timestamp <= sent_at && previous.map_or(true, |p| timestamp > p) {
+                        previous = Some(timestamp);
+                    } else if timestamp > sent_at && next.map_or(true, |n| timestamp < n) {
+                        next = Some(timestamp);
+                    }
+                }
+                let reorder = previous.map_or(true, |p| relayed_at > p)
+                    && next.map_or(true, |n| relayed_at < n);
+                message.mark_sent(relayed_at, reorder);
+            }
+            None => message.mark_failed(),
+        }
+        let mut batch = Batch::default();
+        batch.insert(
+            DEFAULT_TREE,
+            id.as_str().as_bytes(),
+            self.message_db.encrypt_value(&message)?,
+        );
+        batch.remove(OUTBOX_TREE, id.as_str().as_bytes());
+        self.index_server_message_id(&mut batch, id, &message)?;
+        let _guard = self.preview_lock.lock().unwrap();
+        if let Some(current) = self.chat_preview(&chat_id)? {
+            if &current.message_id == id {
+                batch.insert(
+                    CHAT_PREVIEWS_TREE,
+                    chat_id.as_str().as_bytes(),
+                    self.message_db.encrypt_value(&ChatPreview::new(id.clone(), &message))?,
+                );
+            }
+        }
+        self.message_db.store().apply_batch(batch)?;
+        Ok(message)
+    }
+
+    fn index_server_message_id(
+        &self,
+        batch: &mut Batch,
+        id: &MessageId,
+        message: &Message,
+    ) -> Result<(), Error> {
+        if let Some(server_message_id) = message.server_message_id() {
+            batch.insert(
+                SERVER_MESSAGE_IDS_TREE,
+                server_message_id.as_bytes(),
+                self.message_db.encrypt_value(id)?,
+            );
+        }
+        Ok(())
+    }
+
+    /// Moves the message the server calls `server_message_id` on to what a recipient
+    /// acknowledged. Returns it if its status changed; acknowledgements of messages we
+    /// don't have, or that are behind its status, change nothing.
+    pub fn ack_message(
+        &self,
+        server_message_id: &str,
+        status: AckStatus,
+    ) -> Result<Option<(MessageId, Message)>, Error> {
+        let indexed = self
+            .message_db
+            .store()
+            .get(SERVER_MESSAGE_IDS_TREE, server_message_id.as_bytes())?;
+        let id: MessageId = match indexed {
+            Some(entry) => self.message_db.decrypt_value(&entry)?,
+            None => return Ok(None),
+        };
+        // deleted since
+        let mut message = match self.get_message(&id) {
+            Ok(message) => message,
+            Err(_) => return Ok(None),
+        };
+        if !message.mark_acked(status) {
+            return Ok(None);
+        }
+        self.message_db.update_entry(id.as_str(), message)?;
+        let message = self.get_message(&id)?;
+        Ok(Some((id, message)))
+    }
+
+    pub fn chat_preview(&self, chat_id: &ChatId) -> Result<Option<ChatPreview>, Error> {
+        let store = self.message_db.store();
+        match store.get(CHAT_PREVIEWS_TREE, chat_id.as_str().as_bytes())? {
+            Some(preview) => Ok(Some(self.message_db.decrypt_value(&preview)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Lists every chat with its preview, newest activity first, without decrypting
+    /// any messages.
+    pub fn list_chats(&self) -> Result<Vec<ChatSummary>, Error> {
+        let mut chats = vec![];
+        for (id, chat) in self.chat_db.get_all_entries::<Chat>()? {
+            let id = ChatId::from(id);
+            let preview = self.chat_preview(&id)?;
+            chats.push(ChatSummary { id, chat, preview });
+        }
+        chats.sort_by(|a, b| {
+            let a = a.preview.as_ref().map(|p| p.timestamp);
+            let b = b.preview.as_ref().map(|p| p.timestamp);
+            b.cmp(&a)
+        });
+        Ok(chats)
+    }
+
+    /// Finds messages containing `query`, ignoring case. Trashed messages are never
+    /// searched.
+    pub fn search_messages(&self, query: &str) -> Result<Vec<(MessageId, Message)>, Error> {
+        let query = query.to_lowercase();
+        Ok(self
+            .message_db
+            .find_entries(|message: &Message| message.message().to_lowercase().contains(&query))?
+            .into_iter()
+            .map(|(id, message)| (MessageId::from(id), message))
+            .collect())
+    }
+
+    /// Pages through a chat's messages newest first: up to `limit` of those older than
+    /// the message `before`, or the newest ones without it. Messages sharing a timestamp
+    /// are ordered by id, so the last id of a page picks up exactly where it ended.
+    pub fn chat_messages(
+        &self,
+        chat_id: &ChatId,
+        before: Option<&MessageId>,
+        limit: usize,
+    ) -> Result<Vec<(MessageId, Message)>, Error> {
+        let mut messages: Vec<(MessageId, Message)> = self
+            .message_db
+            .find_entries(|message: &Message| message.chat_id() == chat_id)?
+            .into_iter()
+            .map(|(id, message)| (MessageId::from(id), message))
+            .collect();
+        messages.sort_by(|(a_id, a), (b_id, b)| {
+            (b.timestamp(), b_id.as_str()).cmp(&(a.timestamp(), a_id.as_str()))
+        });
+        let start = match before {
+            Some(before) => {
+                messages
+                    .iter()
+                    .position(|(id, _)| id == before)
+                    .ok_or("Message not found in chat")?
+                    + 1
+            }
+            None => 0,
+        };
+        Ok(messages.into_iter().skip(start).take(limit).collect())
+    }
+
+    /// Recomputes a chat's preview from scratch, e.g. after messages came back from
+    /// the trash.
+    fn refresh_preview(&self, chat_id: &ChatId) -> Result<(), Error> {
+        let key = chat_id.as_str().as_bytes();
+        let _guard = self.preview_lock.lock().unwrap();
+        match self.newest_message(chat_id, None)? {
+            Some((id, message)) => self.message_db.store().insert(
+                CHAT_PREVIEWS_TREE,
+                key,
+                &self.message_db.encrypt_value(&ChatPreview::new(id, &message))?,
+            ),
+            None => self.message_db.store().remove(CHAT_PREVIEWS_TREE, key),
+        }
+    }
+
+    /// Finds the newest message of a chat by scanning the message tree.
+    fn newest_message(
+        &self,
+        chat_id: &ChatId,
+        excluding: Option<&MessageId>,
+    ) -> Result<Option<(MessageId, Message)>, Error> {
+        let newest = self
+            .message_db
+            .find_entries(|message: &Message| message.chat_id() == chat_id)?
+            .into_iter()
+            .map(|(id, message)| (MessageId::from(id), message))
+            .filter(|(id, _)| Some(id) != excluding)
+            .max_by_key(|(_, message)| message.timestamp());
+        Ok(newest)
+    }
+
+    /// Collects a chat's messages for export, oldest first, with the names of senders
+    /// that are still known.
+    pub fn transcript(&self, chat_id: &ChatId) -> Result<Transcript, Error> {
+        let chat = self.get_chat(chat_id)?;
+        let mut messages: Vec<(MessageId, Message)> = self
+            .message_db
+            .find_entries(|message: &Message| message.chat_id() == chat_id)?
+            .into_iter()
+            .map(|(id, message)| (MessageId::from(id), message))
+            .collect();
+        messages.sort_by_key(|(_, message)| message.timestamp());
+        let mut names = HashMap::new();
+        let mut transcript = vec![];
+        for (id, message) in messages {
+            let sender_name = match message.sender_id() {
+                Some(sender) => names
+                    .entry(sender.clone())
+                    .or_insert_with(|| {
+                        self.get_user(sender)
+                            .ok()
+                            .map(|user| user.username().to_string())
+                    })
+                    .clone(),
+                None => None,
+            };
+            transcript.push(TranscriptMessage {
+                message_id: id,
+                chat_id: chat_id.clone(),
+                server_id: message.server_id().clone(),
+                sender_id: message.sender_id().cloned(),
+                sender_name,
+                timestamp: message.timestamp(),
+                text: message.message().to_string(),
+                truncated: message.is_truncated(),
+            });
+        }
+        Ok(Transcript {
+            chat_id: chat_id.clone(),
+            chat_name: chat.name().to_string(),
+            messages: transcript,
         })
     }
+
+    pub fn get_chat(&self, id: &ChatId) -> Result<Chat, Error> {
+        self.chat_db.get_entry(id.as_str())
+    }
+
+    pub fn save_chat(&self, chat: Chat) -> Result<ChatId, Error> {
+        Ok(ChatId::from(self.chat_db.save_entry(chat)?))
+    }
+
+    /// Sets the chat's color and emoji, signed with our key. Returns the control message
+    /// to forward to the other participants.
+    pub fn customize_chat(
+        &self,
+        id: &ChatId,
+        color: &str,
+        emoji: &str,
+        sk: &RsaPrivateKey,
+    ) -> Result<ChatCustomizationParams, Error> {
+        let mut chat = self.get_chat(id)?;
+        let customization = ChatCustomization::new_signed(id.as_str(), color, emoji, sk)?;
+        chat.apply_customization(customization.clone());
+        self.chat_db.update_entry(id.as_str(), chat)?;
+        Ok(ChatCustomizationParams {
+            chat_id: id.to_string(),
+            customization,
+        })
+    }
+
+    /// Applies a customization received from `sender`, who must be a participant of the
+    /// chat and must have signed it. Returns whether it replaced the current one.
+    pub fn apply_chat_customization(
+        &self,
+        sender: &UserId,
+        params: ChatCustomizationParams,
+    ) -> Result<bool, Error> {
+        let chat_id = ChatId::from(params.chat_id);
+        let mut chat = self.get_chat(&chat_id)?;
+        if !chat.user_ids().contains(sender) {
+            Err(format!("{} is not a participant of chat {}", sender, chat_id))?;
+        }
+        params.customization.validate()?;
+        let pub_key = pki::pub_key_from_str(self.get_user(sender)?.pub_key())?;
+        if !params.customization.verify(chat_id.as_str(), &pub_key)? {
+            Err(Error::Auth(String::from("Invalid chat customization signature")))?;
+        }
+        let applied = chat.apply_customization(params.customization);
+        if applied {
+            self.chat_db.update_entry(chat_id.as_str(), chat)?;
+        }
+        Ok(applied)
+    }
+
+    pub fn get_server(&self, id: &ServerId) -> Result<ServerModel, Error> {
+        self.server_db.get_entry(id.as_str())
+    }
+
+    pub fn save_server(&self, server: ServerModel) -> Result<ServerId, Error> {
+        Ok(ServerId::from(self.server_db.save_entry(server)?))
+    }
+
+    /// The saved server that lists the chat, if any.
+    pub fn server_of_chat(
+        &self,
+        chat_id: &ChatId,
+    ) -> Result<Option<(ServerId, ServerModel)>, Error> {
+        for (server_id, server) in self.server_db.get_all_entries::<ServerModel>()? {
+            if server.chat_ids().contains(chat_id) {
+                return Ok(Some((ServerId::from(server_id), server)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The saved server whose pinned key has the `pki::fingerprint`, if any.
+    pub fn find_server_by_fingerprint(
+        &self,
+        fingerprint: &str,
+    ) -> Result<Option<(ServerId, ServerModel)>, Error> {
+        for (server_id, server) in self.server_db.get_all_entries::<ServerModel>()? {
+            let pinned = server.pub_key.as_ref().map(pki::fingerprint).transpose()?;
+            if pinned.as_deref() == Some(fingerprint) {
+                return Ok(Some((ServerId::from(server_id), server)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Posts `text` without a sender to the server's system chat, creating the chat if
+    /// it doesn't exist yet.
+    pub fn add_system_notice(
+        &self,
+        server_id: &ServerId,
+        text: String,
+    ) -> Result<MessageId, Error> {
+        let mut server = self.get_server(server_id)?;
+        let existing = server
+            .system_chat_id
+            .clone()
+            .filter(|id| self.get_chat(id).is_ok());
+        let chat_id = match existing {
+            Some(existing) => existing,
+            None => {
+                let chat = Chat::new(vec![], SYSTEM_CHAT_NAME.to_string(), vec![], HashMap::new());
+                let id = self.save_chat(chat)?;
+                server.system_chat_id = Some(id.clone());
+                self.server_db.update_entry(server_id.as_str(), server)?;
+                id
+            }
+        };
+        let message = Message::new(server_id.clone(), None, chat_id, text);
+        self.add_message(message)
+    }
+
+    /// Saves what was read from another messenger's export: its contacts as unverified
+    /// users and each conversation as an archived, local-only chat. A message that
+    /// can't be saved is reported and skipped.
+    pub fn import_archive(
+        &self,
+        format: ImportFormat,
+        archive: ExternalArchive,
+    ) -> Result<ImportReport, Error> {
+        let mut report = ImportReport {
+            errors: archive.errors,
+            ..ImportReport::default()
+        };
+        let mut user_ids = HashMap::new();
+        for contact in archive.contacts {
+            let user = User::imported(contact.name, format, contact.extras);
+            user_ids.insert(contact.id, self.save_user(user)?);
+            report.contacts += 1;
+        }
+        for conversation in archive.conversations {
+            let mut participants: Vec<UserId> = vec![];
+            for message in &conversation.messages {
+                if let Some(id) = message.sender.as_ref().and_then(|sender| user_ids.get(sender)) {
+                    if !participants.contains(id) {
+                        participants.push(id.clone());
+                    }
+                }
+            }
+            if let Some(id) = user_ids.get(&conversation.id) {
+                if !participants.contains(id) {
+                    participants.push(id.clone());
+                }
+            }
+            let chat = Chat::imported(participants, conversation.name, format, conversation.extras);
+            let chat_id = self.save_chat(chat)?;
+            for (i, message) in conversation.messages.into_iter().enumerate() {
+                let sender_id = message.sender.and_then(|sender| user_ids.get(&sender).cloned());
+                let message = Message::imported(
+                    sender_id,
+                    chat_id.clone(),
+                    message.text,
+                    message.timestamp,
+                    message.extras,
+                );
+                // linked into the chat all at once below, rather than a chat write each
+                match self.write_message(message, None) {
+                    Ok(_) => report.messages += 1,
+                    Err(e) => report.errors.push(RecordError {
+                        record: format!("conversation {} message {}", conversation.id, i),
+                        message: e.to_string(),
+                    }),
+                }
+            }
+            self.settle_chat_links()?;
+            report.chats.push(chat_id);
+        }
+        Ok(report)
+    }
+
+    /// Walks every tree and reports ids that point at entries which no longer exist.
+    pub fn check_references(&self) -> Result<Vec<DanglingReference>, Error> {
+        let mut dangling = vec![];
+        let mut check = |target_db: &EntryDb,
+                         tree: &'static str,
+                         entry_id: &str,
+                         field: &'static str,
+                         target: &str|
+         -> Result<(), Error> {
+            if !target_db.contains(target)? {
+                dangling.push(DanglingReference {
+                    tree,
+                    entry_id: entry_id.to_string(),
+                    field,
+                    target: target.to_string(),
+                });
+            }
+            Ok(())
+        };
+        for (id, message) in self.message_db.get_all_entries::<Message>()? {
+            if !message.is_imported() {
+                check(&self.server_db, MESSAGES_DB, &id, "server_id", message.server_id().as_str())?;
+            }
+            check(&self.chat_db, MESSAGES_DB, &id, "chat_id", message.chat_id().as_str())?;
+            if let Some(sender_id) = message.sender_id() {
+                check(&self.known_user_db, MESSAGES_DB, &id, "sender_id", sender_id.as_str())?;
+            }
+        }
+        for (id, chat) in self.chat_db.get_all_entries::<Chat>()? {
+            for user_id in chat.user_ids() {
+                check(&self.known_user_db, CHATS_DB, &id, "user_ids", user_id.as_str())?;
+            }
+            for message_id in chat.message_ids() {
+                check(&self.message_db, CHATS_DB, &id, "message_ids", message_id.as_str())?;
+            }
+            if let Some(message_id) = chat.last_message_id() {
+                check(&self.message_db, CHATS_DB, &id, "last_message_id", message_id.as_str())?;
+            }
+        }
+        for (id, server) in self.server_db.get_all_entries::<ServerModel>()? {
+            for user_id in server.user_ids() {
+                check(&self.known_user_db, SERVER_DB, &id, "user_ids", user_id.as_str())?;
+            }
+            for chat_id in server.chat_ids() {
+                check(&self.chat_db, SERVER_DB, &id, "chat_ids", chat_id.as_str())?;
+            }
+        }
+        Ok(dangling)
+    }
+
+    // The trees live in separate stores, so the cascading deletes below can't
+    // share one transaction. References are removed before their target so an
+    // interrupted delete leaves at worst an unreferenced entry, never a dangling id.
+
+    /// Deletes a contact, removing it from every chat (which become orphaned), server
+    /// and message that referenced it.
+    pub fn delete_contact(&self, id: &UserId) -> Result<(), Error> {
+        let chats = self
+            .chat_db
+            .find_entries(|chat: &Chat| chat.user_ids().contains(id))?;
+        for (chat_id, mut chat) in chats {
+            chat.remove_user(id);
+            self.chat_db.update_entry(&chat_id, chat)?;
+        }
+        let sent = self
+            .message_db
+            .find_entries(|message: &Message| message.sender_id() == Some(id))?;
+        for (message_id, mut message) in sent {
+            message.clear_sender();
+            self.message_db.update_entry(&message_id, message)?;
+        }
+        for (server_id, mut server) in self.server_db.get_all_entries::<ServerModel>()? {
+            if server.user_ids().contains(id) {
+                server.remove_user(id);
+                self.server_db.update_entry(&server_id, server)?;
+            }
+        }
+        self.known_user_db.delete_entry(id.as_str())
+    }
+
+    /// Moves a chat and all of its messages to the trash.
+    pub fn delete_chat(&self, id: &ChatId) -> Result<(), Error> {
+        let key = id.as_str().as_bytes();
+        let chat_entry = self
+            .chat_db
+            .store()
+            .get(DEFAULT_TREE, key)?
+            .ok_or("Id not found")?;
+        let mut server_ids = vec![];
+        for (server_id, server) in self.server_db.get_all_entries::<ServerModel>()? {
+            if server.chat_ids().contains(id) {
+                server_ids.push(ServerId::from(server_id));
+            }
+        }
+        let deleted_at = SystemTime::now();
+        let mut messages = Batch::default();
+        let mut message_ids = vec![];
+        for (message_key, entry) in self.message_db.store().iter(DEFAULT_TREE)? {
+            let message: Message = self.message_db.decrypt_value(&entry)?;
+            if message.chat_id() == id {
+                let item = TrashedItem::ChatMessage { chat_id: id.clone() };
+                let trashed = TrashEntry::new(item, deleted_at, &self.message_db, &entry)?;
+                messages.remove(DEFAULT_TREE, &message_key);
+                messages.remove(OUTBOX_TREE, &message_key);
+                messages.insert(TRASH_TREE, &message_key, self.message_db.encrypt_value(&trashed)?);
+                message_ids.push(MessageId::from(String::from_utf8(message_key)?));
+            }
+        }
+        messages.remove(CHAT_PREVIEWS_TREE, key);
+        let item = TrashedItem::Chat {
+            server_ids,
+            message_ids,
+        };
+        let trashed = TrashEntry::new(item, deleted_at, &self.chat_db, &chat_entry)?;
+        let moved = self.message_db.encrypt_value(&ChatMove::Trash(trashed))?;
+        messages.insert(CHAT_MOVES_TREE, key, moved);
+        {
+            let _guard = self.preview_lock.lock().unwrap();
+            self.message_db.store().apply_batch(messages)?;
+        }
+        self.settle_chat_moves()
+    }
+
+    /// Moves the chats whose messages went into or came out of the trash after them,
+    /// along with their servers' chat lists. Each step can be repeated, so settling
+    /// again after a crash part way through is harmless.
+    fn settle_chat_moves(&self) -> Result<(), Error> {
+        for (key, moved) in self.message_db.store().iter(CHAT_MOVES_TREE)? {
+            let chat_id = ChatId::from(String::from_utf8(key.clone())?);
+            let mut chat = Batch::default();
+            let (trashed, restored) = match self.message_db.decrypt_value(&moved)? {
+                ChatMove::Trash(trashed) => {
+                    chat.remove(DEFAULT_TREE, &key);
+                    chat.insert(TRASH_TREE, &key, self.chat_db.encrypt_value(&trashed)?);
+                    (trashed, false)
+                }
+                ChatMove::Restore(trashed) => {
+                    chat.insert(DEFAULT_TREE, &key, trashed.restored(&self.chat_db)?);
+                    chat.remove(TRASH_TREE, &key);
+                    (trashed, true)
+                }
+            };
+            self.chat_db.store().apply_batch(chat)?;
+            if let TrashedItem::Chat { server_ids, .. } = trashed.item {
+                for server_id in server_ids {
+                    if let Ok(mut server) = self.get_server(&server_id) {
+                        if restored {
+                            server.add_chat(chat_id.clone());
+                        } else {
+                            server.remove_chat(&chat_id);
+                        }
+                        self.server_db.update_entry(server_id.as_str(), server)?;
+                    }
+                }
+            }
+            if restored {
+                self.refresh_preview(&chat_id)?;
+            }
+            self.message_db.store().remove(CHAT_MOVES_TREE, &key)?;
+        }
+        Ok(())
+    }
+
+    /// Moves a message to the trash. If it was the chat's preview, the next newest
+    /// message takes its place in the same batch.
+    pub fn delete_message(&self, id: &MessageId) -> Result<(), Error> {
+        let entry = self
+            .message_db
+            .store()
+            .get(DEFAULT_TREE, id.as_str().as_bytes())?
+            .ok_or("Id not found")?;
+        let message: Message = self.message_db.decrypt_value(&entry)?;
+        let chat_id = message.chat_id();
+        let mut position = None;
+        if let Ok(mut chat) = self.get_chat(chat_id) {
+            position = chat.message_ids().iter().position(|m| m == id);
+            chat.remove_message(id);
+            self.chat_db.update_entry(chat_id.as_str(), chat)?;
+        }
+        let replacement = match self.newest_message(chat_id, Some(id))? {
+            Some((next_id, next)) => {
+                Some(self.message_db.encrypt_value(&ChatPreview::new(next_id, &next))?)
+            }
+            None => None,
+        };
+        let item = TrashedItem::Message {
+            chat_id: chat_id.clone(),
+            position,
+        };
+        let trashed = TrashEntry::new(item, SystemTime::now(), &self.message_db, &entry)?;
+        let mut batch = Batch::default();
+        batch.remove(DEFAULT_TREE, id.as_str().as_bytes());
+        // a deleted message is never sent
+        batch.remove(OUTBOX_TREE, id.as_str().as_bytes());
+        batch.insert(
+            TRASH_TREE,
+            id.as_str().as_bytes(),
+            self.message_db.encrypt_value(&trashed)?,
+        );
+        let _guard = self.preview_lock.lock().unwrap();
+        if let Some(current) = self.chat_preview(chat_id)? {
+            if &current.message_id == id {
+                let key = chat_id.as_str().as_bytes();
+                match replacement {
+                    Some(preview) => batch.insert(CHAT_PREVIEWS_TREE, key, preview),
+                    None => batch.remove(CHAT_PREVIEWS_TREE, key),
+                }
+            }
+        }
+        self.message_db.store().apply_batch(batch)
+    }
+
+    fn trashed(&self, db: &EntryDb, id: &str) -> Result<Option<TrashEntry>, Error> {
+        match db.store().get(TRASH_TREE, id.as_bytes())? {
+            Some(entry) => Ok(Some(db.decrypt_value(&entry)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn trash_entries(&self, db: &EntryDb) -> Result<Vec<(String, TrashEntry)>, Error> {
+        let mut entries = vec![];
+        for (id, entry) in db.store().iter(TRASH_TREE)? {
+            entries.push((String::from_utf8(id)?, db.decrypt_value(&entry)?));
+        }
+        Ok(entries)
+    }
+
+    fn is_expired(&self, trashed: &TrashEntry) -> bool {
+        // a clock that went backwards never expires anything
+        trashed
+            .deleted_at
+            .elapsed()
+            .map(|age| age > self.trash_retention)
+            .unwrap_or(false)
+    }
+
+    /// Lists everything in the trash. Messages deleted along with their chat are
+    /// included, flagged as such, so a full backup can carry the trash too.
+    pub fn list_trash(&self) -> Result<Vec<TrashListing>, Error> {
+        let mut listings = vec![];
+        for db in [&self.chat_db, &self.message_db] {
+            for (id, trashed) in self.trash_entries(db)? {
+                listings.push(TrashListing {
+                    id,
+                    item: trashed.item,
+                    deleted_at: trashed.deleted_at,
+                });
+            }
+        }
+        Ok(listings)
+    }
+
+    /// Brings a deleted message or chat back, as long as it is still within the
+    /// retention window. A chat comes back with its messages and servers.
+    pub fn restore_from_trash(&self, id: &str) -> Result<(), Error> {
+        let (db, trashed) = match self.trashed(&self.chat_db, id)? {
+            Some(trashed) => (&self.chat_db, trashed),
+            None => match self.trashed(&self.message_db, id)? {
+                Some(trashed) => (&self.message_db, trashed),
+                None => Err(format!("{} is not in the trash", id))?,
+            },
+        };
+        if self.is_expired(&trashed) {
+            Err(format!("{} was deleted too long ago to be restored", id))?;
+        }
+        let mut batch = Batch::default();
+        batch.insert(DEFAULT_TREE, id.as_bytes(), trashed.restored(db)?);
+        batch.remove(TRASH_TREE, id.as_bytes());
+        match trashed.item {
+            TrashedItem::Message { chat_id, position } => {
+                if self.chat_db.store().contains(TRASH_TREE, chat_id.as_str().as_bytes())? {
+                    Err(format!("Chat {} is in the trash, restore it first", chat_id))?;
+                }
+                db.store().apply_batch(batch)?;
+                if let (Some(position), Ok(mut chat)) = (position, self.get_chat(&chat_id)) {
+                    chat.restore_message(MessageId::from(id), position);
+                    self.chat_db.update_entry(chat_id.as_str(), chat)?;
+                }
+                self.refresh_preview(&chat_id)
+            }
+            TrashedItem::ChatMessage { chat_id } => Err(format!(
+                "Message was deleted along with chat {}, restore the chat instead",
+                chat_id
+            ))?,
+            TrashedItem::Chat {
+                ref message_ids,
+                ..
+            } => {
+                let mut messages = Batch::default();
+                for message_id in message_ids {
+                    let key = message_id.as_str().as_bytes();
+                    if let Some(message) = self.trashed(&self.message_db, message_id.as_str())? {
+                        messages.insert(DEFAULT_TREE, key, message.restored(&self.message_db)?);
+                        messages.remove(TRASH_TREE, key);
+                    }
+                }
+                let moved = self.message_db.encrypt_value(&ChatMove::Restore(trashed))?;
+                messages.insert(CHAT_MOVES_TREE, id.as_bytes(), moved);
+                self.message_db.store().apply_batch(messages)?;
+                self.settle_chat_moves()
+            }
+        }
+    }
+
+    /// Permanently removes whatever has been in the trash for longer than the retention
+    /// window. Returns the number of entries removed.
+    pub fn purge_expired_trash(&self) -> Result<usize, Error> {
+        let mut purged = 0;
+        for db in [&self.message_db, &self.chat_db] {
+            let mut batch = Batch::default();
+            for (id, trashed) in self.trash_entries(db)? {
+                if self.is_expired(&trashed) {
+                    batch.remove(TRASH_TREE, id.as_bytes());
+                    purged += 1;
+                }
+            }
+            if !batch.is_empty() {
+                db.store().apply_batch(batch)?;
+            }
+        }
+        Ok(purged)
+    }
+
+    /// Rewrites every tree to reclaim space left behind by deletions. Returns the total
+    /// number of bytes reclaimed.
+    pub fn compact(&mut self) -> Result<u64, Error> {
+        let mut reclaimed = 0;
+        for db in [
+            &mut self.known_user_db,
+            &mut self.message_db,
+            &mut self.server_db,
+            &mut self.chat_db,
+        ] {
+            reclaimed += db.compact()?;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Copies the profile at `loc` from one backend to another, e.g. from sled into
+    /// SQLite. Entries are copied as ciphertext, so no key is needed, and the profile
+    /// must not be open while it runs. Returns the number of entries copied.
+    pub fn migrate(loc: &str, from: &DbConfig, to: &DbConfig) -> Result<usize, Error> {
+        let base = Self::base_dir(loc);
+        let mut copied = 0;
+        for name in [KNOWN_USERS_DB, MESSAGES_DB, SERVER_DB, CHATS_DB] {
+            let source = from.open(base.join(name))?;
+            let target = to.open(base.join(name))?;
+            copied += kv::copy_store(&*source, &*target)?;
+        }
+        Ok(copied)
+    }
 }
 
 
@@ -35,21 +1366,82 @@ impl ClientDatabase {
 #[cfg(test)]
 mod tests {
 
-    use std::net::IpAddr;
+    use std::{
+        collections::HashMap,
+        net::IpAddr,
+        time::{Duration, SystemTime},
+    };
+
+    use rsa::pkcs8::{EncodePublicKey, LineEnding};
+
+    use crate::client::models::MessageStatus;
+    use crate::shared::{
+        db::Backend,
+        models::{CHAT_COLORS, CHAT_EMOJIS},
+    };
 
     use super::*;
 
-    use crate::client::models::ServerModel;
+    /// Runs each listed test once against every storage backend that is compiled in.
+    macro_rules! backend_tests {
+        ($($name:ident),* $(,)?) => {
+            mod sled_backend {
+                $(#[test]
+                fn $name() {
+                    super::$name(super::Backend::Sled);
+                })*
+            }
+            #[cfg(feature = "sqlite")]
+            mod sqlite_backend {
+                $(#[test]
+                fn $name() {
+                    super::$name(super::Backend::Sqlite);
+                })*
+            }
+        };
+    }
 
-    #[test]
-    fn test_save_entry() {
+    backend_tests!(
+        test_save_entry,
+        test_storage_config_and_compaction,
+        test_check_references_and_cascade,
+        test_chat_previews_match_recomputation,
+        test_message_limit,
+        test_chat_links,
+        test_chat_moves,
+        test_upgrade_legacy_entries,
+        test_trash_restore,
+        test_trash_expiry,
+        test_trash_after_rekey,
+        test_entry_pages,
+        test_outbox_settling,
+        test_find_entries,
+        test_scan_prefix,
+        test_chat_message_pages,
+        test_entry_batches,
+        test_store_watch,
+        test_reencrypt,
+        test_entry_ttl,
+    );
 
-        // test save and delete entry
+    fn location(name: &str, backend: Backend) -> String {
+        format!("{}_{:?}", name, backend).to_lowercase()
+    }
+
+    fn open(name: &str, backend: Backend) -> ClientDatabase {
         let key = b"an example very very secret key";
-        let db = ClientDatabase::new(key).unwrap();
-        db.server_db.db.clear().unwrap();
-        let shared_key = ski::gen_key();
-        let nonce = ski::nonce();
+        let config = DbConfig {
+            backend,
+            ..DbConfig::default()
+        };
+        ClientDatabase::with_config(&location(name, backend), key, config).unwrap()
+    }
+
+    fn test_save_entry(backend: Backend) {
+
+        // test save and delete entry
+        let db = open("client_test_save", backend);
+        db.server_db.clear().unwrap();
         let srv = ServerModel::new(
             String::from("server_id"),
             vec![],
@@ -106,5 +1498,1210 @@ mod tests {
         assert_eq!(entries.len(), 2);
         assert!(entries.iter().find(|(id, _)| id == &id1).is_some());
         assert!(entries.iter().find(|(id, _)| id == &id2).is_some());
+
+        let base = db.base.clone();
+        drop(db);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    fn test_storage_config_and_compaction(backend: Backend) {
+        let key = b"an example very very secret key";
+        let config = DbConfig {
+            cache_capacity: 8 * 1024 * 1024,
+            flush_every_ms: None,
+            use_compression: true,
+            backend,
+        };
+        let loc = location("client_test_storage", backend);
+        let mut db = ClientDatabase::with_config(&loc, key, config).unwrap();
+        db.message_db.clear().unwrap();
+        if backend == Backend::Sled {
+            let conf = std::fs::read(db.base.join(MESSAGES_DB).join("conf")).unwrap();
+            assert!(String::from_utf8_lossy(&conf).contains("use_compression: true"));
+        }
+
+        let ids: Vec<String> = (0..2000)
+            .map(|i| {
+                let msg = Message::new(
+                    ServerId::from("server_id"),
+                    None,
+                    ChatId::from("chat_id"),
+                    format!("message number {} {}", i, Uuid::new_v4()),
+                );
+                db.message_db.save_entry(msg).unwrap()
+            })
+            .collect();
+        db.message_db.flush().unwrap();
+        let usage = db.storage_usage().unwrap();
+        assert_eq!(usage.messages.entries, 2000);
+        assert!(usage.messages.size_on_disk > 0);
+
+        for id in ids.iter().skip(10) {
+            db.message_db.delete_entry(id).unwrap();
+        }
+        db.message_db.flush().unwrap();
+        let before = db.storage_usage().unwrap();
+        assert_eq!(before.messages.entries, 10);
+
+        let reclaimed = db.compact().unwrap();
+        let after = db.storage_usage().unwrap();
+        assert!(reclaimed > 0);
+        assert!(after.messages.size_on_disk < before.messages.size_on_disk);
+        assert_eq!(after.messages.entries, 10);
+        for id in ids.iter().take(10) {
+            assert!(db.message_db.get_entry::<Message>(id).is_ok());
+        }
+        if backend == Backend::Sled {
+            let path = db.base.join(MESSAGES_DB);
+            assert!(!path.with_extension("replaced").exists());
+            assert!(!path.with_extension("compact").exists());
+        }
+
+        let base = db.base.clone();
+        drop(db);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_compaction() {
+        let path = PathBuf::from(location("client_test_interrupted_compaction", Backend::Sled));
+        let _ = std::fs::remove_dir_all(&path);
+        let config = DbConfig::default();
+        {
+            let store = config.open(&path).unwrap();
+            store.insert(DEFAULT_TREE, b"key", b"value").unwrap();
+            store.flush().unwrap();
+        }
+        // cut short after the live database was moved aside, before the copy took over
+        std::fs::rename(&path, path.with_extension("replaced")).unwrap();
+        std::fs::create_dir_all(path.with_extension("compact")).unwrap();
+        {
+            let store = config.open(&path).unwrap();
+            assert_eq!(store.get(DEFAULT_TREE, b"key").unwrap().unwrap(), b"value");
+        }
+        assert!(!path.with_extension("replaced").exists());
+        assert!(!path.with_extension("compact").exists());
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    fn test_check_references_and_cascade(backend: Backend) {
+        let db = open("client_test_references", backend);
+        for tree in [&db.known_user_db, &db.message_db, &db.server_db, &db.chat_db] {
+            tree.clear().unwrap();
+        }
+
+        // seed an inconsistent database
+        let server_id = db
+            .save_server(ServerModel::new(
+                String::from("server"),
+                vec![UserId::from("missing_user")],
+                vec![],
+                IpAddr::V4([127, 0, 0, 1].into()),
+                8080,
+            ))
+            .unwrap();
+        let mut chat = Chat::new(vec![], String::from("chat"), vec![], HashMap::new());
+        chat.push_message(MessageId::from("missing_message"));
+        let chat_id = db.save_chat(chat).unwrap();
+        let message_id = db
+            .save_message(Message::new(
+                server_id.clone(),
+                None,
+                ChatId::from("missing_chat"),
+                String::from("hello"),
+            ))
+            .unwrap();
+        let dangling = db.check_references().unwrap();
+        assert_eq!(dangling.len(), 4);
+        assert!(dangling.contains(&DanglingReference {
+            tree: SERVER_DB,
+            entry_id: server_id.to_string(),
+            field: "user_ids",
+            target: String::from("missing_user"),
+        }));
+        assert!(dangling.contains(&DanglingReference {
+            tree: MESSAGES_DB,
+            entry_id: message_id.to_string(),
+            field: "chat_id",
+            target: String::from("missing_chat"),
+        }));
+        assert!(dangling
+            .iter()
+            .any(|d| d.tree == CHATS_DB && d.entry_id == chat_id.as_str() && d.field == "message_ids"));
+        assert!(dangling
+            .iter()
+            .any(|d| d.tree == CHATS_DB && d.entry_id == chat_id.as_str() && d.field == "last_message_id"));
+        for tree in [&db.known_user_db, &db.message_db, &db.server_db, &db.chat_db] {
+            tree.clear().unwrap();
+        }
+
+        // deleting a contact nulls out every reference to it
+        let user_id = db
+            .save_user(User::new(String::from("bob"), String::from("key")))
+            .unwrap();
+        let server_id = db
+            .save_server(ServerModel::new(
+                String::from("server"),
+                vec![user_id.clone()],
+                vec![],
+                IpAddr::V4([127, 0, 0, 1].into()),
+                8080,
+            ))
+            .unwrap();
+        let chat_id = db
+            .save_chat(Chat::new(
+                vec![user_id.clone()],
+                String::from("chat"),
+                vec![],
+                HashMap::new(),
+            ))
+            .unwrap();
+        let message_id = db
+            .save_message(Message::new(
+                server_id.clone(),
+                Some(user_id.clone()),
+                chat_id.clone(),
+                String::from("hello"),
+            ))
+            .unwrap();
+        let mut chat = db.get_chat(&chat_id).unwrap();
+        chat.push_message(message_id.clone());
+        db.chat_db.update_entry(chat_id.as_str(), chat).unwrap();
+        assert!(db.check_references().unwrap().is_empty());
+
+        db.delete_contact(&user_id).unwrap();
+        assert!(db.check_references().unwrap().is_empty());
+        let chat = db.get_chat(&chat_id).unwrap();
+        assert!(chat.is_orphaned());
+        assert!(chat.user_ids().is_empty());
+        assert!(db.get_message(&message_id).unwrap().sender_id().is_none());
+        assert!(db.get_server(&server_id).unwrap().user_ids().is_empty());
+
+        // deleting a message moves the chat's last message back
+        let second_id = db
+            .save_message(Message::new(
+                server_id.clone(),
+                None,
+                chat_id.clone(),
+                String::from("second"),
+            ))
+            .unwrap();
+        let mut chat = db.get_chat(&chat_id).unwrap();
+        chat.push_message(second_id.clone());
+        db.chat_db.update_entry(chat_id.as_str(), chat).unwrap();
+        db.delete_message(&second_id).unwrap();
+        assert_eq!(db.get_chat(&chat_id).unwrap().last_message_id(), Some(&message_id));
+
+        // deleting a chat removes its messages
+        db.delete_chat(&chat_id).unwrap();
+        assert!(db.get_message(&message_id).is_err());
+        assert!(db.check_references().unwrap().is_empty());
+
+        let base = db.base.clone();
+        drop(db);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    fn test_chat_previews_match_recomputation(backend: Backend) {
+        let db = open("client_test_previews", backend);
+        for tree in [&db.known_user_db, &db.message_db, &db.server_db, &db.chat_db] {
+            tree.clear().unwrap();
+        }
+        db.message_db.store().clear(CHAT_PREVIEWS_TREE).unwrap();
+
+        let chat_ids: Vec<ChatId> = (0..3)
+            .map(|i| {
+                db.save_chat(Chat::new(vec![], format!("chat {}", i), vec![], HashMap::new()))
+                    .unwrap()
+            })
+            .collect();
+        let mut message_ids: Vec<MessageId> = vec![];
+        // small xorshift so the sequence is random but reproducible
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for i in 0..200 {
+            if message_ids.is_empty() || next() % 10 < 7 {
+                let chat_id = &chat_ids[(next() % 3) as usize];
+                let text = format!("line one\nmessage {} {}", i, "x".repeat((next() % 100) as usize));
+                let id = db
+                    .add_message(Message::new(ServerId::from("server"), None, chat_id.clone(), text))
+                    .unwrap();
+                message_ids.push(id);
+            } else {
+                let id = message_ids.remove((next() % message_ids.len() as u64) as usize);
+                db.delete_message(&id).unwrap();
+            }
+            for chat_id in chat_ids.iter() {
+                let expected = db
+                    .newest_message(chat_id, None)
+                    .unwrap()
+                    .map(|(id, message)| ChatPreview::new(id, &message));
+                assert_eq!(db.chat_preview(chat_id).unwrap(), expected);
+            }
+        }
+
+        let preview = db.list_chats().unwrap()[0].preview.clone().unwrap();
+        assert!(!preview.text.contains('\n'));
+        assert!(preview.text.chars().count() <= 65);
+
+        db.delete_chat(&chat_ids[0]).unwrap();
+        assert!(db.chat_preview(&chat_ids[0]).unwrap().is_none());
+
+        let base = db.base.clone();
+        drop(db);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    fn test_chat_links(backend: Backend) {
+        let name = "client_test_chat_links";
+        let mut db = open(name, backend);
+        db.message_db.clear().unwrap();
+        db.chat_db.clear().unwrap();
+        db.server_db.clear().unwrap();
+        let chat_id = db
+            .save_chat(Chat::new(vec![], String::from("chat"), vec![], HashMap::new()))
+            .unwrap();
+        let server_id = db
+            .save_server(ServerModel::new(
+                String::from("server"),
+                vec![],
+                vec![chat_id.clone()],
+                IpAddr::V4([127, 0, 0, 1].into()),
+                8080,
+            ))
+            .unwrap();
+        let message = |text: &str| {
+            Message::new(server_id.clone(), None, chat_id.clone(), text.to_string())
+        };
+        let links = |db: &ClientDatabase| db.message_db.store().iter(CHAT_LINKS_TREE).unwrap();
+
+        let first = db.add_message(message("first")).unwrap();
+        let chat = db.get_chat(&chat_id).unwrap();
+        assert_eq!(chat.message_ids().to_vec(), vec![first.clone()]);
+        assert_eq!(chat.last_message_id(), Some(&first));
+        assert!(links(&db).is_empty());
+
+        // a crash after the message's batch, before its chat was written
+        let second = db.write_message(message("second"), None).unwrap();
+        assert_eq!(db.get_chat(&chat_id).unwrap().message_ids().len(), 1);
+        drop(db);
+        db = open(name, backend);
+        let chat = db.get_chat(&chat_id).unwrap();
+        assert_eq!(chat.message_ids().to_vec(), vec![first.clone(), second.clone()]);
+        assert_eq!(chat.last_message_id(), Some(&second));
+        assert!(links(&db).is_empty());
+        assert!(db.check_references().unwrap().is_empty());
+
+        // a crash after the chat was written, before its link was removed
+        let third = db.write_message(message("third"), None).unwrap();
+        let mut chat = db.get_chat(&chat_id).unwrap();
+        chat.push_message(third.clone());
+        db.chat_db.update_entry(chat_id.as_str(), chat).unwrap();
+        db.settle_chat_links().unwrap();
+        assert_eq!(
+            db.get_chat(&chat_id).unwrap().message_ids().to_vec(),
+            vec![first, second, third]
+        );
+        assert!(links(&db).is_empty());
+
+        // a refused message leaves neither the message nor its link
+        db.set_message_limit(MessageLimit {
+            max_bytes: 8,
+            policy: OversizePolicy::Reject,
+        });
+        assert!(db.add_message(message("way past the limit")).is_err());
+        assert_eq!(db.message_db.len().unwrap(), 3);
+        assert_eq!(db.get_chat(&chat_id).unwrap().message_ids().len(), 3);
+        assert!(links(&db).is_empty());
+
+        // there's nothing to link into once the chat is gone
+        db.delete_chat(&chat_id).unwrap();
+        db.write_message(message("late"), None).unwrap();
+        db.settle_chat_links().unwrap();
+        assert!(links(&db).is_empty());
+
+        let base = db.base.clone();
+        drop(db);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    fn test_chat_moves(backend: Backend) {
+        let name = "client_test_chat_moves";
+        let mut db = open(name, backend);
+        for tree in [&db.message_db, &db.server_db, &db.chat_db] {
+            tree.clear().unwrap();
+            tree.store().clear(TRASH_TREE).unwrap();
+        }
+        let moves = |db: &ClientDatabase| db.message_db.store().iter(CHAT_MOVES_TREE).unwrap();
+
+        // chats saved before ids were typed held "" for no last message
+        let mut legacy = serde_json::to_value(Chat::new(
+            vec![],
+            String::from("legacy"),
+            vec![],
+            HashMap::new(),
+        ))
+        .unwrap();
+        legacy["last_message_id"] = serde_json::json!("");
+        let chat_id = ChatId::from("legacy");
+        let key = chat_id.as_str().as_bytes();
+        let sealed = db.chat_db.encrypt_value(&legacy).unwrap();
+        db.chat_db.store().insert(DEFAULT_TREE, key, &sealed).unwrap();
+        assert_eq!(db.get_chat(&chat_id).unwrap().last_message_id(), None);
+        assert!(db.check_references().unwrap().is_empty());
+
+        let server = ServerModel::new(
+            String::from("server"),
+            vec![],
+            vec![chat_id.clone()],
+            IpAddr::V4([127, 0, 0, 1].into()),
+            8080,
+        );
+        let server_id = db.save_server(server).unwrap();
+
+        // a crash after the messages' batch, before the chat followed them
+        let item = TrashedItem::Chat {
+            server_ids: vec![server_id.clone()],
+            message_ids: vec![],
+        };
+        let trashed = TrashEntry::new(item, SystemTime::now(), &db.chat_db, &sealed).unwrap();
+        let moved = db.message_db.encrypt_value(&ChatMove::Trash(trashed)).unwrap();
+        db.message_db.store().insert(CHAT_MOVES_TREE, key, &moved).unwrap();
+        drop(db);
+        db = open(name, backend);
+        assert!(db.get_chat(&chat_id).is_err());
+        assert!(db.get_server(&server_id).unwrap().chat_ids().is_empty());
+        assert_eq!(db.list_trash().unwrap().len(), 1);
+        assert!(moves(&db).is_empty());
+
+        db.restore_from_trash(chat_id.as_str()).unwrap();
+        assert_eq!(db.get_chat(&chat_id).unwrap().name(), "legacy");
+        assert_eq!(db.get_server(&server_id).unwrap().chat_ids(), vec![chat_id.clone()]);
+        assert!(db.list_trash().unwrap().is_empty());
+        assert!(moves(&db).is_empty());
+
+        let base = db.base.clone();
+        drop(db);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    fn test_message_limit(backend: Backend) {
+        let mut db = open("client_test_message_limit", backend);
+        let chat_id = ChatId::from("chat");
+        let message = |text: &str| {
+            Message::new(ServerId::from("server"), None, chat_id.clone(), text.to_string())
+        };
+        db.set_message_limit(MessageLimit {
+            max_bytes: 8,
+            policy: OversizePolicy::Truncate,
+        });
+        let id = db.add_message(message("fits")).unwrap();
+        assert!(!db.get_message(&id).unwrap().is_truncated());
+        // 'é' is two bytes wide and straddles the limit
+        let id = db.add_message(message("abcdefgé tail")).unwrap();
+        let stored = db.get_message(&id).unwrap();
+        assert_eq!(stored.message(), "abcdefg");
+        assert!(stored.is_truncated());
+
+        db.set_message_limit(MessageLimit {
+            max_bytes: 8,
+            policy: OversizePolicy::Reject,
+        });
+        let count = db.message_db.len().unwrap();
+        assert!(db.add_message(message("way past the limit")).is_err());
+        assert!(db.save_message(message("way past the limit")).is_err());
+        assert_eq!(db.message_db.len().unwrap(), count);
+
+        let base = db.base.clone();
+        drop(db);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    fn test_entry_pages(backend: Backend) {
+        let path = PathBuf::from(location("client_test_entry_pages", backend));
+        let config = DbConfig {
+            backend,
+            ..DbConfig::default()
+        };
+        let db = EntryDb::new(b"pages", config.open(&path).unwrap()).unwrap();
+        for i in 0..500 {
+            db.save_entry(i).unwrap();
+        }
+        assert_eq!(db.count_entries().unwrap(), 500);
+
+        let mut seen = vec![];
+        for page in 0..3 {
+            let entries: Vec<(String, i32)> = db.get_entries_page(page * 100, 100).unwrap();
+            assert_eq!(entries.len(), 100);
+            seen.extend(entries);
+        }
+        let rest: Vec<(String, i32)> = db.get_entries_page(300, 1000).unwrap();
+        assert_eq!(rest.len(), 200);
+        seen.extend(rest);
+        let mut ids: Vec<&String> = seen.iter().map(|(id, _)| id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 500);
+        let mut values: Vec<i32> = seen.iter().map(|(_, value)| *value).collect();
+        values.sort_unstable();
+        assert_eq!(values, (0..500).collect::<Vec<_>>());
+        let all: Vec<(String, i32)> = db.get_all_entries().unwrap();
+        assert_eq!(all, seen);
+        assert!(db.get_entries_page::<i32>(500, 100).unwrap().is_empty());
+
+        drop(db);
+        if path.is_dir() {
+            std::fs::remove_dir_all(path).unwrap();
+        } else {
+            std::fs::remove_file(path.with_extension("sqlite")).unwrap();
+        }
+    }
+
+    fn test_chat_message_pages(backend: Backend) {
+        let db = open("client_test_chat_message_pages", backend);
+        db.message_db.clear().unwrap();
+        let chat_id = ChatId::from("chat");
+        let other_chat = ChatId::from("other");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        // pairs of messages share a second, as they do in exports, so some pages end
+        // between two messages with the same timestamp. The newest has its own, so it
+        // comes first.
+        let mut ids = vec![];
+        for i in 0..50 {
+            let timestamp = start + Duration::from_secs(if i == 49 { 25 } else { i / 2 });
+            let message = Message::imported(
+                None,
+                chat_id.clone(),
+                format!("message {}", i),
+                timestamp,
+                Default::default(),
+            );
+            ids.push(db.save_message(message).unwrap());
+        }
+        let elsewhere = String::from("elsewhere");
+        let stray = Message::imported(None, other_chat, elsewhere, start, Default::default());
+        db.save_message(stray).unwrap();
+
+        let mut seen: Vec<(MessageId, Message)> = vec![];
+        let mut pages = vec![];
+        loop {
+            let before = seen.last().map(|(id, _)| id.clone());
+            let page = db.chat_messages(&chat_id, before.as_ref(), 15).unwrap();
+            if page.is_empty() {
+                break;
+            }
+            pages.push(page.len());
+            seen.extend(page);
+        }
+        assert_eq!(pages, vec![15, 15, 15, 5]);
+        assert!(seen.windows(2).all(|pair| pair[0].1.timestamp() >= pair[1].1.timestamp()));
+        assert_eq!(seen[0].1.message(), "message 49");
+        let mut seen_ids: Vec<MessageId> = seen.iter().map(|(id, _)| id.clone()).collect();
+        seen_ids.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        ids.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        assert_eq!(seen_ids, ids);
+
+        // a page of exactly what's left, then nothing
+        let last = &seen[34].0;
+        assert_eq!(db.chat_messages(&chat_id, Some(last), 15).unwrap().len(), 15);
+        assert!(db.chat_messages(&chat_id, Some(&seen[49].0), 15).unwrap().is_empty());
+        assert!(db.chat_messages(&chat_id, None, 0).unwrap().is_empty());
+        assert_eq!(db.chat_messages(&chat_id, None, 100).unwrap().len(), 50);
+        assert!(db.chat_messages(&chat_id, Some(&MessageId::from("missing")), 15).is_err());
+
+        let base = db.base.clone();
+        drop(db);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    fn test_find_entries(backend: Backend) {
+        let path = PathBuf::from(location("client_test_find_entries", backend));
+        let config = DbConfig {
+            backend,
+            ..DbConfig::default()
+        };
+        let db = EntryDb::new(b"find", config.open(&path).unwrap()).unwrap();
+        db.clear().unwrap();
+        for i in 0..50 {
+            db.save_entry(i).unwrap();
+        }
+
+        let even: Vec<(String, i32)> = db.find_entries(|i: &i32| i % 2 == 0).unwrap();
+        assert_eq!(even.len(), 25);
+        assert!(even.iter().all(|(_, i)| i % 2 == 0));
+        let ids = db.find_entry_ids(|i: &i32| i % 2 == 0).unwrap();
+        assert_eq!(ids, even.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>());
+        for (id, value) in &even {
+            assert_eq!(db.get_entry::<i32>(id).unwrap(), *value);
+        }
+        assert!(db.find_entries(|i: &i32| *i >= 50).unwrap().is_empty());
+        let all: Vec<(String, i32)> = db.find_entries(|_: &i32| true).unwrap();
+        assert_eq!(all, db.get_all_entries().unwrap());
+
+        drop(db);
+        if path.is_dir() {
+            std::fs::remove_dir_all(path).unwrap();
+        } else {
+            std::fs::remove_file(path.with_extension("sqlite")).unwrap();
+        }
+    }
+
+    fn test_scan_prefix(backend: Backend) {
+        let path = PathBuf::from(location("client_test_scan_prefix", backend));
+        let config = DbConfig {
+            backend,
+            ..DbConfig::default()
+        };
+        let db = EntryDb::new(b"prefix", config.open(&path).unwrap()).unwrap();
+        db.clear().unwrap();
+        for (id, value) in [("b/1", 4), ("a/2", 2), ("ab/1", 3), ("a/1", 1)] {
+            db.update_entry(id, value).unwrap();
+        }
+
+        let a: Vec<(String, i32)> = db
+            .scan_prefix("a/")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(a, vec![("a/1".to_string(), 1), ("a/2".to_string(), 2)]);
+        let ids: Vec<String> = db
+            .scan_prefix::<i32>("a")
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(ids, vec!["a/1", "a/2", "ab/1"]);
+        assert_eq!(db.scan_prefix::<i32>("c/").unwrap().count(), 0);
+        assert_eq!(db.scan_prefix::<i32>("").unwrap().count(), 4);
+
+        drop(db);
+        if path.is_dir() {
+            std::fs::remove_dir_all(path).unwrap();
+        } else {
+            std::fs::remove_file(path.with_extension("sqlite")).unwrap();
+        }
+    }
+
+    fn test_entry_batches(backend: Backend) {
+        // dropping one of these part way through a batch stands in for a crash
+        #[derive(Serialize, Deserialize)]
+        struct Exploding {
+            value: i32,
+            explode: bool,
+        }
+        impl Drop for Exploding {
+            fn drop(&mut self) {
+                if self.explode {
+                    panic!("crashed mid-batch");
+                }
+            }
+        }
+
+        let path = PathBuf::from(location("client_test_entry_batches", backend));
+        let config = DbConfig {
+            backend,
+            ..DbConfig::default()
+        };
+        let db = EntryDb::new(b"batches", config.open(&path).unwrap()).unwrap();
+        db.clear().unwrap();
+
+        let ids = db.save_batch((0..10).collect::<Vec<i32>>()).unwrap();
+        assert_eq!(ids.len(), 10);
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(db.get_entry::<i32>(id).unwrap(), i as i32);
+        }
+        let ciphertexts: Vec<Vec<u8>> = ids
+            .iter()
+            .map(|id| db.store().get(DEFAULT_TREE, id.as_bytes()).unwrap().unwrap())
+            .collect();
+        let mut distinct = ciphertexts.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+        assert_eq!(distinct.len(), ciphertexts.len());
+
+        let updates: Vec<(&str, i32)> = ids.iter().map(|id| (id.as_str(), 100)).collect();
+        db.update_batch(updates).unwrap();
+        assert!(ids.iter().all(|id| db.get_entry::<i32>(id).unwrap() == 100));
+        // equal values still encrypt differently
+        assert_ne!(
+            db.store().get(DEFAULT_TREE, ids[0].as_bytes()).unwrap(),
+            db.store().get(DEFAULT_TREE, ids[1].as_bytes()).unwrap()
+        );
+
+        db.clear().unwrap();
+        let entries: Vec<Exploding> = (0..10)
+            .map(|value| Exploding {
+                value,
+                explode: value == 5,
+            })
+            .collect();
+        let crashed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            db.save_batch(entries)
+        }));
+        assert!(crashed.is_err());
+        assert_eq!(db.count_entries().unwrap(), 0);
+
+        drop(db);
+        if path.is_dir() {
+            std::fs::remove_dir_all(path).unwrap();
+        } else {
+            std::fs::remove_file(path.with_extension("sqlite")).unwrap();
+        }
+    }
+
+    fn test_store_watch(backend: Backend) {
+        let path = PathBuf::from(location("client_test_store_watch", backend));
+        let config = DbConfig {
+            backend,
+            ..DbConfig::default()
+        };
+        let store = config.open(&path).unwrap();
+        store.clear("watched").unwrap();
+        // nothing to write is fine, sled would otherwise panic on a transaction over no trees
+        store.apply_batch(Batch::default()).unwrap();
+
+        let watched = store.watch("watched", b"a/");
+        let all = store.watch("watched", b"");
+        store.insert("watched", b"a/1", b"one").unwrap();
+        store.insert("watched", b"b/1", b"two").unwrap();
+        store.insert("other", b"a/1", b"three").unwrap();
+        let mut batch = Batch::default();
+        batch.insert("watched", b"a/2", b"four".to_vec());
+        batch.remove("watched", b"a/1");
+        store.apply_batch(batch).unwrap();
+        store.clear("watched").unwrap();
+
+        let events: Vec<kv::KvEvent> = std::iter::from_fn(|| watched.try_recv().ok()).collect();
+        assert_eq!(
+            events,
+            vec![
+                (b"a/1".to_vec(), Some(b"one".to_vec())),
+                (b"a/2".to_vec(), Some(b"four".to_vec())),
+                (b"a/1".to_vec(), None),
+                (b"a/2".to_vec(), None),
+            ]
+        );
+        assert_eq!(std::iter::from_fn(|| all.try_recv().ok()).count(), 6);
+        // a dropped subscriber doesn't hold up writes
+        drop(watched);
+        store.insert("watched", b"a/3", b"five").unwrap();
+        assert_eq!(all.try_recv().unwrap().0, b"a/3".to_vec());
+
+        drop(store);
+        if path.is_dir() {
+            std::fs::remove_dir_all(path).unwrap();
+        } else {
+            std::fs::remove_file(path.with_extension("sqlite")).unwrap();
+        }
+    }
+
+    fn test_reencrypt(backend: Backend) {
+        let old_master = b"an example very very secret key";
+        let new_master = ski::gen_key();
+        let mut db = open("client_test_reencrypt", backend);
+        db.message_db.clear().unwrap();
+        let ids: Vec<String> = (0..10)
+            .map(|i| db.message_db.save_entry(i).unwrap())
+            .collect();
+        let raw = |db: &ClientDatabase| -> Vec<Vec<u8>> {
+            ids.iter()
+                .map(|id| db.message_db.store().get(DEFAULT_TREE, id.as_bytes()).unwrap().unwrap())
+                .collect()
+        };
+        // decrypts whatever it's given under the messages' key from the old master key
+        let old_path = PathBuf::from(location("client_test_reencrypt_old", backend));
+        let config = DbConfig {
+            backend,
+            ..DbConfig::default()
+        };
+        let old_key = ClientDatabase::tree_key(old_master, MESSAGES_DB).unwrap();
+        let under_old = EntryDb::with_key(&old_key, config.open(&old_path).unwrap()).unwrap();
+        assert!(raw(&db)
+            .iter()
+            .all(|entry| under_old.decrypt_value::<i32>(entry).is_ok()));
+
+        let mut done = vec![];
+        db.reencrypt(&new_master, &mut |name: &str| {
+            done.push(name.to_string());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(done, ENTRY_DBS);
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(db.message_db.get_entry::<i32>(id).unwrap(), i as i32);
+        }
+        assert!(raw(&db)
+            .iter()
+            .all(|entry| under_old.decrypt_value::<i32>(entry).is_err()));
+        under_old.store().insert(DEFAULT_TREE, b"moved", &raw(&db)[0]).unwrap();
+        assert!(matches!(under_old.get_entry::<i32>("moved"), Err(Error::Crypto(_))));
+
+        // one entry that won't decrypt and nothing is rewritten
+        let before = raw(&db);
+        db.message_db
+            .store()
+            .insert(DEFAULT_TREE, b"corrupt", b"not an entry")
+            .unwrap();
+        assert!(db.reencrypt(old_master, &mut |_: &str| Ok(())).is_err());
+        assert_eq!(raw(&db), before);
+        assert_eq!(db.message_db.get_entry::<i32>(&ids[0]).unwrap(), 0);
+        db.message_db.delete_entry("corrupt").unwrap();
+        // put back under the master key the other tests open with
+        db.reencrypt(old_master, &mut |_: &str| Ok(())).unwrap();
+
+        drop(under_old);
+        if old_path.is_dir() {
+            std::fs::remove_dir_all(old_path).unwrap();
+        } else {
+            std::fs::remove_file(old_path.with_extension("sqlite")).unwrap();
+        }
+    }
+
+    fn test_outbox_settling(backend: Backend) {
+        let db = open("client_test_outbox", backend);
+        db.message_db.clear().unwrap();
+        db.message_db.store().clear(OUTBOX_TREE).unwrap();
+        let chat_id = db
+            .save_chat(Chat::new(vec![], String::from("chat"), vec![], HashMap::new()))
+            .unwrap();
+        let send = |text: &str| {
+            let message = Message::outgoing(ServerId::from("server"), None, chat_id.clone(), text.to_string());
+            let entry = OutboxEntry {
+                recipients: vec![],
+                payload: vec![],
+                attempts: 0,
+                queued_at: message.timestamp(),
+            };
+            db.add_outgoing_message(message, &entry).unwrap()
+        };
+        let texts = || -> Vec<String> {
+            db.transcript(&chat_id)
+                .unwrap()
+                .messages
+                .into_iter()
+                .map(|m| m.text)
+                .collect()
+        };
+        let pause = || std::thread::sleep(Duration::from_millis(5));
+
+        // a reply arrived before the server's receipt, which is timestamped after it
+        let first = send("first");
+        assert_eq!(db.get_message(&first).unwrap().status(), MessageStatus::Pending);
+        assert_eq!(db.outbox().unwrap().len(), 1);
+        pause();
+        db.add_message(Message::new(ServerId::from("server"), None, chat_id.clone(), String::from("reply")))
+            .unwrap();
+        pause();
+        let sent_at = db.get_message(&first).unwrap().timestamp();
+        let relayed_at = SystemTime::now();
+        let settled = db.settle_outgoing(&first, Some(relayed_at), None).unwrap();
+        assert_eq!(settled.status(), MessageStatus::Sent);
+        assert_eq!(settled.relayed_at(), Some(relayed_at));
+        assert_eq!(settled.timestamp(), sent_at);
+        assert_eq!(texts(), vec!["first", "reply"]);
+
+        // nothing came in between, so the server's time orders it
+        let second = send("second");
+        pause();
+        let relayed_at = SystemTime::now();
+        let settled = db.settle_outgoing(&second, Some(relayed_at), None).unwrap();
+        assert_eq!(settled.timestamp(), relayed_at);
+        assert_eq!(db.chat_preview(&chat_id).unwrap().unwrap().timestamp, relayed_at);
+        assert_eq!(texts(), vec!["first", "reply", "second"]);
+
+        let failed = send("failed");
+        let settled = db.settle_outgoing(&failed, None, None).unwrap();
+        assert_eq!(settled.status(), MessageStatus::Failed);
+        assert!(settled.relayed_at().is_none());
+        assert!(db.outbox().unwrap().is_empty());
+
+        let deleted = send("deleted");
+        db.delete_message(&deleted).unwrap();
+        assert!(db.outbox().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_migrate_sled_to_sqlite() {
+        let key = b"an example very very secret key";
+        let loc = "client_test_migrate";
+        let sled = DbConfig::default();
+        let sqlite = DbConfig {
+            backend: Backend::Sqlite,
+            ..DbConfig::default()
+        };
+        let db = ClientDatabase::with_config(loc, key, sled.clone()).unwrap();
+        let base = db.base.clone();
+        let user_id = db
+            .save_user(User::new(String::from("bob"), String::from("key")))
+            .unwrap();
+        let chat_id = db
+            .save_chat(Chat::new(vec![user_id.clone()], String::from("chat"), vec![], HashMap::new()))
+            .unwrap();
+        let message_id = db
+            .add_message(Message::new(
+                ServerId::from("server"),
+                Some(user_id.clone()),
+                chat_id.clone(),
+                String::from("hello"),
+            ))
+            .unwrap();
+        drop(db);
+
+        let copied = ClientDatabase::migrate(loc, &sled, &sqlite).unwrap();
+        // user, chat, message and its preview, plus each store's data key mark
+        assert_eq!(copied, 8);
+        let db = ClientDatabase::with_config(loc, key, sqlite).unwrap();
+        assert_eq!(db.get_user(&user_id).unwrap().username(), "bob");
+        assert_eq!(db.get_chat(&chat_id).unwrap().name(), "chat");
+        assert_eq!(db.get_message(&message_id).unwrap().message(), "hello");
+        assert_eq!(db.chat_preview(&chat_id).unwrap().unwrap().message_id, message_id);
+
+        drop(db);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    fn test_upgrade_legacy_entries(backend: Backend) {
+        let key = b"an example very very secret key";
+        let loc = location("client_test_legacy_entries", backend);
+        // without a flusher thread sled releases its lock as soon as the store is dropped
+        let config = DbConfig {
+            backend,
+            flush_every_ms: None,
+            ..DbConfig::default()
+        };
+        let base = ClientDatabase::base_dir(&loc);
+        let _ = std::fs::remove_dir_all(&base);
+        {
+            // an entry written before keys went through the KDF: sealed with the raw passkey
+            let store = config.open(base.join(KNOWN_USERS_DB)).unwrap();
+            let nonce = ski::nonce();
+            let user = serde_json::to_vec(&User::new(String::from("bob"), String::from("key")))
+                .unwrap();
+            let entry = serde_json::json!({
+                "nonce": nonce,
+                "value": ski::encrypt_gcm(&user, key, &nonce).unwrap(),
+            });
+            store
+                .insert(DEFAULT_TREE, b"bob", entry.to_string().as_bytes())
+                .unwrap();
+            store.flush().unwrap();
+        }
+        // passkey stores can't be opened under a master key until they're moved over
+        assert!(ClientDatabase::with_config(&loc, key, config.clone()).is_err());
+        let master_key = ski::gen_key();
+        for name in ENTRY_DBS {
+            assert!(ClientDatabase::adopt_master_key(&loc, name, key, &master_key, &config).unwrap());
+            assert!(!ClientDatabase::adopt_master_key(&loc, name, key, &master_key, &config).unwrap());
+        }
+        let bob = UserId::from("bob");
+        let db = ClientDatabase::with_config(&loc, &master_key, config.clone()).unwrap();
+        assert_eq!(db.get_user(&bob).unwrap().username(), "bob");
+        drop(db);
+
+        // the entry was re-encrypted under the database's key and survives reopening
+        let db = ClientDatabase::with_config(&loc, &master_key, config.clone()).unwrap();
+        assert_eq!(db.get_user(&bob).unwrap().username(), "bob");
+        let raw = db.known_user_db.store().get(DEFAULT_TREE, b"bob").unwrap().unwrap();
+        assert!(db.known_user_db.decrypt_value::<User>(&raw).is_ok());
+        // each database has a key of its own
+        assert!(db.message_db.decrypt_value::<User>(&raw).is_err());
+        drop(db);
+        assert!(ClientDatabase::with_config(&loc, &ski::gen_key(), config)
+            .unwrap()
+            .get_user(&bob)
+            .is_err());
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    /// Opens a profile holding `peer` as a contact and a chat with them under `chat_id`,
+    /// which both participants' profiles share.
+    fn customization_peer(
+        name: &str,
+        chat_id: &ChatId,
+        peer: &RsaPrivateKey,
+    ) -> (ClientDatabase, UserId) {
+        let db = open(name, Backend::Sled);
+        db.known_user_db.clear().unwrap();
+        db.chat_db.clear().unwrap();
+        let pem = peer.to_public_key().to_public_key_pem(LineEnding::LF).unwrap();
+        let peer_id = db.save_user(User::new(String::from("peer"), pem)).unwrap();
+        let chat = Chat::new(vec![peer_id.clone()], String::from("chat"), vec![], HashMap::new());
+        db.chat_db.update_entry(chat_id.as_str(), chat).unwrap();
+        (db, peer_id)
+    }
+
+    #[test]
+    fn test_chat_customization() {
+        let chat_id = ChatId::from("shared_chat");
+        let alice_key = pki::gen_key().unwrap();
+        let bob_key = pki::gen_key().unwrap();
+        let (alice, bob_id) = customization_peer("client_test_custom_alice", &chat_id, &bob_key);
+        let (bob, alice_id) = customization_peer("client_test_custom_bob", &chat_id, &alice_key);
+
+        // a change made by one participant propagates to the other
+        let update = alice
+            .customize_chat(&chat_id, CHAT_COLORS[0], CHAT_EMOJIS[0], &alice_key)
+            .unwrap();
+        assert!(bob.apply_chat_customization(&alice_id, update.clone()).unwrap());
+        let on_alice = alice.get_chat(&chat_id).unwrap().customization().cloned();
+        let on_bob = bob.get_chat(&chat_id).unwrap().customization().cloned();
+        assert_eq!(on_bob.as_ref().unwrap().color, CHAT_COLORS[0]);
+        assert_eq!(on_alice, on_bob);
+        // replaying the same update changes nothing
+        assert!(!bob.apply_chat_customization(&alice_id, update).unwrap());
+
+        // both change it at once: each applies the other's update and they converge
+        let from_alice = alice
+            .customize_chat(&chat_id, CHAT_COLORS[1], CHAT_EMOJIS[1], &alice_key)
+            .unwrap();
+        let mut from_bob = bob
+            .customize_chat(&chat_id, CHAT_COLORS[2], CHAT_EMOJIS[2], &bob_key)
+            .unwrap();
+        alice.apply_chat_customization(&bob_id, from_bob.clone()).unwrap();
+        bob.apply_chat_customization(&alice_id, from_alice.clone()).unwrap();
+        let on_alice = alice.get_chat(&chat_id).unwrap().customization().cloned();
+        let on_bob = bob.get_chat(&chat_id).unwrap().customization().cloned();
+        assert_eq!(on_alice, on_bob);
+        assert_eq!(on_bob.unwrap().color, CHAT_COLORS[2]);
+
+        // out of palette values are rejected, whether set locally or received
+        assert!(alice
+            .customize_chat(&chat_id, "#123456", CHAT_EMOJIS[0], &alice_key)
+            .is_err());
+        assert!(alice
+            .customize_chat(&chat_id, CHAT_COLORS[0], "☠", &alice_key)
+            .is_err());
+        from_bob.customization.color = String::from("#123456");
+        assert!(alice.apply_chat_customization(&bob_id, from_bob.clone()).is_err());
+        // as are updates whose signature doesn't match the sender
+        let mut forged = from_alice.clone();
+        forged.customization.updated_at = SystemTime::now() + Duration::from_secs(60);
+        assert!(bob.apply_chat_customization(&alice_id, forged).is_err());
+        assert!(alice.apply_chat_customization(&bob_id, from_alice).is_err());
+        let chat = bob.get_chat(&chat_id).unwrap();
+        assert_eq!(chat.customization().unwrap().color, CHAT_COLORS[2]);
+
+        // the customization is part of the chat DTO
+        let summary = &bob.list_chats().unwrap()[0];
+        let json = serde_json::to_value(summary).unwrap();
+        assert_eq!(json["chat"]["customization"]["color"], CHAT_COLORS[2]);
+    }
+
+    fn test_trash_restore(backend: Backend) {
+        let db = open("client_test_trash", backend);
+        for tree in [&db.message_db, &db.server_db, &db.chat_db] {
+            tree.clear().unwrap();
+            tree.store().clear(TRASH_TREE).unwrap();
+        }
+        let chat_id = db
+            .save_chat(Chat::new(vec![], String::from("chat"), vec![], HashMap::new()))
+            .unwrap();
+        let mut server = ServerModel::new(
+            String::from("server"),
+            vec![],
+            vec![chat_id.clone()],
+            IpAddr::V4([127, 0, 0, 1].into()),
+            8080,
+        );
+        server.add_chat(chat_id.clone());
+        let server_id = db.save_server(server).unwrap();
+        let mut chat = db.get_chat(&chat_id).unwrap();
+        let mut add = |text: &str| {
+            let message = Message::new(server_id.clone(), None, chat_id.clone(), text.to_string());
+            let id = db.add_message(message).unwrap();
+            chat.push_message(id.clone());
+            id
+        };
+        let first = add("first needle");
+        let second = add("second needle");
+        db.chat_db.update_entry(chat_id.as_str(), chat).unwrap();
+
+        // a deleted message leaves every normal query
+        db.delete_message(&second).unwrap();
+        assert!(db.get_message(&second).is_err());
+        let found = db.search_messages("NEEDLE").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, first);
+        assert_eq!(db.chat_preview(&chat_id).unwrap().unwrap().message_id, first);
+        assert_eq!(db.get_chat(&chat_id).unwrap().message_ids(), vec![first.clone()]);
+        let usage = db.storage_usage().unwrap();
+        assert_eq!(usage.messages.entries, 1);
+        assert_eq!(usage.trash.entries, 1);
+        assert!(usage.trash.size_on_disk > 0);
+
+        db.restore_from_trash(second.as_str()).unwrap();
+        assert_eq!(db.get_message(&second).unwrap().message(), "second needle");
+        assert_eq!(db.search_messages("needle").unwrap().len(), 2);
+        assert_eq!(db.chat_preview(&chat_id).unwrap().unwrap().message_id, second);
+        let chat = db.get_chat(&chat_id).unwrap();
+        assert_eq!(chat.message_ids(), &[first.clone(), second.clone()]);
+        assert_eq!(chat.last_message_id(), Some(&second));
+        assert!(db.restore_from_trash(second.as_str()).is_err());
+
+        // a chat goes to the trash with its messages and comes back with them
+        db.delete_message(&first).unwrap();
+        db.delete_chat(&chat_id).unwrap();
+        assert!(db.get_chat(&chat_id).is_err());
+        assert!(db.list_chats().unwrap().is_empty());
+        assert!(db.search_messages("needle").unwrap().is_empty());
+        assert!(db.get_server(&server_id).unwrap().chat_ids().is_empty());
+        assert!(db.check_references().unwrap().is_empty());
+        let trash = db.list_trash().unwrap();
+        assert_eq!(trash.len(), 3);
+        assert!(trash.iter().any(|t| t.id == second.as_str()
+            && t.item == TrashedItem::ChatMessage { chat_id: chat_id.clone() }));
+        assert!(db.restore_from_trash(second.as_str()).is_err());
+        assert!(db.restore_from_trash(first.as_str()).unwrap_err().to_string().contains("restore it first"));
+
+        db.restore_from_trash(chat_id.as_str()).unwrap();
+        assert_eq!(db.get_chat(&chat_id).unwrap().message_ids(), vec![second.clone()]);
+        assert_eq!(db.get_server(&server_id).unwrap().chat_ids(), vec![chat_id.clone()]);
+        assert_eq!(db.search_messages("needle").unwrap().len(), 1);
+        assert_eq!(db.list_chats().unwrap()[0].preview.as_ref().unwrap().message_id, second);
+        db.restore_from_trash(first.as_str()).unwrap();
+        assert_eq!(db.get_chat(&chat_id).unwrap().message_ids(), &[first, second]);
+        assert_eq!(db.storage_usage().unwrap().trash.entries, 0);
+        assert!(db.check_references().unwrap().is_empty());
+
+        let base = db.base.clone();
+        drop(db);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    fn test_trash_after_rekey(backend: Backend) {
+        let name = "client_test_trash_rekey";
+        let other_name = "client_test_trash_import";
+        for name in [name, other_name] {
+            let _ = std::fs::remove_dir_all(ClientDatabase::base_dir(&location(name, backend)));
+        }
+        let mut db = open(name, backend);
+        let chat_id = db
+            .save_chat(Chat::new(vec![], String::from("chat"), vec![], HashMap::new()))
+            .unwrap();
+        let message = |text: &str| {
+            Message::new(ServerId::from("server"), None, chat_id.clone(), text.to_string())
+        };
+        let message_id = db.add_message(message("rotated")).unwrap();
+        db.delete_message(&message_id).unwrap();
+        // the trashed message is re-encrypted along with everything else
+        db.reencrypt(&ski::gen_key(), &mut |_: &str| Ok(())).unwrap();
+        db.restore_from_trash(message_id.as_str()).unwrap();
+        assert_eq!(db.get_message(&message_id).unwrap().message(), "rotated");
+
+        // and carried over to a profile under another key
+        db.delete_chat(&chat_id).unwrap();
+        let config = DbConfig {
+            backend,
+            ..DbConfig::default()
+        };
+        let other =
+            ClientDatabase::with_config(&location(other_name, backend), &ski::gen_key(), config)
+                .unwrap();
+        other.import_entries(db.export_entries().unwrap()).unwrap();
+        other.restore_from_trash(chat_id.as_str()).unwrap();
+        assert_eq!(other.get_chat(&chat_id).unwrap().name(), "chat");
+        assert_eq!(other.get_message(&message_id).unwrap().message(), "rotated");
+
+        for db in [db, other] {
+            let base = db.base.clone();
+            drop(db);
+            std::fs::remove_dir_all(base).unwrap();
+        }
+    }
+
+    fn test_entry_ttl(backend: Backend) {
+        let db = open("client_test_entry_ttl", backend);
+        for tree in [&db.known_user_db, &db.message_db, &db.server_db, &db.chat_db] {
+            tree.clear().unwrap();
+        }
+        db.message_db.save_entry(1).unwrap();
+        let lasting = db
+            .message_db
+            .save_entry_with_ttl(2, Duration::from_secs(60 * 60))
+            .unwrap();
+        let fleeting = db
+            .message_db
+            .save_entry_with_ttl(3, Duration::from_millis(1))
+            .unwrap();
+        let user = db
+            .known_user_db
+            .save_entry_with_ttl(4, Duration::from_millis(1))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(db.message_db.get_entry::<i32>(&lasting).unwrap(), 2);
+        assert!(matches!(db.message_db.get_entry::<i32>(&fleeting), Err(Error::Expired)));
+
+        // left out of listings before it's purged
+        let mut values: Vec<i32> = db
+            .message_db
+            .get_all_entries::<i32>()
+            .unwrap()
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        values.sort_unstable();
+        assert_eq!(values, [1, 2]);
+        // updating doesn't bring it back
+        db.message_db.update_entry(&fleeting, 30).unwrap();
+        assert!(matches!(db.message_db.get_entry::<i32>(&fleeting), Err(Error::Expired)));
+        // the expiry stored next to the ciphertext can't be pushed back
+        let store = db.message_db.store();
+        let kept = db
+            .message_db
+            .save_entry_with_ttl(5, Duration::from_secs(60))
+            .unwrap();
+        let raw = store.get(DEFAULT_TREE, kept.as_bytes()).unwrap().unwrap();
+        let mut entry: serde_json::Value = serde_json::from_slice(&raw).unwrap();
+        entry["expires_at"] = serde_json::json!(SystemTime::now() + Duration::from_secs(60 * 60));
+        let tampered = serde_json::to_vec(&entry).unwrap();
+        store.insert(DEFAULT_TREE, kept.as_bytes(), &tampered).unwrap();
+        assert!(db.message_db.get_entry::<i32>(&kept).is_err());
+        db.message_db.delete_entry(&kept).unwrap();
+
+        assert_eq!(db.purge_expired_all().unwrap(), 2);
+        assert!(!db.message_db.contains(&fleeting).unwrap());
+        assert!(!db.known_user_db.contains(&user).unwrap());
+        assert_eq!(db.message_db.len().unwrap(), 2);
+        assert_eq!(db.purge_expired_all().unwrap(), 0);
+    }
+
+    fn test_trash_expiry(backend: Backend) {
+        let mut db = open("client_test_trash_expiry", backend);
+        for tree in [&db.message_db, &db.chat_db] {
+            tree.clear().unwrap();
+            tree.store().clear(TRASH_TREE).unwrap();
+        }
+        let chat_id = db
+            .save_chat(Chat::new(vec![], String::from("chat"), vec![], HashMap::new()))
+            .unwrap();
+        let message = |text: &str| {
+            Message::new(ServerId::from("server"), None, chat_id.clone(), text.to_string())
+        };
+        let kept = db.add_message(message("kept")).unwrap();
+        let deleted = db.add_message(message("deleted")).unwrap();
+        db.delete_message(&deleted).unwrap();
+        // nothing is due within the default window
+        assert_eq!(db.purge_expired_trash().unwrap(), 0);
+
+        db.set_trash_retention(Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(10));
+        let err = db.restore_from_trash(deleted.as_str()).unwrap_err();
+        assert!(err.to_string().contains("too long ago"));
+        db.delete_chat(&chat_id).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        // the message, the chat and the chat's remaining message
+        assert_eq!(db.purge_expired_trash().unwrap(), 3);
+        assert!(db.list_trash().unwrap().is_empty());
+        assert!(db.restore_from_trash(chat_id.as_str()).is_err());
+        assert!(db.get_message(&kept).is_err());
+
+        let base = db.base.clone();
+        drop(db);
+        std::fs::remove_dir_all(base).unwrap();
     }
 }
@@ -0,0 +1,120 @@
+use std::time::{Duration, Instant};
+
+use async_std::future;
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+
+use crate::shared::{pki, rpc_models};
+use crate::Error;
+
+use super::{
+    models::{DiscoveredServer, ServerEndpoint, ServerId},
+    Client,
+};
+
+impl Client {
+    /// Browses the local network for servers advertising themselves over mDNS, for
+    /// `timeout`. Servers on this machine's loopback interface are found too.
+    pub async fn discover_servers(timeout: Duration) -> Result<Vec<DiscoveredServer>, Error> {
+        let daemon = ServiceDaemon::new()?;
+        daemon.enable_interface(vec![IfKind::LoopbackV4, IfKind::LoopbackV6])?;
+        let events = daemon.browse(rpc_models::MDNS_SERVICE_TYPE)?;
+        let deadline = Instant::now() + timeout;
+        let mut found: Vec<DiscoveredServer> = Vec::new();
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let event = match future::timeout(remaining, events.recv_async()).await {
+                Ok(Ok(event)) => event,
+                // timed out, or the daemon stopped
+                _ => break,
+            };
+            if let ServiceEvent::ServiceResolved(info) = event {
+                match discovered(&info) {
+                    Some(server) if !found.iter().any(|known| known.name == server.name) => {
+                        found.push(server)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let _ = daemon.stop_browse(rpc_models::MDNS_SERVICE_TYPE);
+        let _ = daemon.shutdown();
+        Ok(found)
+    }
+
+    /// Connects to a server found by `discover_servers`. One already saved under the
+    /// advertised fingerprint is reached at the discovered address, which is tried first
+    /// from then on if it checks out; any other is saved. The session is only kept if
+    /// the server holds the key it advertised.
+    pub async fn connect_discovered(
+        &mut self,
+        discovered: &DiscoveredServer,
+    ) -> Result<ServerId, Error> {
+        let (server_id, added) = self.save_discovered(discovered)?;
+        let endpoint = ServerEndpoint::from((discovered.ip, discovered.port));
+        let connected = self.connect_through(server_id.as_str(), Some(endpoint)).await;
+        self.check_discovered(discovered, &server_id, added, connected)?;
+        Ok(server_id)
+    }
+
+    /// The saved server `discovered` is, saving it if there is none. Returns whether it
+    /// was added, for `check_discovered`.
+    pub fn save_discovered(
+        &mut self,
+        discovered: &DiscoveredServer,
+    ) -> Result<(ServerId, bool), Error> {
+        let saved = self.db.find_server_by_fingerprint(&discovered.fingerprint)?;
+        Ok(match saved {
+            Some((server_id, _)) => (server_id, false),
+            None => {
+                let endpoint = ServerEndpoint::from((discovered.ip, discovered.port));
+                (self.add_server(discovered.name.clone(), vec![endpoint])?, true)
+            }
+        })
+    }
+
+    /// Keeps the session `connected` opened with a discovered server only if the server
+    /// holds the key it advertised. A server saved just for the attempt is forgotten
+    /// again when it doesn't.
+    pub fn check_discovered(
+        &mut self,
+        discovered: &DiscoveredServer,
+        server_id: &ServerId,
+        added: bool,
+        connected: Result<(), Error>,
+    ) -> Result<(), Error> {
+        if connected.is_ok() {
+            let server = self.db.get_server(server_id)?;
+            let fingerprint = server.pub_key.as_ref().map(pki::fingerprint).transpose()?;
+            if fingerprint.as_ref() == Some(&discovered.fingerprint) {
+                return Ok(());
+            }
+            self.disconnect_server(server_id.as_str())?;
+        }
+        if added {
+            // nothing was learned about the server worth keeping
+            self.db.server_db.delete_entry(server_id.as_str())?;
+        }
+        connected?;
+        Err(Error::Auth(String::from(
+            "The server's key doesn't match the fingerprint it advertised",
+        )))
+    }
+}
+
+/// The advertised server, `None` for a record without a fingerprint or an address.
+fn discovered(info: &ServiceInfo) -> Option<DiscoveredServer> {
+    let fingerprint = info.get_property_val_str(rpc_models::MDNS_FINGERPRINT_KEY)?;
+    let addresses = info.get_addresses();
+    // IPv4 preferred, a link-local IPv6 address needs a scope to be reached
+    let ip = addresses
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or_else(|| addresses.iter().next())?;
+    let name = info.get_fullname().trim_end_matches(info.get_type());
+    Some(DiscoveredServer {
+        name: name.trim_end_matches('.').to_string(),
+        ip: *ip,
+        port: info.get_port(),
+        fingerprint: fingerprint.to_string(),
+    })
+}
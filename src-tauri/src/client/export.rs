@@ -0,0 +1,245 @@
+use std::{
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::models::{ChatId, MessageId, ServerId, UserId};
+use crate::shared::json;
+use crate::Error;
+
+/// A message as exported. The field names are the JSON Lines schema, so renaming one
+/// breaks every export already written.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TranscriptMessage {
+    pub message_id: MessageId,
+    pub chat_id: ChatId,
+    pub server_id: ServerId,
+    pub sender_id: Option<UserId>,
+    pub sender_name: Option<String>,
+    pub timestamp: SystemTime,
+    pub text: String,
+    pub truncated: bool,
+}
+
+/// A chat's messages, oldest first, ready to be handed to a formatter.
+#[derive(Clone, Debug)]
+pub struct Transcript {
+    pub chat_id: ChatId,
+    pub chat_name: String,
+    pub messages: Vec<TranscriptMessage>,
+}
+
+/// Turns a transcript into one export format. New formats only need a formatter, the
+/// export itself stays the same.
+pub trait TranscriptFormatter {
+    /// Extension of the exported file, without the dot.
+    fn extension(&self) -> &'static str;
+    fn write(&self, transcript: &Transcript, out: &mut dyn Write) -> Result<(), Error>;
+}
+
+/// One `TranscriptMessage` object per line.
+pub struct JsonLinesFormatter;
+impl TranscriptFormatter for JsonLinesFormatter {
+    fn extension(&self) -> &'static str {
+        "jsonl"
+    }
+    fn write(&self, transcript: &Transcript, out: &mut dyn Write) -> Result<(), Error> {
+        for message in &transcript.messages {
+            serde_json::to_writer(&mut *out, message)?;
+            out.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads back what `JsonLinesFormatter` wrote. Blank lines are skipped.
+pub fn read_json_lines(input: &str) -> Result<Vec<TranscriptMessage>, Error> {
+    let mut messages = vec![];
+    for (number, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message = json::from_slice(line.as_bytes())
+            .map_err(|e| format!("Line {}: {}", number + 1, e))?;
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// Plain-text mailbox in the mboxrd flavor, one mail per message, so mail and archive
+/// tools can index a chat.
+pub struct MboxFormatter;
+impl TranscriptFormatter for MboxFormatter {
+    fn extension(&self) -> &'static str {
+        "mbox"
+    }
+    fn write(&self, transcript: &Transcript, out: &mut dyn Write) -> Result<(), Error> {
+        for message in &transcript.messages {
+            let secs = message.timestamp.duration_since(UNIX_EPOCH)?.as_secs();
+            let address = match message.sender_id {
+                Some(ref id) => format!("{}@carapace.invalid", header_value(id.as_str())),
+                None => String::from("unknown@carapace.invalid"),
+            };
+            let name = message
+                .sender_name
+                .as_deref()
+                .or(message.sender_id.as_ref().map(|id| id.as_str()))
+                .unwrap_or("unknown");
+            writeln!(out, "From {} {}", address, asctime(secs))?;
+            writeln!(out, "From: \"{}\" <{}>", quoted(name), address)?;
+            writeln!(out, "Date: {}", rfc2822(secs))?;
+            writeln!(out, "Subject: {}", header_value(&transcript.chat_name))?;
+            writeln!(out, "X-Carapace-Message-Id: {}", header_value(message.message_id.as_str()))?;
+            writeln!(out, "X-Carapace-Chat-Id: {}", header_value(message.chat_id.as_str()))?;
+            writeln!(out, "Content-Type: text/plain; charset=utf-8")?;
+            writeln!(out)?;
+            for line in message.text.lines() {
+                if line.trim_start_matches('>').starts_with("From ") {
+                    out.write_all(b">")?;
+                }
+                writeln!(out, "{}", line)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps peer-controlled text from starting a new header line.
+fn header_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+fn quoted(value: &str) -> String {
+    header_value(value).replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Splits seconds since the epoch into UTC date and time fields: weekday (0 for
+/// Thursday), year, month (0 based), day, hours, minutes, seconds.
+fn civil(secs: u64) -> (usize, u64, usize, u64, u64, u64, u64) {
+    let days = secs / 86400;
+    let rem = secs % 86400;
+    // Howard Hinnant's days_from_civil, inverted
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 2 } else { mp - 10 };
+    let year = yoe + era * 400 + u64::from(month < 2);
+    (
+        (days % 7) as usize,
+        year,
+        month as usize,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+    )
+}
+
+fn rfc2822(secs: u64) -> String {
+    let (weekday, year, month, day, h, m, s) = civil(secs);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+        DAYS[weekday], day, MONTHS[month], year, h, m, s
+    )
+}
+
+fn asctime(secs: u64) -> String {
+    let (weekday, year, month, day, h, m, s) = civil(secs);
+    format!(
+        "{} {} {:>2} {:02}:{:02}:{:02} {}",
+        DAYS[weekday], MONTHS[month], day, h, m, s, year
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn transcript() -> Transcript {
+        let chat_id = ChatId::from("chat-1");
+        let message = |id: &str, sender: Option<(&str, &str)>, secs: u64, text: &str| {
+            TranscriptMessage {
+                message_id: MessageId::from(id),
+                chat_id: chat_id.clone(),
+                server_id: ServerId::from("server-1"),
+                sender_id: sender.map(|(id, _)| UserId::from(id)),
+                sender_name: sender.map(|(_, name)| name.to_string()),
+                timestamp: UNIX_EPOCH + Duration::new(secs, 500),
+                text: text.to_string(),
+                truncated: false,
+            }
+        };
+        Transcript {
+            chat_id: chat_id.clone(),
+            chat_name: String::from("Plans\nBcc: everyone"),
+            messages: vec![
+                message("m1", Some(("u1", "Alice \"A\"")), 1_709_251_199, "hi\nFrom here on"),
+                message("m2", None, 951_782_400, ">From the archive"),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_mbox_format() {
+        let mut out = vec![];
+        MboxFormatter.write(&transcript(), &mut out).unwrap();
+        let expected = "\
+From u1@carapace.invalid Thu Feb 29 23:59:59 2024
+From: \"Alice \\\"A\\\"\" <u1@carapace.invalid>
+Date: Thu, 29 Feb 2024 23:59:59 +0000
+Subject: Plans Bcc: everyone
+X-Carapace-Message-Id: m1
+X-Carapace-Chat-Id: chat-1
+Content-Type: text/plain; charset=utf-8
+
+hi
+>From here on
+
+From unknown@carapace.invalid Tue Feb 29 00:00:00 2000
+From: \"unknown\" <unknown@carapace.invalid>
+Date: Tue, 29 Feb 2000 00:00:00 +0000
+Subject: Plans Bcc: everyone
+X-Carapace-Message-Id: m2
+X-Carapace-Chat-Id: chat-1
+Content-Type: text/plain; charset=utf-8
+
+>>From the archive
+
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_json_lines_round_trip() {
+        let transcript = transcript();
+        let mut out = vec![];
+        JsonLinesFormatter.write(&transcript, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first["message_id"], "m1");
+        assert_eq!(first["sender_name"], "Alice \"A\"");
+        assert_eq!(first["text"], "hi\nFrom here on");
+        assert!(lines[1].contains("\"sender_id\":null"));
+        assert_eq!(read_json_lines(&out).unwrap(), transcript.messages);
+        assert!(read_json_lines("{}\n").unwrap_err().to_string().starts_with("Line 1"));
+    }
+}
@@ -0,0 +1,579 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::shared::json::{self, JsonLimits};
+use crate::Error;
+use super::models::ChatId;
+
+/// Largest export that is read, well past what any messenger writes for a person.
+pub const MAX_IMPORT_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Export formats of other messengers that history can be imported from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ImportFormat {
+    /// Signal Desktop's JSON export: conversations and messages, either side by side
+    /// or with the messages nested in their conversation.
+    Signal,
+    /// Element's "Export chat" JSON for a single Matrix room.
+    Matrix,
+}
+
+/// Fields of an imported record that carapace has no use for, kept as exported.
+pub type Extras = Map<String, Value>;
+
+#[derive(Debug, PartialEq)]
+pub struct ExternalContact {
+    /// Identifier in the source format, e.g. a Signal service id or a Matrix user id.
+    pub id: String,
+    pub name: String,
+    pub extras: Extras,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExternalMessage {
+    /// Contact id of the sender, `None` for messages sent by whoever exported them.
+    pub sender: Option<String>,
+    pub text: String,
+    pub timestamp: SystemTime,
+    pub extras: Extras,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExternalConversation {
+    pub id: String,
+    pub name: String,
+    pub messages: Vec<ExternalMessage>,
+    pub extras: Extras,
+}
+
+/// A record that couldn't be imported. The rest of the export is imported anyway.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RecordError {
+    /// Where the record is in the export, e.g. `messages[3]`.
+    pub record: String,
+    pub message: String,
+}
+
+/// Everything read from an export, before it's written to the database.
+#[derive(Debug, Default)]
+pub struct ExternalArchive {
+    pub contacts: Vec<ExternalContact>,
+    pub conversations: Vec<ExternalConversation>,
+    pub errors: Vec<RecordError>,
+}
+impl ExternalArchive {
+    fn error(&mut self, record: String, message: &str) {
+        self.errors.push(RecordError {
+            record,
+            message: message.to_string(),
+        });
+    }
+
+    /// Adds a contact unless one with the same id was already read.
+    fn add_contact(&mut self, contact: ExternalContact) {
+        if !self.contacts.iter().any(|c| c.id == contact.id) {
+            self.contacts.push(contact);
+        }
+    }
+}
+
+/// What an import added, along with the records it had to skip.
+#[derive(Serialize, Debug, Default)]
+pub struct ImportReport {
+    pub contacts: usize,
+    pub chats: Vec<ChatId>,
+    pub messages: usize,
+    pub errors: Vec<RecordError>,
+}
+
+/// Reads the export at `path`, see `parse`. A file over `max_bytes` is turned away
+/// before any of it is read.
+pub fn read(format: ImportFormat, path: &Path, max_bytes: u64) -> Result<ExternalArchive, Error> {
+    let file = File::open(path)?;
+    if file.metadata()?.len() > max_bytes {
+        Err(format!("Exports over {} bytes can't be imported", max_bytes))?;
+    }
+    // in case it grows while it's read
+    parse(format, file.take(max_bytes))
+}
+
+/// Reads an export. Only a file that isn't an export of `format` at all is an error;
+/// unreadable records end up in the archive's `errors`.
+pub fn parse<R: Read>(format: ImportFormat, input: R) -> Result<ExternalArchive, Error> {
+    let root: Value = json::from_reader(input, &JsonLimits::default())?;
+    match format {
+        ImportFormat::Signal => parse_signal(root),
+        ImportFormat::Matrix => parse_matrix(root),
+    }
+}
+
+/// Takes the first of `keys` holding a non-empty string.
+fn take_string(record: &mut Extras, keys: &[&str]) -> Option<String> {
+    let mut found = None;
+    for key in keys {
+        match record.remove(*key) {
+            Some(Value::String(s)) if found.is_none() && !s.is_empty() => found = Some(s),
+            Some(value) if found.is_some() || value.is_null() => {}
+            // kept for the extras, it just isn't the string we expected
+            Some(value) => {
+                record.insert(key.to_string(), value);
+            }
+            None => {}
+        }
+    }
+    found
+}
+
+/// Takes the first of `keys` holding milliseconds since the epoch, as a number or a
+/// numeric string.
+fn take_millis(record: &mut Extras, keys: &[&str]) -> Option<SystemTime> {
+    let mut found = None;
+    for key in keys {
+        let millis = match record.get(*key) {
+            Some(Value::Number(n)) => n.as_u64(),
+            Some(Value::String(s)) => s.parse().ok(),
+            _ => None,
+        };
+        if let Some(millis) = millis {
+            record.remove(*key);
+            if found.is_none() {
+                found = Some(UNIX_EPOCH + Duration::from_millis(millis));
+            }
+        }
+    }
+    found
+}
+
+fn into_object(value: Value) -> Option<Extras> {
+    match value {
+        Value::Object(object) => Some(object),
+        _ => None,
+    }
+}
+
+fn take_array(record: &mut Extras, key: &str) -> Vec<Value> {
+    match record.remove(key) {
+        Some(Value::Array(values)) => values,
+        Some(value) => {
+            record.insert(key.to_string(), value);
+            vec![]
+        }
+        None => vec![],
+    }
+}
+
+const SIGNAL_CONTACT_IDS: [&str; 5] = ["serviceId", "uuid", "aci", "e164", "id"];
+const SIGNAL_SENDER_IDS: [&str; 4] = ["sourceServiceId", "sourceUuid", "source", "author"];
+
+fn parse_signal(root: Value) -> Result<ExternalArchive, Error> {
+    let mut root = into_object(root).ok_or("Not a Signal export: expected a JSON object")?;
+    if !root.contains_key("conversations") {
+        Err("Not a Signal export: no conversations")?;
+    }
+    let mut archive = ExternalArchive::default();
+    let mut conversations = vec![];
+    // every id a contact goes by, so messages can name their sender by any of them
+    let mut aliases: HashMap<String, String> = HashMap::new();
+    let mut nested = vec![];
+    for (i, conversation) in take_array(&mut root, "conversations").into_iter().enumerate() {
+        let record = format!("conversations[{}]", i);
+        let mut conversation = match into_object(conversation) {
+            Some(conversation) => conversation,
+            None => {
+                archive.error(record, "not an object");
+                continue;
+            }
+        };
+        let ids: Vec<String> = SIGNAL_CONTACT_IDS
+            .iter()
+            .filter_map(|key| conversation.get(*key).and_then(Value::as_str))
+            .filter(|id| !id.is_empty())
+            .map(str::to_string)
+            .collect();
+        let id = match take_string(&mut conversation, &["id"]).or_else(|| ids.first().cloned()) {
+            Some(id) => id,
+            None => {
+                archive.error(record, "no conversation id");
+                continue;
+            }
+        };
+        let name = take_string(
+            &mut conversation,
+            &["name", "profileFullName", "profileName", "title", "e164"],
+        )
+        .unwrap_or_else(|| id.clone());
+        let private = match take_string(&mut conversation, &["type"]) {
+            Some(kind) => kind == "private",
+            None => false,
+        };
+        if private {
+            for alias in &ids {
+                aliases.insert(alias.clone(), id.clone());
+            }
+            aliases.insert(id.clone(), id.clone());
+            let mut extras = conversation.clone();
+            for key in SIGNAL_CONTACT_IDS {
+                extras.remove(key);
+            }
+            extras.remove("messages");
+            archive.add_contact(ExternalContact {
+                id: id.clone(),
+                name: name.clone(),
+                extras,
+            });
+        }
+        for (j, message) in take_array(&mut conversation, "messages").into_iter().enumerate() {
+            nested.push((format!("{}.messages[{}]", record, j), Some(id.clone()), message));
+        }
+        conversations.push((id, name, conversation, private));
+    }
+
+    let mut messages: HashMap<String, Vec<ExternalMessage>> = HashMap::new();
+    let top_level = take_array(&mut root, "messages")
+        .into_iter()
+        .enumerate()
+        .map(|(i, message)| (format!("messages[{}]", i), None, message));
+    for (record, conversation_id, message) in nested.into_iter().chain(top_level) {
+        let mut message = match into_object(message) {
+            Some(message) => message,
+            None => {
+                archive.error(record, "not an object");
+                continue;
+            }
+        };
+        let conversation_id = match take_string(&mut message, &["conversationId"]).or(conversation_id) {
+            Some(id) => id,
+            None => {
+                archive.error(record, "no conversation id");
+                continue;
+            }
+        };
+        let text = match take_string(&mut message, &["body", "text"]) {
+            Some(text) => text,
+            None => {
+                archive.error(record, "no text");
+                continue;
+            }
+        };
+        let timestamp = match take_millis(&mut message, &["sent_at", "timestamp", "received_at"]) {
+            Some(timestamp) => timestamp,
+            None => {
+                archive.error(record, "no timestamp");
+                continue;
+            }
+        };
+        let outgoing = matches!(message.get("type").and_then(Value::as_str), Some("outgoing"));
+        let source = take_string(&mut message, &SIGNAL_SENDER_IDS);
+        let sender = match (outgoing, source) {
+            (true, _) => None,
+            (false, Some(source)) => match aliases.get(&source) {
+                Some(id) => Some(id.clone()),
+                None => {
+                    // a group member we have no conversation with
+                    archive.add_contact(ExternalContact {
+                        id: source.clone(),
+                        name: source.clone(),
+                        extras: Extras::new(),
+                    });
+                    aliases.insert(source.clone(), source.clone());
+                    Some(source)
+                }
+            },
+            // incoming messages in a private conversation are from its contact
+            (false, None) => aliases.get(&conversation_id).cloned(),
+        };
+        messages.entry(conversation_id).or_default().push(ExternalMessage {
+            sender,
+            text,
+            timestamp,
+            extras: message,
+        });
+    }
+
+    for (id, name, extras, _) in conversations {
+        let mut conversation_messages = messages.remove(&id).unwrap_or_default();
+        conversation_messages.sort_by_key(|message| message.timestamp);
+        archive.conversations.push(ExternalConversation {
+            id,
+            name,
+            messages: conversation_messages,
+            extras,
+        });
+    }
+    for (id, orphans) in messages {
+        archive.error(
+            format!("conversation {}", id),
+            &format!("{} messages for a conversation that isn't in the export", orphans.len()),
+        );
+    }
+    Ok(archive)
+}
+
+fn parse_matrix(root: Value) -> Result<ExternalArchive, Error> {
+    let mut root = into_object(root).ok_or("Not a Matrix export: expected a JSON object")?;
+    if !root.contains_key("messages") {
+        Err("Not a Matrix export: no messages")?;
+    }
+    let mut archive = ExternalArchive::default();
+    let events = take_array(&mut root, "messages");
+    let room_id = take_string(&mut root, &["room_id"]).or_else(|| {
+        events
+            .iter()
+            .find_map(|event| event.get("room_id").and_then(Value::as_str))
+            .map(str::to_string)
+    });
+    let name = take_string(&mut root, &["room_name", "name"]);
+    let id = match room_id.or_else(|| name.clone()) {
+        Some(id) => id,
+        None => Err("Not a Matrix export: no room id or name")?,
+    };
+
+    // display names come from membership events, wherever they are in the room
+    let mut display_names: HashMap<String, String> = HashMap::new();
+    for event in &events {
+        if event.get("type").and_then(Value::as_str) == Some("m.room.member") {
+            let user = event.get("state_key").and_then(Value::as_str);
+            let name = event
+                .get("content")
+                .and_then(|content| content.get("displayname"))
+                .and_then(Value::as_str);
+            if let (Some(user), Some(name)) = (user, name) {
+                display_names.insert(user.to_string(), name.to_string());
+            }
+        }
+    }
+
+    let mut messages = vec![];
+    for (i, event) in events.into_iter().enumerate() {
+        let record = format!("messages[{}]", i);
+        let mut event = match into_object(event) {
+            Some(event) => event,
+            None => {
+                archive.error(record, "not an object");
+                continue;
+            }
+        };
+        // membership, topic and other room state isn't part of the history
+        match event.get("type").and_then(Value::as_str) {
+            Some("m.room.message") => {}
+            Some(_) => continue,
+            None => {
+                archive.error(record, "no event type");
+                continue;
+            }
+        }
+        event.remove("type");
+        event.remove("room_id");
+        let sender = match take_string(&mut event, &["sender", "user_id"]) {
+            Some(sender) => sender,
+            None => {
+                archive.error(record, "no sender");
+                continue;
+            }
+        };
+        let timestamp = match take_millis(&mut event, &["origin_server_ts", "ts"]) {
+            Some(timestamp) => timestamp,
+            None => {
+                archive.error(record, "no timestamp");
+                continue;
+            }
+        };
+        let mut content = match event.remove("content").and_then(into_object) {
+            Some(content) => content,
+            None => {
+                archive.error(record, "no content");
+                continue;
+            }
+        };
+        let text = match take_string(&mut content, &["body"]) {
+            Some(text) => text,
+            None => {
+                archive.error(record, "no text");
+                continue;
+            }
+        };
+        if !content.is_empty() {
+            event.insert(String::from("content"), Value::Object(content));
+        }
+        archive.add_contact(ExternalContact {
+            id: sender.clone(),
+            name: display_names.get(&sender).cloned().unwrap_or_else(|| sender.clone()),
+            extras: Extras::new(),
+        });
+        messages.push(ExternalMessage {
+            sender: Some(sender),
+            text,
+            timestamp,
+            extras: event,
+        });
+    }
+    messages.sort_by_key(|message| message.timestamp);
+    archive.conversations.push(ExternalConversation {
+        name: name.unwrap_or_else(|| id.clone()),
+        id,
+        messages,
+        extras: root,
+    });
+    Ok(archive)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A trimmed Signal Desktop export: one private and one group conversation, with a
+    /// field from a newer version and records that can't be imported.
+    pub(crate) const SIGNAL_EXPORT: &str = r#"{
+        "version": 7,
+        "conversations": [
+            { "id": "c1", "type": "private", "serviceId": "aci-alice", "e164": "+15550001",
+              "profileName": "Alice", "color": "ultramarine" },
+            { "id": "c2", "type": "group", "name": "Climbing",
+              "messages": [
+                { "type": "incoming", "sourceServiceId": "aci-bob", "body": "rope?",
+                  "sent_at": "1700000300000" }
+              ] },
+            "garbage"
+        ],
+        "messages": [
+            { "conversationId": "c1", "type": "incoming", "body": "hi", "sent_at": 1700000000000,
+              "hasVisualMediaAttachments": false },
+            { "conversationId": "c1", "type": "outgoing", "body": "hey", "sent_at": 1700000100000 },
+            { "conversationId": "c2", "type": "incoming", "source": "+15550001", "body": "yes",
+              "timestamp": 1700000400000 },
+            { "conversationId": "c1", "type": "incoming", "sent_at": 1700000200000,
+              "attachments": [{}] },
+            { "conversationId": "c9", "body": "lost", "sent_at": 1 }
+        ]
+    }"#;
+
+    /// A trimmed Element room export.
+    pub(crate) const MATRIX_EXPORT: &str = r#"{
+        "room_name": "Book club",
+        "room_creator": "@carol:example.org",
+        "topic": "Monthly",
+        "export_date": "1/2/2024",
+        "messages": [
+            { "type": "m.room.member", "sender": "@carol:example.org",
+              "state_key": "@carol:example.org", "room_id": "!room:example.org",
+              "content": { "membership": "join", "displayname": "Carol" },
+              "origin_server_ts": 1700000000000 },
+            { "type": "m.room.message", "sender": "@dave:example.org", "room_id": "!room:example.org",
+              "event_id": "$2", "origin_server_ts": 1700000200000,
+              "content": { "msgtype": "m.text", "body": "done" } },
+            { "type": "m.room.message", "sender": "@carol:example.org", "room_id": "!room:example.org",
+              "event_id": "$1", "origin_server_ts": 1700000100000,
+              "content": { "msgtype": "m.text", "body": "finished it?", "format": "org.matrix.custom.html" },
+              "unsigned": { "age": 5 } },
+            { "type": "m.room.message", "sender": "@dave:example.org", "origin_server_ts": 1700000300000,
+              "content": { "msgtype": "m.image", "url": "mxc://x" } }
+        ]
+    }"#;
+
+    fn millis(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        let archive = parse(ImportFormat::Signal, SIGNAL_EXPORT.as_bytes()).unwrap();
+        let contacts: Vec<(&str, &str)> = archive
+            .contacts
+            .iter()
+            .map(|c| (c.id.as_str(), c.name.as_str()))
+            .collect();
+        assert_eq!(contacts, vec![("c1", "Alice"), ("aci-bob", "aci-bob")]);
+        assert_eq!(archive.contacts[0].extras["color"], "ultramarine");
+
+        assert_eq!(archive.conversations.len(), 2);
+        let alice = &archive.conversations[0];
+        assert_eq!(alice.name, "Alice");
+        let texts: Vec<(&str, Option<&str>)> = alice
+            .messages
+            .iter()
+            .map(|m| (m.text.as_str(), m.sender.as_deref()))
+            .collect();
+        assert_eq!(texts, vec![("hi", Some("c1")), ("hey", None)]);
+        assert_eq!(alice.messages[0].timestamp, millis(1_700_000_000_000));
+        assert_eq!(alice.messages[0].extras["hasVisualMediaAttachments"], false);
+
+        let group = &archive.conversations[1];
+        assert_eq!(group.name, "Climbing");
+        let senders: Vec<Option<&str>> = group.messages.iter().map(|m| m.sender.as_deref()).collect();
+        // Alice is recognized by her phone number too
+        assert_eq!(senders, vec![Some("aci-bob"), Some("c1")]);
+
+        let errors: Vec<(&str, &str)> = archive
+            .errors
+            .iter()
+            .map(|e| (e.record.as_str(), e.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("conversations[2]", "not an object"),
+                ("messages[3]", "no text"),
+                ("conversation c9", "1 messages for a conversation that isn't in the export"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_matrix() {
+        let archive = parse(ImportFormat::Matrix, MATRIX_EXPORT.as_bytes()).unwrap();
+        let contacts: Vec<(&str, &str)> = archive
+            .contacts
+            .iter()
+            .map(|c| (c.id.as_str(), c.name.as_str()))
+            .collect();
+        assert_eq!(
+            contacts,
+            vec![("@dave:example.org", "@dave:example.org"), ("@carol:example.org", "Carol")]
+        );
+        let room = &archive.conversations[0];
+        assert_eq!(room.id, "!room:example.org");
+        assert_eq!(room.name, "Book club");
+        assert_eq!(room.extras["topic"], "Monthly");
+        let texts: Vec<&str> = room.messages.iter().map(|m| m.text.as_str()).collect();
+        assert_eq!(texts, vec!["finished it?", "done"]);
+        assert_eq!(room.messages[0].extras["event_id"], "$1");
+        assert_eq!(room.messages[0].extras["content"]["format"], "org.matrix.custom.html");
+        assert!(!room.messages[0].extras.contains_key("sender"));
+        assert_eq!(archive.errors.len(), 1);
+        assert_eq!(archive.errors[0].record, "messages[3]");
+    }
+
+    #[test]
+    fn test_parse_wrong_format() {
+        assert!(parse(ImportFormat::Signal, MATRIX_EXPORT.as_bytes()).is_err());
+        assert!(parse(ImportFormat::Matrix, SIGNAL_EXPORT.as_bytes()).is_err());
+        assert!(parse(ImportFormat::Matrix, "[]".as_bytes()).is_err());
+        assert!(parse(ImportFormat::Signal, "not json".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_read_hostile_export() {
+        let name = format!("carapace-import-{}.json", uuid::Uuid::new_v4());
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, SIGNAL_EXPORT).unwrap();
+        let size = SIGNAL_EXPORT.len() as u64;
+        assert!(read(ImportFormat::Signal, &path, size).is_ok());
+        let err = read(ImportFormat::Signal, &path, size - 1).unwrap_err();
+        assert!(err.to_string().contains("can't be imported"));
+
+        // turned away by the depth limit instead of overflowing the stack
+        let nested = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+        std::fs::write(&path, nested).unwrap();
+        let err = read(ImportFormat::Signal, &path, MAX_IMPORT_BYTES).unwrap_err();
+        assert!(err.to_string().contains("depth"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use rsa::{
+    pkcs8::{DecodePrivateKey, EncodePrivateKey},
+    RsaPrivateKey,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::shared::{
+    json::{self, JsonLimits},
+    pki::{
+        ed25519_key_exists, read_ed25519_key_from_file, read_key_from_file,
+        write_ed25519_key_to_file, write_key_to_file,
+    },
+    ski,
+};
+use crate::Error;
+
+use super::{
+    db::{ClientDatabase, ENTRY_DBS, SERVER_DB},
+    master_key::{self, MasterKey},
+    models::{PendingKeyRotation, ServerId, ServerModel},
+};
+
+const INTENT_FILE: &str = "intents.json";
+
+/// Step of the operations that rewrite the key files.
+pub const KEY_FILE_STEP: &str = "key_file";
+
+/// Step of an identity rotation after which `server_id` knows the new key.
+pub fn server_step(server_id: &ServerId) -> String {
+    format!("server:{}", server_id)
+}
+
+/// Step of an identity rotation after which `server_id` was given up on, because it
+/// couldn't be reached or refused the new key.
+pub fn skipped_step(server_id: &ServerId) -> String {
+    format!("skipped:{}", server_id)
+}
+
+/// A secret kept in the intent log, encrypted under a passphrase.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SealedSecret {
+    salt: Vec<u8>,
+    data: Vec<u8>,
+}
+impl SealedSecret {
+    pub fn seal(secret: &[u8], passphrase: &[u8]) -> Result<Self, Error> {
+        let salt = ski::salt();
+        let key = Zeroizing::new(ski::derive_key(passphrase, &salt)?);
+        let data = ski::seal_gcm(secret, key.as_slice())?;
+        Ok(SealedSecret { salt, data })
+    }
+
+    /// The secret, or `None` if it wasn't sealed under `passphrase`.
+    pub fn open(&self, passphrase: &[u8]) -> Result<Option<Zeroizing<Vec<u8>>>, Error> {
+        let key = Zeroizing::new(ski::derive_key(passphrase, &self.salt)?);
+        Ok(ski::open_gcm(&self.data, key.as_slice()).ok().map(Zeroizing::new))
+    }
+}
+
+/// A multi-step operation on the profile, with what recovery needs to finish it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Operation {
+    /// Re-encrypting the key files and every entry database under a new passphrase,
+    /// as done before profiles had a master key. Each passphrase is sealed under the
+    /// other, so whichever one is typed at the next unlock gets the profile back under it.
+    ChangePassphrase {
+        old_under_new: SealedSecret,
+        new_under_old: SealedSecret,
+    },
+    /// Replacing the identity key. Servers are told first, and the key file is only
+    /// rewritten after.
+    RotateIdentity {
+        new_key: SealedSecret,
+        servers: Vec<ServerId>,
+    },
+    /// Moving a profile keyed directly by the passphrase over to a master key, which is
+    /// sealed here under that passphrase until it's saved.
+    AdoptMasterKey { master_key: SealedSecret },
+    /// Re-encrypting the key files and every entry database under a new master key. The
+    /// new key is only saved once everything is under it.
+    RotateMasterKey { new_master_key: SealedSecret },
+}
+impl Operation {
+    fn name(&self) -> &'static str {
+        match self {
+            Operation::ChangePassphrase { .. } => "change_passphrase",
+            Operation::RotateIdentity { .. } => "rotate_identity",
+            Operation::AdoptMasterKey { .. } => "adopt_master_key",
+            Operation::RotateMasterKey { .. } => "rotate_master_key",
+        }
+    }
+}
+
+/// An operation that was started, and the steps of it that completed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Intent {
+    pub id: String,
+    pub operation: Operation,
+    pub completed: Vec<String>,
+    pub started_at: SystemTime,
+}
+impl Intent {
+    pub fn is_done(&self, step: &str) -> bool {
+        self.completed.iter().any(|done| done == step)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum Outcome {
+    RolledForward,
+    RolledBack,
+}
+
+/// What happened to an operation found unfinished at unlock.
+#[derive(Clone, Debug, Serialize)]
+pub struct RecoveredIntent {
+    pub operation: &'static str,
+    pub started_at: SystemTime,
+    pub completed: Vec<String>,
+    pub outcome: Outcome,
+    /// Anything left for the user to do, e.g. servers to register with again.
+    pub notes: Vec<String>,
+}
+
+/// Journal of the multi-step operations in progress on a profile, kept next to its
+/// key file. An intent is written before an operation touches anything, each step is
+/// checkpointed once done, and the intent is dropped when the operation completes.
+pub struct IntentLog {
+    path: PathBuf,
+    // step at which the next operation stops as if the process died, before the step is
+    // checkpointed
+    #[cfg(test)]
+    pub fail_at: Option<String>,
+}
+impl IntentLog {
+    pub fn new(loc: &str) -> Self {
+        IntentLog {
+            path: ClientDatabase::base_dir(loc).join(INTENT_FILE),
+            #[cfg(test)]
+            fail_at: None,
+        }
+    }
+
+    pub fn pending(&self) -> Result<Vec<Intent>, Error> {
+        if !self.path.exists() {
+            return Ok(vec![]);
+        }
+        json::from_reader(fs::File::open(&self.path)?, &JsonLimits::default())
+    }
+
+    fn save(&self, intents: &[Intent]) -> Result<(), Error> {
+        if intents.is_empty() {
+            if self.path.exists() {
+                fs::remove_file(&self.path)?;
+            }
+            return Ok(());
+        }
+        // written aside and renamed over the old file, like the key files
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(intents)?)?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Records that `operation` is starting. Only one operation runs at a time, an
+    /// unfinished one has to be recovered first.
+    pub fn begin(&self, operation: Operation) -> Result<String, Error> {
+        let mut intents = self.pending()?;
+        if let Some(intent) = intents.first() {
+            Err(format!(
+                "Another operation ({}) is unfinished, unlock the profile again to recover it",
+                intent.operation.name()
+            ))?;
+        }
+        let id = Uuid::new_v4().to_string();
+        intents.push(Intent {
+            id: id.clone(),
+            operation,
+            completed: vec![],
+            started_at: SystemTime::now(),
+        });
+        fs::create_dir_all(self.path.parent().ok_or("Intent log has no directory")?)?;
+        self.save(&intents)?;
+        Ok(id)
+    }
+
+    pub fn checkpoint(&self, id: &str, step: &str) -> Result<(), Error> {
+        #[cfg(test)]
+        if self.fail_at.as_deref() == Some(step) {
+            Err(format!("Injected failure before checkpointing {}", step))?;
+        }
+        let mut intents = self.pending()?;
+        let intent = intents
+            .iter_mut()
+            .find(|intent| intent.id == id)
+            .ok_or("Intent not found")?;
+        intent.completed.push(step.to_string());
+        self.save(&intents)
+    }
+
+    pub fn complete(&self, id: &str) -> Result<(), Error> {
+        let mut intents = self.pending()?;
+        intents.retain(|intent| intent.id != id);
+        self.save(&intents)
+    }
+}
+
+/// Finishes or undoes every operation the profile at `loc` was left in the middle of,
+/// so it opens consistently under `passphrase`. Steps are checked before they're
+/// redone, since the one in flight may have completed without being checkpointed.
+pub fn recover(loc: &str, passphrase: &[u8]) -> Result<Vec<RecoveredIntent>, Error> {
+    let log = IntentLog::new(loc);
+    let mut recovered = vec![];
+    for intent in log.pending()? {
+        let (outcome, notes) = match intent.operation {
+            Operation::ChangePassphrase {
+                ref old_under_new,
+                ref new_under_old,
+            } => recover_passphrase_change(loc, passphrase, old_under_new, new_under_old)?,
+            Operation::RotateIdentity {
+                ref new_key,
+                ref servers,
+            } => recover_identity_rotation(loc, passphrase, &intent, new_key, servers)?,
+            Operation::AdoptMasterKey { ref master_key } => {
+                recover_master_key_adoption(loc, passphrase, master_key)?
+            }
+            Operation::RotateMasterKey { ref new_master_key } => {
+                recover_master_key_rotation(loc, passphrase, new_master_key)?
+            }
+        };
+        log.complete(&intent.id)?;
+        recovered.push(RecoveredIntent {
+            operation: intent.operation.name(),
+            started_at: intent.started_at,
+            completed: intent.completed,
+            outcome,
+            notes,
+        });
+    }
+    Ok(recovered)
+}
+
+/// Puts the key files and databases under `passphrase`, whichever of the two it is:
+/// rolled forward when the new one is typed, back when the old one is.
+fn recover_passphrase_change(
+    loc: &str,
+    passphrase: &[u8],
+    old_under_new: &SealedSecret,
+    new_under_old: &SealedSecret,
+) -> Result<(Outcome, Vec<String>), Error> {
+    let (other, outcome) = if let Some(old) = old_under_new.open(passphrase)? {
+        (old, Outcome::RolledForward)
+    } else if let Some(new) = new_under_old.open(passphrase)? {
+        (new, Outcome::RolledBack)
+    } else {
+        Err("A passphrase change was interrupted, unlock with the old or the new passphrase")?
+    };
+    if read_key_from_file(loc, passphrase).is_err() {
+        write_key_to_file(&read_key_from_file(loc, &other)?, loc, passphrase)?;
+    }
+    if ed25519_key_exists(loc) && read_ed25519_key_from_file(loc, passphrase).is_err() {
+        write_ed25519_key_to_file(&read_ed25519_key_from_file(loc, &other)?, loc, passphrase)?;
+    }
+    for name in ENTRY_DBS {
+        let mut db = ClientDatabase::open_legacy_entry_db(loc, name, &other)?;
+        if db.unlocks()? {
+            db.rekey(passphrase)?;
+        }
+    }
+    Ok((outcome, vec![]))
+}
+
+/// Keeps the new key if any server took it, as the old one is refused there now, and
+/// leaves the rotation pending on the servers that didn't. Otherwise the old key, which
+/// the key file still holds, stays, and the rotations recorded for the new one are
+/// pointed back at it.
+fn recover_identity_rotation(
+    loc: &str,
+    passphrase: &[u8],
+    intent: &Intent,
+    new_key: &SealedSecret,
+    servers: &[ServerId],
+) -> Result<(Outcome, Vec<String>), Error> {
+    let not_updated: Vec<&ServerId> = servers
+        .iter()
+        .filter(|server_id| !intent.is_done(&server_step(server_id)))
+        .collect();
+    let new_key = match new_key.open(passphrase)? {
+        Some(der) => Some(RsaPrivateKey::from_pkcs8_der(&der)?),
+        None => None,
+    };
+    let master = MasterKey::unlock(loc, passphrase)?;
+    let file_key = master.key_file_key()?;
+    let kept_key = read_key_from_file(loc, &file_key)?;
+    let server_db = ClientDatabase::open_entry_db(loc, SERVER_DB, master.as_bytes())?;
+    // the rotation each server was left with, if it's the interrupted one
+    let pending = |server: &ServerModel| match (&server.key_rotation, &new_key) {
+        (Some(rotation), Some(new_key)) if *rotation.new_pub_key() == new_key.to_public_key() => {
+            Some(rotation.clone())
+        }
+        _ => None,
+    };
+    if not_updated.len() == servers.len() && !intent.is_done(KEY_FILE_STEP) {
+        // the servers still behind on an earlier rotation are handed the old key instead
+        for (id, mut server) in server_db.get_all_entries::<ServerModel>()? {
+            let rotation = match pending(&server) {
+                Some(rotation) => rotation,
+                None => continue,
+            };
+            let known_key = rotation.old_key()?;
+            server.key_rotation = if known_key == kept_key {
+                None
+            } else {
+                Some(PendingKeyRotation::new(&known_key, kept_key.to_public_key())?)
+            };
+            server_db.update_entry(&id, server)?;
+        }
+        // the server being told when the rotation stopped may have taken the key anyway
+        let in_flight = servers.iter().find(|server_id| {
+            !intent.is_done(&server_step(server_id)) && !intent.is_done(&skipped_step(server_id))
+        });
+        let notes = match in_flight {
+            Some(server_id) => vec![format!(
+                "No server confirmed the new key, so the old one was kept. If server {} took it before the interruption, register with it again.",
+                server_id
+            )],
+            None => vec![],
+        };
+        return Ok((Outcome::RolledBack, notes));
+    }
+    let new_key = new_key
+        .as_ref()
+        .ok_or("An identity rotation was interrupted, unlock with the passphrase it started under")?;
+    if kept_key != *new_key {
+        write_key_to_file(new_key, loc, &file_key)?;
+    }
+    let mut notes = vec![];
+    for server_id in not_updated {
+        let server = server_db.get_entry::<ServerModel>(server_id.as_str()).ok();
+        notes.push(if server.as_ref().and_then(pending).is_some() {
+            format!(
+                "Server {} only knows the old key, it is handed the new one when key rotations are retried.",
+                server_id
+            )
+        } else {
+            format!("Server {} only knows the old key, register with it again.", server_id)
+        });
+    }
+    Ok((Outcome::RolledForward, notes))
+}
+
+/// Finishes moving the profile over to the master key sealed in the intent.
+fn recover_master_key_adoption(
+    loc: &str,
+    passphrase: &[u8],
+    sealed: &SealedSecret,
+) -> Result<(Outcome, Vec<String>), Error> {
+    let key = sealed.open(passphrase)?.ok_or(
+        "Moving the profile to a master key was interrupted, unlock with the passphrase it started under",
+    )?;
+    master_key::adopt(loc, passphrase, &MasterKey::from_bytes(&key), &mut |_: &str| Ok(()))?;
+    Ok((Outcome::RolledForward, vec![]))
+}
+
+/// Finishes putting the key files and databases under the new master key. Whatever
+/// still opens under the saved one is moved; once the new key is saved, all of it was.
+fn recover_master_key_rotation(
+    loc: &str,
+    passphrase: &[u8],
+    sealed: &SealedSecret,
+) -> Result<(Outcome, Vec<String>), Error> {
+    let new = sealed.open(passphrase)?.ok_or(
+        "A master key rotation was interrupted, unlock with the passphrase it started under",
+    )?;
+    let new = MasterKey::from_bytes(&new);
+    let old = MasterKey::unlock(loc, passphrase)?;
+    if old.as_bytes() != new.as_bytes() {
+        master_key::move_key_files(loc, &old.key_file_key()?, &new.key_file_key()?)?;
+        for name in ENTRY_DBS {
+            let mut db = ClientDatabase::open_entry_db(loc, name, old.as_bytes())?;
+            if db.unlocks()? {
+                db.set_key(&ClientDatabase::tree_key(new.as_bytes(), name)?)?;
+            }
+        }
+        new.save(loc, passphrase)?;
+    }
+    Ok((Outcome::RolledForward, vec![]))
+}
+
+/// The DER of `key`, for sealing into the intent log.
+pub fn key_der(key: &RsaPrivateKey) -> Result<Zeroizing<Vec<u8>>, Error> {
+    Ok(Zeroizing::new(key.to_pkcs8_der()?.as_bytes().to_vec()))
+}
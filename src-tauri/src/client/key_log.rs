@@ -0,0 +1,184 @@
+use rsa::RsaPublicKey;
+
+use crate::shared::{
+    rpc::Request,
+    rpc_models,
+    transparency::{self, LogEntry, SignedTreeHead},
+};
+use crate::Error;
+
+use super::{models::ServerId, Client, KEY_LOG_EQUIVOCATION_EVENT};
+
+impl Client {
+    fn connected_server_key(&self, server_id: &ServerId) -> Result<RsaPublicKey, Error> {
+        self.connection_state(server_id.as_str())?
+            .server
+            .pub_key
+            .clone()
+            .ok_or_else(|| "Server key not known".into())
+    }
+
+    /// Asks `server_id` for key log entries. Errors if it couldn't produce them.
+    async fn key_log_entries(
+        &self,
+        server_id: &ServerId,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<Vec<LogEntry>, Error> {
+        let request = Request::new(method.to_string(), params);
+        let response = self.try_send_sym_encrypted_request(server_id.as_str(), request).await?;
+        Ok(serde_json::from_value(response.into_result()?)?)
+    }
+
+    /// Tells the user `server_id` signed heads `old` and `new` of logs that can't both
+    /// be true, and fails.
+    fn report_equivocation(
+        &self,
+        server_id: &ServerId,
+        old: &SignedTreeHead,
+        new: &SignedTreeHead,
+    ) -> Result<(), Error> {
+        self.emit(
+            KEY_LOG_EQUIVOCATION_EVENT,
+            serde_json::json!({ "server_id": server_id, "heads": [old, new] }),
+        );
+        self.db.add_system_notice(
+            server_id,
+            format!(
+                "SEVERE: this server showed two key logs that can't both be true, of {} and {} entries. The keys it hands out can't be trusted.",
+                old.size, new.size
+            ),
+        )?;
+        Err(format!("Server {} equivocated about its key log", server_id).into())
+    }
+
+    /// Checks that the server's log only grew between heads `a` and `b`, in whichever
+    /// order they were seen. If the proof it hands over doesn't hold, it showed a forked
+    /// log and the equivocation is reported; a server that can't answer only fails.
+    async fn check_consistency(
+        &self,
+        server_id: &ServerId,
+        a: &SignedTreeHead,
+        b: &SignedTreeHead,
+    ) -> Result<(), Error> {
+        let (old, new) = if a.size <= b.size { (a, b) } else { (b, a) };
+        let consistent = if old.size == new.size {
+            old.hash == new.hash
+        } else {
+            let params = rpc_models::ConsistencyProofParams {
+                from_size: old.size,
+                to_size: new.size,
+            };
+            let entries = self
+                .key_log_entries(
+                    server_id,
+                    rpc_models::GET_CONSISTENCY_PROOF,
+                    serde_json::json!(params),
+                )
+                .await?;
+            transparency::verify_consistency(old, new, &entries)
+        };
+        if !consistent {
+            self.report_equivocation(server_id, old, new)?;
+        }
+        Ok(())
+    }
+
+    /// Fetches the connected server's key log head and checks it extends the one we saw
+    /// last, then keeps it. Meant to be called periodically, and whenever a key is
+    /// looked up.
+    pub async fn refresh_log_head(&mut self) -> Result<SignedTreeHead, Error> {
+        let server_id = self.server_id.clone().ok_or("Server not found")?;
+        self.ensure_connected(server_id.as_str()).await?;
+        match self.refresh_server_log_head(&server_id).await {
+            Err(e) if self.session_lost(server_id.as_str(), &e) => {
+                self.recover_session(server_id.as_str(), e).await?;
+                self.refresh_server_log_head(&server_id).await
+            }
+            result => result,
+        }
+    }
+
+    /// Like `refresh_log_head`, for `server_id` over the session as it is.
+    async fn refresh_server_log_head(
+        &self,
+        server_id: &ServerId,
+    ) -> Result<SignedTreeHead, Error> {
+        let server_key = self.connected_server_key(server_id)?;
+        let request = Request::new(rpc_models::GET_LOG_HEAD.to_string(), serde_json::json!(null));
+        let response = self.try_send_sym_encrypted_request(server_id.as_str(), request).await?;
+        let head: SignedTreeHead = serde_json::from_value(response.into_result()?)?;
+        if !head.verify(&server_key) {
+            Err(Error::Auth(String::from("Key log head isn't signed by the server")))?;
+        }
+        if let Some(ref known) = self.db.get_server(server_id)?.log_head {
+            if known.size > head.size {
+                // the log can't have shrunk since the server signed `known`
+                self.report_equivocation(server_id, &head, known)?;
+            }
+            self.check_consistency(server_id, known, &head).await?;
+        }
+        let mut server = self.db.get_server(server_id)?;
+        server.log_head = Some(head.clone());
+        self.db.server_db.update_entry(server_id.as_str(), server)?;
+        Ok(head)
+    }
+
+    /// Checks a head of the connected server's key log that someone else was shown,
+    /// e.g. gossiped in a chat, against ours.
+    pub async fn check_log_head(&mut self, head: SignedTreeHead) -> Result<(), Error> {
+        let server_id = self.server_id.clone().ok_or("Server not found")?;
+        self.ensure_connected(server_id.as_str()).await?;
+        match self.check_server_log_head(&server_id, head.clone()).await {
+            Err(e) if self.session_lost(server_id.as_str(), &e) => {
+                self.recover_session(server_id.as_str(), e).await?;
+                self.check_server_log_head(&server_id, head).await
+            }
+            result => result,
+        }
+    }
+
+    /// Like `check_log_head`, against our head of `server_id`'s log, over the session
+    /// as it is.
+    pub async fn check_server_log_head(
+        &self,
+        server_id: &ServerId,
+        head: SignedTreeHead,
+    ) -> Result<(), Error> {
+        if !head.verify(&self.connected_server_key(server_id)?) {
+            Err(Error::Auth(String::from("Key log head isn't signed by the server")))?;
+        }
+        let ours = self.refresh_server_log_head(server_id).await?;
+        if head.size > ours.size {
+            // the server signed `head` before `ours`, and its log can't have shrunk since
+            return self.report_equivocation(server_id, &ours, &head);
+        }
+        self.check_consistency(server_id, &ours, &head).await
+    }
+
+    /// Has `server_id` prove that it logged binding `username` to `pub_key`.
+    pub async fn verify_key_inclusion(
+        &self,
+        server_id: &ServerId,
+        username: &str,
+        pub_key: &RsaPublicKey,
+    ) -> Result<(), Error> {
+        let head = self.refresh_server_log_head(server_id).await?;
+        let params = rpc_models::InclusionProofParams {
+            username: username.to_string(),
+            pub_key: pub_key.clone(),
+            tree_size: head.size,
+        };
+        let entries = self
+            .key_log_entries(
+                server_id,
+                rpc_models::GET_INCLUSION_PROOF,
+                serde_json::json!(params),
+            )
+            .await?;
+        if !transparency::verify_inclusion(&head, username, pub_key, &entries) {
+            Err(format!("Server can't prove {}'s key is in its key log", username))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use std::{fs, path::PathBuf};
+
+use zeroize::Zeroizing;
+
+use crate::shared::{
+    db::DbConfig,
+    json::{self, JsonLimits},
+    pki::{
+        ed25519_key_exists, key_exists, read_ed25519_key_from_file, read_key_from_file,
+        write_ed25519_key_to_file, write_key_to_file,
+    },
+    ski,
+};
+use crate::Error;
+
+use super::{
+    db::{ClientDatabase, ENTRY_DBS},
+    intent::{IntentLog, Operation, SealedSecret, KEY_FILE_STEP},
+};
+
+const MASTER_KEY_FILE: &str = "master_key.json";
+
+/// The random key a profile is encrypted under, kept wrapped by a key derived from the
+/// passphrase. The entry databases and key files each get their own key derived from
+/// it, so changing the passphrase only rewraps this one key.
+pub struct MasterKey {
+    key: Zeroizing<Vec<u8>>,
+}
+impl MasterKey {
+    pub fn generate() -> Self {
+        MasterKey {
+            key: Zeroizing::new(ski::gen_key()),
+        }
+    }
+
+    pub fn from_bytes(key: &[u8]) -> Self {
+        MasterKey {
+            key: Zeroizing::new(key.to_vec()),
+        }
+    }
+
+    fn path(loc: &str) -> PathBuf {
+        ClientDatabase::base_dir(loc).join(MASTER_KEY_FILE)
+    }
+
+    pub fn exists(loc: &str) -> bool {
+        Self::path(loc).exists()
+    }
+
+    /// Unwraps the master key of the profile at `loc`.
+    pub fn unlock(loc: &str, passphrase: &[u8]) -> Result<Self, Error> {
+        let file = fs::File::open(Self::path(loc))?;
+        let sealed: SealedSecret = json::from_reader(file, &JsonLimits::default())?;
+        let key = sealed.open(passphrase)?.ok_or(Error::InvalidPassphrase)?;
+        Ok(MasterKey { key })
+    }
+
+    /// Wraps the key under `passphrase` and saves it as the master key of the profile at
+    /// `loc`, in place of the one it had.
+    pub fn save(&self, loc: &str, passphrase: &[u8]) -> Result<(), Error> {
+        let path = Self::path(loc);
+        fs::create_dir_all(path.parent().ok_or("Master key has no directory")?)?;
+        let sealed = SealedSecret::seal(&self.key, passphrase)?;
+        // written aside and renamed over the old file, like the key files
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(&sealed)?)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The key the profile's key files are encrypted under.
+    pub fn key_file_key(&self) -> Result<Zeroizing<Vec<u8>>, Error> {
+        ski::derive_subkey(&self.key, b"carapace key files")
+    }
+}
+
+/// The key the key files of the profile at `loc` are encrypted under. Profiles from
+/// before there was a master key use the passphrase itself.
+pub fn key_file_key(loc: &str, passphrase: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+    if MasterKey::exists(loc) {
+        MasterKey::unlock(loc, passphrase)?.key_file_key()
+    } else {
+        Ok(Zeroizing::new(passphrase.to_vec()))
+    }
+}
+
+/// Re-encrypts the key files from `from` to `to`. Files already under `to` are left
+/// alone, so it can be run again after an interruption.
+pub fn move_key_files(loc: &str, from: &[u8], to: &[u8]) -> Result<(), Error> {
+    if key_exists(loc) {
+        match read_key_from_file(loc, from) {
+            Ok(key) => write_key_to_file(&key, loc, to)?,
+            Err(e) => {
+                read_key_from_file(loc, to).map_err(|_| e)?;
+            }
+        }
+    }
+    if ed25519_key_exists(loc) {
+        match read_ed25519_key_from_file(loc, from) {
+            Ok(key) => write_ed25519_key_to_file(&key, loc, to)?,
+            Err(e) => {
+                read_ed25519_key_from_file(loc, to).map_err(|_| e)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Moves a profile keyed directly by the passphrase over to a fresh master key. If it's
+/// cut short, the next unlock finishes it.
+pub fn migrate(loc: &str, passphrase: &[u8]) -> Result<MasterKey, Error> {
+    // checked before anything is journaled, a wrong passphrase must leave no trace
+    if key_exists(loc) {
+        read_key_from_file(loc, passphrase)?;
+    } else if ed25519_key_exists(loc) {
+        read_ed25519_key_from_file(loc, passphrase)?;
+    }
+    let master_key = MasterKey::generate();
+    let log = IntentLog::new(loc);
+    let intent = log.begin(Operation::AdoptMasterKey {
+        master_key: SealedSecret::seal(master_key.as_bytes(), passphrase)?,
+    })?;
+    adopt(loc, passphrase, &master_key, &mut |step: &str| log.checkpoint(&intent, step))?;
+    log.complete(&intent)?;
+    Ok(master_key)
+}
+
+/// Puts whatever is still under `passphrase` under keys from `master_key`, then saves
+/// `master_key` for the profile. Every step checks whether it's done already.
+pub fn adopt(
+    loc: &str,
+    passphrase: &[u8],
+    master_key: &MasterKey,
+    checkpoint: &mut dyn FnMut(&str) -> Result<(), Error>,
+) -> Result<(), Error> {
+    move_key_files(loc, passphrase, &master_key.key_file_key()?)?;
+    checkpoint(KEY_FILE_STEP)?;
+    let config = DbConfig::default();
+    for name in ENTRY_DBS {
+        ClientDatabase::adopt_master_key(loc, name, passphrase, master_key.as_bytes(), &config)?;
+        checkpoint(name)?;
+    }
+    master_key.save(loc, passphrase)
+}
@@ -1,23 +1,80 @@
-use std::error::Error;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 
-use async_std::{net::TcpStream, stream, task};
-use rsa::{pkcs1v15::Signature, RsaPrivateKey};
+use async_std::{
+    channel::{self, Receiver, Sender},
+    future,
+    net::{TcpStream, ToSocketAddrs},
+    task,
+};
+use futures::{
+    future::{BoxFuture, FutureExt},
+    StreamExt,
+};
+use rand_core::OsRng;
+use rsa::{
+    pkcs1v15::Signature,
+    pkcs8::{DecodePrivateKey, EncodePrivateKey, EncodePublicKey},
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey};
 
 use crate::shared::{
+    json,
     models::EncryptionConfiguration,
     pki::{
-        decrypt_message, encrypt_message, gen_key, key_exists, read_key_from_file, sign_message,
-        verify_signature, write_key_to_file,
+        self, ed25519_key_exists, gen_key, get_line_ending, key_exists,
+        read_ed25519_key_from_file, read_key_from_file, sign_handshake,
+        verify_handshake_signature, write_ed25519_key_to_file, write_key_to_file,
     },
-    rpc::{self, Handler, Request, Response, RpcError, RpcErrorCode},
-    rpc_models::{self, ClientEncryptionPackage, RespondClientChallenge, RespondServerChallenge},
-    ski::{decrypt_gcm, encrypt_gcm},
+    rpc::{self, Codec, Handler, Request, Response, RpcError, RpcErrorCode},
+    rpc_models::{
+        self, Capabilities, Capability, RespondClientChallenge, RespondServerChallenge,
+        RevokeSessionParams, ServerInfo,
+    },
+    ski::{self, open_gcm},
 };
+use crate::Error;
 
-use self::{db::ClientDatabase, models::ServerModel};
+use self::{
+    backup::BackupContents,
+    connection::Connection,
+    db::ClientDatabase,
+    export::TranscriptFormatter,
+    import::{ImportFormat, ImportReport},
+    intent::{IntentLog, Operation, RecoveredIntent, SealedSecret, KEY_FILE_STEP},
+    master_key::MasterKey,
+    models::{
+        ChatId, KeyFingerprint, PendingKeyRotation, ServerEndpoint, ServerId, ServerModel,
+        ServerStatus, ServerSummary, User, UserId, UserKeyLookup,
+    },
+    security::{CipherSuite, PinStatus, SecurityAssessment, SecurityMinimum, SessionParameters},
+    supervisor::{RestartPolicy, Supervisor, TaskHealth},
+};
 
+pub mod backup;
+mod chat;
+#[cfg(test)]
+mod compat_fixtures;
+mod connection;
+mod contacts;
 mod db;
-mod models;
+mod discovery;
+pub mod export;
+pub mod import;
+pub mod intent;
+mod key_log;
+mod master_key;
+pub mod models;
+pub mod security;
+mod supervisor;
 struct ClientHandler;
 impl Handler for ClientHandler {
     async fn handle(&mut self, request: Request) -> Response {
@@ -35,62 +92,773 @@ impl Handler for ClientHandler {
         response
     }
 }
-struct Client {
+
+/// Receives client events that the UI should know about, e.g. a Tauri `AppHandle`.
+pub trait EventEmitter: Send + Sync {
+    fn emit(&self, event: &str, payload: serde_json::Value);
+}
+
+pub const SESSION_REVOKED_EVENT: &str = "session-revoked";
+pub const SERVERS_REFRESHED_EVENT: &str = "servers-refreshed";
+pub const SECURITY_WARNING_EVENT: &str = "security-warning";
+/// A server showed key logs that can't both be true, so the keys it hands out can't be
+/// trusted.
+pub const KEY_LOG_EQUIVOCATION_EVENT: &str = "key-log-equivocation";
+/// A message from the outbox was relayed or gave up on, see `Client::flush_outbox`.
+pub const MESSAGE_RECONCILED_EVENT: &str = "message-reconciled";
+/// A lost session is being reconnected, came back, or was given up on.
+pub const CONNECTION_STATUS_EVENT: &str = "connection-status";
+/// A chat message from another participant was stored, see `Client::on_forwarded_message`.
+pub const MESSAGE_RECEIVED_EVENT: &str = "message-received";
+/// A recipient acknowledged a message of ours, see `Client::on_message_ack`.
+pub const MESSAGE_ACKED_EVENT: &str = "message-acked";
+/// Another participant changed a chat's look, see `Client::on_forwarded_message`.
+pub const CHAT_CUSTOMIZED_EVENT: &str = "chat-customized";
+
+const MAX_CONCURRENT_PROBES: usize = 8;
+/// How long a quiet connection has to answer a ping before it's taken for lost.
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(2);
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// Connects to the first of `endpoints` that answers, trying every address a hostname
+/// resolves to with `timeout` each. Fails with what went wrong at every endpoint.
+async fn connect_endpoints(
+    endpoints: &[ServerEndpoint],
+    timeout: Duration,
+) -> Result<(TcpStream, ServerEndpoint), Error> {
+    if endpoints.is_empty() {
+        Err("Server has no endpoints")?;
+    }
+    let mut errors = vec![];
+    for endpoint in endpoints {
+        let addrs = match (endpoint.host.as_str(), endpoint.port).to_socket_addrs().await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                errors.push(format!("{}: can't resolve: {}", endpoint, e));
+                continue;
+            }
+        };
+        for addr in addrs {
+            match future::timeout(timeout, TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => return Ok((stream, endpoint.clone())),
+                Ok(Err(e)) => errors.push(format!("{} ({}): {}", endpoint, addr, e)),
+                Err(_) => errors.push(format!("{} ({}): timed out", endpoint, addr)),
+            }
+        }
+    }
+    // kept an io error so a reconnect tries again
+    Err(Error::Io(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        format!("Could not connect to server: {}", errors.join("; ")),
+    )))
+}
+
+/// Checks a server we don't hold a session with by asking for its public info over
+/// a short-lived connection. The key only counts as matching if the server signed our
+/// challenge with the pinned one; a server that doesn't sign leaves it unknown.
+async fn probe_server(
+    endpoints: Vec<ServerEndpoint>,
+    pinned_key: Option<RsaPublicKey>,
+    timeout: Duration,
+    max_frame_size: usize,
+) -> ServerStatus {
+    let started = Instant::now();
+    let challenge = uuid::Uuid::new_v4().to_string();
+    let probe = async {
+        let (mut stream, _) = connect_endpoints(&endpoints, timeout).await?;
+        let params = rpc_models::ServerInfoParams {
+            challenge: challenge.clone(),
+        };
+        let request = Request::new(
+            rpc_models::GET_SERVER_INFO.to_string(),
+            serde_json::json!(params),
+        );
+        let response = request.send_with_max_frame(&mut stream, None, max_frame_size).await?;
+        let info: ServerInfo = serde_json::from_value(response.into_result()?)?;
+        Ok::<_, Error>(info)
+    };
+    match future::timeout(timeout, probe).await {
+        Ok(Ok(info)) => ServerStatus {
+            reachable: true,
+            latency_ms: Some(started.elapsed().as_millis() as u64),
+            key_match: match pinned_key {
+                Some(_) if info.challenge_signature.is_empty() => None,
+                Some(key) => {
+                    let signed = ServerInfo::challenge_signed_data(&challenge);
+                    Some(
+                        Signature::try_from(info.challenge_signature.as_slice())
+                            .map_or(false, |sig| pki::verify_signature(&key, &signed, &sig)),
+                    )
+                }
+                None => None,
+            },
+            open_registration: Some(info.open_registration),
+            checked_at: SystemTime::now(),
+        },
+        _ => ServerStatus::unreachable(),
+    }
+}
+
+/// Refuses `method` locally when `server` didn't advertise the capability it needs.
+fn require_capability(server: &ServerModel, method: &str) -> Result<(), Error> {
+    match Capability::required_by(method) {
+        Some(capability) if !server.capabilities.contains(&capability) => {
+            Err(Error::UnsupportedByPeer(capability))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Timeouts and retry limits for talking to servers.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClientConfig {
+    /// How long opening the TCP connection to a server may take.
+    pub connect_timeout: Duration,
+    /// How long to wait for the response to a request in an established session.
+    pub request_timeout: Duration,
+    /// How long each step of the handshake may take to be answered.
+    pub handshake_timeout: Duration,
+    pub heartbeat_interval: Duration,
+    /// How many times a lost connection is retried before giving up.
+    pub max_reconnect_attempts: u32,
+    /// Longest wait between reconnection attempts, which start 500 ms apart and double.
+    pub max_reconnect_delay: Duration,
+    /// How many times a request that timed out is sent again. Only requests that are
+    /// safe to repeat are, see `rpc_models::is_idempotent`.
+    pub request_retries: u32,
+    /// Largest frame accepted from a server; a larger one ends the connection.
+    pub max_frame_bytes: usize,
+    /// Largest streamed result fetched from a server; a larger announced one is refused
+    /// before any chunk is asked for.
+    pub max_response_bytes: usize,
+    /// Capabilities a server has to advertise in the handshake. Connecting to one that
+    /// lacks any of them fails with `UnsupportedByPeer`.
+    pub required_capabilities: Capabilities,
+}
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            handshake_timeout: Duration::from_secs(30),
+            heartbeat_interval: Duration::from_secs(30),
+            max_reconnect_attempts: 5,
+            max_reconnect_delay: Duration::from_secs(60),
+            request_retries: 2,
+            max_frame_bytes: rpc::MAX_FRAME_SIZE,
+            max_response_bytes: 256 * 1024 * 1024,
+            required_capabilities: Capabilities::new(),
+        }
+    }
+}
+
+/// How the session with a server stands, see `Client::connection_status`.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionStatus {
+    Disconnected,
+    Connected,
+    /// The connection was lost and is redialed before the next request goes out.
+    Reconnecting,
+}
+
+/// An open session with a server, and what the handshake learned about it.
+struct ConnectionState {
+    connection: Connection,
+    server: ServerModel,
+    // assessment of the session, none for dev sessions
+    security: Option<SecurityAssessment>,
+    // opened with `connect_insecure`
+    #[cfg(feature = "insecure-dev")]
+    insecure: bool,
+}
+
+pub struct Client {
+    // profile the key files and databases live under
+    location: String,
     private_key: RsaPrivateKey,
+    // signs handshake challenges in place of `private_key` when present
+    ed25519_key: Option<ed25519_dalek::SigningKey>,
     db: ClientDatabase,
-    server_connection: Option<TcpStream>,
-    server_data: Option<ServerModel>,
+    config: ClientConfig,
+    connections: HashMap<ServerId, ConnectionState>,
+    // where requests that don't name a server go, the server connected last
+    server_id: Option<ServerId>,
+    // requests pushed by each server, waiting for `subscribe`
+    push_channels: HashMap<ServerId, (Sender<Request>, Receiver<Request>)>,
+    event_emitter: Option<Box<dyn EventEmitter>>,
+    supervisor: Supervisor,
+    security_minimum: SecurityMinimum,
+    intent_log: IntentLog,
+    master_key: MasterKey,
+    // operations left unfinished by an earlier run, recovered at unlock
+    startup_report: Vec<RecoveredIntent>,
+    // set while the outbox is sent, so a reconnect in the middle doesn't send it twice
+    flushing_outbox: AtomicBool,
 }
 impl Client {
-    pub fn new(pass_key: Vec<u8>) -> Result<Self, Box<dyn Error>> {
-        if !key_exists("client") {
+    pub fn new(pass_key: Vec<u8>, config: Option<ClientConfig>) -> Result<Self, Error> {
+        Self::with_location("client", pass_key, config)
+    }
+
+    pub fn with_location(
+        loc: &str,
+        pass_key: Vec<u8>,
+        config: Option<ClientConfig>,
+    ) -> Result<Self, Error> {
+        let startup_report = intent::recover(loc, &pass_key)?;
+        // both unlock and migrate turn a wrong passphrase away before any database is opened
+        let master_key = if MasterKey::exists(loc) {
+            MasterKey::unlock(loc, &pass_key)?
+        } else if key_exists(loc) || ed25519_key_exists(loc) {
+            // keyed directly by the passphrase, from before profiles had a master key
+            master_key::migrate(loc, &pass_key)?
+        } else {
+            let master_key = MasterKey::generate();
+            master_key.save(loc, &pass_key)?;
+            master_key
+        };
+        let file_key = master_key.key_file_key()?;
+        if !key_exists(loc) {
             let key = gen_key()?;
-            write_key_to_file(&key, "client", &pass_key)?;
+            write_key_to_file(&key, loc, &file_key)?;
         }
-        let private_key = read_key_from_file("client", &pass_key)?;
-        let db = ClientDatabase::new(&pass_key)?;
+        let private_key = read_key_from_file(loc, &file_key)?;
+        let ed25519_key = if ed25519_key_exists(loc) {
+            Some(read_ed25519_key_from_file(loc, &file_key)?)
+        } else {
+            None
+        };
+        let db = ClientDatabase::with_location(loc, master_key.as_bytes())?;
+        db.purge_expired_trash()?;
         Ok(Client {
+            location: loc.to_string(),
             private_key,
+            ed25519_key,
             db,
-            server_connection: None,
-            server_data: None,
+            config: config.unwrap_or_default(),
+            connections: HashMap::new(),
+            server_id: None,
+            push_channels: HashMap::new(),
+            event_emitter: None,
+            supervisor: Supervisor::new(),
+            security_minimum: SecurityMinimum::default(),
+            intent_log: IntentLog::new(loc),
+            master_key,
+            startup_report,
+            flushing_outbox: AtomicBool::new(false),
         })
     }
 
-    pub async fn start<H: Handler + Clone + Send + Sync + 'static>(
+    /// Whether `pass_key` unlocks the default profile, without opening it.
+    pub fn verify_passphrase(pass_key: &[u8]) -> bool {
+        Self::passphrase_unlocks("client", pass_key).unwrap_or(false)
+    }
+
+    /// Whether `pass_key` unlocks the profile at `loc`. Any passphrase unlocks a profile
+    /// that doesn't exist yet, it becomes the profile's passphrase.
+    pub fn passphrase_unlocks(loc: &str, pass_key: &[u8]) -> Result<bool, Error> {
+        if MasterKey::exists(loc) {
+            return match MasterKey::unlock(loc, pass_key) {
+                Ok(_) => Ok(true),
+                Err(Error::InvalidPassphrase) => Ok(false),
+                Err(e) => Err(e),
+            };
+        }
+        if key_exists(loc) || ed25519_key_exists(loc) {
+            return pki::key_file_unlocks(loc, pass_key);
+        }
+        Ok(true)
+    }
+
+    /// Operations an earlier run was interrupted in, and how unlocking recovered them.
+    pub fn startup_report(&self) -> &[RecoveredIntent] {
+        &self.startup_report
+    }
+
+    pub fn set_event_emitter<E: EventEmitter + 'static>(&mut self, emitter: E) {
+        self.event_emitter = Some(Box::new(emitter));
+    }
+
+    fn emit(&self, event: &str, payload: serde_json::Value) {
+        if let Some(ref emitter) = self.event_emitter {
+            emitter.emit(event, payload);
+        }
+    }
+
+    /// Sets the weakest session accepted without a warning, applied from the next
+    /// handshake on.
+    pub fn set_security_minimum(&mut self, minimum: SecurityMinimum) {
+        self.security_minimum = minimum;
+    }
+
+    /// Assessment of the session with the current server.
+    pub fn session_security(&self) -> Option<&SecurityAssessment> {
+        let server_id = self.server_id.as_ref()?;
+        self.connections.get(server_id)?.security.as_ref()
+    }
+
+    fn connection_state(&self, server_id: &str) -> Result<&ConnectionState, Error> {
+        self.connections
+            .get(&ServerId::from(server_id))
+            .ok_or_else(|| "Server connection not found".into())
+    }
+
+    /// Where requests that don't name a server go, the server connected last.
+    pub fn default_server(&self) -> Option<&ServerId> {
+        self.server_id.as_ref()
+    }
+
+    /// Servers the client holds a session with, in no particular order.
+    pub fn connected_servers(&self) -> Vec<ServerId> {
+        self.connections.keys().cloned().collect()
+    }
+
+    pub fn connection_status(&self, server_id: &str) -> ConnectionStatus {
+        match self.connection_state(server_id) {
+            Err(_) => ConnectionStatus::Disconnected,
+            Ok(state) if state.connection.is_closed() => ConnectionStatus::Reconnecting,
+            Ok(_) => ConnectionStatus::Connected,
+        }
+    }
+
+    fn emit_connection_status(&self, server_id: &str, status: ConnectionStatus) {
+        self.emit(
+            CONNECTION_STATUS_EVENT,
+            serde_json::json!({ "server_id": server_id, "status": status }),
+        );
+    }
+
+    /// Closes the session with `server_id`. Sessions with other servers stay open.
+    pub fn disconnect_server(&mut self, server_id: &str) -> Result<(), Error> {
+        let server_id = ServerId::from(server_id);
+        if self.connections.remove(&server_id).is_none() {
+            Err(format!("Not connected to server {}", server_id))?;
+        }
+        if self.server_id.as_ref() == Some(&server_id) {
+            self.server_id = None;
+        }
+        Ok(())
+    }
+
+    /// Tells the user a session came out weaker than their minimum, both as an event
+    /// and as a notice in the server's system chat.
+    fn report_downgrade(
         &self,
-        handler: H,
-    ) -> Result<(), Box<dyn Error>> {
-        if let Some(ref stream) = self.server_connection {
-            let mut handler = handler.clone();
-            let mut stream = stream.clone();
-            task::spawn(async move {
-                if let Err(e) = rpc::listen(&mut stream, &mut handler).await {
-                    eprintln!("Error: {}", e);
-                }
-            });
-        } else {
-            Err("Server connection not found")?;
+        server_id: &ServerId,
+        assessment: &SecurityAssessment,
+    ) -> Result<(), Error> {
+        self.emit(
+            SECURITY_WARNING_EVENT,
+            serde_json::json!({ "server_id": server_id, "assessment": assessment }),
+        );
+        self.db.add_system_notice(server_id, assessment.notice())?;
+        Ok(())
+    }
+
+    /// Handles a notification pushed by `server_id`. Notifications arrive as
+    /// `ENCRYPTED_REQUEST`s under the session key.
+    pub fn on_notify(&mut self, server_id: &str, request: Request) -> Result<(), Error> {
+        if request.method != rpc_models::ENCRYPTED_REQUEST {
+            Err("Notifications must be encrypted")?;
+        }
+        let server = &self.connection_state(server_id)?.server;
+        let enc_pkg = server
+            .encryption
+            .as_ref()
+            .ok_or("Server encryption not initialized")?;
+        let params: rpc_models::EncryptedRequestParams = serde_json::from_value(request.params)?;
+        let data = open_gcm(&params.data, &enc_pkg.shared_key)?;
+        let notification: Request = json::from_slice(&data)?;
+        match notification.method.as_str() {
+            rpc_models::REVOKE_SESSION => {
+                let params: RevokeSessionParams = serde_json::from_value(notification.params)?;
+                self.disconnect_server(server_id)?;
+                self.emit(
+                    SESSION_REVOKED_EVENT,
+                    serde_json::json!({ "server_id": server_id, "reason": params.reason }),
+                );
+            }
+            _ => Err("Unknown notification method")?,
         }
         Ok(())
     }
 
+    fn push_channel(&mut self, server_id: &ServerId) -> &(Sender<Request>, Receiver<Request>) {
+        self.push_channels
+            .entry(server_id.clone())
+            .or_insert_with(channel::unbounded)
+    }
+
+    /// Requests `server_id` pushes while a session with it is open, already decrypted.
+    /// The session subscribes to them on connecting, so nothing has to be polled.
+    /// Pushes received before anyone listened are kept; with several receivers, each
+    /// push goes to only one of them.
+    pub fn incoming(&mut self, server_id: &str) -> Receiver<Request> {
+        self.push_channel(&ServerId::from(server_id)).1.clone()
+    }
+
+    /// Calls `callback` with every request `server_id` pushes, as `incoming` hands
+    /// them out.
+    pub fn subscribe(&mut self, server_id: &str, callback: impl Fn(Request) + Send + 'static) {
+        let server_id = ServerId::from(server_id);
+        let pushed = self.incoming(server_id.as_str());
+        let callback = Arc::new(Mutex::new(callback));
+        self.supervisor.spawn(
+            &format!("subscription:{}", server_id),
+            RestartPolicy::Never,
+            move || {
+                let pushed = pushed.clone();
+                let callback = callback.clone();
+                async move {
+                    while let Ok(request) = pushed.recv().await {
+                        (callback.lock().unwrap())(request);
+                    }
+                    Ok(())
+                }
+            },
+        );
+    }
+
+    /// Whether `server_id` advertised `capability` when last connected to.
+    pub fn server_supports(&self, server_id: &str, capability: Capability) -> bool {
+        self.db
+            .get_server(&ServerId::from(server_id))
+            .map_or(false, |server| server.capabilities.contains(&capability))
+    }
+
+    /// Brings back a message or chat deleted within the trash retention window.
+    pub fn restore_from_trash(&self, id: &str) -> Result<(), Error> {
+        self.db.restore_from_trash(id)
+    }
+
+    /// Permanently removes expired trash. Also runs whenever the client is unlocked.
+    pub fn prune_trash(&self) -> Result<usize, Error> {
+        self.db.purge_expired_trash()
+    }
+
+    /// Writes a chat's transcript into `dir` in the formatter's format and returns the
+    /// path of the file.
+    pub fn export_chat(
+        &self,
+        chat_id: &str,
+        formatter: &dyn TranscriptFormatter,
+        dir: &Path,
+    ) -> Result<PathBuf, Error> {
+        let chat_id = ChatId::from(chat_id);
+        let transcript = self.db.transcript(&chat_id)?;
+        let path = dir.join(format!("{}.{}", chat_id, formatter.extension()));
+        let mut out = BufWriter::new(File::create(&path)?);
+        formatter.write(&transcript, &mut out)?;
+        out.flush()?;
+        Ok(path)
+    }
+
+    /// Imports contacts and history from another messenger's export. Records that
+    /// can't be read are listed in the report instead of failing the import.
+    pub fn import_external(
+        &self,
+        path: &Path,
+        format: ImportFormat,
+    ) -> Result<ImportReport, Error> {
+        let archive = import::read(format, path, import::MAX_IMPORT_BYTES)?;
+        self.db.import_archive(format, archive)
+    }
+
+    pub fn task_health(&self) -> Vec<TaskHealth> {
+        self.supervisor.health()
+    }
+
+    /// Stops every background task owned by the client and waits for them to finish.
+    pub async fn shutdown(&mut self) {
+        self.supervisor.shutdown().await;
+    }
+
+    /// Makes sure the session with `server_id` is alive. A connection that was quiet
+    /// for a heartbeat interval has to answer a ping first; one that doesn't, or that
+    /// closed, is reconnected, see `reconnect`.
+    pub async fn ensure_connected(&mut self, server_id: &str) -> Result<(), Error> {
+        if let Ok(state) = self.connection_state(server_id) {
+            #[cfg(feature = "insecure-dev")]
+            if state.insecure {
+                // dev sessions aren't saved, there is nothing to reconnect to
+                return Ok(());
+            }
+            if state.connection.is_closed() {
+                return self.reconnect(server_id).await;
+            }
+            if state.connection.idle_for() < self.config.heartbeat_interval {
+                return Ok(());
+            }
+            let ping = Request::new(rpc_models::PING.to_string(), serde_json::json!(null));
+            let pinged = future::timeout(
+                LIVENESS_TIMEOUT,
+                self.try_send_sym_encrypted_request(server_id, ping),
+            )
+            .await;
+            let alive = match pinged {
+                Ok(Ok(_)) => true,
+                // the server answered, and the retrying send deals with anything short
+                // of a lost session
+                Ok(Err(e)) => {
+                    e.rpc_code().is_some()
+                        && e.rpc_code() != Some(RpcErrorCode::SessionNotEstablished)
+                }
+                Err(_) => false,
+            };
+            if alive {
+                return Ok(());
+            }
+        }
+        self.reconnect(server_id).await
+    }
+
+    fn connection_closed(&self, server_id: &str) -> bool {
+        self.connection_state(server_id)
+            .is_ok_and(|state| state.connection.is_closed())
+    }
+
+    /// Whether a request to `server_id` that failed with `e` never got a chance because
+    /// of the session: it's gone, dropped, or the server wants it rekeyed first. Such a
+    /// request can be sent again once `recover_session` has dealt with it.
+    pub fn session_lost(&self, server_id: &str, e: &Error) -> bool {
+        matches!(e, Error::Io(_))
+            || matches!(
+                e.rpc_code(),
+                Some(RpcErrorCode::SessionNotEstablished | RpcErrorCode::RekeyRequired)
+            )
+            || self.connection_state(server_id).is_err()
+            || self.connection_closed(server_id)
+    }
+
+    /// Rekeys or reconnects the session with `server_id`, whichever `e`, see
+    /// `session_lost`, calls for. A failed rekey falls back to a new handshake.
+    async fn recover_session(&mut self, server_id: &str, e: Error) -> Result<(), Error> {
+        if e.rpc_code() == Some(RpcErrorCode::RekeyRequired) && self.rekey(server_id).await.is_ok() {
+            return Ok(());
+        }
+        self.reconnect(server_id)
+            .await
+            .map_err(|handshake_err| format!("{}; re-handshake failed: {}", e, handshake_err).into())
+    }
+
+    /// Redoes the handshake with `server_id`, retrying failures to reach it up to
+    /// `max_reconnect_attempts` times. The first retry waits 500 ms and each one after
+    /// twice as long as the last, up to `max_reconnect_delay`. A server that answers
+    /// and refuses isn't retried. Once it gives up the dead session is dropped. Messages
+    /// that waited in the outbox while the server was away are sent once it's back.
+    async fn reconnect(&mut self, server_id: &str) -> Result<(), Error> {
+        let mut delay = INITIAL_RECONNECT_DELAY;
+        let mut attempts = 0;
+        self.emit_connection_status(server_id, ConnectionStatus::Reconnecting);
+        loop {
+            match self.server_connect(server_id).await {
+                Ok(()) => {
+                    self.emit_connection_status(server_id, ConnectionStatus::Connected);
+                    if let Err(e) = self.flush_after_reconnect(server_id).await {
+                        eprintln!("Error: flushing the outbox for {}: {}", server_id, e);
+                    }
+                    return Ok(());
+                }
+                Err(e @ (Error::Io(_) | Error::Timeout))
+                    if attempts < self.config.max_reconnect_attempts =>
+                {
+                    eprintln!("Error: reconnecting to {} failed: {}", server_id, e);
+                }
+                Err(e) => {
+                    let _ = self.disconnect_server(server_id);
+                    self.emit_connection_status(server_id, ConnectionStatus::Disconnected);
+                    return Err(e);
+                }
+            }
+            task::sleep(delay).await;
+            delay = (delay * 2).min(self.config.max_reconnect_delay);
+            attempts += 1;
+        }
+    }
+
+    // boxed, as sending the outbox may itself have to reconnect
+    fn flush_after_reconnect<'a>(
+        &'a mut self,
+        server_id: &str,
+    ) -> BoxFuture<'a, Result<usize, Error>> {
+        let server_id = server_id.to_string();
+        async move { self.flush_pending(&server_id).await }.boxed()
+    }
+
+    /// Sends a request to `server_id` under its session key, connecting first if there
+    /// is no session or the connection was lost. If the server wants a new key, the
+    /// session is rekeyed and the request retried; if it has lost the session, the
+    /// connection drops, or the rekey fails, the client reconnects and retries once
+    /// instead. A request that times out is only retried if it's safe to repeat, see
+    /// `retry_timed_out`.
     pub async fn send_sym_encrypted_request(
         &mut self,
+        server_id: &str,
+        request: Request,
+    ) -> Result<Response, Error> {
+        self.ensure_connected(server_id).await?;
+        let sent = self
+            .try_send_sym_encrypted_request(server_id, request.clone())
+            .await;
+        let (e, rekey) = match sent {
+            Err(Error::Timeout) if rpc_models::is_idempotent(&request.method) => {
+                return self.retry_timed_out(server_id, request).await;
+            }
+            Err(e) if e.rpc_code() == Some(RpcErrorCode::RekeyRequired) => (e, true),
+            Err(e) if e.rpc_code() == Some(RpcErrorCode::SessionNotEstablished) => (e, false),
+            // dropped since `ensure_connected` looked
+            Err(e) if matches!(e, Error::Io(_)) || self.connection_closed(server_id) => (e, false),
+            result => return result,
+        };
+        if rekey && self.rekey(server_id).await.is_ok() {
+            return self
+                .try_send_sym_encrypted_request(server_id, request)
+                .await
+                .map_err(|retry_err| format!("{}; retry after rekey failed: {}", e, retry_err).into());
+        }
+        if let Err(handshake_err) = self.reconnect(server_id).await {
+            Err(format!("{}; re-handshake failed: {}", e, handshake_err))?;
+        }
+        self.try_send_sym_encrypted_request(server_id, request)
+            .await
+            .map_err(|retry_err| {
+                format!("{}; retry after re-handshake failed: {}", e, retry_err).into()
+            })
+    }
+
+    /// Like `send_sym_encrypted_request`, but lets the server stream a large result. Its
+    /// chunks are fetched one after another and put back together, so the response
+    /// comes back as though it had been sent whole.
+    pub async fn send_sym_encrypted_streaming_request(
+        &mut self,
+        server_id: &str,
+        mut request: Request,
+    ) -> Result<Response, Error> {
+        request.stream = true;
+        let mut response = self.send_sym_encrypted_request(server_id, request).await?;
+        let info = match response.stream.take() {
+            Some(info) => info,
+            None => return Ok(response),
+        };
+        if info.total_bytes > self.config.max_response_bytes {
+            return Err(Error::MessageTooLarge {
+                actual: info.total_bytes,
+                limit: self.config.max_response_bytes,
+            });
+        }
+        // every chunk carries at least a byte
+        if info.chunks > info.total_bytes.max(1) {
+            Err("Streamed result announces too many chunks")?;
+        }
+        // sized as it arrives rather than trusting the server's count up front
+        let mut data = vec![];
+        for chunk_index in 0..info.chunks {
+            let params = rpc_models::FetchChunkParams {
+                stream_id: info.stream_id.clone(),
+                chunk_index,
+            };
+            let fetch = Request::new(rpc_models::FETCH_CHUNK.to_string(), serde_json::json!(params));
+            let result = self
+                .send_sym_encrypted_request(server_id, fetch)
+                .await?
+                .into_result()?;
+            let chunk: rpc_models::FetchChunkResult = serde_json::from_value(result)?;
+            data.extend(chunk.data);
+            if data.len() > info.total_bytes {
+                Err("Streamed result is longer than announced")?;
+            }
+        }
+        if data.len() != info.total_bytes {
+            Err("Streamed result is shorter than announced")?;
+        }
+        response.result = json::from_slice(&data)?;
+        Ok(response)
+    }
+
+    /// Sends a request that timed out again, up to `request_retries` times, waiting
+    /// between attempts as `reconnect` does. Requests are answered in order, so each
+    /// retry goes over a fresh connection rather than queue behind the stuck one.
+    async fn retry_timed_out(
+        &mut self,
+        server_id: &str,
+        request: Request,
+    ) -> Result<Response, Error> {
+        let mut delay = INITIAL_RECONNECT_DELAY;
+        for _ in 0..self.config.request_retries {
+            task::sleep(delay).await;
+            delay = (delay * 2).min(self.config.max_reconnect_delay);
+            self.reconnect(server_id).await?;
+            match self
+                .try_send_sym_encrypted_request(server_id, request.clone())
+                .await
+            {
+                Err(Error::Timeout) => continue,
+                result => return result,
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Replaces the session key with one agreed over fresh ephemeral X25519 keys. The
+    /// request goes out under the old key, and pushes are opened under either until the
+    /// next rekey.
+    pub async fn rekey(&mut self, server_id: &str) -> Result<(), Error> {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_key = X25519PublicKey::from(&secret);
+        let params = rpc_models::RekeyParams { ephemeral_key };
+        let request = Request::new(rpc_models::REKEY.to_string(), serde_json::json!(params));
+        let response = self.try_send_sym_encrypted_request(server_id, request).await?;
+        let answer: rpc_models::RekeyAnswer = serde_json::from_value(response.into_result()?)?;
+        let state = self.connection_state(server_id)?;
+        let server_pub_key = state.server.pub_key.as_ref().ok_or("Server key not known")?;
+        let signature = Signature::try_from(answer.ephemeral_signature.as_slice())?;
+        let signed_data =
+            rpc_models::RekeyAnswer::signed_data(&ephemeral_key, &answer.ephemeral_key);
+        if !pki::verify_signature(server_pub_key, &signed_data, &signature) {
+            Err(Error::Auth(String::from("Server's ephemeral key isn't signed by the server")))?;
+        }
+        let shared_secret = secret.diffie_hellman(&answer.ephemeral_key);
+        if !shared_secret.was_contributory() {
+            Err("Server's ephemeral key is of low order")?;
+        }
+        let (new_key, _) = ski::derive_session_key(shared_secret.as_bytes())?;
+        state.connection.add_session_key(new_key.clone());
+        let mut stored = self.db.server_db.get_entry::<ServerModel>(server_id)?;
+        stored.add_encryption(EncryptionConfiguration::new(new_key.clone()));
+        self.db.server_db.update_entry(server_id, stored)?;
+        if let Some(state) = self.connections.get_mut(&ServerId::from(server_id)) {
+            state.server.add_encryption(EncryptionConfiguration::new(new_key));
+        }
+        Ok(())
+    }
+
+    /// Sends over the session with `server_id` as it is, nothing is reconnected or
+    /// rekeyed. Only borrows the client, so several requests can be in flight at once:
+    /// the connection hands each response to the request with its id.
+    pub async fn try_send_sym_encrypted_request(
+        &self,
+        server_id: &str,
         request: Request,
-    ) -> Result<Response, Box<dyn Error>> {
-        let stream = self
-            .server_connection
-            .as_mut()
-            .ok_or("Server connection not found")?;
-        let server = self.server_data.as_ref().ok_or("Server data not found")?;
+    ) -> Result<Response, Error> {
+        let state = self.connection_state(server_id)?;
+        let (connection, server) = (&state.connection, &state.server);
+        require_capability(server, &request.method)?;
+        #[cfg(feature = "insecure-dev")]
+        if state.insecure {
+            let response = connection.call(&request, Some(self.config.request_timeout)).await?;
+            if !response.insecure {
+                Err("Expected a plaintext dev session response")?;
+            }
+            return Ok(response);
+        }
         let request_id = request.id.clone();
         let enc_pkg = server
             .encryption
             .as_ref()
             .ok_or("Server encryption not initialized")?;
         let req_bytes = serde_json::to_vec(&request)?;
-        let encrypted_request = encrypt_gcm(&req_bytes, &enc_pkg.shared_key, &enc_pkg.nonce)?;
+        let encrypted_request = enc_pkg.seal(&req_bytes)?;
         let request_params = rpc_models::EncryptedRequestParams {
             enc_type: rpc_models::EncryptionType::AesGcm,
             data: encrypted_request,
@@ -100,144 +868,1052 @@ impl Client {
             serde_json::json!(request_params),
             request_id,
         );
-        let response = request.send(stream, None).await?;
-        let ct: Vec<u8> = serde_json::from_value(response.result)?;
-        let response = decrypt_gcm(&ct, &enc_pkg.shared_key, &enc_pkg.nonce)?;
-        let response: Response = serde_json::from_slice(&response)?;
+        let response = connection.call(&request, Some(self.config.request_timeout)).await?;
+        let ct: Vec<u8> = serde_json::from_value(response.into_result()?)?;
+        let response = open_gcm(&ct, &enc_pkg.shared_key)?;
+        let response: Response = json::from_slice(&response)?;
         Ok(response)
     }
 
-    pub async fn server_ping(&mut self) -> Result<(), Box<dyn Error>> {
-        let request = Request::new(rpc_models::PING.to_string(), serde_json::json!(null));
-        let response = self.send_sym_encrypted_request(request).await?;
-        println!("response {:?}", response);
-        let resp_val: String = serde_json::from_value(response.result)?;
-        if resp_val != "pong" {
-            Err("Server did not respond with pong")?;
+    /// Sends `requests` and returns their responses in the same order. Servers that
+    /// speak `BATCH_PROTOCOL_VERSION` get them sealed together and serve them at once;
+    /// older ones, and dev sessions, get them one after another.
+    pub async fn send_batch(
+        &mut self,
+        server_id: &str,
+        requests: Vec<Request>,
+    ) -> Result<Vec<Response>, Error> {
+        self.ensure_connected(server_id).await?;
+        let batched = match self.connection_state(server_id)?.security {
+            Some(ref security) => security.protocol_version >= rpc_models::BATCH_PROTOCOL_VERSION,
+            None => false,
+        };
+        let mut responses = Vec::with_capacity(requests.len());
+        if !batched {
+            for request in requests {
+                responses.push(self.send_sym_encrypted_request(server_id, request).await?);
+            }
+            return Ok(responses);
         }
-        Ok(())
+        for batch in requests.chunks(rpc_models::MAX_BATCH_REQUESTS) {
+            let sent = match self.try_send_batch(server_id, batch).await {
+                Err(e) if e.rpc_code() == Some(RpcErrorCode::RekeyRequired) => {
+                    self.rekey(server_id).await?;
+                    self.try_send_batch(server_id, batch).await
+                }
+                sent => sent,
+            };
+            responses.extend(sent?);
+        }
+        Ok(responses)
     }
 
-    pub async fn server_connect(&mut self, server_id: &str) -> Result<(), Box<dyn Error>> {
-        let mut server = self
-            .db
-            .server_db
-            .get_entry::<models::ServerModel>(server_id)?;
-        let mut stream = TcpStream::connect((server.ip, server.port)).await?;
+    async fn try_send_batch(
+        &self,
+        server_id: &str,
+        requests: &[Request],
+    ) -> Result<Vec<Response>, Error> {
+        let state = self.connection_state(server_id)?;
+        let (connection, server) = (&state.connection, &state.server);
+        for request in requests {
+            require_capability(server, &request.method)?;
+        }
+        let enc_pkg = server
+            .encryption
+            .as_ref()
+            .ok_or("Server encryption not initialized")?;
+        let request_params = rpc_models::EncryptedRequestParams {
+            enc_type: rpc_models::EncryptionType::BatchedAesGcm,
+            data: enc_pkg.seal(&serde_json::to_vec(requests)?)?,
+        };
         let request = Request::new(
-            rpc_models::START_SERVER_HANDSHAKE.to_string(),
-            serde_json::json!(null),
+            rpc_models::ENCRYPTED_REQUEST.to_string(),
+            serde_json::json!(request_params),
         );
-        let response = request.send(&mut stream, None).await?;
-        let challenge: String = serde_json::from_value(response.result)?;
-        let challenge = challenge.as_bytes();
-        let sig = sign_message(&self.private_key, challenge);
+        let response = connection.call(&request, Some(self.config.request_timeout)).await?;
+        let ct: Vec<u8> = serde_json::from_value(response.into_result()?)?;
+        let responses: Vec<Response> = json::from_slice(&open_gcm(&ct, &enc_pkg.shared_key)?)?;
+        let in_order = responses.len() == requests.len()
+            && responses.iter().zip(requests).all(|(response, request)| response.id() == request.id);
+        if !in_order {
+            Err("Server answered a batch with responses to other requests")?;
+        }
+        Ok(responses)
+    }
 
-        let server_challenge = uuid::Uuid::new_v4().to_string();
+    /// Replaces the client's RSA key with a fresh one, and has every saved server
+    /// authorize the new key in place of the old. Every open session is closed. Returns
+    /// the servers that couldn't be reached or refused the change; they keep the old key
+    /// and its signature over the new one until `retry_key_rotations` gets through.
+    pub async fn rotate_identity_key(&mut self, pass: &[u8]) -> Result<Vec<ServerId>, Error> {
+        MasterKey::unlock(&self.location, pass)?;
+        let file_key = self.master_key.key_file_key()?;
+        let servers = self.db.server_db.get_all_entries::<ServerModel>()?;
+        let server_ids: Vec<ServerId> =
+            servers.iter().map(|(id, _)| ServerId::from(id.as_str())).collect();
+        // the key file keeps the old key until every rotation is recorded
+        let old_key = read_key_from_file(&self.location, &file_key)?;
+        let new_key = gen_key()?;
+        let intent = self.intent_log.begin(Operation::RotateIdentity {
+            new_key: SealedSecret::seal(&intent::key_der(&new_key)?, pass)?,
+            servers: server_ids.clone(),
+        })?;
+        for (id, mut server) in servers {
+            // a server still behind on an earlier rotation only knows an older key
+            let known_key = match server.key_rotation {
+                Some(ref rotation) => rotation.old_key()?,
+                None => old_key.clone(),
+            };
+            server.key_rotation =
+                Some(PendingKeyRotation::new(&known_key, new_key.to_public_key())?);
+            self.db.server_db.update_entry(&id, server)?;
+        }
+        let mut not_updated = vec![];
+        for server_id in server_ids {
+            match self.finish_key_rotation(&server_id).await {
+                Ok(()) => self.intent_log.checkpoint(&intent, &intent::server_step(&server_id))?,
+                Err(e) => {
+                    eprintln!("Error: key rotation on server {}: {}", server_id, e);
+                    self.intent_log.checkpoint(&intent, &intent::skipped_step(&server_id))?;
+                    not_updated.push(server_id);
+                }
+            }
+        }
+        write_key_to_file(&new_key, &self.location, &file_key)?;
+        self.intent_log.checkpoint(&intent, KEY_FILE_STEP)?;
+        self.intent_log.complete(&intent)?;
+        self.private_key = new_key;
+        self.connections.clear();
+        self.server_id = None;
+        Ok(not_updated)
+    }
 
-        let response = RespondClientChallenge {
-            pub_key: self.private_key.to_public_key(),
-            signiture: sig,
-            server_challenge: server_challenge.clone(),
-        };
+    /// Hands the client's current key to every server a rotation didn't get through to.
+    /// Returns the ones that still couldn't be reached or refused it.
+    pub async fn retry_key_rotations(&mut self) -> Result<Vec<ServerId>, Error> {
+        let pending: Vec<ServerId> = self
+            .db
+            .server_db
+            .get_all_entries::<ServerModel>()?
+            .into_iter()
+            .filter(|(_, server)| server.key_rotation.is_some())
+            .map(|(id, _)| ServerId::from(id))
+            .collect();
+        let mut not_updated = vec![];
+        for server_id in pending {
+            if let Err(e) = self.finish_key_rotation(&server_id).await {
+                eprintln!("Error: key rotation on server {}: {}", server_id, e);
+                not_updated.push(server_id);
+            }
+        }
+        Ok(not_updated)
+    }
 
+    /// Sends `server_id` its pending key rotation over a session opened with the key it
+    /// knows, then forgets the old key. That session is closed, the next one is opened
+    /// with the new key.
+    async fn finish_key_rotation(&mut self, server_id: &ServerId) -> Result<(), Error> {
+        let rotation = self
+            .db
+            .get_server(server_id)?
+            .key_rotation
+            .ok_or("No key rotation pending for this server")?;
+        self.server_connect(server_id.as_str()).await?;
         let request = Request::new(
-            rpc_models::CLIENT_CHALLENGE_RESPONSE.to_string(),
-            serde_json::json!(response),
+            rpc_models::KEY_ROTATION.to_string(),
+            serde_json::json!(rotation.params()),
         );
-        let response = request.send(&mut stream, None).await?;
-        let server_challenge_response: RespondServerChallenge =
-            serde_json::from_value(response.result)?;
-        let sig = Signature::try_from(server_challenge_response.signiture.as_slice())?;
-        let server_pub_key = server_challenge_response.pub_key;
-
-        // Verify the server's signature
-        if !verify_signature(&server_pub_key, server_challenge.as_bytes(), &sig) {
-            Err("Server verification failed")?;
+        let rotated = async {
+            let response = self.send_sym_encrypted_request(server_id.as_str(), request).await?;
+            response.into_result()?;
+            Ok::<_, Error>(())
+        }
+        .await;
+        self.connections.remove(server_id);
+        if self.server_id.as_ref() == Some(server_id) {
+            self.server_id = None;
         }
+        rotated?;
+        let mut server = self.db.get_server(server_id)?;
+        server.key_rotation = None;
+        self.db.server_db.update_entry(server_id.as_str(), server)?;
+        Ok(())
+    }
 
-        // Get the shared key for faster encryption
-        let request = Request::new(
-            rpc_models::REQUEST_ENCRYPTION_PACKAGE.to_string(),
-            serde_json::json!(null),
-        );
-        let req_id = request.id.clone();
-        let encrypted_request = encrypt_message(
-            &server_pub_key,
-            serde_json::json!(request).to_string().as_bytes(),
-        )?;
-        let request_params = rpc_models::EncryptedRequestParams {
-            enc_type: rpc_models::EncryptionType::RsaPkcs1v15,
-            data: encrypted_request,
-        };
-        let request = Request::new_with_id(
-            rpc_models::ENCRYPTED_REQUEST.to_string(),
-            serde_json::json!(request_params),
-            req_id,
-        );
+    /// Rewraps the master key under `new_pass`. Nothing else is encrypted under the
+    /// passphrase, so this is a single write that either happens or doesn't.
+    pub fn change_passphrase(&mut self, old_pass: &[u8], new_pass: &[u8]) -> Result<(), Error> {
+        MasterKey::unlock(&self.location, old_pass)?;
+        self.master_key.save(&self.location, new_pass)
+    }
 
-        let response = request.send(&mut stream, None).await?;
-        let ct: Vec<u8> = serde_json::from_value(response.result)?;
-        let response = decrypt_message(&self.private_key, &ct)?;
-        let response: Response = serde_json::from_slice(&response)?;
-        let package: ClientEncryptionPackage = serde_json::from_value(response.result)?;
-        server.add_encryption(EncryptionConfiguration::new(
-            package.shared_key(),
-            package.nonce(),
-        ));
-        self.db.server_db.update_entry(server_id, server.clone())?;
-        self.server_connection = Some(stream);
-        self.server_data = Some(server);
+    /// Replaces the master key with a fresh one, re-encrypting the key files and every
+    /// database under keys derived from it. This is the slow path, for when the master
+    /// key itself may have leaked. If it's cut short, the next unlock finishes it.
+    pub fn rotate_master_key(&mut self, passphrase: &[u8]) -> Result<(), Error> {
+        let old = MasterKey::unlock(&self.location, passphrase)?;
+        let new = MasterKey::generate();
+        let intent = self.intent_log.begin(Operation::RotateMasterKey {
+            new_master_key: SealedSecret::seal(new.as_bytes(), passphrase)?,
+        })?;
+        master_key::move_key_files(&self.location, &old.key_file_key()?, &new.key_file_key()?)?;
+        self.intent_log.checkpoint(&intent, KEY_FILE_STEP)?;
+        let log = &self.intent_log;
+        self.db
+            .reencrypt(new.as_bytes(), &mut |name: &str| log.checkpoint(&intent, name))?;
+        new.save(&self.location, passphrase)?;
+        self.intent_log.complete(&intent)?;
+        self.master_key = new;
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::net::IpAddr;
-    use std::sync::Arc;
+    /// Writes the profile's identity keys and the entries of all of its databases to
+    /// `path`, sealed under `backup_passphrase`, to be restored with `import_backup`.
+    pub fn export_backup(&self, path: &Path, backup_passphrase: &[u8]) -> Result<(), Error> {
+        let ed25519_key_pem = match &self.ed25519_key {
+            Some(key) => Some(key.to_pkcs8_pem(get_line_ending())?.to_string()),
+            None => None,
+        };
+        let contents = BackupContents {
+            private_key_pem: self.private_key.to_pkcs8_pem(get_line_ending())?.to_string(),
+            ed25519_key_pem,
+            databases: self.db.export_entries()?,
+        };
+        backup::write(path, &contents, backup_passphrase)
+    }
 
-    use async_std::sync::RwLock;
-    use async_std::task;
+    /// Restores a backup into the default profile, see `import_backup_to`.
+    pub fn import_backup(
+        path: &Path,
+        backup_passphrase: &[u8],
+        local_passphrase: &[u8],
+    ) -> Result<Self, Error> {
+        Self::import_backup_to("client", path, backup_passphrase, local_passphrase)
+    }
 
-    use crate::server::handler::ServerHandler;
-    use crate::server::start_server;
-    use crate::{
-        server::Server,
-        shared::pki::delete_key_file,
-    };
+    /// Restores a backup written by `export_backup` into the profile at `loc`, and
+    /// returns the profile unlocked. A profile that doesn't exist yet is created under
+    /// `local_passphrase` with the backup's identity, and everything is encrypted under
+    /// its own keys. An existing profile has to hold the same identity. Its entries are
+    /// merged with the backup's by id, a backed up entry replacing the local one with
+    /// the same id, so importing again is harmless and an import cut short can be rerun.
+    pub fn import_backup_to(
+        loc: &str,
+        path: &Path,
+        backup_passphrase: &[u8],
+        local_passphrase: &[u8],
+    ) -> Result<Self, Error> {
+        let contents = backup::read(path, backup_passphrase)?;
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&contents.private_key_pem)?;
+        let ed25519_key = match &contents.ed25519_key_pem {
+            Some(pem) => Some(ed25519_dalek::SigningKey::from_pkcs8_pem(pem)?),
+            None => None,
+        };
+        if !MasterKey::exists(loc) && !key_exists(loc) && !ed25519_key_exists(loc) {
+            MasterKey::generate().save(loc, local_passphrase)?;
+        }
+        // a master key without a key file is only left by a restore or first unlock that
+        // was cut short, there's no identity to lose yet
+        if MasterKey::exists(loc) && !key_exists(loc) {
+            let file_key = MasterKey::unlock(loc, local_passphrase)?.key_file_key()?;
+            if let Some(key) = &ed25519_key {
+                write_ed25519_key_to_file(key, loc, &file_key)?;
+            }
+            write_key_to_file(&private_key, loc, &file_key)?;
+        }
+        let client = Self::with_location(loc, local_passphrase.to_vec(), None)?;
+        let same_identity = client.private_key.to_public_key() == private_key.to_public_key()
+            && client.ed25519_key.as_ref().map(|key| key.verifying_key())
+                == ed25519_key.as_ref().map(|key| key.verifying_key());
+        if !same_identity {
+            Err("The profile holds another identity, restore the backup into a new profile")?;
+        }
+        client.db.import_entries(contents.databases)?;
+        Ok(client)
+    }
 
-    use crate::client::models::ServerModel;
+    /// Registers `username` with the connected server for the client's key, and records
+    /// it as one of the server's users. Registering again renames the client there.
+    pub async fn register(&mut self, username: &str) -> Result<UserId, Error> {
+        let params = rpc_models::RegisterUserParams {
+            username: username.to_string(),
+        };
+        let server_id = self.server_id.clone().ok_or("Server not found")?;
+        let request = Request::new(rpc_models::REGISTER_USER.to_string(), serde_json::json!(params));
+        let response = self.send_sym_encrypted_request(server_id.as_str(), request).await?;
+        response.into_result()?;
+        let mut server = self.db.server_db.get_entry::<ServerModel>(server_id.as_str())?;
+        let pub_key = self.private_key.to_public_key().to_public_key_pem(get_line_ending())?;
+        let user = User::new(username.to_string(), pub_key);
+        let mut registered = None;
+        for id in server.user_ids() {
+            if self.db.get_user(id)?.fingerprint() == user.fingerprint() {
+                registered = Some(id.clone());
+            }
+        }
+        let user_id = match registered {
+            Some(id) => {
+                self.db.known_user_db.update_entry(id.as_str(), user)?;
+                id
+            }
+            None => self.db.save_user(user)?,
+        };
+        server.add_user(user_id.clone());
+        self.db.server_db.update_entry(server_id.as_str(), server)?;
+        Ok(user_id)
+    }
 
-    use super::*;
+    /// The connected server's entry for `username`, if we know the user.
+    fn find_server_user(
+        &self,
+        server: &ServerModel,
+        username: &str,
+    ) -> Result<Option<(UserId, User)>, Error> {
+        for id in server.user_ids() {
+            let user = self.db.get_user(id)?;
+            if user.username() == username {
+                return Ok(Some((id.clone(), user)));
+            }
+        }
+        Ok(None)
+    }
 
-    #[test]
-    fn test_client() {
-        let client = Client::new(b"example key1".to_vec()).unwrap();
-        let server_private_key = gen_key().unwrap();
-        let server_model = ServerModel::new(
-            "test_server".to_string(),
+    /// Lists the users registered with the connected server. Users we didn't know yet
+    /// are recorded without a key until `get_user_key` fetches it.
+    pub async fn list_users(&mut self) -> Result<Vec<String>, Error> {
+        let server_id = self.server_id.clone().ok_or("Server not found")?;
+        let request = Request::new(rpc_models::LIST_USERS.to_string(), serde_json::json!(null));
+        let response = self.send_sym_encrypted_request(server_id.as_str(), request).await?;
+        let usernames: Vec<String> = serde_json::from_value(response.into_result()?)?;
+        let mut server = self.db.get_server(&server_id)?;
+        for username in &usernames {
+            if self.find_server_user(&server, username)?.is_none() {
+                let user_id = self.db.save_user(User::new(username.clone(), String::new()))?;
+                server.add_user(user_id);
+            }
+        }
+        self.db.server_db.update_entry(server_id.as_str(), server)?;
+        Ok(usernames)
+    }
+
+    /// Fetches `username`'s key from the connected server and records it, once the
+    /// server proved it's in its key log. If we had a different key for them, it is
+    /// replaced and returned as `previous_key`.
+    pub async fn get_user_key(&mut self, username: &str) -> Result<UserKeyLookup, Error> {
+        let server_id = self.server_id.clone().ok_or("Server not found")?;
+        self.ensure_connected(server_id.as_str()).await?;
+        match self.get_server_user_key(&server_id, username).await {
+            Err(e) if self.session_lost(server_id.as_str(), &e) => {
+                self.recover_session(server_id.as_str(), e).await?;
+                self.get_server_user_key(&server_id, username).await
+            }
+            result => result,
+        }
+    }
+
+    /// Like `get_user_key`, from `server_id`, over the session as it is.
+    async fn get_server_user_key(
+        &self,
+        server_id: &ServerId,
+        username: &str,
+    ) -> Result<UserKeyLookup, Error> {
+        let params = rpc_models::GetUserKeyParams {
+            username: username.to_string(),
+        };
+        let request = Request::new(rpc_models::GET_USER_KEY.to_string(), serde_json::json!(params));
+        let response = self.try_send_sym_encrypted_request(server_id.as_str(), request).await?;
+        let user_key: rpc_models::UserKey = serde_json::from_value(response.into_result()?)?;
+        if user_key.username != username {
+            Err("Server returned the key of a different user")?;
+        }
+        let pub_key = pki::pub_key_from_str(&user_key.pub_key)?;
+        self.verify_key_inclusion(server_id, username, &pub_key).await?;
+        // stored in our own PEM format so equal keys compare equal
+        let pub_key = pub_key.to_public_key_pem(get_line_ending())?;
+        let mut server = self.db.get_server(server_id)?;
+        let user = User::new(username.to_string(), pub_key.clone());
+        let (user_id, previous_key) = match self.find_server_user(&server, username)? {
+            Some((id, known)) => {
+                let previous_key = match known.fingerprint() {
+                    None => None,
+                    fingerprint if fingerprint == user.fingerprint() => None,
+                    Some(_) => Some(known.pub_key().to_string()),
+                };
+                self.db.known_user_db.update_entry(id.as_str(), user)?;
+                (id, previous_key)
+            }
+            None => {
+                let id = self.db.save_user(user)?;
+                server.add_user(id.clone());
+                self.db.server_db.update_entry(server_id.as_str(), server)?;
+                (id, None)
+            }
+        };
+        Ok(UserKeyLookup {
+            user_id,
+            pub_key,
+            previous_key,
+        })
+    }
+
+    /// The key `server_id` proved it holds, in the forms a user compares with what the
+    /// server's operator publishes. Only known once the server has been connected to,
+    /// or its key provisioned.
+    pub fn server_fingerprint(&self, server_id: &str) -> Result<KeyFingerprint, Error> {
+        let server = self.db.get_server(&ServerId::from(server_id))?;
+        let key = server.pub_key.ok_or("The server's key isn't known yet")?;
+        KeyFingerprint::of(&key)
+    }
+
+    /// Accepts the key `server_id` presented in place of the pinned one, after the user
+    /// checked its fingerprint out of band. The next session is opened with it and
+    /// reported as `PinStatus::Changed`; from then on it's the pinned key.
+    pub fn trust_new_server_key(&mut self, server_id: &str) -> Result<KeyFingerprint, Error> {
+        let mut server = self.db.get_server(&ServerId::from(server_id))?;
+        let key = server
+            .presented_key
+            .take()
+            .ok_or("The server hasn't presented a different key")?;
+        let fingerprint = KeyFingerprint::of(&key)?;
+        server.pub_key = Some(key);
+        server.key_provisioned = false;
+        server.key_change_trusted = true;
+        self.db.server_db.update_entry(server_id, server)?;
+        Ok(fingerprint)
+    }
+
+    /// Saves a server reachable at `endpoints`, which are tried in order when connecting.
+    pub fn add_server(
+        &self,
+        name: String,
+        endpoints: Vec<ServerEndpoint>,
+    ) -> Result<ServerId, Error> {
+        if endpoints.is_empty() {
+            Err("A server needs at least one endpoint")?;
+        }
+        self.db
+            .save_server(ServerModel::with_endpoints(name, vec![], vec![], endpoints))
+    }
+
+    pub fn list_servers(&self) -> Result<Vec<ServerSummary>, Error> {
+        let servers = self.db.server_db.get_all_entries::<ServerModel>()?;
+        Ok(servers
+            .into_iter()
+            .map(|(id, server)| {
+                let id = ServerId::from(id);
+                let security = self
+                    .connections
+                    .get(&id)
+                    .and_then(|state| state.security.clone());
+                ServerSummary::new(id, &server, security)
+            })
+            .collect())
+    }
+
+    /// Pings `server_id`, connecting to it first if there is no session with it.
+    pub async fn ping_server(&mut self, server_id: &str) -> Result<(), Error> {
+        if self.connection_state(server_id).is_err() {
+            self.server_connect(server_id).await?;
+        }
+        self.server_ping(server_id).await
+    }
+
+    pub async fn server_ping(&mut self, server_id: &str) -> Result<(), Error> {
+        let request = Request::new(rpc_models::PING.to_string(), serde_json::json!(null));
+        let response = self.send_sym_encrypted_request(server_id, request).await?;
+        check_pong(response)
+    }
+
+    /// Like `server_ping`, over the open session as it is. Nothing is retried, so it
+    /// doesn't need the client to itself.
+    pub async fn ping_session(&self, server_id: &str) -> Result<(), Error> {
+        let request = Request::new(rpc_models::PING.to_string(), serde_json::json!(null));
+        let response = self.try_send_sym_encrypted_request(server_id, request).await?;
+        check_pong(response)
+    }
+
+    /// Asks the connected server to relay `data` to `recipients`. Payloads over the
+    /// server's advertised limit are refused before anything is sent.
+    pub async fn forward_message(
+        &mut self,
+        recipients: Vec<String>,
+        data: Vec<u8>,
+    ) -> Result<(), Error> {
+        self.forward(
+            recipients,
+            rpc_models::PayloadType::Opaque,
+            rpc_models::EncryptionType::AesGcm,
+            data,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the server's receipt, or `None` from servers that don't send one.
+    async fn forward(
+        &mut self,
+        recipients: Vec<String>,
+        payload_type: rpc_models::PayloadType,
+        enc_type: rpc_models::EncryptionType,
+        data: Vec<u8>,
+    ) -> Result<Option<rpc_models::ForwardReceipt>, Error> {
+        let server_id = self.server_id.clone().ok_or("Server not found")?;
+        self.ensure_connected(server_id.as_str()).await?;
+        let forwarded = self
+            .forward_to(&server_id, recipients.clone(), payload_type, enc_type, data.clone())
+            .await;
+        match forwarded {
+            Err(e) if self.session_lost(server_id.as_str(), &e) => {
+                self.recover_session(server_id.as_str(), e).await?;
+                self.forward_to(&server_id, recipients, payload_type, enc_type, data)
+                    .await
+            }
+            result => result,
+        }
+    }
+
+    /// Like `forward`, to `server_id` over the session as it is.
+    async fn forward_to(
+        &self,
+        server_id: &ServerId,
+        recipients: Vec<String>,
+        payload_type: rpc_models::PayloadType,
+        enc_type: rpc_models::EncryptionType,
+        data: Vec<u8>,
+    ) -> Result<Option<rpc_models::ForwardReceipt>, Error> {
+        let max_message_bytes = self
+            .connection_state(server_id.as_str())?
+            .server
+            .max_message_bytes
+            .unwrap_or(rpc_models::DEFAULT_MAX_MESSAGE_BYTES);
+        if data.len() > max_message_bytes {
+            Err(Error::MessageTooLarge {
+                actual: data.len(),
+                limit: max_message_bytes,
+            })?;
+        }
+        let params = rpc_models::ForwardedMessageParams {
+            enc_type,
+            data,
+            recipients,
+            payload_type,
+            message_id: None,
+        };
+        let request = Request::new(rpc_models::FORWARDED_MSG.to_string(), serde_json::json!(params));
+        let response = self.try_send_sym_encrypted_request(server_id.as_str(), request).await?;
+        Ok(serde_json::from_value(response.into_result()?).ok())
+    }
+
+    /// Probes every saved server concurrently, caches the results on the server entries
+    /// and emits them as a single `SERVERS_REFRESHED_EVENT`. Connected servers are
+    /// pinged over their existing sessions; the others get a `GET_SERVER_INFO` request
+    /// on a separate connection so established sessions are left alone.
+    pub async fn refresh_all_servers(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<HashMap<ServerId, ServerStatus>, Error> {
+        let mut servers: HashMap<ServerId, ServerModel> = self
+            .db
+            .server_db
+            .get_all_entries::<ServerModel>()?
+            .into_iter()
+            .map(|(id, server)| (ServerId::from(id), server))
+            .collect();
+        let mut statuses = HashMap::new();
+
+        let connected: Vec<ServerId> = self
+            .connections
+            .keys()
+            .filter(|id| servers.contains_key(*id))
+            .cloned()
+            .collect();
+        for id in connected {
+            let started = Instant::now();
+            let status = match future::timeout(timeout, self.server_ping(id.as_str())).await {
+                Ok(Ok(())) => ServerStatus {
+                    reachable: true,
+                    latency_ms: Some(started.elapsed().as_millis() as u64),
+                    // the handshake that opened the session already proved the key
+                    key_match: Some(true),
+                    open_registration: None,
+                    checked_at: SystemTime::now(),
+                },
+                _ => ServerStatus::unreachable(),
+            };
+            statuses.insert(id, status);
+        }
+
+        let max_frame_size = self.config.max_frame_bytes;
+        let probes = servers
+            .iter()
+            .filter(|(id, _)| !statuses.contains_key(*id))
+            .map(|(id, server)| {
+                let probe = probe_server(
+                    server.endpoints.clone(),
+                    server.pub_key.clone(),
+                    timeout,
+                    max_frame_size,
+                );
+                async move { (id.clone(), probe.await) }
+            })
+            .collect::<Vec<_>>();
+        let probed: Vec<(ServerId, ServerStatus)> = futures::stream::iter(probes)
+            .buffer_unordered(MAX_CONCURRENT_PROBES)
+            .collect()
+            .await;
+        statuses.extend(probed);
+
+        for (id, status) in statuses.iter() {
+            if let Some(mut server) = servers.remove(id) {
+                server.last_status = Some(status.clone());
+                self.db.server_db.update_entry(id.as_str(), server)?;
+            }
+        }
+        self.emit(SERVERS_REFRESHED_EVENT, serde_json::json!(statuses));
+        Ok(statuses)
+    }
+
+    /// Opens a plaintext session with a local dev server, skipping the handshake and all
+    /// encryption. Only loopback addresses are accepted. Returns the id the session goes
+    /// by; the server isn't saved.
+    #[cfg(feature = "insecure-dev")]
+    pub async fn connect_insecure(
+        &mut self,
+        addr: std::net::SocketAddr,
+    ) -> Result<ServerId, Error> {
+        if !addr.ip().is_loopback() {
+            Err(format!("Refusing an insecure connection to non-loopback address {}", addr))?;
+        }
+        let mut stream = future::timeout(self.config.connect_timeout, TcpStream::connect(addr)).await??;
+        // pushes aren't read in dev sessions, so the server queues for us instead
+        let params = rpc_models::DevPlaintextSessionParams {
+            pub_key: self.private_key.to_public_key(),
+            capabilities: Capabilities::new(),
+        };
+        let request = Request::new(
+            rpc_models::DEV_PLAINTEXT_SESSION.to_string(),
+            serde_json::json!(params),
+        );
+        let response = request
+            .send_with_max_frame(
+                &mut stream,
+                Some(self.config.request_timeout),
+                self.config.max_frame_bytes,
+            )
+            .await?;
+        let insecure = response.insecure;
+        response.into_result()?;
+        if !insecure {
+            Err("Server did not open a plaintext dev session")?;
+        }
+        let mut server = ServerModel::new(
+            String::from("insecure-dev"),
             vec![],
             vec![],
-            IpAddr::V4([127, 0, 0, 1].into()),
-            8890,
+            addr.ip(),
+            addr.port(),
         );
+        server.capabilities = serde_json::from_value(response.result).unwrap_or_default();
+        let server_id = ServerId::from(format!("insecure-dev:{}", addr));
+        let state = ConnectionState {
+            connection: Connection::new(
+                stream,
+                None,
+                None,
+                self.config.max_frame_bytes,
+                Codec::Json,
+            ),
+            server,
+            security: None,
+            insecure: true,
+        };
+        self.connections.insert(server_id.clone(), state);
+        self.server_id = Some(server_id.clone());
+        Ok(server_id)
+    }
 
-        let server_id = client
+    pub async fn server_connect(&mut self, server_id: &str) -> Result<(), Error> {
+        self.connect_through(server_id, None).await
+    }
+
+    /// Like `server_connect`, through `discovered` alone when it's given. An address
+    /// found on the network proves nothing, so it only becomes the server's first
+    /// endpoint once the handshake checks out, and a key presented there isn't kept.
+    async fn connect_through(
+        &mut self,
+        server_id: &str,
+        discovered: Option<ServerEndpoint>,
+    ) -> Result<(), Error> {
+        let handshake = self.prepare_connect(server_id, discovered)?.run().await?;
+        let server_id = self.finish_connect(handshake)?;
+        self.subscribe_pushes(server_id.as_str()).await
+    }
+
+    /// Takes what a handshake with `server_id` needs out of the client, so the network
+    /// part can run without holding on to it. `finish_connect` checks the outcome.
+    pub fn prepare_connect(
+        &self,
+        server_id: &str,
+        discovered: Option<ServerEndpoint>,
+    ) -> Result<ConnectAttempt, Error> {
+        let server = self
             .db
             .server_db
-            .save_entry(server_model)
-            .expect("Failed to save server");
+            .get_entry::<models::ServerModel>(server_id)?;
+        // a server that wasn't handed our rotated key yet only knows the old one
+        let identity_key = match server.key_rotation {
+            Some(ref rotation) => rotation.old_key()?,
+            None => self.private_key.clone(),
+        };
+        Ok(ConnectAttempt {
+            server_id: ServerId::from(server_id),
+            server,
+            discovered,
+            identity_key,
+            ed25519_key: self.ed25519_key.clone(),
+            config: self.config.clone(),
+        })
+    }
+
+    /// Checks a handshake from `ConnectAttempt::run` and opens the session it set up.
+    /// Pushes aren't subscribed to yet, see `subscribe_pushes`.
+    pub fn finish_connect(&mut self, handshake: Handshake) -> Result<ServerId, Error> {
+        let Handshake {
+            attempt,
+            stream,
+            client_transcript,
+            server_challenge,
+            ephemeral_secret,
+            ephemeral_key,
+            response: server_challenge_response,
+        } = handshake;
+        let ConnectAttempt {
+            server_id,
+            server: attempted,
+            discovered,
+            ..
+        } = attempt;
+        // read again, as it may have changed while the handshake was under way
+        let mut server = self
+            .db
+            .server_db
+            .get_entry::<models::ServerModel>(server_id.as_str())?;
+        server.last_endpoint = attempted.last_endpoint;
+        let server_transcript = server_challenge_response.transcript(&client_transcript)?;
+        let server_pub_key = server_challenge_response.pub_key;
+
+        // Verify the server's signature, over the whole handshake unless it predates that
+        let (signed, signature) = if server_challenge_response.protocol_version
+            >= rpc_models::TRANSCRIPT_PROTOCOL_VERSION
+        {
+            (
+                server_transcript.as_slice(),
+                &server_challenge_response.transcript_signature,
+            )
+        } else {
+            (server_challenge.as_bytes(), &server_challenge_response.signiture)
+        };
+        if !verify_handshake_signature(
+            server_challenge_response.key_type,
+            &server_pub_key,
+            server_challenge_response.signing_key.as_ref(),
+            &server_challenge_response.signing_key_binding,
+            signed,
+            signature,
+        ) {
+            Err("Server verification failed")?;
+        }
+
+        let pin_status = match &server.pub_key {
+            None => PinStatus::FirstUse,
+            Some(pinned) if *pinned == server_pub_key && server.key_provisioned => {
+                PinStatus::Provisioned
+            }
+            Some(pinned) if *pinned == server_pub_key && server.key_change_trusted => {
+                PinStatus::Changed
+            }
+            Some(pinned) if *pinned == server_pub_key => PinStatus::Verified,
+            Some(_) if server.key_provisioned => {
+                Err("Server key does not match the provisioned key")?
+            }
+            Some(pinned) => {
+                let changed = Error::ServerKeyChanged {
+                    pinned: pki::display_fingerprint(pinned)?,
+                    presented: pki::display_fingerprint(&server_pub_key)?,
+                };
+                // kept for the user to trust once they checked it, unless anyone on
+                // the network could have pointed us there
+                if discovered.is_none() {
+                    server.presented_key = Some(server_pub_key);
+                    self.db.server_db.update_entry(server_id.as_str(), server.clone())?;
+                }
+                return Err(changed);
+            }
+        };
+        let session = SessionParameters {
+            pin_status,
+            cipher_suite: CipherSuite::from(server_challenge_response.key_type),
+            // the session key comes from ephemeral X25519 keys, checked below
+            forward_secrecy: true,
+            protocol_version: server_challenge_response.protocol_version,
+        };
+        let assessment = security::assess(&session, &self.security_minimum);
+        if assessment.is_downgraded() && assessment.strict_mode {
+            self.report_downgrade(&server_id, &assessment)?;
+            Err(format!("Connection refused in strict mode. {}", assessment.notice()))?;
+        }
+
+        if server_challenge_response.protocol_version < rpc_models::ECDH_PROTOCOL_VERSION {
+            Err(Error::HandshakeFailed(format!(
+                "Server speaks protocol version {}, which predates forward secret session keys; it has to be upgraded",
+                server_challenge_response.protocol_version
+            )))?;
+        }
+        let server_ephemeral = server_challenge_response
+            .ephemeral_key
+            .ok_or("Server sent no ephemeral key")?;
+        let signature = Signature::try_from(server_challenge_response.ephemeral_signature.as_slice())?;
+        let signed_data = RespondServerChallenge::ephemeral_signed_data(
+            &server_challenge,
+            &ephemeral_key,
+            &server_ephemeral,
+        );
+        if !pki::verify_signature(&server_pub_key, &signed_data, &signature) {
+            Err(Error::Auth(String::from("Server's ephemeral key isn't signed by the server")))?;
+        }
+        let shared_secret = ephemeral_secret.diffie_hellman(&server_ephemeral);
+        if !shared_secret.was_contributory() {
+            Err("Server's ephemeral key is of low order")?;
+        }
+        let (shared_key, nonce) = ski::derive_session_key(shared_secret.as_bytes())?;
+        let confirmation = ski::decrypt_gcm(
+            &server_challenge_response.key_confirmation,
+            &shared_key,
+            &nonce,
+        );
+        if !matches!(confirmation, Ok(ref challenge) if challenge == server_challenge.as_bytes()) {
+            Err(Error::Auth(String::from("Server derived a different session key")))?;
+        }
+        let missing = self
+            .config
+            .required_capabilities
+            .difference(&server_challenge_response.capabilities)
+            .next();
+        if let Some(capability) = missing {
+            Err(Error::UnsupportedByPeer(*capability))?;
+        }
+        server.add_encryption(EncryptionConfiguration::new(shared_key.clone()));
+        server.pub_key = Some(server_pub_key);
+        server.presented_key = None;
+        server.key_change_trusted = false;
+        server.max_message_bytes = Some(server_challenge_response.max_message_bytes);
+        server.capabilities = server_challenge_response.capabilities;
+        if let Some(endpoint) = discovered {
+            server.endpoints.retain(|known| *known != endpoint);
+            server.endpoints.insert(0, endpoint);
+        }
+        self.db.server_db.update_entry(server_id.as_str(), server.clone())?;
+        // written after the server entry so the system chat id isn't overwritten
+        if assessment.is_downgraded() {
+            self.report_downgrade(&server_id, &assessment)?;
+        }
+        let pushes = self.push_channel(&server_id).0.clone();
+        // only advertised to clients whose version handles it, so to us
+        let codec = if server.capabilities.contains(&Capability::MsgPack) {
+            Codec::MsgPack
+        } else {
+            Codec::Json
+        };
+        let state = ConnectionState {
+            connection: Connection::new(
+                stream,
+                Some(shared_key),
+                Some(pushes),
+                self.config.max_frame_bytes,
+                codec,
+            ),
+            server,
+            security: Some(assessment),
+            #[cfg(feature = "insecure-dev")]
+            insecure: false,
+        };
+        // a session this replaces is closed as it's dropped
+        self.connections.insert(server_id.clone(), state);
+        self.server_id = Some(server_id.clone());
+        Ok(server_id)
+    }
+
+    /// Asks a server a session was just opened with to push to it, if it can.
+    pub async fn subscribe_pushes(&self, server_id: &str) -> Result<(), Error> {
+        let state = self.connection_state(server_id)?;
+        if !state.server.capabilities.contains(&Capability::Push) {
+            return Ok(());
+        }
+        let request = Request::new(rpc_models::SUBSCRIBE.to_string(), serde_json::json!(null));
+        // not the retrying send, which would reconnect through here
+        let response = self.try_send_sym_encrypted_request(server_id, request).await?;
+        response.into_result()?;
+        Ok(())
+    }
+}
+
+fn check_pong(response: Response) -> Result<(), Error> {
+    let resp_val: String = serde_json::from_value(response.into_result()?)?;
+    if resp_val != "pong" {
+        Err("Server did not respond with pong")?;
+    }
+    Ok(())
+}
+
+/// What a handshake with a saved server needs, from `Client::prepare_connect`.
+pub struct ConnectAttempt {
+    server_id: ServerId,
+    server: ServerModel,
+    discovered: Option<ServerEndpoint>,
+    identity_key: RsaPrivateKey,
+    ed25519_key: Option<ed25519_dalek::SigningKey>,
+    config: ClientConfig,
+}
+impl ConnectAttempt {
+    /// Dials the server and exchanges the handshake messages. None of what comes back
+    /// is trusted until `Client::finish_connect` checked it.
+    pub async fn run(mut self) -> Result<Handshake, Error> {
+        let endpoints = match self.discovered {
+            Some(ref endpoint) => vec![endpoint.clone()],
+            None => self.server.endpoints.clone(),
+        };
+        let (mut stream, endpoint) =
+            connect_endpoints(&endpoints, self.config.connect_timeout).await?;
+        self.server.last_endpoint = Some(endpoint);
+        let handshake_timeout = Some(self.config.handshake_timeout);
+        let max_frame_size = self.config.max_frame_bytes;
+        let request = Request::new(
+            rpc_models::START_SERVER_HANDSHAKE.to_string(),
+            serde_json::json!(null),
+        );
+        let response = request
+            .send_with_max_frame(&mut stream, handshake_timeout, max_frame_size)
+            .await?;
+        let challenge: String = serde_json::from_value(response.into_result()?)?;
+        let identity_key = &self.identity_key;
+        let ed25519_key = self.ed25519_key.as_ref();
+        let (key_type, sig, signing_key) =
+            sign_handshake(identity_key, ed25519_key, challenge.as_bytes());
 
+        let server_challenge = uuid::Uuid::new_v4().to_string();
+        // never persisted, the session key can't be recovered once it's gone
+        let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_key = X25519PublicKey::from(&ephemeral_secret);
+
+        let mut response = RespondClientChallenge {
+            pub_key: identity_key.to_public_key(),
+            signiture: sig,
+            server_challenge: server_challenge.clone(),
+            key_type,
+            signing_key,
+            signing_key_binding: pki::bind_signing_key(identity_key, ed25519_key),
+            ephemeral_key: Some(ephemeral_key),
+            capabilities: rpc_models::client_capabilities(),
+            protocol_version: rpc_models::PROTOCOL_VERSION,
+            transcript_signature: Vec::new(),
+        };
+        let client_transcript = response.transcript(&challenge)?;
+        let (_, transcript_signature, _) =
+            sign_handshake(identity_key, ed25519_key, &client_transcript);
+        response.transcript_signature = transcript_signature;
+
+        let request = Request::new(
+            rpc_models::CLIENT_CHALLENGE_RESPONSE.to_string(),
+            serde_json::json!(response),
+        );
+        let response = request
+            .send_with_max_frame(&mut stream, handshake_timeout, max_frame_size)
+            .await?;
+        if let Some(error) = response.error {
+            Err(Error::HandshakeFailed(format!(
+                "Server refused the handshake: {}",
+                error.message
+            )))?;
+        }
+        let response: RespondServerChallenge = serde_json::from_value(response.result)?;
+        Ok(Handshake {
+            attempt: self,
+            stream,
+            client_transcript,
+            server_challenge,
+            ephemeral_secret,
+            ephemeral_key,
+            response,
+        })
+    }
+}
+
+/// The messages of a handshake, for `Client::finish_connect` to check.
+pub struct Handshake {
+    attempt: ConnectAttempt,
+    stream: TcpStream,
+    client_transcript: Vec<u8>,
+    server_challenge: String,
+    ephemeral_secret: EphemeralSecret,
+    ephemeral_key: X25519PublicKey,
+    response: RespondServerChallenge,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    use async_std::sync::RwLock;
+    use async_std::task;
+
+    use crate::server::handler::ServerHandler;
+    use crate::server::{start_server_with_handle, ServerConfig};
+    use crate::{
+        server::Server,
+        shared::pki::{delete_key_file, gen_key_ed25519, write_ed25519_key_to_file},
+    };
+
+    use crate::client::models::ServerModel;
+    use crate::shared::ski;
+
+    use super::*;
+
+    fn local_endpoint(port: u16) -> Vec<ServerEndpoint> {
+        vec![ServerEndpoint::new("127.0.0.1", port)]
+    }
+
+    fn open_registration() -> ServerConfig {
+        ServerConfig {
+            open_registration: true,
+            ..ServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_client() {
+        let client = Client::new(b"example key1".to_vec(), None).unwrap();
+        let server_private_key = gen_key().unwrap();
         let server = Server::new(
             server_private_key,
-            Vec::new(),
+            vec![client.private_key.to_public_key()],
             None,
         );
         let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
-        task::spawn(async move {
-            start_server(handler, String::from("127.0.0.1"), 8890).await.unwrap();
-        });
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let server_model = ServerModel::new(
+            "test_server".to_string(),
+            vec![],
+            vec![],
+            IpAddr::V4([127, 0, 0, 1].into()),
+            port,
+        );
+        let server_id = client
+            .db
+            .server_db
+            .save_entry(server_model)
+            .expect("Failed to save server");
         task::block_on(async {
             let mut client = client;
             client
@@ -250,8 +1926,2333 @@ mod tests {
                 .get_entry::<ServerModel>(&server_id)
                 .expect("Failed to get server");
             assert!(updated_server.encryption.is_some());
-            client.server_ping().await.unwrap();
+            client.server_ping(&server_id).await.unwrap();
+
+            // a failed call comes back as the server's error, not a null result
+            let request = Request::new(String::from("no_such_method"), serde_json::json!(null));
+            let response = client.send_sym_encrypted_request(&server_id, request).await.unwrap();
+            let error = Error::from(response.into_result().unwrap_err());
+            assert_eq!(error.rpc_code(), Some(RpcErrorCode::MethodNotFound));
         });
         let _ = delete_key_file("client").unwrap_or_default();
     }
+
+    #[test]
+    fn test_concurrent_connections() {
+        let loc = "client_test_concurrent_connections";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let mut handles = Vec::new();
+        let mut server_ids = Vec::new();
+        for name in ["first", "second"] {
+            let server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+            let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+            let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+            let port = handle.local_addr().port();
+            server_ids.push(client.add_server(String::from(name), local_endpoint(port)).unwrap());
+            handles.push(handle);
+        }
+        let (first, second) = (&server_ids[0], &server_ids[1]);
+        task::block_on(async {
+            client.server_connect(first.as_str()).await.unwrap();
+            client.server_connect(second.as_str()).await.unwrap();
+            let mut connected = client.connected_servers();
+            connected.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+            let mut expected = server_ids.clone();
+            expected.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+            assert_eq!(connected, expected);
+            // each request goes over the session of the server it names
+            client.server_ping(first.as_str()).await.unwrap();
+            client.server_ping(second.as_str()).await.unwrap();
+            let summaries = client.list_servers().unwrap();
+            assert!(summaries.iter().all(|summary| summary.connected));
+
+            client.disconnect_server(first.as_str()).unwrap();
+            assert_eq!(client.connected_servers(), vec![second.clone()]);
+            client.server_ping(second.as_str()).await.unwrap();
+            assert!(client.disconnect_server(first.as_str()).is_err());
+            for handle in handles {
+                handle.shutdown(None).await.unwrap();
+            }
+        });
+        drop(client);
+        std::fs::remove_dir_all(ClientDatabase::base_dir(loc)).unwrap();
+    }
+
+    #[test]
+    fn test_revoke_session_notification() {
+        #[derive(Clone)]
+        struct TestEmitter {
+            events: Arc<std::sync::Mutex<Vec<(String, serde_json::Value)>>>,
+        }
+        impl EventEmitter for TestEmitter {
+            fn emit(&self, event: &str, payload: serde_json::Value) {
+                self.events.lock().unwrap().push((event.to_string(), payload));
+            }
+        }
+
+        let loc = "client_test_revoke";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let emitter = TestEmitter {
+            events: Arc::new(std::sync::Mutex::new(Vec::new())),
+        };
+        client.set_event_emitter(emitter.clone());
+        let encryption = EncryptionConfiguration::new(ski::gen_key());
+        let mut server_model = ServerModel::new(
+            "test_server".to_string(),
+            vec![],
+            vec![],
+            IpAddr::V4([127, 0, 0, 1].into()),
+            8891,
+        );
+        server_model.add_encryption(encryption.clone());
+        let listener = task::block_on(async_std::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let stream = task::block_on(TcpStream::connect(listener.local_addr().unwrap())).unwrap();
+        let server_id = ServerId::from("test_server");
+        let state = ConnectionState {
+            connection: Connection::new(stream, None, None, rpc::MAX_FRAME_SIZE, Codec::Json),
+            server: server_model,
+            security: None,
+            #[cfg(feature = "insecure-dev")]
+            insecure: false,
+        };
+        client.connections.insert(server_id.clone(), state);
+        client.server_id = Some(server_id.clone());
+
+        let params = RevokeSessionParams {
+            reason: rpc_models::RevocationReason::SessionExpired,
+        };
+        let notification = Request::new(
+            rpc_models::REVOKE_SESSION.to_string(),
+            serde_json::json!(params),
+        );
+        let data = encryption.seal(&serde_json::to_vec(&notification).unwrap()).unwrap();
+        let request = Request::new(
+            rpc_models::ENCRYPTED_REQUEST.to_string(),
+            serde_json::json!(rpc_models::EncryptedRequestParams {
+                enc_type: rpc_models::EncryptionType::AesGcm,
+                data,
+            }),
+        );
+        client.on_notify(server_id.as_str(), request).unwrap();
+        assert!(client.connected_servers().is_empty());
+        assert!(client.server_id.is_none());
+        let events = emitter.events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, SESSION_REVOKED_EVENT);
+        assert_eq!(events[0].1["server_id"], "test_server");
+        assert_eq!(events[0].1["reason"], "SessionExpired");
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_refresh_all_servers() {
+        #[derive(Clone)]
+        struct TestEmitter {
+            events: Arc<std::sync::Mutex<Vec<(String, serde_json::Value)>>>,
+        }
+        impl EventEmitter for TestEmitter {
+            fn emit(&self, event: &str, payload: serde_json::Value) {
+                self.events.lock().unwrap().push((event.to_string(), payload));
+            }
+        }
+
+        let loc = "client_test_refresh";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        client.db.server_db.clear().unwrap();
+        let emitter = TestEmitter {
+            events: Arc::new(std::sync::Mutex::new(Vec::new())),
+        };
+        client.set_event_emitter(emitter.clone());
+
+        let localhost = IpAddr::V4([127, 0, 0, 1].into());
+        let save_server = |port: u16, pub_key: Option<RsaPublicKey>| {
+            let mut server = ServerModel::new(format!("server_{}", port), vec![], vec![], localhost, port);
+            server.pub_key = pub_key;
+            client.db.save_server(server).unwrap()
+        };
+        let mut live_keys = Vec::new();
+        let mut ports = Vec::new();
+        let mut handles = Vec::new();
+        for i in 0..3 {
+            let server_private_key = gen_key().unwrap();
+            live_keys.push(server_private_key.to_public_key());
+            // only the server the client handshakes with needs to let it in
+            let config = if i == 0 { Some(open_registration()) } else { None };
+            let server = Server::new(server_private_key, Vec::new(), config);
+            let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+            let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+            ports.push(handle.local_addr().port());
+            handles.push(handle);
+        }
+        let connected = save_server(ports[0], None);
+        let pinned = save_server(ports[1], Some(live_keys[1].clone()));
+        let key_changed = save_server(ports[2], Some(gen_key().unwrap().to_public_key()));
+        let dead = save_server(8895, None);
+
+        task::block_on(async {
+            client.server_connect(connected.as_str()).await.unwrap();
+            let statuses = client
+                .refresh_all_servers(Duration::from_secs(2))
+                .await
+                .unwrap();
+            assert_eq!(statuses.len(), 4);
+
+            assert!(statuses[&connected].reachable);
+            assert_eq!(statuses[&connected].key_match, Some(true));
+            assert!(statuses[&pinned].reachable);
+            assert!(statuses[&pinned].latency_ms.is_some());
+            assert_eq!(statuses[&pinned].key_match, Some(true));
+            assert_eq!(statuses[&pinned].open_registration, Some(false));
+            assert!(statuses[&key_changed].reachable);
+            assert_eq!(statuses[&key_changed].key_match, Some(false));
+            assert!(!statuses[&dead].reachable);
+            assert!(statuses[&dead].latency_ms.is_none());
+
+            // the session survives the refresh
+            client.server_ping(connected.as_str()).await.unwrap();
+        });
+
+        let cached = client.db.get_server(&key_changed).unwrap().last_status.unwrap();
+        assert_eq!(cached.key_match, Some(false));
+        let events = emitter.events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, SERVERS_REFRESHED_EVENT);
+        assert_eq!(events[0].1[dead.as_str()]["reachable"], false);
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_forward_message_limit() {
+        let loc = "client_test_forward_limit";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let server_private_key = gen_key().unwrap();
+        let server = Server::new(server_private_key, Vec::new(), Some(open_registration()));
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let server_id = client
+            .db
+            .save_server(ServerModel::new(
+                "test_server".to_string(),
+                vec![],
+                vec![],
+                IpAddr::V4([127, 0, 0, 1].into()),
+                port,
+            ))
+            .unwrap();
+        task::block_on(async {
+            client.server_connect(server_id.as_str()).await.unwrap();
+            let max = client.connection_state(server_id.as_str()).unwrap().server.max_message_bytes.unwrap();
+            assert_eq!(max, rpc_models::DEFAULT_MAX_MESSAGE_BYTES);
+            let recipients = vec![String::from("recipient")];
+            client.forward_message(recipients.clone(), vec![0; max]).await.unwrap();
+
+            let err = client
+                .forward_message(recipients, vec![0; max + 1])
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::MessageTooLarge { limit, .. } if limit == max));
+            // the oversized message never reached the wire, so the session still works
+            client.server_ping(server_id.as_str()).await.unwrap();
+        });
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_subscribe_to_pushes() {
+        let server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let connect = |loc: &str| {
+            let client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+            let server_id = client
+                .add_server(String::from("test_server"), local_endpoint(port))
+                .unwrap();
+            (client, server_id)
+        };
+        let (mut recipient, recipient_server) = connect("client_test_push_recipient");
+        let (mut sender, sender_server) = connect("client_test_push_sender");
+        let (received, pushed) = channel::unbounded();
+        recipient.subscribe(recipient_server.as_str(), move |request| {
+            received.try_send(request).unwrap();
+        });
+        task::block_on(async {
+            recipient.server_connect(recipient_server.as_str()).await.unwrap();
+            sender.server_connect(sender_server.as_str()).await.unwrap();
+            let fingerprint =
+                crate::shared::pki::fingerprint(&recipient.private_key.to_public_key()).unwrap();
+            sender.forward_message(vec![fingerprint], vec![1, 2, 3]).await.unwrap();
+
+            let request = future::timeout(Duration::from_secs(5), pushed.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(request.method, rpc_models::FORWARDED_MSG);
+            let params: rpc_models::ForwardedMessageParams =
+                serde_json::from_value(request.params).unwrap();
+            assert_eq!(params.data, vec![1, 2, 3]);
+            // responses still reach their callers alongside pushes
+            recipient.server_ping(recipient_server.as_str()).await.unwrap();
+            recipient.shutdown().await;
+        });
+        delete_key_file("client_test_push_recipient").unwrap_or_default();
+        delete_key_file("client_test_push_sender").unwrap_or_default();
+    }
+
+    #[test]
+    fn test_incoming_without_polling() {
+        let server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let connect = |loc: &str| {
+            let client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+            let server_id = client
+                .add_server(String::from("test_server"), local_endpoint(port))
+                .unwrap();
+            (client, server_id)
+        };
+        let (mut recipient, recipient_server) = connect("client_test_incoming_recipient");
+        let (mut sender, sender_server) = connect("client_test_incoming_sender");
+        task::block_on(async {
+            // connecting is the only request the recipient makes
+            recipient.server_connect(recipient_server.as_str()).await.unwrap();
+            let incoming = recipient.incoming(recipient_server.as_str());
+            sender.server_connect(sender_server.as_str()).await.unwrap();
+            let fingerprint =
+                crate::shared::pki::fingerprint(&recipient.private_key.to_public_key()).unwrap();
+            for data in [vec![1], vec![2]] {
+                sender.forward_message(vec![fingerprint.clone()], data).await.unwrap();
+            }
+            for expected in [vec![1], vec![2]] {
+                let request = future::timeout(Duration::from_secs(5), incoming.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(request.method, rpc_models::FORWARDED_MSG);
+                let params: rpc_models::ForwardedMessageParams =
+                    serde_json::from_value(request.params).unwrap();
+                assert_eq!(params.data, expected);
+            }
+            recipient.shutdown().await;
+        });
+        delete_key_file("client_test_incoming_recipient").unwrap_or_default();
+        delete_key_file("client_test_incoming_sender").unwrap_or_default();
+    }
+
+    #[test]
+    fn test_register() {
+        let mut server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let path = std::env::temp_dir().join(format!("carapace-register-{}", uuid::Uuid::new_v4()));
+        server
+            .open_database(path, &crate::shared::db::DbConfig::default())
+            .unwrap();
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let connect = |loc: &str| {
+            let client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+            let server_id = client
+                .add_server(String::from("test_server"), local_endpoint(port))
+                .unwrap();
+            (client, server_id)
+        };
+        let (mut alice, alice_server) = connect("client_test_register_alice");
+        let (mut bob, bob_server) = connect("client_test_register_bob");
+        task::block_on(async {
+            alice.server_connect(alice_server.as_str()).await.unwrap();
+            bob.server_connect(bob_server.as_str()).await.unwrap();
+
+            let user_id = alice.register("alice").await.unwrap();
+            let user = alice.db.get_user(&user_id).unwrap();
+            assert_eq!(user.username(), "alice");
+            let pub_key = crate::shared::pki::pub_key_from_str(user.pub_key()).unwrap();
+            assert_eq!(pub_key, alice.private_key.to_public_key());
+            assert_eq!(user.fingerprint(), crate::shared::pki::fingerprint(&pub_key).ok());
+            let server = alice.db.server_db.get_entry::<ServerModel>(alice_server.as_str()).unwrap();
+            assert_eq!(server.user_ids().to_vec(), vec![user_id.clone()]);
+
+            let err = bob.register("alice").await.unwrap_err();
+            assert!(err.to_string().contains("already taken"));
+
+            // renaming updates the same entry
+            assert_eq!(alice.register("alice2").await.unwrap(), user_id);
+            assert_eq!(alice.db.get_user(&user_id).unwrap().username(), "alice2");
+            bob.register("alice").await.unwrap();
+
+            // looking users up
+            assert_eq!(alice.list_users().await.unwrap(), vec!["alice", "alice2"]);
+            let server = alice.db.get_server(&alice_server).unwrap();
+            assert_eq!(server.user_ids().len(), 2);
+            let lookup = alice.get_user_key("alice").await.unwrap();
+            assert!(lookup.previous_key.is_none());
+            assert_eq!(alice.db.get_server(&alice_server).unwrap().user_ids().len(), 2);
+            let bob_key = crate::shared::pki::pub_key_from_str(&lookup.pub_key).unwrap();
+            assert_eq!(bob_key, bob.private_key.to_public_key());
+            assert!(alice.get_user_key("alice").await.unwrap().previous_key.is_none());
+            assert!(alice.get_user_key("carol").await.is_err());
+
+            // a key that differs from the one on record is reported
+            let stale = gen_key().unwrap().to_public_key().to_public_key_pem(get_line_ending()).unwrap();
+            alice
+                .db
+                .known_user_db
+                .update_entry(lookup.user_id.as_str(), User::new(String::from("alice"), stale.clone()))
+                .unwrap();
+            let lookup = alice.get_user_key("alice").await.unwrap();
+            assert_eq!(lookup.previous_key, Some(stale));
+            assert_eq!(alice.db.get_user(&lookup.user_id).unwrap().pub_key(), lookup.pub_key);
+        });
+        delete_key_file("client_test_register_alice").unwrap_or_default();
+        delete_key_file("client_test_register_bob").unwrap_or_default();
+    }
+
+    #[test]
+    fn test_contacts() {
+        let loc = "client_test_contacts";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        client.db.known_user_db.clear().unwrap();
+        let key = gen_key().unwrap().to_public_key();
+        // any PEM line ending is fine, the key is kept in ours
+        let pem = key.to_public_key_pem(rsa::pkcs8::LineEnding::CRLF).unwrap();
+        let bob = client.add_contact(String::from("bob"), &pem).unwrap();
+        assert!(client.add_contact(String::from("bobby"), &pem).is_err());
+        assert!(client.add_contact(String::from("carol"), "not a key").is_err());
+        let own = client.private_key.to_public_key();
+        let own = own.to_public_key_pem(get_line_ending()).unwrap();
+        client.db.save_user(User::new(String::from("me"), own)).unwrap();
+        let carol = gen_key().unwrap().to_public_key();
+        let carol = carol.to_public_key_pem(get_line_ending()).unwrap();
+        let carol = client.add_contact(String::from("carol"), &carol).unwrap();
+
+        let (id, user) = client.get_contact_by_username("bob").unwrap().unwrap();
+        assert_eq!(id, bob);
+        assert_eq!(user.fingerprint(), crate::shared::pki::fingerprint(&key).ok());
+        assert!(client.get_contact_by_username("dave").unwrap().is_none());
+        let names: Vec<(UserId, String)> = client
+            .list_contacts()
+            .unwrap()
+            .into_iter()
+            .map(|(id, user)| (id, user.username().to_string()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![(bob.clone(), String::from("bob")), (carol, String::from("carol"))]
+        );
+
+        client.remove_contact(&bob).unwrap();
+        assert!(client.get_contact_by_username("bob").unwrap().is_none());
+        assert!(client.remove_contact(&bob).is_err());
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_end_to_end_chat() {
+        use crate::client::models::ChatEvent;
+        use crate::shared::models::{CHAT_COLORS, CHAT_EMOJIS};
+
+        let mut server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let path = std::env::temp_dir().join(format!("carapace-chat-{}", uuid::Uuid::new_v4()));
+        server
+            .open_database(path, &crate::shared::db::DbConfig::default())
+            .unwrap();
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let connect = |loc: &str| {
+            let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+            let server_id = client
+                .add_server(String::from("test_server"), local_endpoint(port))
+                .unwrap();
+            let (received, pushed) = channel::unbounded();
+            client.subscribe(server_id.as_str(), move |request| {
+                received.try_send(request).unwrap();
+            });
+            (client, server_id, pushed)
+        };
+        let next = |pushed: &Receiver<Request>| {
+            task::block_on(future::timeout(Duration::from_secs(5), pushed.recv()))
+                .unwrap()
+                .unwrap()
+        };
+        let (mut alice, alice_server, alice_pushes) = connect("client_test_chat_alice");
+        let (mut bob, bob_server, bob_pushes) = connect("client_test_chat_bob");
+        task::block_on(async {
+            alice.server_connect(alice_server.as_str()).await.unwrap();
+            bob.server_connect(bob_server.as_str()).await.unwrap();
+            alice.register("alice").await.unwrap();
+            bob.register("bob").await.unwrap();
+
+            let chat_id = alice.create_chat("bob", "alice & bob").await.unwrap();
+            let err = alice.send_chat_message(chat_id.as_str(), "too early").await.unwrap_err();
+            assert!(err.to_string().contains("accepted"));
+
+            let invite = next(&bob_pushes);
+            assert_eq!(
+                bob.on_forwarded_message(bob_server.as_str(), invite).await.unwrap(),
+                ChatEvent::Joined(chat_id.clone())
+            );
+            let bob_chat = bob.db.get_chat(&chat_id).unwrap();
+            assert_eq!(bob_chat.name(), "alice & bob");
+            let alice_chat = alice.db.get_chat(&chat_id).unwrap();
+            assert_eq!(bob_chat.shared_key(), alice_chat.shared_key());
+            let accept = next(&alice_pushes);
+            assert_eq!(
+                alice.on_forwarded_message(alice_server.as_str(), accept).await.unwrap(),
+                ChatEvent::Accepted(chat_id.clone())
+            );
+            // bob moving on to another server doesn't change where pushes came from
+            let other = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+            let other = ServerHandler::new(Arc::new(RwLock::new(other)));
+            let other = start_server_with_handle(other, String::from("127.0.0.1"), 0).await.unwrap();
+            let other_server = bob
+                .add_server(String::from("other"), local_endpoint(other.local_addr().port()))
+                .unwrap();
+            bob.server_connect(other_server.as_str()).await.unwrap();
+
+            alice.send_chat_message(chat_id.as_str(), "hi bob").await.unwrap();
+            let push = next(&bob_pushes);
+            let params: rpc_models::ForwardedMessageParams =
+                serde_json::from_value(push.params.clone()).unwrap();
+            // the server only ever relayed ciphertext
+            assert!(!params.data.windows(6).any(|w| w == b"hi bob"));
+            let message_id = match bob.on_forwarded_message(bob_server.as_str(), push).await.unwrap() {
+                ChatEvent::Message(id) => id,
+                event => panic!("unexpected event {:?}", event),
+            };
+            let message = bob.db.get_message(&message_id).unwrap();
+            assert_eq!(message.message(), "hi bob");
+            assert_eq!(message.server_id(), &bob_server);
+            let sender = bob.db.get_user(message.sender_id().unwrap()).unwrap();
+            assert_eq!(sender.username(), "alice");
+
+            bob.server_connect(bob_server.as_str()).await.unwrap();
+            bob.send_chat_message(chat_id.as_str(), "hi alice").await.unwrap();
+            let push = next(&alice_pushes);
+            assert!(matches!(
+                alice.on_forwarded_message(alice_server.as_str(), push).await.unwrap(),
+                ChatEvent::Message(_)
+            ));
+            let texts: Vec<String> = alice
+                .db
+                .transcript(&chat_id)
+                .unwrap()
+                .messages
+                .into_iter()
+                .map(|m| m.text)
+                .collect();
+            assert_eq!(texts, vec!["hi bob", "hi alice"]);
+
+            // a new look chosen by one participant reaches the other
+            alice
+                .customize_chat(chat_id.as_str(), CHAT_COLORS[3], CHAT_EMOJIS[3])
+                .await
+                .unwrap();
+            let push = next(&bob_pushes);
+            assert_eq!(
+                bob.on_forwarded_message(bob_server.as_str(), push.clone()).await.unwrap(),
+                ChatEvent::Customized(chat_id.clone())
+            );
+            let on_bob = bob.db.get_chat(&chat_id).unwrap().customization().cloned();
+            assert_eq!(on_bob.as_ref().unwrap().emoji, CHAT_EMOJIS[3]);
+            assert_eq!(on_bob.as_ref(), alice.db.get_chat(&chat_id).unwrap().customization());
+            // and one made since isn't undone by an older one arriving late
+            bob.customize_chat(chat_id.as_str(), CHAT_COLORS[4], CHAT_EMOJIS[4])
+                .await
+                .unwrap();
+            bob.on_forwarded_message(bob_server.as_str(), push).await.unwrap();
+            let on_bob = bob.db.get_chat(&chat_id).unwrap().customization().cloned();
+            assert_eq!(on_bob.unwrap().color, CHAT_COLORS[4]);
+            let push = next(&alice_pushes);
+            alice.on_forwarded_message(alice_server.as_str(), push).await.unwrap();
+            let on_alice = alice.db.get_chat(&chat_id).unwrap().customization().cloned();
+            assert_eq!(on_alice.unwrap().color, CHAT_COLORS[4]);
+            alice.shutdown().await;
+            bob.shutdown().await;
+        });
+        delete_key_file("client_test_chat_alice").unwrap_or_default();
+        delete_key_file("client_test_chat_bob").unwrap_or_default();
+    }
+
+    #[test]
+    fn test_received_messages() {
+        use crate::client::models::{ChatEvent, MessageId};
+
+        #[derive(Clone)]
+        struct TestEmitter {
+            events: Arc<std::sync::Mutex<Vec<(String, serde_json::Value)>>>,
+        }
+        impl EventEmitter for TestEmitter {
+            fn emit(&self, event: &str, payload: serde_json::Value) {
+                self.events.lock().unwrap().push((event.to_string(), payload));
+            }
+        }
+
+        let mut server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let path = std::env::temp_dir().join(format!("carapace-received-{}", uuid::Uuid::new_v4()));
+        server
+            .open_database(path, &crate::shared::db::DbConfig::default())
+            .unwrap();
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let connect = |loc: &str| {
+            let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+            let server_id = client
+                .add_server(String::from("test_server"), local_endpoint(port))
+                .unwrap();
+            let (received, pushed) = channel::unbounded();
+            client.subscribe(server_id.as_str(), move |request| {
+                received.try_send(request).unwrap();
+            });
+            (client, server_id, pushed)
+        };
+        let next = |pushed: &Receiver<Request>| {
+            task::block_on(future::timeout(Duration::from_secs(5), pushed.recv()))
+                .unwrap()
+                .unwrap()
+        };
+        let (mut alice, alice_server, alice_pushes) = connect("client_test_received_alice");
+        let (mut bob, bob_server, bob_pushes) = connect("client_test_received_bob");
+        let emitter = TestEmitter {
+            events: Arc::new(std::sync::Mutex::new(Vec::new())),
+        };
+        bob.set_event_emitter(emitter.clone());
+        let received = || -> Vec<serde_json::Value> {
+            let events = emitter.events.lock().unwrap();
+            events
+                .iter()
+                .filter(|(event, _)| event == MESSAGE_RECEIVED_EVENT)
+                .map(|(_, payload)| payload.clone())
+                .collect()
+        };
+        task::block_on(async {
+            alice.server_connect(alice_server.as_str()).await.unwrap();
+            bob.server_connect(bob_server.as_str()).await.unwrap();
+            alice.register("alice").await.unwrap();
+            bob.register("bob").await.unwrap();
+            let chat_id = alice.create_chat("bob", "alice & bob").await.unwrap();
+            let invite = next(&bob_pushes);
+            bob.on_forwarded_message(bob_server.as_str(), invite.clone()).await.unwrap();
+            alice.on_forwarded_message(alice_server.as_str(), next(&alice_pushes)).await.unwrap();
+
+            alice.send_chat_message(chat_id.as_str(), "first").await.unwrap();
+            let message_id = match bob.on_forwarded_message(bob_server.as_str(), next(&bob_pushes)).await.unwrap() {
+                ChatEvent::Message(id) => id,
+                event => panic!("unexpected event {:?}", event),
+            };
+            assert_eq!(
+                received(),
+                vec![serde_json::json!({ "chat_id": chat_id, "message_id": message_id })]
+            );
+
+            // a message for a chat bob hasn't joined is kept until bob joins it
+            bob.db.chat_db.delete_entry(chat_id.as_str()).unwrap();
+            alice.send_chat_message(chat_id.as_str(), "second").await.unwrap();
+            assert_eq!(
+                bob.on_forwarded_message(bob_server.as_str(), next(&bob_pushes)).await.unwrap(),
+                ChatEvent::Stashed(chat_id.clone())
+            );
+            assert_eq!(received().len(), 1);
+            assert_eq!(bob.db.chat_messages(&chat_id, None, 10).unwrap().len(), 1);
+
+            bob.on_forwarded_message(bob_server.as_str(), invite).await.unwrap();
+            let received = received();
+            assert_eq!(received.len(), 2);
+            assert_eq!(received[1]["chat_id"], chat_id.as_str());
+            let message_id = MessageId::from(received[1]["message_id"].as_str().unwrap());
+            assert_eq!(bob.db.get_message(&message_id).unwrap().message(), "second");
+            assert!(bob.db.take_stashed(&chat_id).unwrap().is_empty());
+            alice.shutdown().await;
+            bob.shutdown().await;
+        });
+        delete_key_file("client_test_received_alice").unwrap_or_default();
+        delete_key_file("client_test_received_bob").unwrap_or_default();
+    }
+
+    #[test]
+    fn test_outbox_reconciliation() {
+        use crate::client::models::{ChatEvent, MessageStatus};
+
+        #[derive(Clone)]
+        struct TestEmitter {
+            events: Arc<std::sync::Mutex<Vec<(String, serde_json::Value)>>>,
+        }
+        impl EventEmitter for TestEmitter {
+            fn emit(&self, event: &str, payload: serde_json::Value) {
+                self.events.lock().unwrap().push((event.to_string(), payload));
+            }
+        }
+
+        let mut server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let path = std::env::temp_dir().join(format!("carapace-outbox-{}", uuid::Uuid::new_v4()));
+        server
+            .open_database(path, &crate::shared::db::DbConfig::default())
+            .unwrap();
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let connect = |loc: &str| {
+            let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+            let server_id = client
+                .add_server(String::from("test_server"), local_endpoint(port))
+                .unwrap();
+            let (received, pushed) = channel::unbounded();
+            client.subscribe(server_id.as_str(), move |request| {
+                received.try_send(request).unwrap();
+            });
+            (client, server_id, pushed)
+        };
+        let next = |pushed: &Receiver<Request>| {
+            task::block_on(future::timeout(Duration::from_secs(5), pushed.recv()))
+                .unwrap()
+                .unwrap()
+        };
+        let (mut alice, alice_server, alice_pushes) = connect("client_test_outbox_alice");
+        let (mut bob, bob_server, bob_pushes) = connect("client_test_outbox_bob");
+        let emitter = TestEmitter {
+            events: Arc::new(std::sync::Mutex::new(Vec::new())),
+        };
+        alice.set_event_emitter(emitter.clone());
+        task::block_on(async {
+            alice.server_connect(alice_server.as_str()).await.unwrap();
+            bob.server_connect(bob_server.as_str()).await.unwrap();
+            alice.register("alice").await.unwrap();
+            bob.register("bob").await.unwrap();
+            let chat_id = alice.create_chat("bob", "outbox").await.unwrap();
+            bob.on_forwarded_message(bob_server.as_str(), next(&bob_pushes)).await.unwrap();
+            alice.on_forwarded_message(alice_server.as_str(), next(&alice_pushes)).await.unwrap();
+
+            // stored and shown before the server hears of it
+            let pending = alice.send_message(chat_id.as_str(), "first").unwrap();
+            assert_eq!(pending.status, MessageStatus::Pending);
+            let stored = alice.db.get_message(&pending.message_id).unwrap();
+            assert_eq!(stored.status(), MessageStatus::Pending);
+            assert_eq!(stored.timestamp(), pending.timestamp);
+            assert_eq!(alice.db.outbox().unwrap().len(), 1);
+            assert!(bob_pushes.is_empty());
+
+            // bob's reply lands before alice's outbox is flushed
+            bob.send_chat_message(chat_id.as_str(), "reply").await.unwrap();
+            assert!(matches!(
+                alice.on_forwarded_message(alice_server.as_str(), next(&alice_pushes)).await.unwrap(),
+                ChatEvent::Message(_)
+            ));
+            let settled = alice.flush_outbox().await.unwrap();
+            assert_eq!(settled.len(), 1);
+            assert_eq!(settled[0].message_id, pending.message_id);
+            assert_eq!(settled[0].status, MessageStatus::Sent);
+            let relayed_at = settled[0].relayed_at.unwrap();
+            assert!(relayed_at >= pending.timestamp);
+            // relayed after the reply came in, but still shown above it
+            assert_eq!(settled[0].timestamp, pending.timestamp);
+            assert!(alice.db.outbox().unwrap().is_empty());
+            let message_id = match bob.on_forwarded_message(bob_server.as_str(), next(&bob_pushes)).await.unwrap() {
+                ChatEvent::Message(id) => id,
+                event => panic!("unexpected event {:?}", event),
+            };
+            assert_eq!(bob.db.get_message(&message_id).unwrap().message(), "first");
+            let texts: Vec<String> = alice
+                .db
+                .transcript(&chat_id)
+                .unwrap()
+                .messages
+                .into_iter()
+                .map(|m| m.text)
+                .collect();
+            assert_eq!(texts, vec!["first", "reply"]);
+
+            // a send that can never succeed flips to failed and stays in the chat
+            alice.connections.get_mut(&alice_server).unwrap().server.max_message_bytes = Some(16);
+            let pending = alice.send_message(chat_id.as_str(), "too long to fit").unwrap();
+            let settled = alice.flush_outbox().await.unwrap();
+            assert_eq!(settled[0].status, MessageStatus::Failed);
+            assert!(settled[0].error.as_ref().unwrap().contains("exceeds"));
+            assert_eq!(
+                alice.db.get_message(&pending.message_id).unwrap().status(),
+                MessageStatus::Failed
+            );
+            assert!(alice.db.outbox().unwrap().is_empty());
+            alice.shutdown().await;
+            bob.shutdown().await;
+        });
+        let events = emitter.events.lock().unwrap();
+        let reconciled: Vec<&serde_json::Value> = events
+            .iter()
+            .filter(|(event, _)| event == MESSAGE_RECONCILED_EVENT)
+            .map(|(_, payload)| payload)
+            .collect();
+        assert_eq!(reconciled.len(), 2);
+        assert_eq!(reconciled[0]["status"], "Sent");
+        assert_eq!(reconciled[1]["status"], "Failed");
+        delete_key_file("client_test_outbox_alice").unwrap_or_default();
+        delete_key_file("client_test_outbox_bob").unwrap_or_default();
+    }
+
+    #[test]
+    fn test_outbox_survives_server_restart() {
+        use crate::client::models::{ChatEvent, MessageStatus};
+
+        let mut server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let path = std::env::temp_dir().join(format!("carapace-restart-{}", uuid::Uuid::new_v4()));
+        server
+            .open_database(path, &crate::shared::db::DbConfig::default())
+            .unwrap();
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(
+            handler.clone(),
+            String::from("127.0.0.1"),
+            0,
+        ))
+        .unwrap();
+        let port = handle.local_addr().port();
+        let connect = |loc: &str| {
+            let config = ClientConfig {
+                max_reconnect_attempts: 0,
+                ..ClientConfig::default()
+            };
+            let mut client = Client::with_location(loc, b"example key1".to_vec(), Some(config)).unwrap();
+            let server_id = client
+                .add_server(String::from("test_server"), local_endpoint(port))
+                .unwrap();
+            let (received, pushed) = channel::unbounded();
+            client.subscribe(server_id.as_str(), move |request| {
+                received.try_send(request).unwrap();
+            });
+            (client, server_id, pushed)
+        };
+        let next = |pushed: &Receiver<Request>| {
+            task::block_on(future::timeout(Duration::from_secs(5), pushed.recv()))
+                .unwrap()
+                .unwrap()
+        };
+        let (mut alice, alice_server, alice_pushes) = connect("client_test_restart_alice");
+        let (mut bob, bob_server, bob_pushes) = connect("client_test_restart_bob");
+        task::block_on(async {
+            alice.server_connect(alice_server.as_str()).await.unwrap();
+            bob.server_connect(bob_server.as_str()).await.unwrap();
+            alice.register("alice").await.unwrap();
+            bob.register("bob").await.unwrap();
+            let chat_id = alice.create_chat("bob", "restart").await.unwrap();
+            bob.on_forwarded_message(bob_server.as_str(), next(&bob_pushes)).await.unwrap();
+            alice.on_forwarded_message(alice_server.as_str(), next(&alice_pushes)).await.unwrap();
+
+            handle.shutdown(None).await.unwrap();
+            while !alice.connection_closed(alice_server.as_str())
+                || !bob.connection_closed(bob_server.as_str())
+            {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+            let mut sent = vec![];
+            for text in ["first", "second", "third"] {
+                let pending = alice.send_message(chat_id.as_str(), text).unwrap();
+                assert_eq!(pending.status, MessageStatus::Pending);
+                sent.push(pending.message_id);
+            }
+            // the server being away doesn't use up any attempts
+            assert_eq!(alice.flush_pending(alice_server.as_str()).await.unwrap(), 0);
+            let outbox = alice.db.outbox().unwrap();
+            assert_eq!(outbox.len(), 3);
+            assert!(outbox.iter().all(|(_, entry)| entry.attempts == 0));
+            assert!(alice.connection_state(alice_server.as_str()).is_err());
+            // with no session at all the message still goes to the chat's server
+            sent.push(alice.send_message(chat_id.as_str(), "fourth").unwrap().message_id);
+
+            let handle = start_server_with_handle(handler, String::from("127.0.0.1"), port)
+                .await
+                .unwrap();
+            bob.ensure_connected(bob_server.as_str()).await.unwrap();
+            alice.ensure_connected(alice_server.as_str()).await.unwrap();
+            assert!(alice.db.outbox().unwrap().is_empty());
+            let mut texts = vec![];
+            for _ in 0..4 {
+                let message_id = match bob.on_forwarded_message(bob_server.as_str(), next(&bob_pushes)).await.unwrap() {
+                    ChatEvent::Message(id) => id,
+                    event => panic!("unexpected event {:?}", event),
+                };
+                texts.push(bob.db.get_message(&message_id).unwrap().message().to_string());
+            }
+            assert_eq!(texts, vec!["first", "second", "third", "fourth"]);
+            for message_id in sent.iter() {
+                assert_eq!(
+                    alice.db.get_message(message_id).unwrap().status(),
+                    MessageStatus::Sent
+                );
+            }
+            alice.shutdown().await;
+            bob.shutdown().await;
+            handle.shutdown(None).await.unwrap();
+        });
+        delete_key_file("client_test_restart_alice").unwrap_or_default();
+        delete_key_file("client_test_restart_bob").unwrap_or_default();
+    }
+
+    #[test]
+    fn test_message_acks() {
+        use crate::client::models::{ChatEvent, MessageStatus};
+        use crate::shared::rpc_models::AckStatus;
+
+        let mut server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let path = std::env::temp_dir().join(format!("carapace-acks-{}", uuid::Uuid::new_v4()));
+        server
+            .open_database(path, &crate::shared::db::DbConfig::default())
+            .unwrap();
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let connect = |loc: &str| {
+            let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+            let server_id = client
+                .add_server(String::from("test_server"), local_endpoint(port))
+                .unwrap();
+            let (received, pushed) = channel::unbounded();
+            client.subscribe(server_id.as_str(), move |request| {
+                received.try_send(request).unwrap();
+            });
+            (client, server_id, pushed)
+        };
+        let next = |pushed: &Receiver<Request>| {
+            task::block_on(future::timeout(Duration::from_secs(5), pushed.recv()))
+                .unwrap()
+                .unwrap()
+        };
+        let (mut alice, alice_server, alice_pushes) = connect("client_test_acks_alice");
+        let (mut bob, bob_server, bob_pushes) = connect("client_test_acks_bob");
+        task::block_on(async {
+            alice.server_connect(alice_server.as_str()).await.unwrap();
+            bob.server_connect(bob_server.as_str()).await.unwrap();
+            alice.register("alice").await.unwrap();
+            bob.register("bob").await.unwrap();
+            let chat_id = alice.create_chat("bob", "acks").await.unwrap();
+            bob.on_forwarded_message(bob_server.as_str(), next(&bob_pushes)).await.unwrap();
+            alice.on_forwarded_message(alice_server.as_str(), next(&alice_pushes)).await.unwrap();
+
+            let sent = alice.send_chat_message(chat_id.as_str(), "hello").await.unwrap();
+            let server_message_id = alice
+                .db
+                .get_message(&sent)
+                .unwrap()
+                .server_message_id()
+                .unwrap()
+                .to_string();
+            let received = match bob.on_forwarded_message(bob_server.as_str(), next(&bob_pushes)).await.unwrap() {
+                ChatEvent::Message(id) => id,
+                event => panic!("unexpected event {:?}", event),
+            };
+            assert_eq!(
+                bob.db.get_message(&received).unwrap().server_message_id(),
+                Some(server_message_id.as_str())
+            );
+
+            for (ack, status) in [
+                (AckStatus::Delivered, MessageStatus::Delivered),
+                (AckStatus::Read, MessageStatus::Read),
+            ] {
+                bob.ack_message(bob_server.as_str(), &received, ack).await.unwrap();
+                let push = next(&alice_pushes);
+                assert_eq!(push.method, rpc_models::ACK_MESSAGE);
+                assert_eq!(alice.on_message_ack(push).unwrap(), Some(sent.clone()));
+                assert_eq!(alice.db.get_message(&sent).unwrap().status(), status);
+            }
+            // read is as far as it goes, the server doesn't pass on a step back
+            bob.ack_message(bob_server.as_str(), &received, AckStatus::Delivered)
+                .await
+                .unwrap();
+            assert!(future::timeout(Duration::from_millis(200), alice_pushes.recv())
+                .await
+                .is_err());
+            assert_eq!(alice.db.get_message(&sent).unwrap().status(), MessageStatus::Read);
+
+            // only a message's recipients can acknowledge it
+            let params = rpc_models::AckParams {
+                message_id: server_message_id,
+                status: AckStatus::Read,
+            };
+            let request = Request::new(rpc_models::ACK_MESSAGE.to_string(), serde_json::json!(params));
+            alice
+                .send_sym_encrypted_request(alice_server.as_str(), request)
+                .await
+                .unwrap();
+            assert!(future::timeout(Duration::from_millis(200), alice_pushes.recv())
+                .await
+                .is_err());
+            alice.shutdown().await;
+            bob.shutdown().await;
+        });
+        delete_key_file("client_test_acks_alice").unwrap_or_default();
+        delete_key_file("client_test_acks_bob").unwrap_or_default();
+    }
+
+    #[test]
+    fn test_forked_key_log() {
+        struct TestEmitter {
+            events: Arc<std::sync::Mutex<Vec<(String, serde_json::Value)>>>,
+        }
+        impl EventEmitter for TestEmitter {
+            fn emit(&self, event: &str, payload: serde_json::Value) {
+                self.events.lock().unwrap().push((event.to_string(), payload));
+            }
+        }
+
+        // two servers sharing a key but not a log, as if one server forked it
+        let server_key = gen_key().unwrap();
+        let mut ports = Vec::new();
+        let mut handles = Vec::new();
+        for _ in 0..2 {
+            let mut server = Server::new(server_key.clone(), Vec::new(), Some(open_registration()));
+            let path = std::env::temp_dir().join(format!("carapace-key-log-{}", uuid::Uuid::new_v4()));
+            server
+                .open_database(path, &crate::shared::db::DbConfig::default())
+                .unwrap();
+            let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+            let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+            ports.push(handle.local_addr().port());
+            handles.push(handle);
+        }
+        let connect = |loc: &str, port: u16| {
+            let client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+            let server_id = client
+                .add_server(String::from("test_server"), local_endpoint(port))
+                .unwrap();
+            (client, server_id)
+        };
+        let (mut alice, alice_server) = connect("client_test_key_log_alice", ports[0]);
+        let (mut carol, carol_server) = connect("client_test_key_log_carol", ports[0]);
+        let (mut bob, bob_server) = connect("client_test_key_log_bob", ports[1]);
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        bob.set_event_emitter(TestEmitter {
+            events: events.clone(),
+        });
+        task::block_on(async {
+            alice.server_connect(alice_server.as_str()).await.unwrap();
+            carol.server_connect(carol_server.as_str()).await.unwrap();
+            bob.server_connect(bob_server.as_str()).await.unwrap();
+            alice.register("alice").await.unwrap();
+            carol.register("carol").await.unwrap();
+            bob.register("bob").await.unwrap();
+
+            // lookups come with an inclusion proof, and the log only ever grows
+            alice.get_user_key("carol").await.unwrap();
+            assert_eq!(alice.refresh_log_head().await.unwrap().size, 2);
+            carol.register("carol2").await.unwrap();
+            let alice_head = alice.refresh_log_head().await.unwrap();
+            assert_eq!(alice_head.size, 3);
+            let stored = alice.db.get_server(&alice_server).unwrap().log_head;
+            assert_eq!(stored, Some(alice_head.clone()));
+            alice.check_log_head(alice_head.clone()).await.unwrap();
+
+            // alice's and bob's views of "the same" server can't be reconciled
+            let bob_head = bob.refresh_log_head().await.unwrap();
+            assert_eq!(bob_head.size, 1);
+            let err = bob.check_log_head(alice_head).await.unwrap_err();
+            assert!(err.to_string().contains("equivocated"));
+            assert!(events
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|(event, _)| event == KEY_LOG_EQUIVOCATION_EVENT));
+            let system_chat = bob.db.get_server(&bob_server).unwrap().system_chat_id.unwrap();
+            let notices = bob.db.transcript(&system_chat).unwrap().messages;
+            assert!(notices[0].text.starts_with("SEVERE"));
+            assert!(alice.check_log_head(bob_head).await.is_err());
+        });
+        for loc in ["alice", "bob", "carol"] {
+            delete_key_file(&format!("client_test_key_log_{}", loc)).unwrap_or_default();
+        }
+    }
+
+    #[test]
+    fn test_unsupported_capability() {
+        let mut features = rpc_models::server_capabilities();
+        features.remove(&Capability::KeyLog);
+        let config = ServerConfig {
+            features,
+            ..open_registration()
+        };
+        let mut server = Server::new(gen_key().unwrap(), Vec::new(), Some(config));
+        let path = std::env::temp_dir().join(format!("carapace-capabilities-{}", uuid::Uuid::new_v4()));
+        server
+            .open_database(path, &crate::shared::db::DbConfig::default())
+            .unwrap();
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let loc = "client_test_capabilities";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let server_id = client
+            .add_server(String::from("test_server"), local_endpoint(port))
+            .unwrap();
+        task::block_on(async {
+            client.server_connect(server_id.as_str()).await.unwrap();
+            let summary = client.list_servers().unwrap().remove(0);
+            assert!(summary.capabilities.contains(&Capability::UserDirectory));
+            assert!(!summary.capabilities.contains(&Capability::KeyLog));
+
+            // refused locally instead of by the server
+            let err = client.refresh_log_head().await.unwrap_err();
+            assert!(matches!(err, Error::UnsupportedByPeer(Capability::KeyLog)));
+            client.register("alice").await.unwrap();
+            assert!(client.get_user_key("alice").await.is_err());
+            assert_eq!(client.list_users().await.unwrap(), vec![String::from("alice")]);
+        });
+        assert!(client.server_supports(server_id.as_str(), Capability::UserDirectory));
+        assert!(!client.server_supports(server_id.as_str(), Capability::KeyLog));
+        delete_key_file(loc).unwrap_or_default();
+
+        // clients that need a capability don't connect to servers without it
+        let loc = "client_test_required_capabilities";
+        for (required, connects) in [(Capability::UserDirectory, true), (Capability::KeyLog, false)] {
+            let config = ClientConfig {
+                required_capabilities: [required].into_iter().collect(),
+                ..ClientConfig::default()
+            };
+            let mut client = Client::with_location(loc, b"example key1".to_vec(), Some(config)).unwrap();
+            let server_id = client
+                .add_server(String::from("test_server"), local_endpoint(port))
+                .unwrap();
+            let result = task::block_on(client.server_connect(server_id.as_str()));
+            if connects {
+                result.unwrap();
+            } else {
+                assert!(matches!(result, Err(Error::UnsupportedByPeer(Capability::KeyLog))));
+                let status = client.connection_status(server_id.as_str());
+                assert_eq!(status, ConnectionStatus::Disconnected);
+            }
+            task::block_on(client.shutdown());
+            delete_key_file(loc).unwrap_or_default();
+        }
+    }
+
+    #[test]
+    fn test_forced_rekey() {
+        let config = ServerConfig {
+            session_keys: crate::server::session_key::SessionKeyPolicy {
+                max_bytes: 300,
+                ..Default::default()
+            },
+            ..open_registration()
+        };
+        let server = Arc::new(RwLock::new(Server::new(gen_key().unwrap(), Vec::new(), Some(config))));
+        let handler = ServerHandler::new(server.clone());
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let loc = "client_test_forced_rekey";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let server_id = client
+            .add_server(String::from("test_server"), local_endpoint(port))
+            .unwrap();
+        task::block_on(async {
+            client.server_connect(server_id.as_str()).await.unwrap();
+            let first_key = client.connection_state(server_id.as_str()).unwrap().server.encryption.as_ref().unwrap().shared_key.clone();
+            // every ping goes through, rekeying whenever the budget runs out
+            for _ in 0..10 {
+                client.server_ping(server_id.as_str()).await.unwrap();
+            }
+            assert!(server.read().await.metrics().forced_rekeys >= 2);
+            let key = client.connection_state(server_id.as_str()).unwrap().server.encryption.as_ref().unwrap().shared_key.clone();
+            assert_ne!(key, first_key);
+            let stored = client.db.server_db.get_entry::<ServerModel>(server_id.as_str()).unwrap();
+            assert_eq!(stored.encryption.unwrap().shared_key, key);
+        });
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_rotate_identity_key() {
+        let loc = "client_test_rotate_key";
+        let pass = b"example key1";
+        let mut client = Client::with_location(loc, pass.to_vec(), None).unwrap();
+        client.db.server_db.clear().unwrap();
+        let old_key = client.private_key.clone();
+        let server = Server::new(gen_key().unwrap(), vec![old_key.to_public_key()], None);
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let live = client.add_server(String::from("live"), local_endpoint(port)).unwrap();
+        let dead = client.add_server(String::from("dead"), local_endpoint(8905)).unwrap();
+        let pending = |client: &Client, server_id: &ServerId| {
+            client.db.get_server(server_id).unwrap().key_rotation.is_some()
+        };
+
+        task::block_on(async {
+            assert!(client.rotate_identity_key(b"wrong passphrase").await.is_err());
+            let not_updated = client.rotate_identity_key(pass).await.unwrap();
+            assert!(not_updated.contains(&dead));
+            assert!(!not_updated.contains(&live));
+            assert!(pending(&client, &dead));
+            assert!(!pending(&client, &live));
+            assert_ne!(client.private_key, old_key);
+            let file_key = client.master_key.key_file_key().unwrap();
+            assert_eq!(read_key_from_file(loc, &file_key).unwrap(), client.private_key);
+            client.server_connect(live.as_str()).await.unwrap();
+            client.server_ping(live.as_str()).await.unwrap();
+
+            // the dead server comes back, still knowing only the old key
+            let server = Server::new(gen_key().unwrap(), vec![old_key.to_public_key()], None);
+            let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+            let back = start_server_with_handle(handler, String::from("127.0.0.1"), 0).await.unwrap();
+            let mut server = client.db.get_server(&dead).unwrap();
+            server.endpoints = local_endpoint(back.local_addr().port());
+            client.db.server_db.update_entry(dead.as_str(), server).unwrap();
+            assert!(client.retry_key_rotations().await.unwrap().is_empty());
+            assert!(!pending(&client, &dead));
+            client.server_connect(dead.as_str()).await.unwrap();
+            client.server_ping(dead.as_str()).await.unwrap();
+
+            // whoever still holds the old key is refused
+            let old_loc = "client_test_rotate_key_old";
+            let _ = std::fs::remove_dir_all(ClientDatabase::base_dir(old_loc));
+            write_key_to_file(&old_key, old_loc, pass).unwrap();
+            let mut old_client = Client::with_location(old_loc, pass.to_vec(), None).unwrap();
+            let server_id = old_client.add_server(String::from("live"), local_endpoint(port)).unwrap();
+            let err = old_client.server_connect(server_id.as_str()).await.unwrap_err();
+            assert!(err.to_string().contains("refused"));
+            drop(old_client);
+            std::fs::remove_dir_all(ClientDatabase::base_dir(old_loc)).unwrap();
+        });
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_change_passphrase() {
+        use crate::client::models::Message;
+        use crate::shared::kv::DEFAULT_TREE;
+
+        let loc = "client_test_change_passphrase";
+        let _ = std::fs::remove_dir_all(ClientDatabase::base_dir(loc));
+        let (old, new) = (b"old passphrase".as_slice(), b"new passphrase".as_slice());
+        let mut client = Client::with_location(loc, old.to_vec(), None).unwrap();
+        let key = client.private_key.clone();
+        let server_id = client.add_server(String::from("server"), local_endpoint(1)).unwrap();
+        let message = Message::new(server_id, None, ChatId::from("chat"), String::from("hi"));
+        let message_id = client.db.add_message(message).unwrap();
+        let stored = client
+            .db
+            .message_db
+            .store()
+            .get(DEFAULT_TREE, message_id.as_str().as_bytes())
+            .unwrap();
+
+        assert!(client.change_passphrase(b"wrong passphrase", new).is_err());
+        client.change_passphrase(old, new).unwrap();
+        // only the master key was rewrapped, the entries weren't touched
+        let after = client
+            .db
+            .message_db
+            .store()
+            .get(DEFAULT_TREE, message_id.as_str().as_bytes())
+            .unwrap();
+        assert_eq!(after, stored);
+        drop(client);
+
+        let err = Client::with_location(loc, old.to_vec(), None).err().unwrap();
+        assert!(matches!(err, Error::InvalidPassphrase));
+        let client = Client::with_location(loc, new.to_vec(), None).unwrap();
+        assert_eq!(client.private_key, key);
+        assert_eq!(client.db.get_message(&message_id).unwrap().message(), "hi");
+        assert_eq!(client.list_servers().unwrap().len(), 1);
+        drop(client);
+        std::fs::remove_dir_all(ClientDatabase::base_dir(loc)).unwrap();
+    }
+
+    #[test]
+    fn test_verify_passphrase() {
+        let loc = "client_test_verify_passphrase";
+        let _ = std::fs::remove_dir_all(ClientDatabase::base_dir(loc));
+        let (right, wrong) = (b"right passphrase".as_slice(), b"wrong passphrase".as_slice());
+        assert!(Client::passphrase_unlocks(loc, right).unwrap());
+
+        // keyed directly by the passphrase, from before profiles had a master key
+        write_key_to_file(&gen_key().unwrap(), loc, right).unwrap();
+        assert!(Client::passphrase_unlocks(loc, right).unwrap());
+        assert!(!Client::passphrase_unlocks(loc, wrong).unwrap());
+
+        let client = Client::with_location(loc, right.to_vec(), None).unwrap();
+        drop(client);
+        assert!(Client::passphrase_unlocks(loc, right).unwrap());
+        assert!(!Client::passphrase_unlocks(loc, wrong).unwrap());
+        let err = Client::with_location(loc, wrong.to_vec(), None).err().unwrap();
+        assert!(matches!(err, Error::InvalidPassphrase));
+        std::fs::remove_dir_all(ClientDatabase::base_dir(loc)).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_to_master_key() {
+        use crate::client::models::Message;
+        use crate::shared::db::EntryDb;
+
+        let loc = "client_test_migrate_master_key";
+        let pass = b"example key1";
+        let _ = std::fs::remove_dir_all(ClientDatabase::base_dir(loc));
+        // a profile from before the master key, everything keyed by the passphrase
+        let key = gen_key().unwrap();
+        write_key_to_file(&key, loc, pass).unwrap();
+        let message = Message::new(
+            ServerId::from("server"),
+            None,
+            ChatId::from("chat"),
+            String::from("hi"),
+        );
+        let message_id = ClientDatabase::open_legacy_entry_db(loc, db::ENTRY_DBS[1], pass)
+            .unwrap()
+            .save_entry(message)
+            .unwrap();
+
+        // a wrong passphrase leaves the profile as it was
+        assert!(Client::with_location(loc, b"wrong passphrase".to_vec(), None).is_err());
+        assert!(!MasterKey::exists(loc));
+        assert!(IntentLog::new(loc).pending().unwrap().is_empty());
+
+        let mut client = Client::with_location(loc, pass.to_vec(), None).unwrap();
+        assert!(MasterKey::exists(loc));
+        assert!(client.startup_report().is_empty());
+        assert_eq!(client.private_key, key);
+        assert!(read_key_from_file(loc, pass).is_err());
+        let message = client.db.message_db.get_entry::<Message>(&message_id).unwrap();
+        assert_eq!(message.message(), "hi");
+        for (_, db) in client.db.entry_dbs_mut() {
+            assert!(EntryDb::uses_data_key(db.store()).unwrap());
+        }
+        drop(client);
+        let client = Client::with_location(loc, pass.to_vec(), None).unwrap();
+        assert_eq!(client.private_key, key);
+        drop(client);
+        std::fs::remove_dir_all(ClientDatabase::base_dir(loc)).unwrap();
+    }
+
+    #[test]
+    fn test_backup_round_trip() {
+        use crate::client::models::{Chat, Message};
+
+        let (loc, restored_loc, other_loc) = (
+            "client_test_backup",
+            "client_test_backup_restored",
+            "client_test_backup_other",
+        );
+        for loc in [loc, restored_loc, other_loc] {
+            let _ = std::fs::remove_dir_all(ClientDatabase::base_dir(loc));
+        }
+        let backup_pass = b"backup passphrase".as_slice();
+        let local_pass = b"new passphrase".as_slice();
+        let client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        // only the backed up identity gets in
+        let server = Server::new(gen_key().unwrap(), vec![client.private_key.to_public_key()], None);
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let server_id = client.add_server(String::from("server"), local_endpoint(port)).unwrap();
+        let shared_key = ski::gen_key();
+        let chat = Chat::new(vec![], String::from("chat"), shared_key.clone(), HashMap::new());
+        let chat_id = client.db.save_chat(chat).unwrap();
+        let message = Message::new(server_id.clone(), None, chat_id.clone(), String::from("hi"));
+        let message_id = client.db.add_message(message).unwrap();
+        // as a participant would have sealed it before the move
+        let sealed = ski::seal_gcm(b"sealed before", &shared_key).unwrap();
+        let path = std::env::temp_dir().join("client_test_backup.backup");
+        client.export_backup(&path, backup_pass).unwrap();
+
+        let err = Client::import_backup_to(restored_loc, &path, b"wrong", local_pass).err().unwrap();
+        assert!(matches!(err, Error::InvalidPassphrase));
+        let import = || Client::import_backup_to(restored_loc, &path, backup_pass, local_pass);
+        let mut restored = import().unwrap();
+        assert_eq!(restored.private_key, client.private_key);
+        assert_ne!(restored.master_key.as_bytes(), client.master_key.as_bytes());
+        let chat = restored.db.get_chat(&chat_id).unwrap();
+        assert_eq!(chat.message_ids().to_vec(), vec![message_id.clone()]);
+        assert_eq!(restored.db.get_message(&message_id).unwrap().message(), "hi");
+        assert_eq!(ski::open_gcm(&sealed, chat.shared_key()).unwrap(), b"sealed before");
+        task::block_on(async {
+            restored.server_connect(server_id.as_str()).await.unwrap();
+            restored.server_ping(server_id.as_str()).await.unwrap();
+        });
+        drop(restored);
+
+        // the restored profile is under the local passphrase, and importing again merges
+        assert!(Client::with_location(restored_loc, backup_pass.to_vec(), None).is_err());
+        let restored = import().unwrap();
+        assert_eq!(restored.db.get_chat(&chat_id).unwrap().message_ids().len(), 1);
+        assert_eq!(restored.list_servers().unwrap().len(), 1);
+        drop(restored);
+
+        // a profile with an identity of its own isn't merged into
+        drop(Client::with_location(other_loc, local_pass.to_vec(), None).unwrap());
+        assert!(Client::import_backup_to(other_loc, &path, backup_pass, local_pass).is_err());
+
+        drop(client);
+        std::fs::remove_file(path).unwrap();
+        for loc in [loc, restored_loc, other_loc] {
+            std::fs::remove_dir_all(ClientDatabase::base_dir(loc)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rotate_master_key() {
+        use self::intent::Outcome;
+        use crate::client::models::Message;
+
+        let pass = b"example key1";
+        // stopped before each step is checkpointed, so the step itself may be done
+        for step in [None, Some(KEY_FILE_STEP), Some(db::ENTRY_DBS[1]), Some(db::ENTRY_DBS[3])] {
+            let loc = "client_test_rotate_master_key";
+            let _ = std::fs::remove_dir_all(ClientDatabase::base_dir(loc));
+            let mut client = Client::with_location(loc, pass.to_vec(), None).unwrap();
+            client.ed25519_key = Some(gen_key_ed25519());
+            let file_key = client.master_key.key_file_key().unwrap();
+            write_ed25519_key_to_file(client.ed25519_key.as_ref().unwrap(), loc, &file_key).unwrap();
+            let key = client.private_key.clone();
+            let server_id = client.add_server(String::from("server"), local_endpoint(1)).unwrap();
+            let message = Message::new(server_id, None, ChatId::from("chat"), String::from("hi"));
+            let message_id = client.db.add_message(message).unwrap();
+            let old_master = client.master_key.as_bytes().to_vec();
+            assert!(client.rotate_master_key(b"wrong passphrase").is_err());
+            client.intent_log.fail_at = step.map(str::to_string);
+            assert_eq!(client.rotate_master_key(pass).is_ok(), step.is_none());
+            drop(client);
+
+            let client = Client::with_location(loc, pass.to_vec(), None).unwrap();
+            let report = client.startup_report().to_vec();
+            match step {
+                None => assert!(report.is_empty()),
+                Some(_) => {
+                    assert_eq!(report.len(), 1);
+                    assert_eq!(report[0].outcome, Outcome::RolledForward);
+                }
+            }
+            assert_ne!(client.master_key.as_bytes(), old_master.as_slice());
+            assert_eq!(client.private_key, key);
+            assert!(client.ed25519_key.is_some());
+            assert_eq!(client.db.get_message(&message_id).unwrap().message(), "hi");
+            assert_eq!(client.list_servers().unwrap().len(), 1);
+            drop(client);
+            // nothing is left under the old master key
+            for name in [db::ENTRY_DBS[1], db::ENTRY_DBS[2]] {
+                let db = ClientDatabase::open_entry_db(loc, name, &old_master).unwrap();
+                assert!(!db.unlocks().unwrap());
+            }
+            assert!(read_key_from_file(loc, &file_key).is_err());
+            std::fs::remove_dir_all(ClientDatabase::base_dir(loc)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_recover_identity_rotation() {
+        use self::intent::Outcome;
+
+        let pass = b"example key1";
+        let server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let interrupt = |loc: &str, step: &dyn Fn(&ServerId) -> String| {
+            let mut client = Client::with_location(loc, pass.to_vec(), None).unwrap();
+            let old_key = client.private_key.clone();
+            let live = client.add_server(String::from("live"), local_endpoint(port)).unwrap();
+            let dead = client.add_server(String::from("dead"), local_endpoint(8915)).unwrap();
+            client.intent_log.fail_at = Some(step(&live));
+            task::block_on(async {
+                assert!(client.rotate_identity_key(pass).await.is_err());
+            });
+            drop(client);
+            let client = Client::with_location(loc, pass.to_vec(), None).unwrap();
+            (client, old_key, live, dead)
+        };
+
+        // the live server took the new key, so it's kept and the dead one is reported
+        let loc = "client_test_recover_rotation";
+        let (mut client, old_key, live, dead) = interrupt(loc, &|_| KEY_FILE_STEP.to_string());
+        let report = client.startup_report().to_vec();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].outcome, Outcome::RolledForward);
+        assert!(report[0].notes.iter().any(|note| note.contains(dead.as_str())));
+        assert!(!report[0].notes.iter().any(|note| note.contains(live.as_str())));
+        assert_ne!(client.private_key, old_key);
+        assert!(client.db.get_server(&dead).unwrap().key_rotation.is_some());
+        task::block_on(async {
+            client.server_connect(live.as_str()).await.unwrap();
+            client.server_ping(live.as_str()).await.unwrap();
+            client.shutdown().await;
+        });
+        drop(client);
+        std::fs::remove_dir_all(ClientDatabase::base_dir(loc)).unwrap();
+
+        // no server confirmed it, so the old key stays
+        let loc = "client_test_recover_rotation_back";
+        let (client, old_key, live, dead) = interrupt(loc, &intent::server_step);
+        let report = client.startup_report();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].outcome, Outcome::RolledBack);
+        assert!(report[0].notes[0].contains(live.as_str()));
+        assert_eq!(client.private_key, old_key);
+        assert!(client.db.get_server(&dead).unwrap().key_rotation.is_none());
+        drop(client);
+        std::fs::remove_dir_all(ClientDatabase::base_dir(loc)).unwrap();
+    }
+
+    #[test]
+    fn test_export_chat() {
+        use crate::client::export::{read_json_lines, JsonLinesFormatter, MboxFormatter};
+        use crate::client::models::{Chat, Message, User};
+
+        let loc = "client_test_export";
+        let client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let server_id = ServerId::from("server");
+        let chat_id = client
+            .db
+            .save_chat(Chat::new(vec![], String::from("export"), vec![], HashMap::new()))
+            .unwrap();
+        let alice = client
+            .db
+            .save_user(User::new(String::from("alice"), String::new()))
+            .unwrap();
+        for (sender, text) in [(Some(alice), "first"), (None, "From the second")] {
+            let message = Message::new(server_id.clone(), sender, chat_id.clone(), text.to_string());
+            client.db.add_message(message).unwrap();
+        }
+        let dir = std::env::temp_dir();
+
+        let path = client
+            .export_chat(chat_id.as_str(), &JsonLinesFormatter, &dir)
+            .unwrap();
+        assert_eq!(path.extension().unwrap(), "jsonl");
+        let exported = read_json_lines(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(exported, client.db.transcript(&chat_id).unwrap().messages);
+        assert_eq!(exported[0].sender_name.as_deref(), Some("alice"));
+        assert_eq!(exported[1].text, "From the second");
+
+        let path = client.export_chat(chat_id.as_str(), &MboxFormatter, &dir).unwrap();
+        let mbox = std::fs::read_to_string(&path).unwrap();
+        assert!(mbox.contains("From: \"alice\""));
+        assert!(mbox.contains("\n>From the second\n"));
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_import_external() {
+        use crate::client::import::tests::{MATRIX_EXPORT, SIGNAL_EXPORT};
+
+        let loc = "client_test_import";
+        let client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let path = std::env::temp_dir().join("client_test_import_signal.json");
+        std::fs::write(&path, SIGNAL_EXPORT).unwrap();
+        let report = client.import_external(&path, ImportFormat::Signal).unwrap();
+        assert_eq!(report.contacts, 2);
+        assert_eq!(report.chats.len(), 2);
+        assert_eq!(report.messages, 4);
+        assert_eq!(report.errors.len(), 3);
+
+        let chat = client.db.get_chat(&report.chats[0]).unwrap();
+        assert_eq!(chat.name(), "Alice");
+        assert_eq!(chat.imported_from(), Some(ImportFormat::Signal));
+        let alice = client.db.get_user(&chat.user_ids()[0]).unwrap();
+        assert_eq!(alice.username(), "Alice");
+        assert!(!alice.is_verified());
+        let transcript = client.db.transcript(&report.chats[0]).unwrap();
+        let texts: Vec<&str> = transcript.messages.iter().map(|m| m.text.as_str()).collect();
+        assert_eq!(texts, vec!["hi", "hey"]);
+        let first = client.db.get_message(&transcript.messages[0].message_id).unwrap();
+        assert!(first.is_imported());
+        assert_eq!(first.extras()["hasVisualMediaAttachments"], false);
+        // imported messages point at no server, which isn't a dangling reference
+        assert!(client.db.check_references().unwrap().is_empty());
+
+        let path = std::env::temp_dir().join("client_test_import_matrix.json");
+        std::fs::write(&path, MATRIX_EXPORT).unwrap();
+        let report = client.import_external(&path, ImportFormat::Matrix).unwrap();
+        assert_eq!((report.contacts, report.messages, report.errors.len()), (2, 2, 1));
+        let chat = client.db.get_chat(&report.chats[0]).unwrap();
+        assert_eq!(chat.name(), "Book club");
+        assert_eq!(chat.user_ids().len(), 2);
+        assert!(client.import_external(&path, ImportFormat::Signal).is_err());
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        // answers the challenge, then never responds again
+        #[derive(Clone)]
+        struct StallingHandler {
+            inner: ServerHandler,
+        }
+        impl Handler for StallingHandler {
+            async fn handle(&mut self, request: Request) -> Response {
+                if request.method != rpc_models::START_SERVER_HANDSHAKE {
+                    future::pending::<()>().await;
+                }
+                self.inner.handle(request).await
+            }
+        }
+
+        let loc = "client_test_handshake_timeout";
+        let config = ClientConfig {
+            handshake_timeout: Duration::from_millis(500),
+            ..ClientConfig::default()
+        };
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), Some(config)).unwrap();
+        let server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let handler = StallingHandler {
+            inner: ServerHandler::new(Arc::new(RwLock::new(server))),
+        };
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let server_id = client
+            .add_server(String::from("stalling"), local_endpoint(port))
+            .unwrap();
+        task::block_on(async {
+            let started = Instant::now();
+            let err = client.server_connect(server_id.as_str()).await.unwrap_err();
+            assert!(matches!(err, Error::Timeout));
+            assert!(started.elapsed() < Duration::from_secs(5));
+        });
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_retry_timed_out_requests() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // sits on the next `stalls` encrypted requests for a while before answering
+        #[derive(Clone)]
+        struct SlowHandler {
+            inner: ServerHandler,
+            stalls: Arc<AtomicUsize>,
+            requests: Arc<AtomicUsize>,
+        }
+        impl Handler for SlowHandler {
+            async fn handle(&mut self, request: Request) -> Response {
+                if request.method == rpc_models::ENCRYPTED_REQUEST {
+                    self.requests.fetch_add(1, Ordering::SeqCst);
+                    let stall = self
+                        .stalls
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok();
+                    if stall {
+                        task::sleep(Duration::from_secs(2)).await;
+                    }
+                }
+                self.inner.handle(request).await
+            }
+        }
+
+        let loc = "client_test_retry_timed_out";
+        let config = ClientConfig {
+            request_timeout: Duration::from_millis(300),
+            // reconnecting subscribes, which stalls too
+            max_reconnect_attempts: 0,
+            ..ClientConfig::default()
+        };
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), Some(config)).unwrap();
+        let server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let stalls = Arc::new(AtomicUsize::new(0));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let handler = SlowHandler {
+            inner: ServerHandler::new(Arc::new(RwLock::new(server))),
+            stalls: stalls.clone(),
+            requests: requests.clone(),
+        };
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let server_id = client
+            .add_server(String::from("slow"), local_endpoint(port))
+            .unwrap();
+        task::block_on(async {
+            client.server_connect(server_id.as_str()).await.unwrap();
+
+            // a ping is safe to repeat, and goes through on a fresh connection
+            stalls.store(1, Ordering::SeqCst);
+            requests.store(0, Ordering::SeqCst);
+            client.server_ping(server_id.as_str()).await.unwrap();
+            assert!(requests.load(Ordering::SeqCst) >= 2);
+
+            // gives up once the server stays silent
+            stalls.store(10, Ordering::SeqCst);
+            let err = client.server_ping(server_id.as_str()).await.unwrap_err();
+            assert!(matches!(err, Error::Timeout));
+
+            // registering isn't safe to repeat, so it's sent once and the timeout reported
+            stalls.store(0, Ordering::SeqCst);
+            client.server_connect(server_id.as_str()).await.unwrap();
+            stalls.store(1, Ordering::SeqCst);
+            requests.store(0, Ordering::SeqCst);
+            let params = serde_json::json!({ "username": "slow" });
+            let request = Request::new(rpc_models::REGISTER_USER.to_string(), params);
+            let err = client
+                .send_sym_encrypted_request(server_id.as_str(), request)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::Timeout));
+            assert_eq!(requests.load(Ordering::SeqCst), 1);
+        });
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_send_batch() {
+        use crate::shared::rpc::MethodInfo;
+
+        // answers with its params, after a pause
+        let mut methods = ServerHandler::builtin_methods();
+        methods.register("SLOW_ECHO", MethodInfo::SESSION, |_, request: Request| async move {
+            task::sleep(Duration::from_millis(300)).await;
+            Ok::<_, Error>(Response::new(request.params, None, request.id))
+        });
+        let server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let handler = ServerHandler::with_methods(Arc::new(RwLock::new(server)), methods);
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let loc = "client_test_send_batch";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let server_id = client
+            .add_server(String::from("test_server"), local_endpoint(port))
+            .unwrap();
+        task::block_on(async {
+            client.server_connect(server_id.as_str()).await.unwrap();
+            let requests: Vec<Request> = (0..3)
+                .map(|n| Request::new(String::from("SLOW_ECHO"), serde_json::json!(n)))
+                .collect();
+            let started = Instant::now();
+            let responses = client.send_batch(server_id.as_str(), requests.clone()).await.unwrap();
+            // served at once rather than one after another
+            assert!(started.elapsed() < Duration::from_millis(800));
+            assert_eq!(responses.len(), 3);
+            for (n, (request, response)) in requests.iter().zip(responses).enumerate() {
+                assert_eq!(response.id(), request.id);
+                assert_eq!(response.into_result().unwrap(), serde_json::json!(n));
+            }
+
+            // requests that change the session are refused, the rest still served
+            let ping = Request::new(rpc_models::PING.to_string(), serde_json::json!(null));
+            let params = rpc_models::RekeyParams {
+                ephemeral_key: X25519PublicKey::from(&EphemeralSecret::random_from_rng(OsRng)),
+            };
+            let rekey = Request::new(rpc_models::REKEY.to_string(), serde_json::json!(params));
+            let responses = client
+                .send_batch(server_id.as_str(), vec![ping, rekey])
+                .await
+                .unwrap();
+            assert_eq!(responses[0].result, serde_json::json!("pong"));
+            let error = responses[1].error.as_ref().unwrap();
+            assert_eq!(error.code, RpcErrorCode::InvalidRequest);
+            client.server_ping(server_id.as_str()).await.unwrap();
+        });
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_streaming_request() {
+        use crate::server::DEFAULT_STREAM_THRESHOLD_BYTES;
+        use crate::shared::rpc::MethodInfo;
+
+        // answers with about 4 MB of JSON, dozens of chunks
+        let blob: Vec<String> = (0..65536).map(|n| format!("{:058}", n)).collect();
+        let expected = serde_json::json!(blob);
+        let served = expected.clone();
+        let mut methods = ServerHandler::builtin_methods();
+        methods.register("BLOB", MethodInfo::SESSION, move |_, request: Request| {
+            let blob = served.clone();
+            async move { Ok::<_, Error>(Response::new(blob, None, request.id)) }
+        });
+        let server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let handler = ServerHandler::with_methods(Arc::new(RwLock::new(server)), methods);
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let loc = "client_test_streaming_request";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let server_id = client
+            .add_server(String::from("test_server"), local_endpoint(port))
+            .unwrap();
+        task::block_on(async {
+            client.server_connect(server_id.as_str()).await.unwrap();
+            let total_bytes = serde_json::to_vec(&expected).unwrap().len();
+            let chunk_bytes = DEFAULT_STREAM_THRESHOLD_BYTES;
+            assert!(total_bytes > 32 * chunk_bytes);
+
+            // the first response only announces the result
+            let mut request = Request::new(String::from("BLOB"), serde_json::json!(null));
+            request.stream = true;
+            let response = client
+                .send_sym_encrypted_request(server_id.as_str(), request)
+                .await
+                .unwrap();
+            assert_eq!(response.result, serde_json::json!(null));
+            let info = response.stream.unwrap();
+            assert_eq!(info.total_bytes, total_bytes);
+            assert_eq!(info.chunks, (total_bytes + chunk_bytes - 1) / chunk_bytes);
+
+            let request = Request::new(String::from("BLOB"), serde_json::json!(null));
+            let response = client
+                .send_sym_encrypted_streaming_request(server_id.as_str(), request)
+                .await
+                .unwrap();
+            assert!(response.stream.is_none());
+            assert_eq!(response.into_result().unwrap(), expected);
+
+            // small results come back whole
+            let ping = Request::new(rpc_models::PING.to_string(), serde_json::json!(null));
+            let response = client
+                .send_sym_encrypted_streaming_request(server_id.as_str(), ping)
+                .await
+                .unwrap();
+            assert_eq!(response.result, serde_json::json!("pong"));
+
+            // the announced stream is held until its last chunk is fetched
+            let fetch_last = || {
+                let params = rpc_models::FetchChunkParams {
+                    stream_id: info.stream_id.clone(),
+                    chunk_index: info.chunks - 1,
+                };
+                Request::new(rpc_models::FETCH_CHUNK.to_string(), serde_json::json!(params))
+            };
+            let response = client
+                .send_sym_encrypted_request(server_id.as_str(), fetch_last())
+                .await
+                .unwrap();
+            assert!(response.into_result().is_ok());
+            let response = client
+                .send_sym_encrypted_request(server_id.as_str(), fetch_last())
+                .await
+                .unwrap();
+            assert_eq!(response.into_result().unwrap_err().code, RpcErrorCode::InvalidParams);
+
+            // a result over the configured size is refused before fetching it
+            client.config.max_response_bytes = total_bytes - 1;
+            let request = Request::new(String::from("BLOB"), serde_json::json!(null));
+            let error = client
+                .send_sym_encrypted_streaming_request(server_id.as_str(), request)
+                .await
+                .unwrap_err();
+            assert!(matches!(error, Error::MessageTooLarge { actual, .. } if actual == total_bytes));
+        });
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_rehandshake_after_session_loss() {
+        #[derive(Clone)]
+        struct CountingHandler {
+            inner: ServerHandler,
+            handshakes: Arc<std::sync::atomic::AtomicUsize>,
+        }
+        impl Handler for CountingHandler {
+            async fn handle(&mut self, request: Request) -> Response {
+                if request.method == rpc_models::START_SERVER_HANDSHAKE {
+                    self.handshakes
+                        .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                }
+                self.inner.handle(request).await
+            }
+        }
+
+        let loc = "client_test_rehandshake";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let handshakes = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let handler = CountingHandler {
+            inner: ServerHandler::new(Arc::new(RwLock::new(server))),
+            handshakes: handshakes.clone(),
+        };
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let server_id = client
+            .db
+            .save_server(ServerModel::new(
+                "test_server".to_string(),
+                vec![],
+                vec![],
+                IpAddr::V4([127, 0, 0, 1].into()),
+                port,
+            ))
+            .unwrap();
+        task::block_on(async {
+            client.server_connect(server_id.as_str()).await.unwrap();
+            client.server_ping(server_id.as_str()).await.unwrap();
+            assert_eq!(handshakes.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+            // a fresh connection is served by a handler that never saw our session,
+            // just like a restarted server
+            let stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+            client.connections.get_mut(&server_id).unwrap().connection =
+                Connection::new(stream, None, None, rpc::MAX_FRAME_SIZE, Codec::Json);
+            client.server_ping(server_id.as_str()).await.unwrap();
+            assert_eq!(handshakes.load(std::sync::atomic::Ordering::SeqCst), 2);
+            client.server_ping(server_id.as_str()).await.unwrap();
+            assert_eq!(handshakes.load(std::sync::atomic::Ordering::SeqCst), 2);
+        });
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_reconnect_after_server_restart() {
+        let loc = "client_test_reconnect";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let server_key = gen_key().unwrap();
+        let serve = move |port: u16| {
+            let server = Server::new(server_key.clone(), Vec::new(), Some(open_registration()));
+            let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+            start_server_with_handle(handler, String::from("127.0.0.1"), port)
+        };
+        let handle = task::block_on(serve(0)).unwrap();
+        let port = handle.local_addr().port();
+        let server_id = client
+            .add_server(String::from("restarting"), local_endpoint(port))
+            .unwrap();
+        assert_eq!(client.connection_status(server_id.as_str()), ConnectionStatus::Disconnected);
+        task::block_on(async {
+            client.server_connect(server_id.as_str()).await.unwrap();
+            client.server_ping(server_id.as_str()).await.unwrap();
+            assert_eq!(client.connection_status(server_id.as_str()), ConnectionStatus::Connected);
+
+            handle.shutdown(None).await.unwrap();
+            // back up after the first retry or two
+            let restarted = task::spawn(async move {
+                task::sleep(Duration::from_millis(700)).await;
+                serve(port).await.unwrap()
+            });
+            let started = Instant::now();
+            client.server_ping(server_id.as_str()).await.unwrap();
+            assert!(started.elapsed() >= Duration::from_millis(500));
+            assert_eq!(client.connection_status(server_id.as_str()), ConnectionStatus::Connected);
+            restarted.await.shutdown(None).await.unwrap();
+        });
+
+        // gives up once the attempts run out; pinging even a fresh connection finds
+        // the server gone before the reader may have
+        client.config.max_reconnect_attempts = 1;
+        client.config.heartbeat_interval = Duration::ZERO;
+        task::block_on(async {
+            let started = Instant::now();
+            assert!(client.ensure_connected(server_id.as_str()).await.is_err());
+            assert!(started.elapsed() >= Duration::from_millis(500));
+        });
+        // and drops the dead session
+        assert_eq!(client.connection_status(server_id.as_str()), ConnectionStatus::Disconnected);
+        drop(client);
+        let _ = std::fs::remove_dir_all(ClientDatabase::base_dir(loc));
+    }
+
+    #[test]
+    fn test_ed25519_handshake() {
+        #[derive(Clone)]
+        struct RecordingHandler {
+            inner: ServerHandler,
+            key_types: Arc<std::sync::Mutex<Vec<rpc_models::KeyType>>>,
+        }
+        impl Handler for RecordingHandler {
+            async fn handle(&mut self, request: Request) -> Response {
+                let is_challenge = request.method == rpc_models::CLIENT_CHALLENGE_RESPONSE;
+                if is_challenge {
+                    let params: RespondClientChallenge =
+                        serde_json::from_value(request.params.clone()).unwrap();
+                    self.key_types.lock().unwrap().push(params.key_type);
+                }
+                let response = self.inner.handle(request).await;
+                if is_challenge {
+                    let result: RespondServerChallenge =
+                        serde_json::from_value(response.result.clone()).unwrap();
+                    self.key_types.lock().unwrap().push(result.key_type);
+                }
+                response
+            }
+        }
+
+        let loc = "client_test_ed25519";
+        let pass_key = b"example key1";
+        // a key file on its own is a profile from before the master key, and is migrated
+        let _ = std::fs::remove_dir_all(ClientDatabase::base_dir(loc));
+        write_ed25519_key_to_file(&gen_key_ed25519(), loc, pass_key).unwrap();
+        let mut client = Client::with_location(loc, pass_key.to_vec(), None).unwrap();
+        assert!(client.ed25519_key.is_some());
+        let mut server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        server.ed25519_key = Some(gen_key_ed25519());
+        let key_types = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler = RecordingHandler {
+            inner: ServerHandler::new(Arc::new(RwLock::new(server))),
+            key_types: key_types.clone(),
+        };
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let server_id = client
+            .db
+            .save_server(ServerModel::new(
+                "test_server".to_string(),
+                vec![],
+                vec![],
+                IpAddr::V4([127, 0, 0, 1].into()),
+                port,
+            ))
+            .unwrap();
+        task::block_on(async {
+            client.server_connect(server_id.as_str()).await.unwrap();
+            client.server_ping(server_id.as_str()).await.unwrap();
+        });
+        assert_eq!(
+            *key_types.lock().unwrap(),
+            vec![rpc_models::KeyType::Ed25519, rpc_models::KeyType::Ed25519]
+        );
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_add_list_and_ping_servers() {
+        let loc = "client_test_servers";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        client.db.server_db.clear().unwrap();
+        let server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let server_id = client
+            .add_server("test_server".to_string(), local_endpoint(port))
+            .unwrap();
+        let servers = client.list_servers().unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].id, server_id);
+        assert_eq!(servers[0].name, "test_server");
+        assert!(!servers[0].connected);
+
+        task::block_on(async {
+            // connects on first use, then reuses the session
+            client.ping_server(server_id.as_str()).await.unwrap();
+            client.ping_server(server_id.as_str()).await.unwrap();
+        });
+        let servers = client.list_servers().unwrap();
+        assert!(servers[0].connected);
+        // the summary handed to the frontend never carries the session key
+        let json = serde_json::to_value(&servers[0]).unwrap();
+        assert!(json.get("encryption").is_none());
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_server_endpoints() {
+        let loc = "client_test_server_endpoints";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        // a port nothing listens on anymore
+        let dead_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let dead = ServerEndpoint::new("127.0.0.1", dead_port);
+        let live = ServerEndpoint::new("localhost", port);
+        let server_id = client
+            .add_server(String::from("test_server"), vec![dead.clone(), live.clone()])
+            .unwrap();
+        task::block_on(async {
+            client.server_connect(server_id.as_str()).await.unwrap();
+            client.server_ping(server_id.as_str()).await.unwrap();
+        });
+        let server = client.db.get_server(&server_id).unwrap();
+        assert_eq!(server.last_endpoint, Some(live));
+
+        // every endpoint that failed shows up in the error
+        let unresolvable = ServerEndpoint::new("no-such-host.invalid", port);
+        let server_id = client
+            .add_server(String::from("unreachable"), vec![unresolvable, dead])
+            .unwrap();
+        let error = task::block_on(client.server_connect(server_id.as_str())).unwrap_err();
+        assert!(matches!(error, Error::Io(_)));
+        let message = error.to_string();
+        assert!(message.contains("no-such-host.invalid"), "{}", message);
+        assert!(message.contains(&format!("127.0.0.1:{}", dead_port)), "{}", message);
+        assert!(client.add_server(String::from("nowhere"), vec![]).is_err());
+        task::block_on(handle.shutdown(None)).unwrap();
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_legacy_server_entry() {
+        let loc = "client_test_legacy_server_entry";
+        let client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        // a server as saved before it could have several endpoints
+        let legacy = serde_json::json!({
+            "server_name": "legacy",
+            "encryption": null,
+            "user_ids": [],
+            "chat_ids": [],
+            "ip": "192.0.2.1",
+            "port": 8080,
+        });
+        let server_id = client.db.server_db.save_entry(legacy).unwrap();
+        let server = client.db.server_db.get_entry::<ServerModel>(&server_id).unwrap();
+        assert_eq!(server.endpoints, vec![ServerEndpoint::new("192.0.2.1", 8080)]);
+        assert_eq!(server.last_endpoint, None);
+
+        // saved again it's in the current layout
+        client.db.server_db.update_entry(&server_id, server).unwrap();
+        let saved = client.db.server_db.get_entry::<serde_json::Value>(&server_id).unwrap();
+        assert!(saved.get("ip").is_none());
+        assert_eq!(saved["endpoints"][0]["host"], "192.0.2.1");
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[derive(Clone)]
+    struct SecurityEmitter {
+        events: Arc<std::sync::Mutex<Vec<(String, serde_json::Value)>>>,
+    }
+    impl EventEmitter for SecurityEmitter {
+        fn emit(&self, event: &str, payload: serde_json::Value) {
+            self.events.lock().unwrap().push((event.to_string(), payload));
+        }
+    }
+
+    fn system_notices(client: &Client, server_id: &ServerId) -> Vec<String> {
+        let server = client.db.get_server(server_id).unwrap();
+        let chat_id = match server.system_chat_id {
+            Some(id) => id,
+            None => return vec![],
+        };
+        let chat = client.db.get_chat(&chat_id).unwrap();
+        chat.message_ids()
+            .iter()
+            .map(|id| client.db.get_message(id).unwrap().message().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_first_use_pin_warning() {
+        let loc = "client_test_tofu";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let emitter = SecurityEmitter {
+            events: Arc::new(std::sync::Mutex::new(Vec::new())),
+        };
+        client.set_event_emitter(emitter.clone());
+        client.set_security_minimum(SecurityMinimum {
+            pin_status: PinStatus::Verified,
+            ..SecurityMinimum::default()
+        });
+        let server_key = gen_key().unwrap();
+        let server_pub_key = server_key.to_public_key();
+        let server = Server::new(server_key, Vec::new(), Some(open_registration()));
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let server_id = client.add_server("test_server".to_string(), local_endpoint(port)).unwrap();
+        assert!(client.server_fingerprint(server_id.as_str()).is_err());
+        task::block_on(async {
+            client.server_connect(server_id.as_str()).await.unwrap();
+        });
+        // what the user checks before trusting the key
+        assert_eq!(
+            client.server_fingerprint(server_id.as_str()).unwrap(),
+            KeyFingerprint::of(&server_pub_key).unwrap()
+        );
+        let assessment = client.session_security().unwrap();
+        assert_eq!(assessment.pin_status, PinStatus::FirstUse);
+        assert_eq!(assessment.warnings, vec![security::SecurityWarning::Pin]);
+        {
+            let events = emitter.events.lock().unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].0, SECURITY_WARNING_EVENT);
+            assert_eq!(events[0].1["server_id"], server_id.as_str());
+            assert_eq!(events[0].1["assessment"]["pin_status"], "FirstUse");
+        }
+        let notices = system_notices(&client, &server_id);
+        assert_eq!(notices.len(), 1);
+        assert!(notices[0].contains("not verified"));
+        let summary = client.list_servers().unwrap();
+        let summary = summary.iter().find(|s| s.id == server_id).unwrap();
+        assert_eq!(summary.security.as_ref().unwrap().pin_status, PinStatus::FirstUse);
+
+        // the key learned on first use is verified from then on
+        task::block_on(client.server_connect(server_id.as_str())).unwrap();
+        let assessment = client.session_security().unwrap();
+        assert_eq!(assessment.pin_status, PinStatus::Verified);
+        assert!(!assessment.is_downgraded());
+        assert_eq!(emitter.events.lock().unwrap().len(), 1);
+        assert_eq!(system_notices(&client, &server_id).len(), 1);
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_discover_servers() {
+        use crate::client::models::DiscoveredServer;
+
+        let loc = "client_test_discovery";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        client.db.server_db.clear().unwrap();
+        let server_key = gen_key().unwrap();
+        let fingerprint = pki::fingerprint(&server_key.to_public_key()).unwrap();
+        let server = Server::new(server_key, Vec::new(), Some(open_registration()));
+        let server = Arc::new(RwLock::new(server));
+        let handler = ServerHandler::new(server.clone());
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let advertised = task::block_on(server.read())
+            .advertise_mdns(handle.local_addr())
+            .unwrap();
+
+        let found = task::block_on(Client::discover_servers(Duration::from_secs(2))).unwrap();
+        let discovered = found
+            .into_iter()
+            .find(|server| server.fingerprint == fingerprint)
+            .expect("the server wasn't discovered");
+        assert_eq!(discovered.port, handle.local_addr().port());
+        let server_id = task::block_on(client.connect_discovered(&discovered)).unwrap();
+        assert!(client.connection_state(server_id.as_str()).is_ok());
+        // found again later, it's the same server
+        client.disconnect_server(server_id.as_str()).unwrap();
+        assert_eq!(task::block_on(client.connect_discovered(&discovered)).unwrap(), server_id);
+        assert_eq!(client.list_servers().unwrap().len(), 1);
+
+        // a server that doesn't hold the advertised key isn't saved
+        client.disconnect_server(server_id.as_str()).unwrap();
+        let impostor = DiscoveredServer {
+            name: String::from("impostor"),
+            fingerprint: pki::fingerprint(&gen_key().unwrap().to_public_key()).unwrap(),
+            ..discovered
+        };
+        assert!(task::block_on(client.connect_discovered(&impostor)).is_err());
+        assert_eq!(client.list_servers().unwrap().len(), 1);
+
+        // nor is one advertising the saved server's fingerprint somewhere else
+        let spoofer = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+        let spoofer = ServerHandler::new(Arc::new(RwLock::new(spoofer)));
+        let spoofer = task::block_on(start_server_with_handle(spoofer, String::from("127.0.0.1"), 0)).unwrap();
+        let spoofed = DiscoveredServer {
+            port: spoofer.local_addr().port(),
+            ..discovered.clone()
+        };
+        let err = task::block_on(client.connect_discovered(&spoofed)).unwrap_err();
+        assert!(matches!(err, Error::ServerKeyChanged { .. }));
+        let saved = client.db.get_server(&server_id).unwrap();
+        assert_eq!(saved.endpoints, local_endpoint(handle.local_addr().port()));
+        assert!(saved.presented_key.is_none());
+        task::block_on(spoofer.shutdown(None)).unwrap();
+
+        drop(advertised);
+        task::block_on(handle.shutdown(None)).unwrap();
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_changed_server_key_refused() {
+        let loc = "client_test_key_change";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let start = |port: u16| {
+            let server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+            let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+            task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), port))
+                .unwrap()
+        };
+        let handle = start(0);
+        let port = handle.local_addr().port();
+        let server_id = client.add_server("test_server".to_string(), local_endpoint(port)).unwrap();
+        task::block_on(client.server_connect(server_id.as_str())).unwrap();
+        let pinned = client.server_fingerprint(server_id.as_str()).unwrap();
+        assert!(client.trust_new_server_key(server_id.as_str()).is_err());
+        client.disconnect_server(server_id.as_str()).unwrap();
+        task::block_on(handle.shutdown(None)).unwrap();
+
+        // same address, different key
+        let handle = start(port);
+        for _ in 0..2 {
+            match task::block_on(client.server_connect(server_id.as_str())) {
+                Err(Error::ServerKeyChanged { pinned: old, .. }) => {
+                    assert_eq!(old, pinned.fingerprint)
+                }
+                result => panic!("expected a key change, got {:?}", result.map(|_| ())),
+            }
+            assert!(client.connection_state(server_id.as_str()).is_err());
+            assert_eq!(client.server_fingerprint(server_id.as_str()).unwrap(), pinned);
+        }
+
+        let trusted = client.trust_new_server_key(server_id.as_str()).unwrap();
+        assert_ne!(trusted, pinned);
+        assert_eq!(client.server_fingerprint(server_id.as_str()).unwrap(), trusted);
+        task::block_on(client.server_connect(server_id.as_str())).unwrap();
+        assert_eq!(client.session_security().unwrap().pin_status, PinStatus::Changed);
+        task::block_on(client.server_connect(server_id.as_str())).unwrap();
+        assert_eq!(client.session_security().unwrap().pin_status, PinStatus::Verified);
+        task::block_on(client.shutdown());
+        task::block_on(handle.shutdown(None)).unwrap();
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[test]
+    fn test_legacy_cipher_warning() {
+        let loc = "client_test_legacy_cipher";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let emitter = SecurityEmitter {
+            events: Arc::new(std::sync::Mutex::new(Vec::new())),
+        };
+        client.set_event_emitter(emitter.clone());
+        let mut minimum = SecurityMinimum {
+            cipher_suite: CipherSuite::Ed25519AesGcm,
+            ..SecurityMinimum::default()
+        };
+        client.set_security_minimum(minimum.clone());
+        // a server without an Ed25519 key can only sign with RSA
+        let server_private_key = gen_key().unwrap();
+        let server_pub_key = server_private_key.to_public_key();
+        let server = Server::new(server_private_key, Vec::new(), Some(open_registration()));
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
+        let port = handle.local_addr().port();
+        let mut server_model = ServerModel::new(
+            "test_server".to_string(),
+            vec![],
+            vec![],
+            IpAddr::V4([127, 0, 0, 1].into()),
+            port,
+        );
+        server_model.provision_key(server_pub_key);
+        let server_id = client.db.save_server(server_model).unwrap();
+        task::block_on(async {
+            client.server_connect(server_id.as_str()).await.unwrap();
+        });
+        let assessment = client.session_security().unwrap();
+        assert_eq!(assessment.pin_status, PinStatus::Provisioned);
+        assert_eq!(assessment.cipher_suite, CipherSuite::Rsa2048AesGcm);
+        assert_eq!(assessment.protocol_version, rpc_models::PROTOCOL_VERSION);
+        assert_eq!(assessment.warnings, vec![security::SecurityWarning::CipherSuite]);
+        assert_eq!(emitter.events.lock().unwrap()[0].1["assessment"]["cipher_suite"], "Rsa2048AesGcm");
+        assert!(system_notices(&client, &server_id)[0].contains("legacy cipher suite"));
+
+        // strict mode refuses the same session, but still tells the user why
+        minimum.strict_mode = true;
+        client.set_security_minimum(minimum);
+        let err = task::block_on(client.server_connect(server_id.as_str())).unwrap_err();
+        assert!(err.to_string().contains("strict mode"));
+        assert_eq!(emitter.events.lock().unwrap().len(), 2);
+        assert_eq!(system_notices(&client, &server_id).len(), 2);
+        delete_key_file(loc).unwrap_or_default();
+    }
+
+    #[cfg(feature = "insecure-dev")]
+    #[test]
+    fn test_connect_insecure() {
+        use crate::server::start_insecure_dev_server;
+
+        let loc = "client_test_insecure";
+        let mut client = Client::with_location(loc, b"example key1".to_vec(), None).unwrap();
+        let new_handler = || {
+            let server = Server::new(gen_key().unwrap(), Vec::new(), Some(open_registration()));
+            ServerHandler::new(Arc::new(RwLock::new(server)))
+        };
+        let public_ip = IpAddr::V4([10, 0, 0, 1].into());
+        let err = match task::block_on(start_insecure_dev_server(new_handler(), public_ip, 0)) {
+            Ok(_) => panic!("served plaintext sessions on a public address"),
+            Err(e) => e,
+        };
+        assert!(err.to_string().contains("non-loopback"));
+        let localhost = IpAddr::V4([127, 0, 0, 1].into());
+        let handle = task::block_on(start_insecure_dev_server(new_handler(), localhost, 0)).unwrap();
+        let port = handle.local_addr().port();
+        task::block_on(async {
+            let err = client
+                .connect_insecure((public_ip, port).into())
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("non-loopback"));
+            let server_id = client.connect_insecure((localhost, port).into()).await.unwrap();
+            client.server_ping(server_id.as_str()).await.unwrap();
+            let err = client.list_users().await.unwrap_err();
+            assert!(err.to_string().contains("encrypted session"));
+        });
+        delete_key_file(loc).unwrap_or_default();
+    }
 }
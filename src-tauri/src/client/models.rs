@@ -1,31 +1,235 @@
-use std::{collections::HashMap, net::IpAddr, time::SystemTime};
+use std::{collections::HashMap, fmt, net::IpAddr, time::SystemTime};
 
-use crate::shared::models::EncryptionConfiguration;
+use rsa::{
+    pkcs8::{DecodePrivateKey, EncodePrivateKey},
+    RsaPrivateKey, RsaPublicKey,
+};
+
+use super::import::{Extras, ImportFormat};
+use super::security::SecurityAssessment;
+use crate::shared::models::{ChatCustomization, EncryptionConfiguration};
+use crate::shared::pki;
+use crate::shared::rpc_models::{AckStatus, Capabilities, KeyRotationParams};
+use crate::shared::transparency::SignedTreeHead;
+use crate::Error;
+
+/// Declares a newtype around the `EntryDb` key of one of the client trees so ids
+/// pointing into different trees can't be mixed up.
+macro_rules! entry_id {
+    ($name:ident) => {
+        #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+        #[serde(transparent)]
+        pub struct $name(String);
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+        impl From<String> for $name {
+            fn from(id: String) -> Self {
+                $name(id)
+            }
+        }
+        impl From<&str> for $name {
+            fn from(id: &str) -> Self {
+                $name(id.to_string())
+            }
+        }
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+    };
+}
+
+entry_id!(UserId);
+entry_id!(MessageId);
+entry_id!(ChatId);
+entry_id!(ServerId);
+
+impl ServerId {
+    /// Stands in for a server on messages that never went through one, e.g. imported
+    /// history.
+    pub fn local() -> Self {
+        ServerId::from("local")
+    }
+}
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct User {
     username: String,
     pub_key: String,
+    /// Of `pub_key`, see `pki::fingerprint`. Entries saved before it was kept have it
+    /// worked out when asked for.
+    #[serde(default)]
+    fingerprint: Option<String>,
+    /// Set for contacts read from another messenger's export. They have no key until
+    /// one is learned from a carapace server.
+    #[serde(default)]
+    imported_from: Option<ImportFormat>,
+    #[serde(default)]
+    extras: Extras,
 }
 impl User {
     pub fn new(username: String, pub_key: String) -> Self {
-        User { username, pub_key }
+        User {
+            fingerprint: fingerprint_of(&pub_key),
+            username,
+            pub_key,
+            imported_from: None,
+            extras: Extras::new(),
+        }
+    }
+    pub fn imported(username: String, format: ImportFormat, extras: Extras) -> Self {
+        User {
+            username,
+            pub_key: String::new(),
+            fingerprint: None,
+            imported_from: Some(format),
+            extras,
+        }
+    }
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+    pub fn pub_key(&self) -> &str {
+        &self.pub_key
+    }
+    /// What the user is known by across servers and databases, `None` until their key
+    /// is.
+    pub fn fingerprint(&self) -> Option<String> {
+        self.fingerprint
+            .clone()
+            .or_else(|| fingerprint_of(&self.pub_key))
+    }
+    pub fn imported_from(&self) -> Option<ImportFormat> {
+        self.imported_from
+    }
+    /// Whether the key is one a carapace server vouched for, which imported contacts'
+    /// never is.
+    pub fn is_verified(&self) -> bool {
+        self.imported_from.is_none() && !self.pub_key.is_empty()
+    }
+    pub fn extras(&self) -> &Extras {
+        &self.extras
     }
 }
 
+/// Where a message stands with the server and, once a recipient acknowledges it, with
+/// them. Received messages are always `Sent`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum MessageStatus {
+    /// Stored locally, waiting in the outbox for the server to relay it.
+    Pending,
+    #[default]
+    Sent,
+    /// The server refused it, or it ran out of attempts. It stays in the chat.
+    Failed,
+    /// A recipient acknowledged getting it.
+    Delivered,
+    /// A recipient acknowledged reading it.
+    Read,
+}
+
+/// A message `Client::send_message` stored, before the server has seen it.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct OutgoingMessage {
+    pub message_id: MessageId,
+    pub chat_id: ChatId,
+    pub status: MessageStatus,
+    /// Local time the message is ordered by until it's reconciled.
+    pub timestamp: SystemTime,
+}
+
+/// How a message from the outbox settled, as emitted with `MESSAGE_RECONCILED_EVENT`
+/// for the UI to update it in place.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct Reconciliation {
+    pub message_id: MessageId,
+    pub chat_id: ChatId,
+    pub status: MessageStatus,
+    /// The timestamp to order the message by from now on.
+    pub timestamp: SystemTime,
+    pub relayed_at: Option<SystemTime>,
+    /// Why the message failed, for `Failed` ones.
+    pub error: Option<String>,
+}
+
+/// What a chat payload forwarded by the server changed.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub enum ChatEvent {
+    /// We were invited to the chat and joined it.
+    Joined(ChatId),
+    /// The participant we invited accepted.
+    Accepted(ChatId),
+    Message(MessageId),
+    /// A message for a chat we haven't joined, kept until we do.
+    Stashed(ChatId),
+    /// A participant gave the chat a new look. Older than the one it has, it's dropped.
+    Customized(ChatId),
+}
+
+fn fingerprint_of(pub_key: &str) -> Option<String> {
+    pki::pub_key_from_str(pub_key)
+        .and_then(|key| pki::fingerprint(&key))
+        .ok()
+}
+
+/// A key in the forms people compare out of band, see `pki::display_fingerprint` and
+/// `pki::safety_number`.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct KeyFingerprint {
+    pub fingerprint: String,
+    pub safety_number: String,
+}
+impl KeyFingerprint {
+    pub fn of(key: &RsaPublicKey) -> Result<Self, Error> {
+        Ok(KeyFingerprint {
+            fingerprint: pki::display_fingerprint(key)?,
+            safety_number: pki::safety_number(key)?,
+        })
+    }
+}
+
+/// A user's key as a server reported it.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct UserKeyLookup {
+    pub user_id: UserId,
+    pub pub_key: String,
+    /// The key we had for the user before, if the server reported a different one. The
+    /// caller should warn before trusting the new key.
+    pub previous_key: Option<String>,
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct Message {
-    server_id: String,
-    sender_id: Option<String>,
-    chat_id: String,
+    server_id: ServerId,
+    sender_id: Option<UserId>,
+    chat_id: ChatId,
     message: String,
     timestamp: SystemTime,
+    #[serde(default)]
+    truncated: bool,
+    /// Read from another messenger's export, so it only exists locally.
+    #[serde(default)]
+    imported: bool,
+    #[serde(default)]
+    extras: Extras,
+    #[serde(default)]
+    status: MessageStatus,
+    /// When the server relayed the message, for messages we sent through the outbox.
+    #[serde(default)]
+    relayed_at: Option<SystemTime>,
+    /// What the relaying server calls the message, for acknowledgements to refer to it.
+    #[serde(default)]
+    server_message_id: Option<String>,
 }
 impl Message {
     pub fn new(
-        server_id: String,
-        sender_id: Option<String>,
-        chat_id: String,
+        server_id: ServerId,
+        sender_id: Option<UserId>,
+        chat_id: ChatId,
         message: String,
     ) -> Self {
         Message {
@@ -34,25 +238,201 @@ impl Message {
             chat_id,
             message,
             timestamp: SystemTime::now(),
+            truncated: false,
+            imported: false,
+            extras: Extras::new(),
+            status: MessageStatus::Sent,
+            relayed_at: None,
+            server_message_id: None,
+        }
+    }
+    /// A message of ours that waits in the outbox, timestamped for ordering until the
+    /// server relays it.
+    pub fn outgoing(
+        server_id: ServerId,
+        sender_id: Option<UserId>,
+        chat_id: ChatId,
+        message: String,
+    ) -> Self {
+        Message {
+            status: MessageStatus::Pending,
+            ..Message::new(server_id, sender_id, chat_id, message)
+        }
+    }
+    /// A message from another messenger's export, keeping its original timestamp.
+    pub fn imported(
+        sender_id: Option<UserId>,
+        chat_id: ChatId,
+        message: String,
+        timestamp: SystemTime,
+        extras: Extras,
+    ) -> Self {
+        Message {
+            server_id: ServerId::local(),
+            sender_id,
+            chat_id,
+            message,
+            timestamp,
+            truncated: false,
+            imported: true,
+            extras,
+            status: MessageStatus::Sent,
+            relayed_at: None,
+            server_message_id: None,
+        }
+    }
+    pub fn server_id(&self) -> &ServerId {
+        &self.server_id
+    }
+    pub fn sender_id(&self) -> Option<&UserId> {
+        self.sender_id.as_ref()
+    }
+    pub fn chat_id(&self) -> &ChatId {
+        &self.chat_id
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+    pub fn clear_sender(&mut self) {
+        self.sender_id = None;
+    }
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+    pub fn is_imported(&self) -> bool {
+        self.imported
+    }
+    pub fn extras(&self) -> &Extras {
+        &self.extras
+    }
+    pub fn status(&self) -> MessageStatus {
+        self.status
+    }
+    pub fn relayed_at(&self) -> Option<SystemTime> {
+        self.relayed_at
+    }
+    /// Records the server's relay time. The ordering timestamp only moves to it when
+    /// `reorder` is set, see `ClientDatabase::settle_outgoing`.
+    pub fn mark_sent(&mut self, relayed_at: SystemTime, reorder: bool) {
+        self.status = MessageStatus::Sent;
+        self.relayed_at = Some(relayed_at);
+        if reorder {
+            self.timestamp = relayed_at;
+        }
+    }
+    pub fn mark_failed(&mut self) {
+        self.status = MessageStatus::Failed;
+    }
+    pub fn server_message_id(&self) -> Option<&str> {
+        self.server_message_id.as_deref()
+    }
+    pub fn set_server_message_id(&mut self, id: Option<String>) {
+        self.server_message_id = id;
+    }
+    /// Moves the message on to what a recipient acknowledged, never back. Returns
+    /// whether its status changed.
+    pub fn mark_acked(&mut self, status: AckStatus) -> bool {
+        let acked = match status {
+            AckStatus::Delivered => MessageStatus::Delivered,
+            AckStatus::Read => MessageStatus::Read,
+        };
+        let moves_on = match self.status {
+            MessageStatus::Sent => true,
+            MessageStatus::Delivered => acked == MessageStatus::Read,
+            MessageStatus::Pending | MessageStatus::Failed | MessageStatus::Read => false,
+        };
+        if moves_on {
+            self.status = acked;
+        }
+        moves_on
+    }
+    /// Cuts the text down to at most `max_bytes`, keeping it valid UTF-8, and flags the
+    /// message as truncated if anything was removed.
+    pub fn truncate(&mut self, max_bytes: usize) {
+        if self.message.len() <= max_bytes {
+            return;
+        }
+        let mut end = max_bytes;
+        while !self.message.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.message.truncate(end);
+        self.truncated = true;
+    }
+}
+
+const PREVIEW_LENGTH: usize = 64;
+
+/// Materialized summary of a chat's newest message, kept so the chat list can render
+/// without decrypting any messages.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct ChatPreview {
+    pub message_id: MessageId,
+    pub sender_id: Option<UserId>,
+    pub text: String,
+    pub timestamp: SystemTime,
+}
+impl ChatPreview {
+    pub fn new(message_id: MessageId, message: &Message) -> Self {
+        let text: String = message
+            .message
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        let text = text.trim();
+        let mut preview: String = text.chars().take(PREVIEW_LENGTH).collect();
+        if text.chars().count() > PREVIEW_LENGTH {
+            preview.push('…');
+        }
+        ChatPreview {
+            message_id,
+            sender_id: message.sender_id.clone(),
+            text: preview,
+            timestamp: message.timestamp,
         }
     }
 }
 
+// chats saved before ids were typed held an empty string for no last message
+fn optional_message_id<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<MessageId>, D::Error> {
+    let id: Option<MessageId> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(id.filter(|id| !id.as_str().is_empty()))
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct Chat {
-    user_ids: Vec<String>,
+    user_ids: Vec<UserId>,
     name: String,
     shared_key: Vec<u8>,
-    user_nonces: HashMap<String, Vec<u8>>,
-    message_ids: Vec<String>,
-    last_message_id: String,
+    user_nonces: HashMap<UserId, Vec<u8>>,
+    message_ids: Vec<MessageId>,
+    #[serde(default, deserialize_with = "optional_message_id")]
+    last_message_id: Option<MessageId>,
+    #[serde(default)]
+    orphaned: bool,
+    #[serde(default)]
+    customization: Option<ChatCustomization>,
+    /// Set for archived history imported from another messenger. Such a chat has no
+    /// shared key and nothing is ever sent to it.
+    #[serde(default)]
+    imported_from: Option<ImportFormat>,
+    #[serde(default)]
+    extras: Extras,
+    /// We invited the other participant and they haven't accepted yet.
+    #[serde(default)]
+    invite_pending: bool,
 }
 impl Chat {
     pub fn new(
-        user_ids: Vec<String>,
+        user_ids: Vec<UserId>,
         name: String,
         shared_key: Vec<u8>,
-        user_nonces: HashMap<String, Vec<u8>>,
+        user_nonces: HashMap<UserId, Vec<u8>>,
     ) -> Self {
         Chat {
             user_ids,
@@ -60,38 +440,372 @@ impl Chat {
             shared_key,
             user_nonces,
             message_ids: Vec::new(),
-            last_message_id: String::new(),
+            last_message_id: None,
+            orphaned: false,
+            customization: None,
+            imported_from: None,
+            extras: Extras::new(),
+            invite_pending: false,
+        }
+    }
+    /// A chat we invited `user_ids` to, usable once they accept.
+    pub fn invite(user_ids: Vec<UserId>, name: String, shared_key: Vec<u8>) -> Self {
+        Chat {
+            invite_pending: true,
+            ..Chat::new(user_ids, name, shared_key, HashMap::new())
         }
     }
+    pub fn imported(user_ids: Vec<UserId>, name: String, format: ImportFormat, extras: Extras) -> Self {
+        Chat {
+            imported_from: Some(format),
+            extras,
+            ..Chat::new(user_ids, name, Vec::new(), HashMap::new())
+        }
+    }
+    pub fn user_ids(&self) -> &[UserId] {
+        &self.user_ids
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Key the chat's messages are sealed under, empty for chats that are local only.
+    pub fn shared_key(&self) -> &[u8] {
+        &self.shared_key
+    }
+    pub fn is_invite_pending(&self) -> bool {
+        self.invite_pending
+    }
+    pub fn mark_accepted(&mut self) {
+        self.invite_pending = false;
+    }
+    pub fn message_ids(&self) -> &[MessageId] {
+        &self.message_ids
+    }
+    pub fn last_message_id(&self) -> Option<&MessageId> {
+        self.last_message_id.as_ref()
+    }
+    /// A chat is orphaned once one of its participants has been deleted.
+    pub fn is_orphaned(&self) -> bool {
+        self.orphaned
+    }
+    pub fn imported_from(&self) -> Option<ImportFormat> {
+        self.imported_from
+    }
+    pub fn extras(&self) -> &Extras {
+        &self.extras
+    }
+    pub fn customization(&self) -> Option<&ChatCustomization> {
+        self.customization.as_ref()
+    }
+    /// Keeps `customization` if it is newer than the current one. Returns whether it
+    /// was applied.
+    pub fn apply_customization(&mut self, customization: ChatCustomization) -> bool {
+        match &self.customization {
+            Some(current) if !customization.supersedes(current) => false,
+            _ => {
+                self.customization = Some(customization);
+                true
+            }
+        }
+    }
+    pub fn push_message(&mut self, id: MessageId) {
+        self.message_ids.push(id.clone());
+        self.last_message_id = Some(id);
+    }
+    pub fn remove_message(&mut self, id: &MessageId) {
+        self.message_ids.retain(|m| m != id);
+        if self.last_message_id.as_ref() == Some(id) {
+            self.last_message_id = self.message_ids.last().cloned();
+        }
+    }
+    /// Puts a message taken out by `remove_message` back where it was.
+    pub fn restore_message(&mut self, id: MessageId, position: usize) {
+        let position = position.min(self.message_ids.len());
+        self.message_ids.insert(position, id);
+        self.last_message_id = self.message_ids.last().cloned();
+    }
+    pub fn remove_user(&mut self, id: &UserId) {
+        self.user_ids.retain(|u| u != id);
+        self.user_nonces.remove(id);
+        self.orphaned = true;
+    }
+}
+
+/// What a trash entry was before it was deleted.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub enum TrashedItem {
+    /// `position` is where the message was listed in its chat, if it was.
+    Message {
+        chat_id: ChatId,
+        position: Option<usize>,
+    },
+    /// Deleted along with its chat, and only restored together with it.
+    ChatMessage { chat_id: ChatId },
+    Chat {
+        server_ids: Vec<ServerId>,
+        message_ids: Vec<MessageId>,
+    },
+}
+
+/// Result of the last health probe of a saved server. Fields the probe couldn't
+/// determine are left as `None`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ServerStatus {
+    pub reachable: bool,
+    pub latency_ms: Option<u64>,
+    pub key_match: Option<bool>,
+    pub open_registration: Option<bool>,
+    pub checked_at: SystemTime,
+}
+impl ServerStatus {
+    pub fn unreachable() -> Self {
+        ServerStatus {
+            reachable: false,
+            latency_ms: None,
+            key_match: None,
+            open_registration: None,
+            checked_at: SystemTime::now(),
+        }
+    }
+}
+
+/// What the frontend gets to see of a saved server. Leaves out the session key.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct ServerSummary {
+    pub id: ServerId,
+    pub name: String,
+    pub endpoints: Vec<ServerEndpoint>,
+    pub connected: bool,
+    pub last_status: Option<ServerStatus>,
+    /// How the session with the server was secured, only set while connected.
+    pub security: Option<SecurityAssessment>,
+    /// Optional features the server advertised last time we connected.
+    pub capabilities: Capabilities,
+    /// Whether the server still has to be handed the client's new identity key.
+    pub key_rotation_pending: bool,
+}
+impl ServerSummary {
+    pub fn new(
+        id: ServerId,
+        server: &ServerModel,
+        security: Option<SecurityAssessment>,
+    ) -> Self {
+        ServerSummary {
+            id,
+            name: server.server_name.clone(),
+            endpoints: server.endpoints.clone(),
+            connected: security.is_some(),
+            last_status: server.last_status.clone(),
+            security,
+            capabilities: server.capabilities.clone(),
+            key_rotation_pending: server.key_rotation.is_some(),
+        }
+    }
+}
+
+/// Where a server can be reached: a hostname or IP address, and a port.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ServerEndpoint {
+    pub host: String,
+    pub port: u16,
+}
+impl ServerEndpoint {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        ServerEndpoint {
+            host: host.into(),
+            port,
+        }
+    }
+}
+impl From<(IpAddr, u16)> for ServerEndpoint {
+    fn from((ip, port): (IpAddr, u16)) -> Self {
+        ServerEndpoint::new(ip.to_string(), port)
+    }
+}
+impl fmt::Display for ServerEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// A server found advertising itself on the local network, see `Client::discover_servers`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveredServer {
+    /// The mDNS instance name, unique on the network.
+    pub name: String,
+    pub ip: IpAddr,
+    pub port: u16,
+    /// The `pki::fingerprint` the server advertised. Only trusted once the server proved
+    /// it holds the matching key, see `Client::connect_discovered`.
+    pub fingerprint: String,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[serde(remote = "Self")]
 pub struct ServerModel {
     pub server_name: String,
     pub encryption: Option<EncryptionConfiguration>,
-    user_ids: Vec<String>,
-    chat_ids: Vec<String>,
-    pub ip: IpAddr,
-    pub port: u16,
+    user_ids: Vec<UserId>,
+    chat_ids: Vec<ChatId>,
+    /// Tried in order when connecting.
+    pub endpoints: Vec<ServerEndpoint>,
+    /// The endpoint the last session was opened through.
+    #[serde(default)]
+    pub last_endpoint: Option<ServerEndpoint>,
+    /// Key the server proved ownership of on the last successful handshake.
+    #[serde(default)]
+    pub pub_key: Option<RsaPublicKey>,
+    /// Whether `pub_key` was supplied when the server was added rather than learned on
+    /// first use. A provisioned key is never replaced by a handshake.
+    #[serde(default)]
+    pub key_provisioned: bool,
+    /// A key the server proved ownership of in place of `pub_key`. Sessions are refused
+    /// until the user trusts it, see `Client::trust_new_server_key`.
+    #[serde(default)]
+    pub presented_key: Option<RsaPublicKey>,
+    /// Set when the user trusted a changed key, until a session is opened with it.
+    #[serde(default)]
+    pub key_change_trusted: bool,
+    #[serde(default)]
+    pub last_status: Option<ServerStatus>,
+    /// Largest message the server relays, as advertised when the session was opened.
+    #[serde(default)]
+    pub max_message_bytes: Option<usize>,
+    /// Local-only chat holding notices about this server, created on first use.
+    #[serde(default)]
+    pub system_chat_id: Option<ChatId>,
+    /// Latest head of the server's key log we checked, see `Client::refresh_log_head`.
+    #[serde(default)]
+    pub log_head: Option<SignedTreeHead>,
+    /// What the server advertised when the session was opened.
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    /// Set while the server only knows a key the client rotated away from.
+    #[serde(default)]
+    pub key_rotation: Option<PendingKeyRotation>,
 }
 impl ServerModel {
     pub fn new(
         server_name: String,
-        user_ids: Vec<String>,
-        chat_ids: Vec<String>,
+        user_ids: Vec<UserId>,
+        chat_ids: Vec<ChatId>,
         ip: IpAddr,
         port: u16,
+    ) -> Self {
+        Self::with_endpoints(server_name, user_ids, chat_ids, vec![(ip, port).into()])
+    }
+    pub fn with_endpoints(
+        server_name: String,
+        user_ids: Vec<UserId>,
+        chat_ids: Vec<ChatId>,
+        endpoints: Vec<ServerEndpoint>,
     ) -> Self {
         ServerModel {
             server_name,
             encryption: None,
             user_ids,
             chat_ids,
-            ip,
-            port,
+            endpoints,
+            last_endpoint: None,
+            pub_key: None,
+            key_provisioned: false,
+            presented_key: None,
+            key_change_trusted: false,
+            last_status: None,
+            max_message_bytes: None,
+            system_chat_id: None,
+            log_head: None,
+            capabilities: Capabilities::new(),
+            key_rotation: None,
         }
     }
+    /// Pins a key obtained out of band, e.g. from the server's operator.
+    pub fn provision_key(&mut self, pub_key: RsaPublicKey) {
+        self.pub_key = Some(pub_key);
+        self.key_provisioned = true;
+    }
     pub fn add_encryption(&mut self, encryption: EncryptionConfiguration) {
         self.encryption = Some(encryption);
     }
+    pub fn user_ids(&self) -> &[UserId] {
+        &self.user_ids
+    }
+    pub fn chat_ids(&self) -> &[ChatId] {
+        &self.chat_ids
+    }
+    pub fn add_user(&mut self, id: UserId) {
+        if !self.user_ids.contains(&id) {
+            self.user_ids.push(id);
+        }
+    }
+    pub fn remove_user(&mut self, id: &UserId) {
+        self.user_ids.retain(|u| u != id);
+    }
+    pub fn add_chat(&mut self, id: ChatId) {
+        if !self.chat_ids.contains(&id) {
+            self.chat_ids.push(id);
+        }
+    }
+    pub fn remove_chat(&mut self, id: &ChatId) {
+        self.chat_ids.retain(|c| c != id);
+    }
+}
+
+/// An identity key rotation a server hasn't taken yet. Sessions with it are opened with
+/// the old key meanwhile, see `Client::retry_key_rotations`.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct PendingKeyRotation {
+    /// PKCS#8 DER of the key the server knows.
+    old_key: Vec<u8>,
+    new_pub_key: RsaPublicKey,
+    signature_over_new_key: Vec<u8>,
+}
+impl PendingKeyRotation {
+    /// Has `old_key` hand its place over to `new_pub_key`.
+    pub fn new(old_key: &RsaPrivateKey, new_pub_key: RsaPublicKey) -> Result<Self, Error> {
+        Ok(PendingKeyRotation {
+            old_key: old_key.to_pkcs8_der()?.as_bytes().to_vec(),
+            signature_over_new_key: pki::sign_key_rotation(old_key, &new_pub_key)?,
+            new_pub_key,
+        })
+    }
+    pub fn old_key(&self) -> Result<RsaPrivateKey, Error> {
+        Ok(RsaPrivateKey::from_pkcs8_der(&self.old_key)?)
+    }
+    pub fn new_pub_key(&self) -> &RsaPublicKey {
+        &self.new_pub_key
+    }
+    pub fn params(&self) -> KeyRotationParams {
+        KeyRotationParams {
+            new_pub_key: self.new_pub_key.clone(),
+            signature_over_new_key: self.signature_over_new_key.clone(),
+        }
+    }
+}
+
+// the derive above is `remote = "Self"` so these can wrap it
+impl serde::Serialize for ServerModel {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ServerModel::serialize(self, serializer)
+    }
+}
+impl<'de> serde::Deserialize<'de> for ServerModel {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error as _;
+        let mut value = <serde_json::Value as serde::Deserialize>::deserialize(deserializer)?;
+        // servers saved before they could have several endpoints held a single ip and port
+        if let Some(fields) = value.as_object_mut() {
+            if !fields.contains_key("endpoints") {
+                let host = fields.remove("ip").unwrap_or_default();
+                let port = fields.remove("port").unwrap_or_default();
+                let endpoint = serde_json::json!({ "host": host, "port": port });
+                fields.insert(String::from("endpoints"), serde_json::json!([endpoint]));
+            }
+        }
+        ServerModel::deserialize(value).map_err(D::Error::custom)
+    }
 }
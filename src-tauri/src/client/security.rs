@@ -0,0 +1,220 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::shared::rpc_models::{KeyType, PROTOCOL_VERSION};
+
+/// How much the server's key is trusted, weakest first.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PinStatus {
+    /// A previously seen key was replaced by a different one, which the user trusted.
+    Changed,
+    /// No key was known, the one presented is trusted on first use.
+    FirstUse,
+    /// The key matches the one seen on an earlier connection.
+    Verified,
+    /// The key matches one supplied out of band when the server was added.
+    Provisioned,
+}
+
+/// Algorithms a session ends up with, weakest first. The session key is always
+/// transported under RSA and used with AES-256-GCM; what differs is how the server
+/// proves its identity.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CipherSuite {
+    Rsa2048AesGcm,
+    Ed25519AesGcm,
+}
+impl From<KeyType> for CipherSuite {
+    fn from(key_type: KeyType) -> Self {
+        match key_type {
+            KeyType::Rsa2048 => CipherSuite::Rsa2048AesGcm,
+            KeyType::Ed25519 => CipherSuite::Ed25519AesGcm,
+        }
+    }
+}
+
+/// What was negotiated while establishing a session.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionParameters {
+    pub pin_status: PinStatus,
+    pub cipher_suite: CipherSuite,
+    pub forward_secrecy: bool,
+    pub protocol_version: u32,
+}
+
+/// The weakest session the client accepts without warning. In strict mode sessions
+/// below it are refused instead.
+#[derive(Clone, Debug)]
+pub struct SecurityMinimum {
+    pub pin_status: PinStatus,
+    pub cipher_suite: CipherSuite,
+    pub forward_secrecy: bool,
+    pub protocol_version: u32,
+    pub strict_mode: bool,
+}
+impl Default for SecurityMinimum {
+    /// Accepts everything the current protocol offers except a changed server key.
+    fn default() -> Self {
+        SecurityMinimum {
+            pin_status: PinStatus::FirstUse,
+            cipher_suite: CipherSuite::Rsa2048AesGcm,
+            forward_secrecy: false,
+            protocol_version: 0,
+            strict_mode: false,
+        }
+    }
+}
+
+/// A dimension of the session that fell below the configured minimum.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecurityWarning {
+    Pin,
+    CipherSuite,
+    ForwardSecrecy,
+    ProtocolVersion,
+}
+impl fmt::Display for SecurityWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SecurityWarning::Pin => "the server's key is not verified",
+            SecurityWarning::CipherSuite => "a legacy cipher suite was negotiated",
+            SecurityWarning::ForwardSecrecy => "the session has no forward secrecy",
+            SecurityWarning::ProtocolVersion => "the server speaks an outdated protocol version",
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SecurityAssessment {
+    pub pin_status: PinStatus,
+    pub cipher_suite: CipherSuite,
+    pub forward_secrecy: bool,
+    pub protocol_version: u32,
+    pub newest_protocol_version: u32,
+    pub strict_mode: bool,
+    pub warnings: Vec<SecurityWarning>,
+}
+impl SecurityAssessment {
+    pub fn is_downgraded(&self) -> bool {
+        !self.warnings.is_empty()
+    }
+
+    /// One line summary for the system chat.
+    pub fn notice(&self) -> String {
+        let reasons: Vec<String> = self.warnings.iter().map(|w| w.to_string()).collect();
+        format!(
+            "Connection security is below your minimum: {}.",
+            reasons.join(", ")
+        )
+    }
+}
+
+pub fn assess(session: &SessionParameters, minimum: &SecurityMinimum) -> SecurityAssessment {
+    let mut warnings = Vec::new();
+    if session.pin_status < minimum.pin_status {
+        warnings.push(SecurityWarning::Pin);
+    }
+    if session.cipher_suite < minimum.cipher_suite {
+        warnings.push(SecurityWarning::CipherSuite);
+    }
+    if minimum.forward_secrecy && !session.forward_secrecy {
+        warnings.push(SecurityWarning::ForwardSecrecy);
+    }
+    if session.protocol_version < minimum.protocol_version {
+        warnings.push(SecurityWarning::ProtocolVersion);
+    }
+    SecurityAssessment {
+        pin_status: session.pin_status,
+        cipher_suite: session.cipher_suite,
+        forward_secrecy: session.forward_secrecy,
+        protocol_version: session.protocol_version,
+        newest_protocol_version: PROTOCOL_VERSION,
+        strict_mode: minimum.strict_mode,
+        warnings,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIN_STATUSES: [PinStatus; 4] = [
+        PinStatus::Changed,
+        PinStatus::FirstUse,
+        PinStatus::Verified,
+        PinStatus::Provisioned,
+    ];
+    const CIPHER_SUITES: [CipherSuite; 2] =
+        [CipherSuite::Rsa2048AesGcm, CipherSuite::Ed25519AesGcm];
+
+    #[test]
+    fn test_assess_every_combination() {
+        let versions = [0, PROTOCOL_VERSION, PROTOCOL_VERSION + 1];
+        for (min_pin, pin) in PIN_STATUSES
+            .iter()
+            .flat_map(|a| PIN_STATUSES.map(|b| (*a, b)))
+        {
+            for (min_cipher, cipher) in CIPHER_SUITES
+                .iter()
+                .flat_map(|a| CIPHER_SUITES.map(|b| (*a, b)))
+            {
+                for (min_fs, fs) in [(false, false), (false, true), (true, false), (true, true)] {
+                    for (min_version, version) in
+                        versions.iter().flat_map(|a| versions.map(|b| (*a, b)))
+                    {
+                        for strict_mode in [false, true] {
+                            let session = SessionParameters {
+                                pin_status: pin,
+                                cipher_suite: cipher,
+                                forward_secrecy: fs,
+                                protocol_version: version,
+                            };
+                            let minimum = SecurityMinimum {
+                                pin_status: min_pin,
+                                cipher_suite: min_cipher,
+                                forward_secrecy: min_fs,
+                                protocol_version: min_version,
+                                strict_mode,
+                            };
+                            let assessment = assess(&session, &minimum);
+                            let warned = |w| assessment.warnings.contains(&w);
+                            assert_eq!(warned(SecurityWarning::Pin), pin < min_pin);
+                            assert_eq!(warned(SecurityWarning::CipherSuite), cipher < min_cipher);
+                            assert_eq!(warned(SecurityWarning::ForwardSecrecy), min_fs && !fs);
+                            assert_eq!(
+                                warned(SecurityWarning::ProtocolVersion),
+                                version < min_version
+                            );
+                            assert_eq!(assessment.pin_status, pin);
+                            assert_eq!(assessment.cipher_suite, cipher);
+                            assert_eq!(assessment.forward_secrecy, fs);
+                            assert_eq!(assessment.protocol_version, version);
+                            assert_eq!(assessment.newest_protocol_version, PROTOCOL_VERSION);
+                            assert_eq!(assessment.strict_mode, strict_mode);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_default_minimum() {
+        let minimum = SecurityMinimum::default();
+        let session = SessionParameters {
+            pin_status: PinStatus::FirstUse,
+            cipher_suite: CipherSuite::Rsa2048AesGcm,
+            forward_secrecy: false,
+            protocol_version: 0,
+        };
+        assert!(!assess(&session, &minimum).is_downgraded());
+        let changed = SessionParameters {
+            pin_status: PinStatus::Changed,
+            ..session
+        };
+        let assessment = assess(&changed, &minimum);
+        assert_eq!(assessment.warnings, vec![SecurityWarning::Pin]);
+        assert!(assessment.notice().contains("not verified"));
+    }
+}
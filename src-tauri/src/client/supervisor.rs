@@ -0,0 +1,255 @@
+use std::{
+    any::Any,
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_std::{
+    channel::{self, Receiver, Sender},
+    task::{self, JoinHandle},
+};
+use futures::{
+    future::{self, Either},
+    FutureExt,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::Error;
+
+pub type TaskResult = Result<(), Error>;
+
+// how `RestartPolicy::Always` backs off, so a task that fails right away doesn't spin
+const ALWAYS_INITIAL_DELAY: Duration = Duration::from_millis(100);
+const ALWAYS_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// What to do when a supervised task returns an error or panics. A task that
+/// returns `Ok(())` is considered finished and is never restarted.
+#[derive(Clone, Debug)]
+pub enum RestartPolicy {
+    /// Backoff starting at 100 ms and capped at 30 s.
+    Always,
+    Backoff { initial: Duration, max: Duration },
+    Never,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskHealth {
+    pub name: String,
+    pub running: bool,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+}
+
+struct SupervisedTask {
+    health: Arc<Mutex<TaskHealth>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+pub struct Supervisor {
+    tasks: Vec<SupervisedTask>,
+    cancel_tx: Sender<()>,
+    cancel_rx: Receiver<()>,
+}
+impl Supervisor {
+    pub fn new() -> Self {
+        let (cancel_tx, cancel_rx) = channel::bounded(1);
+        Supervisor {
+            tasks: Vec::new(),
+            cancel_tx,
+            cancel_rx,
+        }
+    }
+
+    /// Spawns a task built by `factory`, calling it again whenever the task has to be
+    /// restarted.
+    pub fn spawn<F, Fut>(&mut self, name: &str, policy: RestartPolicy, factory: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = TaskResult> + Send + 'static,
+    {
+        let health = Arc::new(Mutex::new(TaskHealth {
+            name: name.to_string(),
+            running: true,
+            restarts: 0,
+            last_error: None,
+        }));
+        let task_health = health.clone();
+        let cancel = self.cancel_rx.clone();
+        let name = name.to_string();
+        let handle = task::spawn(async move {
+            let (mut delay, max_delay) = match policy {
+                RestartPolicy::Always => (ALWAYS_INITIAL_DELAY, ALWAYS_MAX_DELAY),
+                RestartPolicy::Backoff { initial, max } => (initial, max),
+                RestartPolicy::Never => (Duration::ZERO, Duration::ZERO),
+            };
+            loop {
+                let run = AssertUnwindSafe(factory()).catch_unwind();
+                let outcome = match future::select(Box::pin(run), Box::pin(cancel.recv())).await {
+                    Either::Left((outcome, _)) => outcome,
+                    Either::Right(_) => break,
+                };
+                let error = match outcome {
+                    Ok(Ok(())) => break,
+                    Ok(Err(e)) => e.to_string(),
+                    Err(panic) => panic_message(panic),
+                };
+                let trace_id = Uuid::new_v4();
+                eprintln!("Task {} failed [{}]: {}", name, trace_id, error);
+                task_health.lock().unwrap().last_error = Some(format!("[{}] {}", trace_id, error));
+
+                if let RestartPolicy::Never = policy {
+                    break;
+                }
+                let sleep = task::sleep(delay);
+                if let Either::Right(_) =
+                    future::select(Box::pin(sleep), Box::pin(cancel.recv())).await
+                {
+                    break;
+                }
+                delay = (delay * 2).min(max_delay);
+                task_health.lock().unwrap().restarts += 1;
+            }
+            task_health.lock().unwrap().running = false;
+        });
+        self.tasks.push(SupervisedTask {
+            health,
+            handle: Some(handle),
+        });
+    }
+
+    pub fn health(&self) -> Vec<TaskHealth> {
+        self.tasks
+            .iter()
+            .map(|task| task.health.lock().unwrap().clone())
+            .collect()
+    }
+
+    /// Cancels every task and waits for all of them to stop.
+    pub async fn shutdown(&mut self) {
+        self.cancel_tx.close();
+        for task in self.tasks.iter_mut() {
+            if let Some(handle) = task.handle.take() {
+                handle.await;
+            }
+        }
+    }
+}
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.cancel_tx.close();
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        String::from("task panicked")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn test_restart_after_panic() {
+        task::block_on(async {
+            let mut supervisor = Supervisor::new();
+            let runs = Arc::new(AtomicUsize::new(0));
+            let task_runs = runs.clone();
+            supervisor.spawn("panics_once", RestartPolicy::Always, move || {
+                let runs = task_runs.clone();
+                async move {
+                    if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                        panic!("first run fails");
+                    }
+                    future::pending::<()>().await;
+                    Ok(())
+                }
+            });
+            for _ in 0..100 {
+                if runs.load(Ordering::SeqCst) >= 2 {
+                    break;
+                }
+                task::sleep(Duration::from_millis(10)).await;
+            }
+            let health = supervisor.health();
+            assert_eq!(runs.load(Ordering::SeqCst), 2);
+            assert_eq!(health[0].restarts, 1);
+            assert!(health[0].running);
+            assert!(health[0].last_error.as_ref().unwrap().contains("first run fails"));
+            supervisor.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn test_backoff_and_never() {
+        task::block_on(async {
+            let mut supervisor = Supervisor::new();
+            supervisor.spawn("never", RestartPolicy::Never, || async {
+                Err("broken".into())
+            });
+            supervisor.spawn(
+                "backoff",
+                RestartPolicy::Backoff {
+                    initial: Duration::from_millis(10),
+                    max: Duration::from_millis(20),
+                },
+                || async { Err("broken".into()) },
+            );
+            supervisor.spawn("always", RestartPolicy::Always, || async {
+                Err("broken".into())
+            });
+            task::sleep(Duration::from_millis(200)).await;
+            let health = supervisor.health();
+            assert!(!health[0].running);
+            assert_eq!(health[0].restarts, 0);
+            assert!(health[1].running);
+            assert!(health[1].restarts >= 2);
+            // waits 100 ms, then 200 ms, rather than spinning
+            assert!(health[2].running);
+            assert!((1..=2).contains(&health[2].restarts));
+            supervisor.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn test_shutdown() {
+        task::block_on(async {
+            let mut supervisor = Supervisor::new();
+            let ticks = Arc::new(AtomicUsize::new(0));
+            for name in ["first", "second"] {
+                let ticks = ticks.clone();
+                supervisor.spawn(name, RestartPolicy::Always, move || {
+                    let ticks = ticks.clone();
+                    async move {
+                        loop {
+                            ticks.fetch_add(1, Ordering::SeqCst);
+                            task::sleep(Duration::from_millis(5)).await;
+                        }
+                    }
+                });
+            }
+            task::sleep(Duration::from_millis(50)).await;
+            supervisor.shutdown().await;
+            assert!(supervisor.health().iter().all(|task| !task.running));
+            let stopped_at = ticks.load(Ordering::SeqCst);
+            assert!(stopped_at > 0);
+            task::sleep(Duration::from_millis(50)).await;
+            assert_eq!(ticks.load(Ordering::SeqCst), stopped_at);
+        });
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use async_std::{channel::Receiver, sync::RwLock, task};
+use serde::Serialize;
+
+use crate::client::{
+    intent::RecoveredIntent,
+    models::{
+        DiscoveredServer, KeyFingerprint, Message, MessageId, OutgoingMessage, ServerEndpoint,
+        ServerId, ServerSummary, User, UserId,
+    },
+    Client, ConnectionStatus,
+};
+use crate::shared::{
+    rpc::{Request, Response},
+    rpc_models::{self, AckStatus},
+};
+use crate::Error;
+
+/// State managed by tauri. The client only exists once `unlock` has succeeded. Clones
+/// share it, for the tasks that carry on after a command returned.
+#[derive(Default, Clone)]
+pub struct AppState {
+    client: Arc<RwLock<Option<Client>>>,
+    // servers whose pushes a task is handling, see `receive_pushes`
+    receiving: Arc<Mutex<HashSet<String>>>,
+}
+
+#[derive(Debug, Serialize)]
+pub enum CommandErrorCode {
+    Locked,
+    InvalidArgument,
+    /// The server didn't answer in time.
+    ServerNotResponding,
+    /// The server's key isn't the one pinned for it; `trust_new_server_key` accepts it.
+    ServerKeyChanged,
+    Failed,
+}
+
+/// Error handed to the frontend, which can branch on `code` and show `message`.
+#[derive(Debug, Serialize)]
+pub struct CommandError {
+    code: CommandErrorCode,
+    message: String,
+}
+impl CommandError {
+    fn new(code: CommandErrorCode, message: impl ToString) -> Self {
+        CommandError {
+            code,
+            message: message.to_string(),
+        }
+    }
+    fn locked() -> Self {
+        Self::new(
+            CommandErrorCode::Locked,
+            "The client is locked, unlock it first",
+        )
+    }
+}
+impl From<Error> for CommandError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Timeout => Self::new(CommandErrorCode::ServerNotResponding, "Server not responding"),
+            e @ Error::ServerKeyChanged { .. } => Self::new(CommandErrorCode::ServerKeyChanged, e),
+            e => Self::new(CommandErrorCode::Failed, e),
+        }
+    }
+}
+
+type CommandResult<T> = Result<T, CommandError>;
+
+#[tauri::command]
+pub async fn unlock(
+    pass_key: String,
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<Vec<RecoveredIntent>> {
+    // deriving the keys is deliberately slow, so keep it off the async executor
+    let client = task::spawn_blocking(move || {
+        Client::new(pass_key.into_bytes(), None).map_err(|e| e.to_string())
+    })
+    .await;
+    let mut client = client.map_err(|e| CommandError::new(CommandErrorCode::Failed, e))?;
+    client.set_event_emitter(app);
+    // operations an earlier run was interrupted in, for the frontend to tell the user about
+    let report = client.startup_report().to_vec();
+    *state.client.write().await = Some(client);
+    // the tasks of a previous client stopped along with its push channels
+    state.receiving.lock().unwrap().clear();
+    Ok(report)
+}
+
+#[tauri::command]
+pub async fn add_server(
+    name: String,
+    endpoints: Vec<ServerEndpoint>,
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<ServerId> {
+    if endpoints.iter().any(|endpoint| endpoint.host.trim().is_empty()) {
+        return Err(CommandError::new(CommandErrorCode::InvalidArgument, "Invalid host"));
+    }
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or_else(CommandError::locked)?;
+    Ok(client.add_server(name, endpoints)?)
+}
+
+#[tauri::command]
+pub async fn list_servers(state: tauri::State<'_, AppState>) -> CommandResult<Vec<ServerSummary>> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or_else(CommandError::locked)?;
+    Ok(client.list_servers()?)
+}
+
+#[tauri::command]
+pub async fn connect_server(
+    server_id: String,
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<()> {
+    connect(&state, &server_id).await
+}
+
+/// Opens a session with `server_id`, and starts handling it, see `connected`.
+async fn connect(state: &AppState, server_id: &str) -> CommandResult<()> {
+    handshake(state, server_id, None).await?;
+    connected(state, server_id).await
+}
+
+/// Runs the handshake with `server_id`, through `discovered` alone when it's given,
+/// and keeps the session. The client is only locked to take what the handshake needs
+/// and to keep the session, not while the server is waited on.
+async fn handshake(
+    state: &AppState,
+    server_id: &str,
+    discovered: Option<ServerEndpoint>,
+) -> CommandResult<()> {
+    let attempt = {
+        let client = state.client.read().await;
+        let client = client.as_ref().ok_or_else(CommandError::locked)?;
+        client.prepare_connect(server_id, discovered)?
+    };
+    let handshake = attempt.run().await?;
+    let mut client = state.client.write().await;
+    let client = client.as_mut().ok_or_else(CommandError::locked)?;
+    client.finish_connect(handshake)?;
+    Ok(())
+}
+
+/// Servers advertising themselves on the local network within `timeout_ms`.
+#[tauri::command]
+pub async fn discover_servers(timeout_ms: u64) -> CommandResult<Vec<DiscoveredServer>> {
+    Ok(Client::discover_servers(std::time::Duration::from_millis(timeout_ms)).await?)
+}
+
+/// Connects to a server from `discover_servers`, saving it if it's new.
+#[tauri::command]
+pub async fn connect_discovered(
+    server: DiscoveredServer,
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<ServerId> {
+    let (server_id, added) = {
+        let mut client = state.client.write().await;
+        let client = client.as_mut().ok_or_else(CommandError::locked)?;
+        client.save_discovered(&server)?
+    };
+    let endpoint = ServerEndpoint::from((server.ip, server.port));
+    let opened = handshake(&state, server_id.as_str(), Some(endpoint))
+        .await
+        .map_err(|e| Error::Other(e.message));
+    {
+        let mut client = state.client.write().await;
+        let client = client.as_mut().ok_or_else(CommandError::locked)?;
+        client.check_discovered(&server, &server_id, added, opened)?;
+    }
+    connected(&state, server_id.as_str()).await?;
+    Ok(server_id)
+}
+
+/// Starts receiving pushes from a server a session was just opened with, and sends
+/// what was written while it was out of reach.
+async fn connected(state: &AppState, server_id: &str) -> CommandResult<()> {
+    if state.receiving.lock().unwrap().insert(server_id.to_string()) {
+        let mut client = state.client.write().await;
+        let client = client.as_mut().ok_or_else(CommandError::locked)?;
+        receive_pushes(state.client.clone(), server_id.to_string(), client.incoming(server_id));
+    }
+    let shared = state.client.clone();
+    let flushed = server_id.to_string();
+    task::spawn(async move {
+        if let Some(client) = shared.read().await.as_ref() {
+            if let Err(e) = client.flush_session_outbox(&flushed).await {
+                eprintln!("Error: flushing the outbox: {}", e);
+            }
+        }
+    });
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or_else(CommandError::locked)?;
+    Ok(client.subscribe_pushes(server_id).await?)
+}
+
+/// Sends `request` to `server_id` under its session key, opening a session first if
+/// there is none. If the session turns out to be lost, or the server wants it rekeyed,
+/// a new one is opened and the request sent once more. The client is only read while
+/// the server is waited on.
+async fn send_request(
+    state: &AppState,
+    server_id: &str,
+    request: Request,
+) -> CommandResult<Response> {
+    let status = {
+        let client = state.client.read().await;
+        let client = client.as_ref().ok_or_else(CommandError::locked)?;
+        client.connection_status(server_id)
+    };
+    if status != ConnectionStatus::Connected {
+        connect(state, server_id).await?;
+    }
+    {
+        let client = state.client.read().await;
+        let client = client.as_ref().ok_or_else(CommandError::locked)?;
+        match client.try_send_sym_encrypted_request(server_id, request.clone()).await {
+            Err(e) if client.session_lost(server_id, &e) => {}
+            sent => return Ok(sent?),
+        }
+    }
+    connect(state, server_id).await?;
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or_else(CommandError::locked)?;
+    Ok(client.try_send_sym_encrypted_request(server_id, request).await?)
+}
+
+/// Sends what waits in the outbox for the connected server, over a new session if its
+/// session was lost, which sends it in turn.
+async fn flush_outbox(state: &AppState) -> CommandResult<()> {
+    let (server_id, status) = {
+        let client = state.client.read().await;
+        let client = client.as_ref().ok_or_else(CommandError::locked)?;
+        match client.default_server() {
+            Some(server_id) => (server_id.clone(), client.connection_status(server_id.as_str())),
+            None => return Ok(()),
+        }
+    };
+    if status != ConnectionStatus::Connected {
+        return connect(state, server_id.as_str()).await;
+    }
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or_else(CommandError::locked)?;
+    client.flush_session_outbox(server_id.as_str()).await?;
+    Ok(())
+}
+
+/// Stores the chat payloads and acknowledgements `server_id` pushes to the client as
+/// they arrive; the client emits a `message-received` or `message-acked` event for
+/// each. Later sessions with the same server push to the same channel, so one task per
+/// server is enough. The client is only read, what a payload has to ask the server goes
+/// over the session it came in on.
+fn receive_pushes(
+    shared: Arc<RwLock<Option<Client>>>,
+    server_id: String,
+    pushed: Receiver<Request>,
+) {
+    task::spawn(async move {
+        while let Ok(request) = pushed.recv().await {
+            if let Some(client) = shared.read().await.as_ref() {
+                let handled = match request.method.as_str() {
+                    rpc_models::FORWARDED_MSG => {
+                        client.on_forwarded_message(&server_id, request).await.map(|_| ())
+                    }
+                    rpc_models::ACK_MESSAGE => client.on_message_ack(request).map(|_| ()),
+                    _ => continue,
+                };
+                if let Err(e) = handled {
+                    eprintln!("Error: handling a pushed message: {}", e);
+                }
+            }
+        }
+    });
+}
+
+#[tauri::command]
+pub async fn disconnect_server(
+    server_id: String,
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<()> {
+    let mut client = state.client.write().await;
+    let client = client.as_mut().ok_or_else(CommandError::locked)?;
+    Ok(client.disconnect_server(&server_id)?)
+}
+
+#[tauri::command]
+pub async fn connection_status(
+    server_id: String,
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<ConnectionStatus> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or_else(CommandError::locked)?;
+    Ok(client.connection_status(&server_id))
+}
+
+/// The server's key as fingerprint and safety number, for the user to compare with
+/// what its operator publishes.
+#[tauri::command]
+pub async fn server_fingerprint(
+    server_id: String,
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<KeyFingerprint> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or_else(CommandError::locked)?;
+    Ok(client.server_fingerprint(&server_id)?)
+}
+
+/// Accepts the key a server presented in place of its pinned one.
+#[tauri::command]
+pub async fn trust_new_server_key(
+    server_id: String,
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<KeyFingerprint> {
+    let mut client = state.client.write().await;
+    let client = client.as_mut().ok_or_else(CommandError::locked)?;
+    Ok(client.trust_new_server_key(&server_id)?)
+}
+
+#[tauri::command]
+pub async fn ping_server(
+    server_id: String,
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<()> {
+    let status = {
+        let client = state.client.read().await;
+        let client = client.as_ref().ok_or_else(CommandError::locked)?;
+        client.connection_status(&server_id)
+    };
+    if status != ConnectionStatus::Connected {
+        connect(&state, &server_id).await?;
+    }
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or_else(CommandError::locked)?;
+    Ok(client.ping_session(&server_id).await?)
+}
+
+/// Stores the message and returns it as pending right away. It is sent in the
+/// background, and a `message-reconciled` event reports how that went.
+#[tauri::command]
+pub async fn send_message(
+    chat_id: String,
+    text: String,
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<OutgoingMessage> {
+    let pending = {
+        let mut client = state.client.write().await;
+        let client = client.as_mut().ok_or_else(CommandError::locked)?;
+        client.send_message(&chat_id, &text)?
+    };
+    let state = AppState::clone(&state);
+    task::spawn(async move {
+        if let Err(e) = flush_outbox(&state).await {
+            eprintln!("Error: flushing the outbox: {}", e.message);
+        }
+    });
+    Ok(pending)
+}
+
+#[tauri::command]
+pub async fn get_messages(
+    chat_id: String,
+    before: Option<String>,
+    limit: usize,
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<Vec<(MessageId, Message)>> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or_else(CommandError::locked)?;
+    Ok(client.chat_messages(&chat_id, before.as_deref(), limit)?)
+}
+
+/// Saves a contact whose key was handed over out of band.
+#[tauri::command]
+pub async fn add_contact(
+    username: String,
+    pub_key: String,
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<UserId> {
+    let mut client = state.client.write().await;
+    let client = client.as_mut().ok_or_else(CommandError::locked)?;
+    Ok(client.add_contact(username, &pub_key)?)
+}
+
+#[tauri::command]
+pub async fn list_contacts(
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<Vec<(UserId, User)>> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or_else(CommandError::locked)?;
+    Ok(client.list_contacts()?)
+}
+
+#[tauri::command]
+pub async fn remove_contact(
+    user_id: String,
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<()> {
+    let mut client = state.client.write().await;
+    let client = client.as_mut().ok_or_else(CommandError::locked)?;
+    Ok(client.remove_contact(&UserId::from(user_id))?)
+}
+
+/// Tells the sender of a received message that it was delivered or read.
+#[tauri::command]
+pub async fn ack_message(
+    server_id: String,
+    message_id: String,
+    status: AckStatus,
+    state: tauri::State<'_, AppState>,
+) -> CommandResult<()> {
+    let request = {
+        let client = state.client.read().await;
+        let client = client.as_ref().ok_or_else(CommandError::locked)?;
+        client.ack_request(&MessageId::from(message_id), status)?
+    };
+    send_request(&state, &server_id, request).await?.into_result().map_err(Error::from)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locked_error() {
+        let state = AppState::default();
+        assert!(task::block_on(state.client.read()).is_none());
+        let error = serde_json::to_value(CommandError::locked()).unwrap();
+        assert_eq!(error["code"], "Locked");
+        assert!(error["message"].as_str().unwrap().contains("unlock"));
+    }
+
+    #[test]
+    fn test_timeout_error() {
+        let error = serde_json::to_value(CommandError::from(Error::Timeout)).unwrap();
+        assert_eq!(error["code"], "ServerNotResponding");
+        let error = serde_json::to_value(CommandError::from(Error::from("no"))).unwrap();
+        assert_eq!(error["code"], "Failed");
+    }
+}
@@ -1,11 +1,44 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 mod client;
+mod commands;
 mod server;
 mod shared;
 
+pub use shared::error::Error;
+
+use tauri::Manager;
+
+impl client::EventEmitter for tauri::AppHandle {
+  fn emit(&self, event: &str, payload: serde_json::Value) {
+    if let Err(e) = self.emit_all(event, payload) {
+      eprintln!("Error: {}", e);
+    }
+  }
+}
+
 fn main() {
-  tauri::Builder::default().invoke_handler(tauri::generate_handler![])
+  tauri::Builder::default()
+    .manage(commands::AppState::default())
+    .invoke_handler(tauri::generate_handler![
+      commands::unlock,
+      commands::add_server,
+      commands::list_servers,
+      commands::connect_server,
+      commands::disconnect_server,
+      commands::connection_status,
+      commands::server_fingerprint,
+      commands::trust_new_server_key,
+      commands::discover_servers,
+      commands::connect_discovered,
+      commands::ping_server,
+      commands::send_message,
+      commands::get_messages,
+      commands::add_contact,
+      commands::list_contacts,
+      commands::remove_contact,
+      commands::ack_message,
+    ])
     .run(tauri::generate_context!())
     .expect("error while running tauri application");
 }
@@ -0,0 +1,589 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rsa::{pkcs8::EncodePrivateKey, RsaPrivateKey, RsaPublicKey};
+use uuid::Uuid;
+
+use super::models::{PendingNotification, SentMessage};
+use crate::shared::db::{DbConfig, EntryDb};
+use crate::shared::kv::Batch;
+use crate::shared::pki;
+use crate::shared::rpc_models::AckStatus;
+use crate::shared::transparency::{LogEntry, EMPTY_LOG_HASH};
+use crate::Error;
+
+/// Keys admitted through open registration, by fingerprint.
+const AUTHORIZED_KEYS_TREE: &str = "authorized_keys";
+/// Keys their holders rotated away from, by fingerprint. They stay refused even when
+/// the server's configuration still lists them.
+const REVOKED_KEYS_TREE: &str = "revoked_keys";
+/// Registered usernames, each with the key that registered it.
+const USERS_TREE: &str = "users";
+/// Every username binding ever made, keyed by big-endian sequence number.
+const KEY_LOG_TREE: &str = "key_log";
+/// Every recipient gets its own tree, named by this prefix and its key fingerprint.
+const PENDING_TREE_PREFIX: &str = "pending:";
+/// Forwarded messages their recipients may still acknowledge, by the id the server
+/// handed out for them.
+const SENT_TREE: &str = "sent";
+
+fn pending_tree(fingerprint: &str) -> String {
+    format!("{}{}", PENDING_TREE_PREFIX, fingerprint)
+}
+
+/// What a server keeps across restarts: the keys clients registered with, their
+/// usernames, and notifications queued for recipients without a live connection until they fetch them
+/// with `GET_PENDING` or they expire.
+pub struct ServerDatabase {
+    db: EntryDb,
+}
+impl ServerDatabase {
+    pub fn new(db: EntryDb) -> Self {
+        ServerDatabase { db }
+    }
+
+    /// Opens the store at `path`, encrypted with a key derived from the server's
+    /// private key so it is only readable by whoever can run the server.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        private_key: &RsaPrivateKey,
+        config: &DbConfig,
+    ) -> Result<Self, Error> {
+        let passkey = private_key.to_pkcs8_der()?;
+        let db = EntryDb::new(passkey.as_bytes(), config.open(path)?)?;
+        Ok(Self::new(db))
+    }
+
+    pub fn authorized_keys(&self) -> Result<Vec<RsaPublicKey>, Error> {
+        let mut keys = vec![];
+        for (_, entry) in self.db.store().iter(AUTHORIZED_KEYS_TREE)? {
+            keys.push(self.db.decrypt_value(&entry)?);
+        }
+        Ok(keys)
+    }
+
+    pub fn add_authorized_key(&self, key: &RsaPublicKey) -> Result<(), Error> {
+        self.db.store().insert(
+            AUTHORIZED_KEYS_TREE,
+            pki::fingerprint(key)?.as_bytes(),
+            &self.db.encrypt_value(key)?,
+        )
+    }
+
+    pub fn revoked_keys(&self) -> Result<Vec<RsaPublicKey>, Error> {
+        let mut keys = vec![];
+        for (_, entry) in self.db.store().iter(REVOKED_KEYS_TREE)? {
+            keys.push(self.db.decrypt_value(&entry)?);
+        }
+        Ok(keys)
+    }
+
+    /// Swaps `old` for `new` in a single batch, so a crash leaves exactly one of them.
+    /// `old` is revoked, in case it was authorized by configuration rather than here.
+    /// The username `old` registered moves over to `new`, and the key log records it.
+    pub fn replace_authorized_key(
+        &self,
+        old: &RsaPublicKey,
+        new: &RsaPublicKey,
+    ) -> Result<(), Error> {
+        let old_fingerprint = pki::fingerprint(old)?;
+        let new_fingerprint = pki::fingerprint(new)?;
+        let mut batch = Batch::default();
+        batch.remove(AUTHORIZED_KEYS_TREE, old_fingerprint.as_bytes());
+        batch.insert(
+            REVOKED_KEYS_TREE,
+            old_fingerprint.as_bytes(),
+            self.db.encrypt_value(old)?,
+        );
+        batch.remove(REVOKED_KEYS_TREE, new_fingerprint.as_bytes());
+        batch.insert(
+            AUTHORIZED_KEYS_TREE,
+            new_fingerprint.as_bytes(),
+            self.db.encrypt_value(new)?,
+        );
+        if let Some(username) = self.username_of(old)? {
+            self.bind_user(&mut batch, &username, new)?;
+        }
+        self.db.store().apply_batch(batch)
+    }
+
+    /// The username registered to `key`, if it has one.
+    pub fn username_of(&self, key: &RsaPublicKey) -> Result<Option<String>, Error> {
+        for (name, entry) in self.db.store().iter(USERS_TREE)? {
+            let registered: RsaPublicKey = self.db.decrypt_value(&entry)?;
+            if registered == *key {
+                return Ok(Some(String::from_utf8(name)?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The key `username` is registered to, if anyone has it.
+    pub fn user(&self, username: &str) -> Result<Option<RsaPublicKey>, Error> {
+        match self.db.store().get(USERS_TREE, username.as_bytes())? {
+            Some(entry) => Ok(Some(self.db.decrypt_value(&entry)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Every registered username, sorted.
+    pub fn users(&self) -> Result<Vec<String>, Error> {
+        let mut names = vec![];
+        for (name, _) in self.db.store().iter(USERS_TREE)? {
+            names.push(String::from_utf8(name)?);
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Binds `username` to `key`, releasing any name the key had before, and appends
+    /// the binding to the key log. The caller checks the name is free.
+    pub fn register_user(&self, username: &str, key: &RsaPublicKey) -> Result<(), Error> {
+        if self.user(username)?.as_ref() == Some(key) {
+            return Ok(());
+        }
+        let mut batch = Batch::default();
+        self.bind_user(&mut batch, username, key)?;
+        self.db.store().apply_batch(batch)
+    }
+
+    /// Adds binding `username` to `key` to `batch`, releasing any name the key had
+    /// before, and logs it.
+    fn bind_user(
+        &self,
+        batch: &mut Batch,
+        username: &str,
+        key: &RsaPublicKey,
+    ) -> Result<(), Error> {
+        self.log_append(batch, self.log_head()?, username, key)?;
+        for (name, entry) in self.db.store().iter(USERS_TREE)? {
+            let registered: RsaPublicKey = self.db.decrypt_value(&entry)?;
+            if registered == *key {
+                batch.remove(USERS_TREE, &name);
+            }
+        }
+        batch.insert(USERS_TREE, username.as_bytes(), self.db.encrypt_value(key)?);
+        Ok(())
+    }
+
+    /// Logs every registered username the key log doesn't bind to its current key, e.g.
+    /// ones registered before the server kept a log, so their keys can be proven too.
+    /// Returns how many were logged.
+    pub fn backfill_key_log(&self) -> Result<usize, Error> {
+        let mut head = self.log_head()?;
+        let mut logged = HashMap::new();
+        for entry in self.log_entries(0, head.0)? {
+            logged.insert(entry.username, entry.pub_key);
+        }
+        let mut batch = Batch::default();
+        let mut backfilled = 0;
+        for username in self.users()? {
+            let key = match self.user(&username)? {
+                Some(key) => key,
+                None => continue,
+            };
+            if logged.get(&username) != Some(&key) {
+                head = self.log_append(&mut batch, head, &username, &key)?;
+                backfilled += 1;
+            }
+        }
+        if !batch.is_empty() {
+            self.db.store().apply_batch(batch)?;
+        }
+        Ok(backfilled)
+    }
+
+    /// Adds the entry binding `username` to `key` to `batch`, after the log `head`
+    /// describes. Returns the head of the log with it.
+    fn log_append(
+        &self,
+        batch: &mut Batch,
+        (seq, prev_hash): (u64, String),
+        username: &str,
+        key: &RsaPublicKey,
+    ) -> Result<(u64, String), Error> {
+        let entry = LogEntry {
+            seq,
+            username: username.to_string(),
+            pub_key: key.clone(),
+            prev_hash,
+        };
+        batch.insert(KEY_LOG_TREE, &seq.to_be_bytes(), self.db.encrypt_value(&entry)?);
+        Ok((seq + 1, entry.hash()?))
+    }
+
+    /// Size of the key log and the hash of its last entry.
+    pub fn log_head(&self) -> Result<(u64, String), Error> {
+        let size = self.db.store().len(KEY_LOG_TREE)? as u64;
+        if size == 0 {
+            return Ok((0, EMPTY_LOG_HASH.to_string()));
+        }
+        let last = self.log_entries(size - 1, size)?;
+        Ok((size, last[0].hash()?))
+    }
+
+    /// The key log entries with sequence numbers from `from` up to, not including, `to`.
+    pub fn log_entries(&self, from: u64, to: u64) -> Result<Vec<LogEntry>, Error> {
+        let mut entries = vec![];
+        for seq in from..to {
+            let entry = self
+                .db
+                .store()
+                .get(KEY_LOG_TREE, &seq.to_be_bytes())?
+                .ok_or_else(|| format!("Key log has no entry {}", seq))?;
+            entries.push(self.db.decrypt_value(&entry)?);
+        }
+        Ok(entries)
+    }
+
+    /// The last entry before `before` binding `username` to `key`.
+    pub fn log_find(
+        &self,
+        username: &str,
+        key: &RsaPublicKey,
+        before: u64,
+    ) -> Result<Option<LogEntry>, Error> {
+        let mut found = None;
+        for (_, entry) in self.db.store().iter(KEY_LOG_TREE)? {
+            let entry: LogEntry = self.db.decrypt_value(&entry)?;
+            if entry.seq < before && entry.username == username && entry.pub_key == *key {
+                found = Some(entry);
+            }
+        }
+        Ok(found)
+    }
+
+    /// Queues `notification` for the recipient with `fingerprint`. Ids sort in the
+    /// order notifications were queued. Nothing is queued, and `None` returned, if it
+    /// would take the recipient's notifications that haven't outlived `ttl` past
+    /// `max_count` or `max_bytes`.
+    pub fn queue(
+        &self,
+        fingerprint: &str,
+        notification: &PendingNotification,
+        max_count: usize,
+        max_bytes: usize,
+        ttl: Duration,
+    ) -> Result<Option<String>, Error> {
+        let tree = pending_tree(fingerprint);
+        let sealed = self.db.encrypt_value(notification)?;
+        let (mut count, mut bytes) = (1, sealed.len());
+        for (_, entry) in self.db.store().iter(&tree)? {
+            let queued: PendingNotification = self.db.decrypt_value(&entry)?;
+            if !queued.is_expired(ttl) {
+                count += 1;
+                bytes += entry.len();
+            }
+        }
+        if count > max_count || bytes > max_bytes {
+            return Ok(None);
+        }
+        let queued_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let id = format!("{:039}-{}", queued_at, Uuid::new_v4());
+        self.db.store().insert(&tree, id.as_bytes(), &sealed)?;
+        Ok(Some(id))
+    }
+
+    /// Everything still queued for `fingerprint`, oldest first, leaving out
+    /// notifications older than `ttl`.
+    pub fn pending(
+        &self,
+        fingerprint: &str,
+        ttl: Duration,
+    ) -> Result<Vec<(String, PendingNotification)>, Error> {
+        let mut pending = vec![];
+        for (id, entry) in self.db.store().iter(&pending_tree(fingerprint))? {
+            let notification: PendingNotification = self.db.decrypt_value(&entry)?;
+            if !notification.is_expired(ttl) {
+                pending.push((String::from_utf8(id)?, notification));
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Removes the delivered notifications in `ids`, along with any of the recipient's
+    /// that expired, in a single batch so a crash leaves either all or none of them.
+    pub fn acknowledge(
+        &self,
+        fingerprint: &str,
+        ids: &[String],
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        let tree = pending_tree(fingerprint);
+        let mut batch = Batch::default();
+        for (id, entry) in self.db.store().iter(&tree)? {
+            let notification: PendingNotification = self.db.decrypt_value(&entry)?;
+            if notification.is_expired(ttl) || ids.iter().any(|acked| acked.as_bytes() == id) {
+                batch.remove(&tree, &id);
+            }
+        }
+        if !batch.is_empty() {
+            self.db.store().apply_batch(batch)?;
+        }
+        Ok(())
+    }
+
+    /// Keeps track of a forwarded message so its recipients can acknowledge it, and
+    /// returns the id they acknowledge it by.
+    pub fn record_sent(&self, message: &SentMessage) -> Result<String, Error> {
+        let id = Uuid::new_v4().to_string();
+        self.db
+            .store()
+            .insert(SENT_TREE, id.as_bytes(), &self.db.encrypt_value(message)?)?;
+        Ok(id)
+    }
+
+    /// Records that `recipient` got as far as `status` with the message `id`, and returns
+    /// the message if that's news for its sender. Messages `recipient` wasn't sent, and
+    /// acknowledgements behind one already recorded, change nothing.
+    pub fn record_ack(
+        &self,
+        id: &str,
+        recipient: &str,
+        status: AckStatus,
+    ) -> Result<Option<SentMessage>, Error> {
+        let entry = match self.db.store().get(SENT_TREE, id.as_bytes())? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let mut message: SentMessage = self.db.decrypt_value(&entry)?;
+        if !message.recipients.iter().any(|r| r == recipient) {
+            return Ok(None);
+        }
+        if message.acks.get(recipient).map_or(false, |acked| *acked >= status) {
+            return Ok(None);
+        }
+        message.acks.insert(recipient.to_string(), status);
+        self.db
+            .store()
+            .insert(SENT_TREE, id.as_bytes(), &self.db.encrypt_value(&message)?)?;
+        Ok(Some(message))
+    }
+
+    /// Drops every notification older than `ttl`, whoever it was queued for, along with
+    /// the records of messages sent before then. Returns the number of notifications
+    /// removed.
+    pub fn purge_expired(&self, ttl: Duration) -> Result<usize, Error> {
+        let mut batch = Batch::default();
+        for (id, entry) in self.db.store().iter(SENT_TREE)? {
+            let message: SentMessage = self.db.decrypt_value(&entry)?;
+            if message.is_expired(ttl) {
+                batch.remove(SENT_TREE, &id);
+            }
+        }
+        let mut purged = 0;
+        for tree in self.db.store().tree_names()? {
+            if !tree.starts_with(PENDING_TREE_PREFIX) {
+                continue;
+            }
+            for (id, entry) in self.db.store().iter(&tree)? {
+                let notification: PendingNotification = self.db.decrypt_value(&entry)?;
+                if notification.is_expired(ttl) {
+                    batch.remove(&tree, &id);
+                    purged += 1;
+                }
+            }
+        }
+        if !batch.is_empty() {
+            self.db.store().apply_batch(batch)?;
+        }
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::{pki, rpc::Request, rpc_models};
+
+    fn open(name: &str) -> ServerDatabase {
+        let path = std::env::temp_dir().join(format!("carapace-{}-{}", name, Uuid::new_v4()));
+        ServerDatabase::open(path, &pki::gen_key().unwrap(), &DbConfig::default())
+            .unwrap()
+    }
+
+    fn notification(recipient: &str) -> PendingNotification {
+        let request = Request::new(rpc_models::FORWARDED_MSG.to_string(), serde_json::json!(null));
+        PendingNotification::new(vec![recipient.to_string()], request)
+    }
+
+    #[test]
+    fn test_key_log() {
+        let store = open("key-log");
+        let alice = pki::gen_key().unwrap().to_public_key();
+        let bob = pki::gen_key().unwrap().to_public_key();
+        assert_eq!(store.log_head().unwrap(), (0, EMPTY_LOG_HASH.to_string()));
+        store.register_user("alice", &alice).unwrap();
+        store.register_user("bob", &bob).unwrap();
+        // nothing changed, so nothing is logged
+        store.register_user("bob", &bob).unwrap();
+        store.register_user("alice2", &alice).unwrap();
+
+        let (size, hash) = store.log_head().unwrap();
+        assert_eq!(size, 3);
+        let entries = store.log_entries(0, size).unwrap();
+        assert_eq!(entries[2].hash().unwrap(), hash);
+        assert_eq!(entries[1].prev_hash, entries[0].hash().unwrap());
+        // released names stay in the log
+        assert_eq!(store.log_find("alice", &alice, size).unwrap(), Some(entries[0].clone()));
+        assert_eq!(store.log_find("alice2", &alice, 2).unwrap(), None);
+        assert!(store.log_entries(2, 4).is_err());
+        assert_eq!(store.backfill_key_log().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_rotation_moves_username() {
+        let store = open("rotation");
+        let old = pki::gen_key().unwrap().to_public_key();
+        let new = pki::gen_key().unwrap().to_public_key();
+        store.add_authorized_key(&old).unwrap();
+        store.register_user("alice", &old).unwrap();
+
+        store.replace_authorized_key(&old, &new).unwrap();
+        assert_eq!(store.authorized_keys().unwrap(), vec![new.clone()]);
+        assert_eq!(store.revoked_keys().unwrap(), vec![old.clone()]);
+        assert_eq!(store.user("alice").unwrap(), Some(new.clone()));
+        assert_eq!(store.username_of(&old).unwrap(), None);
+        let (size, _) = store.log_head().unwrap();
+        assert_eq!(size, 2);
+        assert!(store.log_find("alice", &new, size).unwrap().is_some());
+
+        // a key without a username leaves the log alone
+        let unnamed = pki::gen_key().unwrap().to_public_key();
+        store.replace_authorized_key(&unnamed, &pki::gen_key().unwrap().to_public_key()).unwrap();
+        assert_eq!(store.log_head().unwrap().0, 2);
+    }
+
+    #[test]
+    fn test_backfill_key_log() {
+        let store = open("backfill");
+        let alice = pki::gen_key().unwrap().to_public_key();
+        let bob = pki::gen_key().unwrap().to_public_key();
+        store.register_user("alice", &alice).unwrap();
+        // registered before the server kept a log
+        store
+            .db
+            .store()
+            .insert(USERS_TREE, b"bob", &store.db.encrypt_value(&bob).unwrap())
+            .unwrap();
+        assert_eq!(store.log_find("bob", &bob, 1).unwrap(), None);
+
+        assert_eq!(store.backfill_key_log().unwrap(), 1);
+        let (size, hash) = store.log_head().unwrap();
+        assert_eq!(size, 2);
+        let entries = store.log_entries(0, size).unwrap();
+        assert_eq!(entries[1].prev_hash, entries[0].hash().unwrap());
+        assert_eq!(entries[1].hash().unwrap(), hash);
+        assert_eq!(store.log_find("bob", &bob, size).unwrap(), Some(entries[1].clone()));
+        assert_eq!(store.backfill_key_log().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_queue_and_acknowledge() {
+        let store = open("pending");
+        let ttl = Duration::from_secs(60);
+        let queue = |fingerprint: &str| {
+            let notification = notification(fingerprint);
+            store.queue(fingerprint, &notification, usize::MAX, usize::MAX, ttl).unwrap().unwrap()
+        };
+        let first = queue("alice");
+        let second = queue("alice");
+        queue("bob");
+
+        let pending = store.pending("alice", ttl).unwrap();
+        let ids: Vec<String> = pending.into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![first.clone(), second.clone()]);
+
+        // ids the recipient never saw, or that belong to someone else, are ignored
+        store
+            .acknowledge("alice", &[first, String::from("unknown")], ttl)
+            .unwrap();
+        let ids: Vec<String> = store
+            .pending("alice", ttl)
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(ids, vec![second]);
+        assert_eq!(store.pending("bob", ttl).unwrap().len(), 1);
+        assert!(store.pending("carol", ttl).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_queue_quota() {
+        let store = open("pending-quota");
+        let ttl = Duration::from_millis(150);
+        let queue = |fingerprint: &str, max_count, max_bytes| {
+            let notification = notification(fingerprint);
+            store.queue(fingerprint, &notification, max_count, max_bytes, ttl).unwrap()
+        };
+        assert!(queue("alice", 2, usize::MAX).is_some());
+        assert!(queue("alice", 2, usize::MAX).is_some());
+        assert!(queue("alice", 2, usize::MAX).is_none());
+        assert_eq!(store.pending("alice", ttl).unwrap().len(), 2);
+        // other recipients have their own
+        assert!(queue("bob", 2, usize::MAX).is_some());
+
+        // room for one notification, not two
+        let sealed = store.db.encrypt_value(&notification("carol")).unwrap().len();
+        assert!(queue("carol", usize::MAX, sealed * 3 / 2).is_some());
+        assert!(queue("carol", usize::MAX, sealed * 3 / 2).is_none());
+
+        // expired notifications don't count
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(queue("alice", 2, usize::MAX).is_some());
+    }
+
+    #[test]
+    fn test_pending_expiry() {
+        let store = open("pending-expiry");
+        let ttl = Duration::from_secs(60);
+        let queue = |fingerprint: &str| {
+            let notification = notification(fingerprint);
+            store.queue(fingerprint, &notification, usize::MAX, usize::MAX, ttl).unwrap().unwrap()
+        };
+        queue("alice");
+        queue("bob");
+        std::thread::sleep(Duration::from_millis(300));
+        queue("bob");
+
+        let ttl = Duration::from_millis(150);
+        assert_eq!(store.pending("alice", ttl).unwrap().len(), 0);
+        assert_eq!(store.pending("bob", ttl).unwrap().len(), 1);
+        assert_eq!(store.purge_expired(ttl).unwrap(), 2);
+        let long = Duration::from_secs(60);
+        assert_eq!(store.pending("alice", long).unwrap().len(), 0);
+        assert_eq!(store.pending("bob", long).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_record_ack() {
+        let store = open("acks");
+        let sent = SentMessage::new(String::from("alice"), vec![String::from("bob")]);
+        let id = store.record_sent(&sent).unwrap();
+
+        let acked = store.record_ack(&id, "bob", AckStatus::Delivered).unwrap().unwrap();
+        assert_eq!(acked.sender, "alice");
+        assert_eq!(acked.acks["bob"], AckStatus::Delivered);
+        // repeated, or behind what bob already acknowledged
+        assert!(store.record_ack(&id, "bob", AckStatus::Delivered).unwrap().is_none());
+        let acked = store.record_ack(&id, "bob", AckStatus::Read).unwrap().unwrap();
+        assert_eq!(acked.acks["bob"], AckStatus::Read);
+        assert!(store.record_ack(&id, "bob", AckStatus::Delivered).unwrap().is_none());
+        // only recipients can acknowledge
+        assert!(store.record_ack(&id, "carol", AckStatus::Read).unwrap().is_none());
+        assert!(store.record_ack("unknown", "bob", AckStatus::Read).unwrap().is_none());
+
+        // records go with the notifications once they expire
+        let old = store.record_sent(&sent).unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        let fresh = SentMessage::new(String::from("alice"), vec![String::from("bob")]);
+        let fresh = store.record_sent(&fresh).unwrap();
+        assert_eq!(store.purge_expired(Duration::from_millis(150)).unwrap(), 0);
+        assert!(store.record_ack(&old, "bob", AckStatus::Delivered).unwrap().is_none());
+        assert!(store.record_ack(&fresh, "bob", AckStatus::Delivered).unwrap().is_some());
+    }
+}
@@ -1,58 +1,458 @@
-use std::error::Error;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 
+use async_std::channel::{self, Sender};
 use async_std::sync::RwLock;
-use rsa::pkcs1v15::Signature;
-use rsa::RsaPublicKey;
+use async_std::task;
+use rand_core::OsRng;
+use rsa::{pkcs8::EncodePublicKey, RsaPublicKey};
 use uuid::Uuid;
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey};
 
-use crate::shared::{pki, ski};
-use crate::shared::rpc::{Handler, Request, Response, RpcError, RpcErrorCode};
+use crate::shared::{json, pki, ski};
+use crate::shared::rpc::{
+    Codec, Deadlines, Handler, MethodInfo, MethodRegistry, Request, Response, RpcError,
+    RpcErrorCode, StreamId, StreamInfo,
+};
 use crate::shared::models::EncryptionConfiguration;
-use crate::shared::rpc_models::{self, ClientEncryptionPackage, RespondClientChallenge, RespondServerChallenge};
+use crate::shared::transparency::SignedTreeHead;
+use crate::shared::rpc_models::{
+    self, Capabilities, Capability, RespondClientChallenge, RespondServerChallenge,
+};
+use crate::Error;
 
+use super::models::{PendingNotification, SentMessage};
+use super::rate_limit::RateLimiter;
+use super::session_key::{KeyState, KeyUsage};
 use super::Server;
 
+/// Senders that push requests to clients with an open session, keyed by the
+/// fingerprint of their key. Shared by every connection a handler serves.
+type PushSenders = Arc<Mutex<HashMap<String, Sender<Request>>>>;
+
+/// The error a failed handler answers with. Errors that already are rpc errors, e.g.
+/// parse limits, keep their code; the rest get the code closest to their kind.
+fn rpc_error(e: Error) -> RpcError {
+    let code = match e {
+        Error::Rpc { code, message } => return RpcError { message, code },
+        Error::Serialization(_) => RpcErrorCode::ParseError,
+        Error::Io(_) | Error::Db(_) => RpcErrorCode::InternalError,
+        #[cfg(feature = "sqlite")]
+        Error::Sqlite(_) => RpcErrorCode::InternalError,
+        _ => RpcErrorCode::InvalidRequest,
+    };
+    RpcError {
+        message: e.to_string(),
+        code,
+    }
+}
+
+/// What a session key that ran out is answered with, until the client rekeys.
+fn key_exhausted() -> RpcError {
+    RpcError {
+        message: String::from("Session key ran out, rekey to continue"),
+        code: RpcErrorCode::RekeyRequired,
+    }
+}
+
+/// Most streams a connection may hold at once. Opening another drops the oldest, so a
+/// client that never fetches can't pile up results.
+const MAX_OPEN_STREAMS: usize = 4;
+
+/// Results streamed to the connection's client, held until their last chunk has been
+/// fetched.
+#[derive(Default)]
+struct StreamRegistry {
+    streams: HashMap<String, Vec<u8>>,
+    // ids of `streams`, oldest first
+    order: VecDeque<String>,
+}
+impl StreamRegistry {
+    /// Holds `data` to be fetched in chunks of `chunk_bytes`.
+    fn open(&mut self, data: Vec<u8>, chunk_bytes: usize) -> StreamInfo {
+        if self.order.len() >= MAX_OPEN_STREAMS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.streams.remove(&oldest);
+            }
+        }
+        let id = Uuid::new_v4().to_string();
+        let info = StreamInfo {
+            stream_id: StreamId(id.clone()),
+            chunks: (data.len() + chunk_bytes - 1) / chunk_bytes,
+            total_bytes: data.len(),
+        };
+        self.streams.insert(id.clone(), data);
+        self.order.push_back(id);
+        info
+    }
+
+    /// Chunk `index` of a stream. The stream is let go once its last chunk is taken.
+    fn chunk(
+        &mut self,
+        id: &StreamId,
+        index: usize,
+        chunk_bytes: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let invalid = |message: String| Error::rpc(RpcErrorCode::InvalidParams, message);
+        let data = self
+            .streams
+            .get(&id.0)
+            .ok_or_else(|| invalid(format!("Unknown stream {}", id.0)))?;
+        let start = index
+            .checked_mul(chunk_bytes)
+            .filter(|start| *start < data.len())
+            .ok_or_else(|| invalid(format!("No chunk {} in the stream", index)))?;
+        let end = data.len().min(start + chunk_bytes);
+        let chunk = data[start..end].to_vec();
+        if end == data.len() {
+            self.streams.remove(&id.0);
+            self.order.retain(|open| *open != id.0);
+        }
+        Ok(chunk)
+    }
+}
+
+/// Wraps `request` in an `ENCRYPTED_REQUEST` under the session key, counting it
+/// against the key's `usage`.
+fn seal_notification(
+    request: Request,
+    encryption: &EncryptionConfiguration,
+    usage: &Mutex<KeyUsage>,
+) -> Result<Request, Error> {
+    let data = serde_json::to_vec(&request)?;
+    usage.lock().unwrap().record(data.len());
+    let data = encryption.seal(&data)?;
+    let params = rpc_models::EncryptedRequestParams {
+        enc_type: rpc_models::EncryptionType::AesGcm,
+        data,
+    };
+    Ok(Request::new_with_id(
+        rpc_models::ENCRYPTED_REQUEST.to_string(),
+        serde_json::json!(params),
+        request.id,
+    ))
+}
 
 #[derive(Clone)]
 pub struct ServerHandler {
     server: Arc<RwLock<Server>>,
     encryption: Option<EncryptionConfiguration>,
+    // what `encryption` has protected, replaced along with the key
+    key_usage: Arc<Mutex<KeyUsage>>,
     client_pub_key: Option<RsaPublicKey>,
-    pending_challenge: Option<String>,
+    /// Fingerprint of `client_pub_key` once a session is open.
+    session_fingerprint: Option<String>,
+    /// The challenge from `START_SERVER_HANDSHAKE` and when it was issued. Answerable
+    /// once, within `ServerConfig::handshake_timeout`.
+    pending_challenge: Option<(String, Instant)>,
+    /// Capabilities both the client and the server advertised for the session.
+    session_capabilities: Capabilities,
+    push_senders: PushSenders,
+    // feeds this connection's write task, and the sender registered for its session
+    outgoing: Option<Sender<Request>>,
+    session_push: Option<Sender<Request>>,
+    /// Whether the client sent `SUBSCRIBE` in this session. Survives a rekey.
+    subscribed: bool,
+    // set up with the first request when `ServerConfig::max_rps` is set
+    rate_limiter: Option<RateLimiter>,
+    // whether DEV_PLAINTEXT_SESSION is served, and whether this connection opened one
+    #[cfg(feature = "insecure-dev")]
+    allow_plaintext: bool,
+    #[cfg(feature = "insecure-dev")]
+    plaintext_session: bool,
+    methods: Arc<MethodRegistry<ServerHandler>>,
+    streams: Arc<Mutex<StreamRegistry>>,
 }
 impl ServerHandler {
     pub fn new(server: Arc<RwLock<Server>>) -> Self {
+        Self::with_methods(server, Self::builtin_methods())
+    }
+
+    /// Serves `methods` within sessions in place of the built-in ones. Start from
+    /// `builtin_methods` to add methods rather than replace them all.
+    pub fn with_methods(
+        server: Arc<RwLock<Server>>,
+        methods: MethodRegistry<ServerHandler>,
+    ) -> Self {
         ServerHandler {
             server,
             encryption: None,
+            key_usage: Arc::new(Mutex::new(KeyUsage::new())),
             client_pub_key: None,
+            session_fingerprint: None,
             pending_challenge: None,
+            session_capabilities: Capabilities::new(),
+            push_senders: Arc::new(Mutex::new(HashMap::new())),
+            outgoing: None,
+            session_push: None,
+            subscribed: false,
+            rate_limiter: None,
+            #[cfg(feature = "insecure-dev")]
+            allow_plaintext: false,
+            #[cfg(feature = "insecure-dev")]
+            plaintext_session: false,
+            methods: Arc::new(methods),
+            streams: Arc::new(Mutex::new(StreamRegistry::default())),
         }
     }
 
-    fn handle_get_encryption_package(&self, request: Request) -> Result<Response, Box<dyn Error>> {
-        let method = request.method.as_str();
-        if method == rpc_models::REQUEST_ENCRYPTION_PACKAGE {
-            if self.encryption.is_none() {
-                return Err("Encryption not initialized".into());
+    /// The methods every server serves. The handshake isn't among them, it's what
+    /// sets up the session the others are served in.
+    pub fn builtin_methods() -> MethodRegistry<ServerHandler> {
+        let mut methods = MethodRegistry::new();
+        methods.register(
+            rpc_models::GET_SERVER_INFO,
+            MethodInfo::PUBLIC,
+            |handler: Self, request| async move { handler.handle_get_server_info(request).await },
+        );
+        methods.register(
+            rpc_models::PING,
+            MethodInfo::SESSION,
+            |handler: Self, request| async move { handler.handle_ping(request) },
+        );
+        methods.register(
+            rpc_models::FORWARDED_MSG,
+            MethodInfo::SESSION,
+            |handler: Self, request| async move { handler.handle_forwarded_msg(request).await },
+        );
+        methods.register(
+            rpc_models::ACK_MESSAGE,
+            MethodInfo::ENCRYPTED,
+            |handler: Self, request| async move { handler.handle_ack_message(request).await },
+        );
+        methods.register(
+            rpc_models::GET_PENDING,
+            MethodInfo::SESSION,
+            |handler: Self, request| async move { handler.handle_get_pending(request).await },
+        );
+        methods.register(
+            rpc_models::KEY_ROTATION,
+            MethodInfo::SESSION,
+            |handler: Self, request| async move { handler.handle_key_rotation(request).await },
+        );
+        methods.register(
+            rpc_models::REGISTER_USER,
+            MethodInfo::SESSION,
+            |handler: Self, request| async move { handler.handle_register_user(request).await },
+        );
+        // the directory isn't handed out in the clear, not even in dev mode
+        methods.register(
+            rpc_models::LIST_USERS,
+            MethodInfo::ENCRYPTED,
+            |handler: Self, request| async move { handler.handle_list_users(request).await },
+        );
+        methods.register(
+            rpc_models::GET_USER_KEY,
+            MethodInfo::ENCRYPTED,
+            |handler: Self, request| async move { handler.handle_get_user_key(request).await },
+        );
+        methods.register(
+            rpc_models::FETCH_CHUNK,
+            MethodInfo::ENCRYPTED,
+            |handler: Self, request| async move { handler.handle_fetch_chunk(request).await },
+        );
+        for method in [
+            rpc_models::GET_LOG_HEAD,
+            rpc_models::GET_CONSISTENCY_PROOF,
+            rpc_models::GET_INCLUSION_PROOF,
+        ] {
+            methods.register(method, MethodInfo::SESSION, |handler: Self, request| async move {
+                handler.handle_key_log(request).await
+            });
+        }
+        methods
+    }
+
+    pub fn server(&self) -> &Arc<RwLock<Server>> {
+        &self.server
+    }
+
+    /// The key of the session's client, `None` before the handshake.
+    pub fn client_key(&self) -> Option<&RsaPublicKey> {
+        self.client_pub_key.as_ref()
+    }
+
+    /// Serves `DEV_PLAINTEXT_SESSION`. Use `start_insecure_dev_server`, which checks the
+    /// bind address, instead of calling this directly.
+    #[cfg(feature = "insecure-dev")]
+    pub(super) fn allow_plaintext_sessions(&mut self) {
+        self.allow_plaintext = true;
+    }
+
+    /// Marks the session established for whatever identity the client claims, without
+    /// a handshake or any encryption.
+    #[cfg(feature = "insecure-dev")]
+    async fn handle_dev_plaintext_session(&mut self, request: Request) -> Result<Response, Error> {
+        let params: rpc_models::DevPlaintextSessionParams = serde_json::from_value(request.params)?;
+        // the client sends no version, and plaintext sessions stay JSON anyway
+        let capabilities = rpc_models::capabilities_for(&self.server.read().await.capabilities(), 0);
+        self.session_capabilities = capabilities.intersection(&params.capabilities).copied().collect();
+        self.subscribed = false;
+        self.open_session(params.pub_key)?;
+        self.plaintext_session = true;
+        Ok(Response::new(serde_json::json!(capabilities), None, request.id))
+    }
+
+    /// Records `pub_key` as this connection's client, replacing any session opened
+    /// earlier on the connection. Pushes resume if the client had subscribed to them.
+    fn open_session(&mut self, pub_key: RsaPublicKey) -> Result<(), Error> {
+        let fingerprint = pki::fingerprint(&pub_key)?;
+        self.close_session();
+        self.session_fingerprint = Some(fingerprint);
+        self.client_pub_key = Some(pub_key);
+        if self.subscribed {
+            self.start_pushes();
+        }
+        Ok(())
+    }
+
+    /// Starts pushing requests to the session's client, sealed under the session key.
+    /// Takes a write task on the connection and `Push` advertised by both sides.
+    fn start_pushes(&mut self) {
+        let fingerprint = match self.session_fingerprint {
+            Some(ref fingerprint) => fingerprint.clone(),
+            None => return,
+        };
+        let outgoing = match self.outgoing {
+            Some(ref outgoing) if self.session_capabilities.contains(&Capability::Push) => Some(outgoing),
+            _ => None,
+        };
+        if let Some(outgoing) = outgoing {
+            let (push, pushed) = channel::unbounded::<Request>();
+            let outgoing = outgoing.clone();
+            let encryption = self.encryption.clone();
+            let usage = self.key_usage.clone();
+            task::spawn(async move {
+                while let Ok(request) = pushed.recv().await {
+                    let request = match encryption {
+                        Some(ref encryption) => match seal_notification(request, encryption, &usage) {
+                            Ok(request) => request,
+                            Err(e) => {
+                                eprintln!("Error: {}", e);
+                                continue;
+                            }
+                        },
+                        None => request,
+                    };
+                    if outgoing.send(request).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            self.push_senders.lock().unwrap().insert(fingerprint, push.clone());
+            self.session_push = Some(push);
+        }
+    }
+
+    /// Subscribes the session's client to pushes, e.g. of messages forwarded to it,
+    /// for as long as the connection lasts.
+    fn handle_subscribe(&mut self, request: Request) -> Result<Response, Error> {
+        if !self.session_capabilities.contains(&Capability::Push) {
+            Err(Error::rpc(
+                RpcErrorCode::MethodNotFound,
+                "Pushes weren't agreed on in the handshake",
+            ))?;
+        }
+        if !self.subscribed {
+            self.subscribed = true;
+            self.start_pushes();
+        }
+        Ok(Response::new(serde_json::json!(null), None, request.id))
+    }
+
+    /// Stops pushes to this connection's session, unless a newer connection of the same
+    /// client has taken over its sender.
+    fn close_session(&mut self) {
+        let fingerprint = self.session_fingerprint.take();
+        let push = self.session_push.take();
+        if let (Some(fingerprint), Some(push)) = (fingerprint, push) {
+            push.close();
+            let mut push_senders = self.push_senders.lock().unwrap();
+            if matches!(push_senders.get(&fingerprint), Some(sender) if sender.is_closed()) {
+                push_senders.remove(&fingerprint);
             }
+        }
+    }
 
-            if let Some(ref encryption) = self.encryption {
-                let package = ClientEncryptionPackage::new(
-                    encryption.nonce.clone(),
-                    encryption.shared_key.clone(),
-                );
-                return Ok(Response::new(serde_json::json!(package), None, request.id));
-            } else {
-                return Err("Encryption not initialized".into());
+    /// Sender for pushing requests to this connection's client, if it has a session
+    /// and the connection a write task.
+    pub fn push_sender(&self) -> Option<Sender<Request>> {
+        let fingerprint = self.session_fingerprint.as_ref()?;
+        self.push_senders.lock().unwrap().get(fingerprint).cloned()
+    }
+
+    /// Pushes `request` to the client with `fingerprint`. Returns whether it had a live
+    /// session to push to.
+    fn push_to(&self, fingerprint: &str, request: Request) -> bool {
+        let push = self.push_senders.lock().unwrap().get(fingerprint).cloned();
+        match push {
+            Some(push) => push.try_send(request).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Runs a registered method. `encrypted` and `authenticated` say whether the
+    /// request came sealed under the session key and from within a session.
+    async fn dispatch(&self, request: Request, encrypted: bool, authenticated: bool) -> Response {
+        let req_id = request.id.clone();
+        self.call_method(request, encrypted, authenticated)
+            .await
+            .unwrap_or_else(|e| Response::new(serde_json::json!(null), Some(rpc_error(e)), req_id))
+    }
+
+    async fn call_method(
+        &self,
+        request: Request,
+        encrypted: bool,
+        authenticated: bool,
+    ) -> Result<Response, Error> {
+        self.methods.check(&request.method, encrypted, authenticated)?;
+        if let Some(capability) = Capability::required_by(&request.method) {
+            if !self.server.read().await.capabilities().contains(&capability) {
+                Err(Error::rpc(
+                    RpcErrorCode::MethodNotFound,
+                    format!("{:?} is not enabled on this server", capability),
+                ))?;
             }
-        } else {
-            Err("Invalid method".into())
         }
+        self.methods.call(self.clone(), request, encrypted, authenticated).await
+    }
+
+    fn encrypt_notification(&self, request: Request) -> Result<Request, Error> {
+        let encryption = self
+            .encryption
+            .as_ref()
+            .ok_or("Encryption not initialized")?;
+        seal_notification(request, encryption, &self.key_usage)
     }
 
-    fn handle_ping(&self, request: Request) -> Result<Response, Box<dyn Error>> {
+    /// Pushes the encrypted `REVOKE_SESSION` notification to this connection's client
+    /// and forgets the session so no further encrypted requests are accepted on it.
+    /// Returns whether the connection was still there to push to.
+    pub fn revoke_session(
+        &mut self,
+        reason: rpc_models::RevocationReason,
+    ) -> Result<bool, Error> {
+        let params = rpc_models::RevokeSessionParams { reason };
+        let request = Request::new(
+            rpc_models::REVOKE_SESSION.to_string(),
+            serde_json::json!(params),
+        );
+        // sealed and queued while the session key is still around
+        let notification = self.encrypt_notification(request)?;
+        let pushed = match self.outgoing {
+            Some(ref outgoing) => outgoing.try_send(notification).is_ok(),
+            None => false,
+        };
+        self.close_session();
+        self.encryption = None;
+        self.client_pub_key = None;
+        self.pending_challenge = None;
+        Ok(pushed)
+    }
+
+    fn handle_ping(&self, request: Request) -> Result<Response, Error> {
         let method = request.method.as_str();
         if method == rpc_models::PING {
             Ok(Response::new(serde_json::json!("pong"), None, request.id))
@@ -61,97 +461,585 @@ impl ServerHandler {
         }
     }
 
-    fn handle_forwarded_msg(&self, request: Request) -> Result<Response, Box<dyn Error>>{
+    async fn handle_fetch_chunk(&self, request: Request) -> Result<Response, Error> {
+        let params: rpc_models::FetchChunkParams = serde_json::from_value(request.params)?;
+        let chunk_bytes = self.stream_chunk_bytes().await;
+        let data = self
+            .streams
+            .lock()
+            .unwrap()
+            .chunk(&params.stream_id, params.chunk_index, chunk_bytes)?;
+        let result = rpc_models::FetchChunkResult { data };
+        Ok(Response::new(serde_json::json!(result), None, request.id))
+    }
+
+    async fn stream_chunk_bytes(&self) -> usize {
+        self.server.read().await.config.stream_chunk_bytes.max(1)
+    }
+
+    /// Swaps a successful result over `stream_threshold_bytes` for a `StreamInfo`, and
+    /// holds the result until the client has fetched it.
+    async fn stream_result(&self, mut response: Response) -> Result<Response, Error> {
+        let threshold = self.server.read().await.config.stream_threshold_bytes;
+        if response.error.is_some() {
+            return Ok(response);
+        }
+        let data = serde_json::to_vec(&response.result)?;
+        if data.len() <= threshold {
+            return Ok(response);
+        }
+        let chunk_bytes = self.stream_chunk_bytes().await;
+        response.stream = Some(self.streams.lock().unwrap().open(data, chunk_bytes));
+        response.result = serde_json::json!(null);
+        Ok(response)
+    }
+
+    async fn handle_get_server_info(&self, request: Request) -> Result<Response, Error> {
         let method = request.method.as_str();
-        if method == rpc_models::FORWARDED_MSG {
-            let msg: rpc_models::ForwardedMessageParams = serde_json::from_value(request.params)?;
-            Ok(Response::new(serde_json::json!(msg), None, request.id))
+        if method == rpc_models::GET_SERVER_INFO {
+            let params: Option<rpc_models::ServerInfoParams> = if request.params.is_null() {
+                None
+            } else {
+                Some(serde_json::from_value(request.params)?)
+            };
+            let server = self.server.read().await;
+            let challenge_signature = match params {
+                Some(params) => pki::sign_message(
+                    &server.private_key,
+                    &rpc_models::ServerInfo::challenge_signed_data(&params.challenge),
+                ),
+                None => Vec::new(),
+            };
+            let info = rpc_models::ServerInfo {
+                pub_key: server.private_key.to_public_key(),
+                open_registration: server.config.open_registration,
+                max_message_bytes: server.config.max_message_bytes,
+                // asked before any version is known, so only what every client parses
+                capabilities: rpc_models::capabilities_for(&server.capabilities(), 0),
+                challenge_signature,
+            };
+            Ok(Response::new(serde_json::json!(info), None, request.id))
         } else {
             Err("Invalid method".into())
         }
     }
 
-    async fn handle_encrypted_request(&mut self, request: Request) -> Result<Response, Box<dyn Error>> {
+    async fn handle_forwarded_msg(&self, request: Request) -> Result<Response, Error>{
         let method = request.method.as_str();
-        let req_id = request.id.clone();
-        if method == rpc_models::ENCRYPTED_REQUEST {
-            if self.encryption.is_none() || self.client_pub_key.is_none() {
-                return Err("Encryption not initialized".into());
+        if method == rpc_models::FORWARDED_MSG {
+            let msg: rpc_models::ForwardedMessageParams =
+                serde_json::from_value(request.params.clone())?;
+            let server = self.server.read().await;
+            let max_message_bytes = server.config.max_message_bytes;
+            if msg.data.len() > max_message_bytes {
+                return Ok(Response::new(
+                    serde_json::json!(null),
+                    Some(RpcError {
+                        message: format!(
+                            "Message of {} bytes exceeds the limit of {} bytes",
+                            msg.data.len(),
+                            max_message_bytes
+                        ),
+                        code: RpcErrorCode::PayloadTooLarge,
+                    }),
+                    request.id,
+                ));
             }
-            let enc_params: rpc_models::EncryptedRequestParams =
-                serde_json::from_value(request.params)?;
-            let data = enc_params.data;
-            let enc_type = enc_params.enc_type;
-            let request = match enc_type {
-                rpc_models::EncryptionType::RsaPkcs1v15 => {
-                    let data = pki::decrypt_message(&self.server.read().await.private_key, &data)?;
-                    let request: Request = serde_json::from_slice(&data)?;
-                    request
-                }
-                rpc_models::EncryptionType::AesGcm => {
-                    let key = &self.encryption.as_ref().unwrap().shared_key;
-                    let nonce = &self.encryption.as_ref().unwrap().nonce;
-                    let data = ski::decrypt_gcm(&data, key, nonce)?;
-                    let request: Request = serde_json::from_slice(&data)?;
-                    request
+            // kept for the recipients' acknowledgements to find their way back
+            let message_id = match (&server.db, &self.session_fingerprint) {
+                (Some(db), Some(sender)) => {
+                    let sent = SentMessage::new(sender.clone(), msg.recipients.clone());
+                    Some(db.record_sent(&sent)?)
                 }
+                _ => None,
+            };
+            let recipients = msg.recipients.clone();
+            let params = rpc_models::ForwardedMessageParams {
+                message_id: message_id.clone(),
+                ..msg
             };
-            let error_handler = |e: Box<dyn Error>| {
+            // pushed to recipients with a live session, queued for the others
+            let notification = Request::new_with_id(
+                rpc_models::FORWARDED_MSG.to_string(),
+                serde_json::json!(params),
+                request.id.clone(),
+            );
+            let offline: Vec<&String> = recipients
+                .iter()
+                .filter(|recipient| !self.push_to(recipient, notification.clone()))
+                .collect();
+            let mut receipt = rpc_models::ForwardReceipt {
+                relayed_at: SystemTime::now(),
+                delivered: recipients.len() - offline.len(),
+                queued: 0,
+                refused: Vec::new(),
+                message_id,
+            };
+            if let Some(ref db) = server.db {
+                if !offline.is_empty() {
+                    let notification = PendingNotification::new(recipients.clone(), notification);
+                    let config = &server.config;
+                    for recipient in offline {
+                        let queued = db.queue(
+                            recipient,
+                            &notification,
+                            config.max_pending,
+                            config.max_pending_bytes,
+                            config.pending_ttl,
+                        )?;
+                        match queued {
+                            Some(_) => receipt.queued += 1,
+                            None => receipt.refused.push(recipient.clone()),
+                        }
+                    }
+                }
+            }
+            if receipt.delivered == 0 && receipt.queued == 0 && !receipt.refused.is_empty() {
+                Err(Error::rpc(
+                    RpcErrorCode::ServerError,
+                    "The recipients have too much waiting for them already",
+                ))?;
+            }
+            Ok(Response::new(serde_json::json!(receipt), None, request.id))
+        } else {
+            Err("Invalid method".into())
+        }
+    }
+
+    /// Records a recipient's acknowledgement of a message and pushes it on to the
+    /// message's sender, if they're connected. An acknowledgement that tells the sender
+    /// nothing new isn't passed on.
+    async fn handle_ack_message(&self, request: Request) -> Result<Response, Error> {
+        let method = request.method.as_str();
+        if method == rpc_models::ACK_MESSAGE {
+            let fingerprint = self
+                .session_fingerprint
+                .as_ref()
+                .ok_or("Session not established")?;
+            let params: rpc_models::AckParams = serde_json::from_value(request.params)?;
+            let server = self.server.read().await;
+            let db = server.db.as_ref().ok_or_else(|| {
+                Error::rpc(
+                    RpcErrorCode::MethodNotFound,
+                    "This server keeps no record of forwarded messages",
+                )
+            })?;
+            if let Some(sent) = db.record_ack(&params.message_id, fingerprint, params.status)? {
+                let ack = rpc_models::MessageAck {
+                    message_id: params.message_id,
+                    status: params.status,
+                    recipient: fingerprint.clone(),
+                };
+                let notification =
+                    Request::new(rpc_models::ACK_MESSAGE.to_string(), serde_json::json!(ack));
+                self.push_to(&sent.sender, notification);
+            }
+            Ok(Response::new(serde_json::json!(null), None, request.id))
+        } else {
+            Err("Invalid method".into())
+        }
+    }
+
+    /// Authorizes the client's new key in place of the one this session was opened
+    /// with, once the old key has signed off on it, and rebinds the client's username to
+    /// it. The session itself carries on.
+    async fn handle_key_rotation(&self, request: Request) -> Result<Response, Error> {
+        let method = request.method.as_str();
+        if method == rpc_models::KEY_ROTATION {
+            let old_pub_key = self
+                .client_pub_key
+                .as_ref()
+                .ok_or("Session not established")?;
+            let params: rpc_models::KeyRotationParams = serde_json::from_value(request.params)?;
+            if !pki::verify_key_rotation(
+                old_pub_key,
+                &params.new_pub_key,
+                &params.signature_over_new_key,
+            ) {
+                return Err(Error::Auth(String::from("Invalid key rotation signature")));
+            }
+            self.server
+                .write()
+                .await
+                .rotate_authorized_key(old_pub_key, &params.new_pub_key)?;
+            Ok(Response::new(serde_json::json!(null), None, request.id))
+        } else {
+            Err("Invalid method".into())
+        }
+    }
+
+    /// Binds a username to the key this session was opened with.
+    async fn handle_register_user(&self, request: Request) -> Result<Response, Error> {
+        let method = request.method.as_str();
+        if method == rpc_models::REGISTER_USER {
+            let pub_key = self
+                .client_pub_key
+                .as_ref()
+                .ok_or("Session not established")?;
+            let params: rpc_models::RegisterUserParams = serde_json::from_value(request.params)?;
+            let username = params.username;
+            let invalid_params = |message: String| {
                 Response::new(
                     serde_json::json!(null),
                     Some(RpcError {
-                        message: String::from(e.to_string()),
-                        code: RpcErrorCode::InvalidRequest,
+                        message,
+                        code: RpcErrorCode::InvalidParams,
                     }),
-                    req_id.clone(),
+                    request.id.clone(),
                 )
             };
-            let response = match request.method.as_str() {
-                rpc_models::REQUEST_ENCRYPTION_PACKAGE => self
-                    .handle_get_encryption_package(request)
-                    .unwrap_or_else(error_handler),
-                rpc_models::PING => self.handle_ping(request).unwrap_or_else(error_handler),
-                _ => Response::new(
-                    serde_json::json!(null),
-                    Some(RpcError {
-                        message: String::from("Invalid rpc method"),
-                        code: RpcErrorCode::MethodNotFound,
-                    }),
-                    req_id.clone(),
-                ),
+            if username.trim().is_empty()
+                || username.chars().count() > rpc_models::MAX_USERNAME_LEN
+                || username.chars().any(char::is_control)
+            {
+                return Ok(invalid_params(format!(
+                    "Usernames must be 1 to {} characters, without control characters",
+                    rpc_models::MAX_USERNAME_LEN
+                )));
+            }
+            // held for writing so two sessions can't claim the same name at once
+            let server = self.server.write().await;
+            let db = server
+                .db
+                .as_ref()
+                .ok_or("Server has no database to register users in")?;
+            match db.user(&username)? {
+                Some(ref registered) if registered != pub_key => {
+                    return Ok(invalid_params(format!(
+                        "Username {} is already taken",
+                        username
+                    )));
+                }
+                _ => db.register_user(&username, pub_key)?,
+            }
+            Ok(Response::new(serde_json::json!(null), None, request.id))
+        } else {
+            Err("Invalid method".into())
+        }
+    }
+
+    async fn handle_list_users(&self, request: Request) -> Result<Response, Error> {
+        let method = request.method.as_str();
+        if method == rpc_models::LIST_USERS {
+            let server = self.server.read().await;
+            let users = match server.db {
+                Some(ref db) => db.users()?,
+                None => vec![],
+            };
+            Ok(Response::new(serde_json::json!(users), None, request.id))
+        } else {
+            Err("Invalid method".into())
+        }
+    }
+
+    async fn handle_get_user_key(&self, request: Request) -> Result<Response, Error> {
+        let method = request.method.as_str();
+        if method == rpc_models::GET_USER_KEY {
+            let params: rpc_models::GetUserKeyParams = serde_json::from_value(request.params)?;
+            let server = self.server.read().await;
+            let pub_key = match server.db {
+                Some(ref db) => db.user(&params.username)?,
+                None => None,
+            };
+            let pub_key = match pub_key {
+                Some(pub_key) => pub_key,
+                None => {
+                    return Ok(Response::new(
+                        serde_json::json!(null),
+                        Some(RpcError {
+                            message: format!("Unknown user {}", params.username),
+                            code: RpcErrorCode::InvalidParams,
+                        }),
+                        request.id,
+                    ))
+                }
             };
+            let user_key = rpc_models::UserKey {
+                username: params.username,
+                pub_key: pub_key.to_public_key_pem(pki::get_line_ending())?,
+            };
+            Ok(Response::new(serde_json::json!(user_key), None, request.id))
+        } else {
+            Err("Invalid method".into())
+        }
+    }
 
-            let enc_response = match enc_type {
-                rpc_models::EncryptionType::RsaPkcs1v15 => {
-                    let data = serde_json::json!(&response);
-                    let data = pki::encrypt_message(
-                        &self.client_pub_key.as_ref().unwrap(),
-                        data.to_string().as_bytes(),
-                    )?;
-                    data
+    /// Serves the key log: its signed head, and the entries proving that one head
+    /// extends another or that a binding is part of the log.
+    async fn handle_key_log(&self, request: Request) -> Result<Response, Error> {
+        let server = self.server.read().await;
+        let db = server.db.as_ref().ok_or("Server has no key log")?;
+        let (size, hash) = db.log_head()?;
+        let result = match request.method.as_str() {
+            rpc_models::GET_LOG_HEAD => {
+                serde_json::json!(SignedTreeHead::sign(size, hash, &server.private_key))
+            }
+            rpc_models::GET_CONSISTENCY_PROOF => {
+                let params: rpc_models::ConsistencyProofParams =
+                    serde_json::from_value(request.params)?;
+                if params.from_size > params.to_size || params.to_size > size {
+                    Err(format!(
+                        "No consistency proof from {} to {} entries, the log has {}",
+                        params.from_size, params.to_size, size
+                    ))?;
                 }
-                rpc_models::EncryptionType::AesGcm => {
-                    let data = serde_json::json!(&response);
-                    let key = &self.encryption.as_ref().unwrap().shared_key;
-                    let nonce = &self.encryption.as_ref().unwrap().nonce;
-                    let data = ski::encrypt_gcm(data.to_string().as_bytes(), key, nonce)?;
-                    data
+                serde_json::json!(db.log_entries(params.from_size, params.to_size)?)
+            }
+            rpc_models::GET_INCLUSION_PROOF => {
+                let params: rpc_models::InclusionProofParams =
+                    serde_json::from_value(request.params)?;
+                if params.tree_size > size {
+                    Err(format!("The log only has {} entries", size))?;
                 }
+                let entry = db
+                    .log_find(&params.username, &params.pub_key, params.tree_size)?
+                    .ok_or_else(|| format!("{}'s key is not in the log", params.username))?;
+                serde_json::json!(db.log_entries(entry.seq, params.tree_size)?)
+            }
+            _ => Err("Invalid method")?,
+        };
+        Ok(Response::new(result, None, request.id))
+    }
+
+    /// Hands out what was queued for this session's client while it was offline, after
+    /// dropping the notifications it acknowledged.
+    async fn handle_get_pending(&self, request: Request) -> Result<Response, Error> {
+        let method = request.method.as_str();
+        if method == rpc_models::GET_PENDING {
+            let fingerprint = self
+                .session_fingerprint
+                .as_ref()
+                .ok_or("Session not established")?;
+            let params: rpc_models::GetPendingParams = if request.params.is_null() {
+                rpc_models::GetPendingParams::default()
+            } else {
+                serde_json::from_value(request.params)?
             };
+            let server = self.server.read().await;
+            let mut deliveries = vec![];
+            if let Some(ref db) = server.db {
+                let ttl = server.config.pending_ttl;
+                db.acknowledge(fingerprint, &params.acknowledged, ttl)?;
+                for (id, notification) in db.pending(fingerprint, ttl)? {
+                    deliveries.push(rpc_models::PendingDelivery {
+                        id,
+                        notification: notification.into_notification(),
+                    });
+                }
+            }
+            Ok(Response::new(serde_json::json!(deliveries), None, request.id))
+        } else {
+            Err("Invalid method".into())
+        }
+    }
 
+    async fn handle_encrypted_request(&mut self, request: Request) -> Result<Response, Error> {
+        let method = request.method.as_str();
+        let req_id = request.id.clone();
+        if method == rpc_models::ENCRYPTED_REQUEST {
+            if self.encryption.is_none() || self.client_pub_key.is_none() {
+                // answered in plaintext since there is no key to encrypt with
+                return Ok(Response::new(
+                    serde_json::json!(null),
+                    Some(RpcError {
+                        message: String::from("Encryption not initialized"),
+                        code: RpcErrorCode::SessionNotEstablished,
+                    }),
+                    req_id,
+                ));
+            }
+            let enc_params: rpc_models::EncryptedRequestParams =
+                serde_json::from_value(request.params)?;
+            let data = enc_params.data;
+            let enc_type = enc_params.enc_type;
+            enc_type.check_supported()?;
+            if matches!(enc_type, rpc_models::EncryptionType::AesGcm) {
+                return self.handle_session_request(&data, req_id).await;
+            }
+            if matches!(enc_type, rpc_models::EncryptionType::BatchedAesGcm) {
+                return self.handle_session_batch(&data, req_id).await;
+            }
+            let data = pki::decrypt_message(&self.server.read().await.private_key, &data)?;
+            let request: Request = json::from_slice(&data)?;
+            let response = self.dispatch(request, true, true).await;
+            let data = serde_json::json!(&response);
+            let enc_response = pki::encrypt_message(
+                &self.client_pub_key.as_ref().unwrap(),
+                data.to_string().as_bytes(),
+            )?;
             Ok(Response::new(serde_json::json!(enc_response), None, req_id))
         } else {
             Err("Invalid method".into())
         }
     }
+
+    /// Serves a request sealed under the session key, as long as the key is within the
+    /// server's `SessionKeyPolicy`. A key that ran out is only good for `REKEY` until
+    /// the grace window ends, after which the session is dropped.
+    async fn handle_session_request(
+        &mut self,
+        data: &[u8],
+        req_id: String,
+    ) -> Result<Response, Error> {
+        let usage = self.key_usage.clone();
+        let state = match self.session_key_state().await {
+            Ok(state) => state,
+            Err(error) => return Ok(Response::new(serde_json::json!(null), Some(error), req_id)),
+        };
+        // the response goes out under the key the request came in with, even a REKEY's
+        let encryption = self.encryption.clone().ok_or("Encryption not initialized")?;
+        let data = ski::open_gcm(data, &encryption.shared_key)?;
+        usage.lock().unwrap().record(data.len());
+        let request: Request = json::from_slice(&data)?;
+        let response = if request.method == rpc_models::SUBSCRIBE && state == KeyState::Fresh {
+            let id = request.id.clone();
+            self.handle_subscribe(request)
+                .unwrap_or_else(|e| Response::new(serde_json::json!(null), Some(rpc_error(e)), id))
+        } else if request.method == rpc_models::REKEY {
+            let id = request.id.clone();
+            self.handle_rekey(request).await.unwrap_or_else(|e| {
+                Response::new(
+                    serde_json::json!(null),
+                    Some(RpcError {
+                        message: e.to_string(),
+                        code: RpcErrorCode::InvalidParams,
+                    }),
+                    id,
+                )
+            })
+        } else if state == KeyState::Exhausted {
+            // answered in plaintext like other session errors, there's nothing to hide
+            return Ok(Response::new(serde_json::json!(null), Some(key_exhausted()), req_id));
+        } else if request.stream {
+            let response = self.dispatch(request, true, true).await;
+            self.stream_result(response).await?
+        } else {
+            self.dispatch(request, true, true).await
+        };
+        let data = serde_json::json!(&response).to_string();
+        usage.lock().unwrap().record(data.len());
+        let enc_response = encryption.seal(data.as_bytes())?;
+        Ok(Response::new(serde_json::json!(enc_response), None, req_id))
+    }
+
+    /// Serves a batch of requests sealed under the session key as one, all at once.
+    /// The responses go back in the order of the requests, sealed the same way.
+    async fn handle_session_batch(
+        &mut self,
+        data: &[u8],
+        req_id: String,
+    ) -> Result<Response, Error> {
+        let usage = self.key_usage.clone();
+        let error = match self.session_key_state().await {
+            Ok(KeyState::Fresh) => None,
+            // a batch can't carry the REKEY an exhausted key is still good for
+            Ok(_) => Some(key_exhausted()),
+            Err(error) => Some(error),
+        };
+        if let Some(error) = error {
+            return Ok(Response::new(serde_json::json!(null), Some(error), req_id));
+        }
+        let encryption = self.encryption.clone().ok_or("Encryption not initialized")?;
+        let data = ski::open_gcm(data, &encryption.shared_key)?;
+        usage.lock().unwrap().record(data.len());
+        let requests: Vec<Request> = json::from_slice(&data)?;
+        if requests.len() > rpc_models::MAX_BATCH_REQUESTS {
+            Err(Error::rpc(
+                RpcErrorCode::InvalidRequest,
+                format!("Batches hold at most {} requests", rpc_models::MAX_BATCH_REQUESTS),
+            ))?;
+        }
+        let responses = futures::future::join_all(
+            requests.into_iter().map(|request| self.dispatch_batched(request)),
+        )
+        .await;
+        let data = serde_json::to_vec(&responses)?;
+        usage.lock().unwrap().record(data.len());
+        let enc_response = encryption.seal(&data)?;
+        Ok(Response::new(serde_json::json!(enc_response), None, req_id))
+    }
+
+    /// Runs one request of a batch. Requests that change the session aren't served in
+    /// one, since the rest of the batch runs in that session.
+    async fn dispatch_batched(&self, request: Request) -> Response {
+        if request.method == rpc_models::SUBSCRIBE || request.method == rpc_models::REKEY {
+            let error = Error::rpc(
+                RpcErrorCode::InvalidRequest,
+                format!("{} can't be batched", request.method),
+            );
+            return Response::new(serde_json::json!(null), Some(rpc_error(error)), request.id);
+        }
+        self.dispatch(request, true, true).await
+    }
+
+    /// How the session key stands under the server's `SessionKeyPolicy`. A key past
+    /// its grace window is dropped, and the error to answer with returned instead.
+    async fn session_key_state(&mut self) -> Result<KeyState, RpcError> {
+        let policy = self.server.read().await.config.session_keys.clone();
+        let state = self.key_usage.lock().unwrap().check(&policy);
+        if state == KeyState::Expired {
+            self.expire_session_key().await;
+            return Err(RpcError {
+                message: String::from("Session key expired"),
+                code: RpcErrorCode::SessionNotEstablished,
+            });
+        }
+        Ok(state)
+    }
+
+    /// Switches the session over to a key agreed with the client's ephemeral key and a
+    /// fresh one of ours. Pushes follow once they're sealed under it.
+    async fn handle_rekey(&mut self, request: Request) -> Result<Response, Error> {
+        let params: rpc_models::RekeyParams = serde_json::from_value(request.params)?;
+        let pub_key = self.client_pub_key.clone().ok_or("Session not established")?;
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_key = X25519PublicKey::from(&secret);
+        let shared_secret = secret.diffie_hellman(&params.ephemeral_key);
+        if !shared_secret.was_contributory() {
+            return Err("Ephemeral key is of low order".into());
+        }
+        let (new_key, _) = ski::derive_session_key(shared_secret.as_bytes())?;
+        let server = self.server.read().await;
+        let answer = rpc_models::RekeyAnswer {
+            ephemeral_key,
+            ephemeral_signature: pki::sign_message(
+                &server.private_key,
+                &rpc_models::RekeyAnswer::signed_data(&params.ephemeral_key, &ephemeral_key),
+            ),
+        };
+        if self.key_usage.lock().unwrap().is_exhausted() {
+            server.metrics.forced_rekey();
+        }
+        drop(server);
+        self.encryption = Some(EncryptionConfiguration::new(new_key));
+        self.key_usage = Arc::new(Mutex::new(KeyUsage::new()));
+        self.open_session(pub_key)?;
+        Ok(Response::new(serde_json::json!(answer), None, request.id))
+    }
+
+    /// Drops a session key that outlived its grace window, so the client has to
+    /// handshake again. The key is zeroized once the push task lets go of its copy too.
+    async fn expire_session_key(&mut self) {
+        self.close_session();
+        self.subscribed = false;
+        self.encryption = None;
+        self.client_pub_key = None;
+        self.server.read().await.metrics.expired_session_key();
+    }
     fn handle_start_server_handshake(
         &mut self,
         request: Request,
-    ) -> Result<Response, Box<dyn Error>> {
+    ) -> Result<Response, Error> {
         let method = request.method.as_str();
         if method == rpc_models::START_SERVER_HANDSHAKE {
+            if self.encryption.is_some() {
+                return Err(Error::rpc(
+                    RpcErrorCode::InvalidRequest,
+                    "A session is already established on this connection",
+                ));
+            }
             let challenge = Uuid::new_v4().to_string();
-            self.pending_challenge = Some(challenge.clone());
+            self.pending_challenge = Some((challenge.clone(), Instant::now()));
             Ok(Response::new(
                 serde_json::json!(challenge),
                 None,
@@ -161,34 +1049,119 @@ impl ServerHandler {
             Err("Invalid method".into())
         }
     }
-    async fn handle_challenge_response(&mut self, request: Request) -> Result<Response, Box<dyn Error>> {
+    async fn handle_challenge_response(&mut self, request: Request) -> Result<Response, Error> {
         let method = request.method.as_str();
         if method == rpc_models::CLIENT_CHALLENGE_RESPONSE {
-            let response: RespondClientChallenge = serde_json::from_value(request.params)?;
-            if self.pending_challenge.is_none() {
-                return Err("No pending challenge".into());
+            // answered once, a replay or a retry after a failure needs a new challenge
+            let (challenge, issued) = match self.pending_challenge.take() {
+                Some(pending) => pending,
+                None => return Err("No pending challenge".into()),
+            };
+            if issued.elapsed() > self.server.read().await.config.handshake_timeout {
+                return Err(Error::Auth(String::from(
+                    "Challenge expired, start the handshake again",
+                )));
             }
-            let sig = Signature::try_from(response.signiture.as_slice())?;
-            if !pki::verify_signature(
+            let response: RespondClientChallenge = serde_json::from_value(request.params)?;
+            let client_transcript = response.transcript(&challenge)?;
+            let legacy = response.protocol_version < rpc_models::TRANSCRIPT_PROTOCOL_VERSION;
+            let (signed, signature) = if legacy {
+                if !self.server.read().await.config.allow_legacy_handshake {
+                    return Err(Error::Auth(format!(
+                        "Challenge signature doesn't cover the handshake, upgrade to protocol version {}",
+                        rpc_models::TRANSCRIPT_PROTOCOL_VERSION
+                    )));
+                }
+                (challenge.as_bytes(), &response.signiture)
+            } else {
+                (client_transcript.as_slice(), &response.transcript_signature)
+            };
+            if !pki::verify_handshake_signature(
+                response.key_type,
                 &response.pub_key,
-                self.pending_challenge.as_ref().unwrap().as_bytes(),
-                &sig,
+                response.signing_key.as_ref(),
+                &response.signing_key_binding,
+                signed,
+                signature,
             ) {
-                return Err("Invalid signature".into());
+                return Err(Error::Auth(String::from("Invalid signature")));
             }
-            let nonce = ski::nonce();
-            let shared_key = ski::gen_key();
-            self.encryption = Some(EncryptionConfiguration {
-                shared_key: shared_key.clone(),
-                nonce: nonce.clone(),
-            });
-            self.client_pub_key = Some(response.pub_key.clone());
+            let client_ephemeral = response.ephemeral_key.ok_or_else(|| {
+                format!(
+                    "No ephemeral key sent, upgrade to protocol version {} for forward secrecy",
+                    rpc_models::ECDH_PROTOCOL_VERSION
+                )
+            })?;
+            // a refused client gets no session, so later encrypted requests fail too
+            self.server.write().await.authorize(&response.pub_key)?;
+            // the secret is consumed here, once it's dropped the session key can't be rebuilt
+            let secret = EphemeralSecret::random_from_rng(OsRng);
+            let ephemeral_key = X25519PublicKey::from(&secret);
+            let shared_secret = secret.diffie_hellman(&client_ephemeral);
+            if !shared_secret.was_contributory() {
+                return Err("Ephemeral key is of low order".into());
+            }
+            let (shared_key, nonce) = ski::derive_session_key(shared_secret.as_bytes())?;
+            let encryption = EncryptionConfiguration::new(shared_key);
+            // an older client refuses the whole answer over a capability it doesn't know
+            let capabilities = rpc_models::capabilities_for(
+                &self.server.read().await.capabilities(),
+                response.protocol_version,
+            );
+            let mut client_capabilities = response.capabilities.clone();
+            client_capabilities.extend(rpc_models::implied_capabilities(response.protocol_version));
+            self.session_capabilities =
+                capabilities.intersection(&client_capabilities).copied().collect();
             let server_challenge = response.server_challenge.clone();
-            let response = RespondServerChallenge {
-                pub_key: self.server.read().await.private_key.to_public_key(),
-                signiture: pki::sign_message(&self.server.read().await.private_key, server_challenge.as_bytes()),
+            // the derived nonce is only ever used for this, messages get random ones
+            let key_confirmation =
+                ski::encrypt_gcm(server_challenge.as_bytes(), &encryption.shared_key, &nonce)?;
+            self.encryption = Some(encryption);
+            self.key_usage = Arc::new(Mutex::new(KeyUsage::new()));
+            self.subscribed = false;
+            self.open_session(response.pub_key.clone())?;
+            let server = self.server.read().await;
+            let (key_type, signing_key) = pki::handshake_key(server.ed25519_key.as_ref());
+            let ephemeral_signature = pki::sign_message(
+                &server.private_key,
+                &RespondServerChallenge::ephemeral_signed_data(
+                    &server_challenge,
+                    &client_ephemeral,
+                    &ephemeral_key,
+                ),
+            );
+            let mut answer = RespondServerChallenge {
+                pub_key: server.private_key.to_public_key(),
+                signiture: Vec::new(),
+                key_type,
+                signing_key,
+                signing_key_binding: pki::bind_signing_key(
+                    &server.private_key,
+                    server.ed25519_key.as_ref(),
+                ),
+                protocol_version: rpc_models::PROTOCOL_VERSION,
+                ephemeral_key: Some(ephemeral_key),
+                ephemeral_signature,
+                key_confirmation,
+                max_message_bytes: server.config.max_message_bytes,
+                capabilities,
+                transcript_signature: Vec::new(),
             };
-            Ok(Response::new(serde_json::json!(response), None, request.id))
+            // the bare challenge is whatever the client picked, so it's only signed for
+            // clients that can't check the transcript
+            let signed = if legacy {
+                server_challenge.into_bytes()
+            } else {
+                answer.transcript(&client_transcript)?
+            };
+            let (_, signature, _) =
+                pki::sign_handshake(&server.private_key, server.ed25519_key.as_ref(), &signed);
+            if legacy {
+                answer.signiture = signature;
+            } else {
+                answer.transcript_signature = signature;
+            }
+            Ok(Response::new(serde_json::json!(answer), None, request.id))
         } else {
             Err("Invalid method".into())
         }
@@ -197,16 +1170,39 @@ impl ServerHandler {
 impl Handler for ServerHandler {
     async fn handle(&mut self, request: Request) -> Response {
         let req_id = request.id.clone();
-        let error_handler = |e: Box<dyn Error>| {
-            Response::new(
-                serde_json::json!(null),
-                Some(RpcError {
-                    message: String::from(e.to_string()),
-                    code: RpcErrorCode::InvalidRequest,
-                }),
-                req_id.clone(),
-            )
+        let error_handler = |e: Error| {
+            Response::new(serde_json::json!(null), Some(rpc_error(e)), req_id.clone())
         };
+        let max_rps = self.server.read().await.config.max_rps;
+        if let Some(max_rps) = max_rps {
+            let limiter = self
+                .rate_limiter
+                .get_or_insert_with(|| RateLimiter::new(max_rps));
+            if !limiter.allow(Instant::now()) {
+                return Response::new(
+                    serde_json::json!(null),
+                    Some(RpcError {
+                        message: String::from("rate limit exceeded"),
+                        code: RpcErrorCode::ServerError,
+                    }),
+                    req_id,
+                );
+            }
+        }
+        #[cfg(feature = "insecure-dev")]
+        if self.allow_plaintext
+            && (self.plaintext_session || request.method == rpc_models::DEV_PLAINTEXT_SESSION)
+        {
+            let mut response = match request.method.as_str() {
+                rpc_models::DEV_PLAINTEXT_SESSION => self
+                    .handle_dev_plaintext_session(request)
+                    .await
+                    .unwrap_or_else(error_handler),
+                _ => self.dispatch(request, false, true).await,
+            };
+            response.insecure = true;
+            return response;
+        }
         match request.method.as_str() {
             rpc_models::ENCRYPTED_REQUEST => self
                 .handle_encrypted_request(request)
@@ -217,14 +1213,1101 @@ impl Handler for ServerHandler {
             rpc_models::CLIENT_CHALLENGE_RESPONSE => self
                 .handle_challenge_response(request)
                 .await.unwrap_or_else(error_handler),
-            _ => Response::new(
-                serde_json::json!(null),
-                Some(RpcError {
-                    message: String::from("Invalid rpc method"),
-                    code: RpcErrorCode::MethodNotFound,
-                }),
-                req_id,
-            ),
+            _ => self.dispatch(request, false, false).await,
+        }
+    }
+
+    fn connected(&mut self, pushes: Sender<Request>) {
+        self.outgoing = Some(pushes);
+    }
+
+    async fn disconnected(&mut self) {
+        self.close_session();
+        self.outgoing = None;
+    }
+
+    async fn max_connections(&self) -> Option<usize> {
+        self.server.read().await.config.max_connections
+    }
+    async fn max_frame_size(&self) -> usize {
+        self.server.read().await.config.max_frame_bytes
+    }
+    async fn deadlines(&self) -> Deadlines {
+        let server = self.server.read().await;
+        Deadlines {
+            idle: Some(server.config.idle_timeout),
+            request: Some(server.config.timeout),
+        }
+    }
+    fn codec(&self) -> Codec {
+        if self.encryption.is_some() && self.session_capabilities.contains(&Capability::MsgPack) {
+            Codec::MsgPack
+        } else {
+            Codec::Json
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::server::session_key::SessionKeyPolicy;
+    use crate::server::ServerConfig;
+    use rsa::RsaPrivateKey;
+    use x25519_dalek::EphemeralSecret;
+
+    #[test]
+    fn test_rpc_error_codes() {
+        let error = rpc_error(Error::rpc(RpcErrorCode::Unauthorized, "Not allowed"));
+        assert!(matches!(error.code, RpcErrorCode::Unauthorized));
+        assert_eq!(error.message, "Not allowed");
+        let parse = serde_json::from_str::<u8>("not json").unwrap_err();
+        assert!(matches!(rpc_error(parse.into()).code, RpcErrorCode::ParseError));
+        let io = std::io::Error::new(std::io::ErrorKind::Other, "disk full");
+        assert!(matches!(rpc_error(io.into()).code, RpcErrorCode::InternalError));
+        assert!(matches!(rpc_error("Bad request".into()).code, RpcErrorCode::InvalidRequest));
+    }
+
+    #[test]
+    fn test_revoke_session() {
+        let server = Server::new(pki::gen_key().unwrap(), Vec::new(), None);
+        let mut handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        assert!(handler
+            .revoke_session(rpc_models::RevocationReason::AdminRevoked)
+            .is_err());
+
+        let encryption = EncryptionConfiguration::new(ski::gen_key());
+        handler.encryption = Some(encryption.clone());
+        handler.client_pub_key = Some(pki::gen_key().unwrap().to_public_key());
+        let (outgoing, pushed) = channel::unbounded();
+        handler.connected(outgoing);
+        assert!(handler
+            .revoke_session(rpc_models::RevocationReason::KeyCompromised)
+            .unwrap());
+        let notification = pushed.try_recv().unwrap();
+        assert_eq!(notification.method, rpc_models::ENCRYPTED_REQUEST);
+        assert!(handler.encryption.is_none());
+        assert!(handler.client_pub_key.is_none());
+
+        let params: rpc_models::EncryptedRequestParams =
+            serde_json::from_value(notification.params).unwrap();
+        let data = ski::open_gcm(&params.data, &encryption.shared_key).unwrap();
+        let request: Request = serde_json::from_slice(&data).unwrap();
+        assert_eq!(request.method, rpc_models::REVOKE_SESSION);
+        let params: rpc_models::RevokeSessionParams = serde_json::from_value(request.params).unwrap();
+        assert_eq!(params.reason, rpc_models::RevocationReason::KeyCompromised);
+    }
+
+    #[test]
+    fn test_fresh_nonce_per_message() {
+        let server = Server::new(pki::gen_key().unwrap(), Vec::new(), None);
+        let mut handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let encryption = EncryptionConfiguration::new(ski::gen_key());
+        handler.encryption = Some(encryption.clone());
+        handler.client_pub_key = Some(pki::gen_key().unwrap().to_public_key());
+
+        // the same ping twice, so identical plaintexts go out under the session key
+        let ping = Request::new(rpc_models::PING.to_string(), serde_json::json!(null));
+        let mut requests = vec![];
+        let mut responses = vec![];
+        for _ in 0..2 {
+            let request = handler.encrypt_notification(ping.clone()).unwrap();
+            let params: rpc_models::EncryptedRequestParams =
+                serde_json::from_value(request.params.clone()).unwrap();
+            requests.push(params.data);
+            let response = async_std::task::block_on(handler.handle(request));
+            responses.push(serde_json::from_value::<Vec<u8>>(response.result).unwrap());
+        }
+        assert_ne!(requests[0], requests[1]);
+        assert_ne!(responses[0], responses[1]);
+        for ct in responses {
+            let data = ski::open_gcm(&ct, &encryption.shared_key).unwrap();
+            let response: Response = serde_json::from_slice(&data).unwrap();
+            assert_eq!(response.result, serde_json::json!("pong"));
+        }
+
+        // sessions saved with a single nonce still load
+        let legacy = serde_json::json!({ "shared_key": [1, 2, 3], "nonce": [4, 5, 6] });
+        let legacy: EncryptionConfiguration = serde_json::from_value(legacy).unwrap();
+        assert_eq!(legacy.shared_key, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_server_info_challenge() {
+        let server_key = pki::gen_key().unwrap();
+        let pub_key = server_key.to_public_key();
+        let server = Server::new(server_key, Vec::new(), None);
+        let mut handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let mut info = |params: serde_json::Value| {
+            let request = Request::new(rpc_models::GET_SERVER_INFO.to_string(), params);
+            let response = async_std::task::block_on(handler.handle(request));
+            serde_json::from_value::<rpc_models::ServerInfo>(response.result).unwrap()
+        };
+
+        // older clients don't send a challenge and get nothing signed
+        assert!(info(serde_json::json!(null)).challenge_signature.is_empty());
+
+        let params = rpc_models::ServerInfoParams {
+            challenge: String::from("probe"),
+        };
+        let signature = info(serde_json::json!(params)).challenge_signature;
+        let signature = rsa::pkcs1v15::Signature::try_from(signature.as_slice()).unwrap();
+        let signed = rpc_models::ServerInfo::challenge_signed_data("probe");
+        assert!(pki::verify_signature(&pub_key, &signed, &signature));
+        let other = rpc_models::ServerInfo::challenge_signed_data("replayed");
+        assert!(!pki::verify_signature(&pub_key, &other, &signature));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let config = ServerConfig {
+            max_rps: Some(100),
+            ..ServerConfig::default()
+        };
+        let server = Server::new(pki::gen_key().unwrap(), Vec::new(), Some(config));
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let info = || Request::new(rpc_models::GET_SERVER_INFO.to_string(), serde_json::json!(null));
+        async_std::task::block_on(async {
+            // two connections, each with its own budget
+            let (mut flooding, mut quiet) = (handler.clone(), handler.clone());
+            let (mut served, mut limited) = (0, 0);
+            for _ in 0..200 {
+                match flooding.handle(info()).await.error {
+                    None => served += 1,
+                    Some(error) => {
+                        assert_eq!(error.code, RpcErrorCode::ServerError);
+                        assert_eq!(error.message, "rate limit exceeded");
+                        limited += 1;
+                    }
+                }
+            }
+            assert!(served >= 100);
+            assert!(limited > 0);
+            assert!(quiet.handle(info()).await.error.is_none());
+
+            async_std::task::sleep(Duration::from_secs(1)).await;
+            assert!(flooding.handle(info()).await.error.is_none());
+        });
+    }
+
+    #[test]
+    fn test_forwarded_message_limit() {
+        let config = ServerConfig {
+            max_message_bytes: 16,
+            ..ServerConfig::default()
+        };
+        let server = Server::new(pki::gen_key().unwrap(), Vec::new(), Some(config));
+        let mut handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let encryption = EncryptionConfiguration::new(ski::gen_key());
+        handler.encryption = Some(encryption.clone());
+        handler.client_pub_key = Some(pki::gen_key().unwrap().to_public_key());
+
+        let mut forward = |len: usize| {
+            let params = rpc_models::ForwardedMessageParams {
+                enc_type: rpc_models::EncryptionType::AesGcm,
+                data: vec![0; len],
+                recipients: vec![String::from("recipient")],
+                payload_type: rpc_models::PayloadType::Opaque,
+                message_id: None,
+            };
+            let request = Request::new(rpc_models::FORWARDED_MSG.to_string(), serde_json::json!(params));
+            let request = handler.encrypt_notification(request).unwrap();
+            let response = async_std::task::block_on(handler.handle(request));
+            let ct: Vec<u8> = serde_json::from_value(response.result).unwrap();
+            let data = ski::open_gcm(&ct, &encryption.shared_key).unwrap();
+            serde_json::from_slice::<Response>(&data).unwrap()
+        };
+        assert!(forward(16).error.is_none());
+        let response = forward(17);
+        assert!(matches!(
+            response.error.unwrap().code,
+            RpcErrorCode::PayloadTooLarge
+        ));
+    }
+
+    #[test]
+    fn test_pending_quota() {
+        let config = ServerConfig {
+            max_pending: 1,
+            ..ServerConfig::default()
+        };
+        let mut server = Server::new(pki::gen_key().unwrap(), Vec::new(), Some(config));
+        let path = std::env::temp_dir().join(format!("carapace-quota-{}", Uuid::new_v4()));
+        server
+            .open_database(path, &crate::shared::db::DbConfig::default())
+            .unwrap();
+        let mut handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let encryption = EncryptionConfiguration::new(ski::gen_key());
+        handler.encryption = Some(encryption.clone());
+        handler.client_pub_key = Some(pki::gen_key().unwrap().to_public_key());
+
+        let mut forward = |recipients: &[&str]| {
+            let params = rpc_models::ForwardedMessageParams {
+                enc_type: rpc_models::EncryptionType::AesGcm,
+                data: vec![1, 2, 3],
+                recipients: recipients.iter().map(|r| r.to_string()).collect(),
+                payload_type: rpc_models::PayloadType::Opaque,
+                message_id: None,
+            };
+            let request = Request::new(rpc_models::FORWARDED_MSG.to_string(), serde_json::json!(params));
+            let request = handler.encrypt_notification(request).unwrap();
+            let response = async_std::task::block_on(handler.handle(request));
+            let ct: Vec<u8> = serde_json::from_value(response.result).unwrap();
+            let data = ski::open_gcm(&ct, &encryption.shared_key).unwrap();
+            serde_json::from_slice::<Response>(&data).unwrap()
+        };
+        let receipt = |response: Response| {
+            serde_json::from_value::<rpc_models::ForwardReceipt>(response.into_result().unwrap())
+                .unwrap()
+        };
+        assert_eq!(receipt(forward(&["full"])).queued, 1);
+
+        // refused for the recipient that has too much waiting, queued for the rest
+        let both = receipt(forward(&["full", "empty"]));
+        assert_eq!(both.queued, 1);
+        assert_eq!(both.refused, vec![String::from("full")]);
+
+        // and refused outright when no one would get it
+        let error = forward(&["full"]).error.unwrap();
+        assert!(matches!(error.code, RpcErrorCode::ServerError));
+    }
+
+    #[test]
+    fn test_reject_pkcs1v15_request() {
+        let server_key = pki::gen_key().unwrap();
+        let server_pub_key = server_key.to_public_key();
+        let client_key = pki::gen_key().unwrap();
+        let server = Server::new(server_key, Vec::new(), None);
+        let mut handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        handler.encryption = Some(EncryptionConfiguration::new(ski::gen_key()));
+        handler.client_pub_key = Some(client_key.to_public_key());
+        let ping = serde_json::to_vec(&Request::new(rpc_models::PING.to_string(), serde_json::json!(null))).unwrap();
+        let mut send = |enc_type, data| {
+            let params = rpc_models::EncryptedRequestParams { enc_type, data };
+            let request = Request::new(rpc_models::ENCRYPTED_REQUEST.to_string(), serde_json::json!(params));
+            async_std::task::block_on(handler.handle(request))
+        };
+
+        let legacy = server_pub_key
+            .encrypt(&mut rand_core::OsRng {}, rsa::Pkcs1v15Encrypt, &ping)
+            .unwrap();
+        let error = send(rpc_models::EncryptionType::RsaPkcs1v15, legacy).error.unwrap();
+        assert!(matches!(error.code, RpcErrorCode::InvalidRequest));
+        assert!(error.message.contains("RSA-OAEP"));
+
+        let oaep = pki::encrypt_message(&server_pub_key, &ping).unwrap();
+        let response = send(rpc_models::EncryptionType::RsaOaep, oaep);
+        let ct: Vec<u8> = serde_json::from_value(response.result).unwrap();
+        let data = pki::decrypt_message(&client_key, &ct).unwrap();
+        let response: Response = serde_json::from_slice(&data).unwrap();
+        assert_eq!(response.result, serde_json::json!("pong"));
+    }
+
+    #[test]
+    fn test_forwarded_message_delivery() {
+        let mut server = Server::new(pki::gen_key().unwrap(), Vec::new(), None);
+        let path = std::env::temp_dir().join(format!("carapace-pending-{}", Uuid::new_v4()));
+        server
+            .open_database(path, &crate::shared::db::DbConfig::default())
+            .unwrap();
+        let server = Arc::new(RwLock::new(server));
+        // connections accepted by the same server share their push senders
+        let base = ServerHandler::new(server.clone());
+        let session = |handler: &mut ServerHandler, pub_key: RsaPublicKey| {
+            let encryption = EncryptionConfiguration::new(ski::gen_key());
+            handler.encryption = Some(encryption.clone());
+            handler.session_capabilities = rpc_models::client_capabilities();
+            handler.open_session(pub_key).unwrap();
+            encryption
+        };
+        let send = |handler: &mut ServerHandler, encryption: &EncryptionConfiguration, request| {
+            let request = handler.encrypt_notification(request).unwrap();
+            let response = async_std::task::block_on(handler.handle(request));
+            let ct: Vec<u8> = serde_json::from_value(response.result).unwrap();
+            let data = ski::open_gcm(&ct, &encryption.shared_key).unwrap();
+            serde_json::from_slice::<Response>(&data).unwrap()
+        };
+        let subscribe = |handler: &mut ServerHandler, encryption: &EncryptionConfiguration| {
+            let request = Request::new(rpc_models::SUBSCRIBE.to_string(), serde_json::json!(null));
+            assert!(send(handler, encryption, request).error.is_none());
+        };
+        let get_pending = |handler: &mut ServerHandler, encryption, acknowledged| {
+            let params = rpc_models::GetPendingParams { acknowledged };
+            let request = Request::new(rpc_models::GET_PENDING.to_string(), serde_json::json!(params));
+            let response = send(handler, encryption, request);
+            serde_json::from_value::<Vec<rpc_models::PendingDelivery>>(response.result).unwrap()
+        };
+
+        let mut sender = base.clone();
+        let sender_encryption = session(&mut sender, pki::gen_key().unwrap().to_public_key());
+        assert!(sender.push_sender().is_none());
+        let mut online = base.clone();
+        let (pushes, pushed) = channel::unbounded();
+        online.connected(pushes);
+        let online_key = pki::gen_key().unwrap().to_public_key();
+        let online_fingerprint = pki::fingerprint(&online_key).unwrap();
+        let online_encryption = session(&mut online, online_key);
+        // nothing is pushed until the client subscribes
+        assert!(online.push_sender().is_none());
+        subscribe(&mut online, &online_encryption);
+        assert!(online.push_sender().is_some());
+        let recipient_key = pki::gen_key().unwrap().to_public_key();
+        let offline_fingerprint = pki::fingerprint(&recipient_key).unwrap();
+
+        let params = rpc_models::ForwardedMessageParams {
+            enc_type: rpc_models::EncryptionType::AesGcm,
+            data: vec![1, 2, 3],
+            recipients: vec![online_fingerprint, offline_fingerprint.clone()],
+            payload_type: rpc_models::PayloadType::Opaque,
+            message_id: None,
+        };
+        let forward = Request::new(rpc_models::FORWARDED_MSG.to_string(), serde_json::json!(params));
+        assert!(send(&mut sender, &sender_encryption, forward.clone()).error.is_none());
+
+        // the online recipient gets it pushed under its session key, and nothing queued
+        let push = async_std::task::block_on(pushed.recv()).unwrap();
+        assert_eq!(push.method, rpc_models::ENCRYPTED_REQUEST);
+        let params: rpc_models::EncryptedRequestParams = serde_json::from_value(push.params).unwrap();
+        let data = ski::open_gcm(&params.data, &online_encryption.shared_key).unwrap();
+        let push: Request = serde_json::from_slice(&data).unwrap();
+        assert_eq!(push.method, rpc_models::FORWARDED_MSG);
+        assert_eq!(push.id, forward.id);
+        assert!(get_pending(&mut online, &online_encryption, vec![]).is_empty());
+
+        // the offline one pulls it once connected, until it acknowledges the delivery
+        let mut recipient = base.clone();
+        let (pushes, pushed) = channel::unbounded();
+        recipient.connected(pushes);
+        let encryption = session(&mut recipient, recipient_key);
+        subscribe(&mut recipient, &encryption);
+        for _ in 0..2 {
+            let pending = get_pending(&mut recipient, &encryption, vec![]);
+            assert_eq!(pending.len(), 1);
+            assert_eq!(pending[0].notification.id, forward.id);
+            assert_eq!(pending[0].notification.method, rpc_models::FORWARDED_MSG);
+        }
+        let pending = get_pending(&mut recipient, &encryption, vec![]);
+        assert!(get_pending(&mut recipient, &encryption, vec![pending[0].id.clone()]).is_empty());
+
+        // once it disconnects, new messages are queued for it again
+        async_std::task::block_on(recipient.disconnected());
+        assert!(recipient.push_sender().is_none());
+        assert!(send(&mut sender, &sender_encryption, forward).error.is_none());
+        assert!(pushed.try_recv().is_err());
+        let server = async_std::task::block_on(server.read());
+        let queued = server.db.as_ref().unwrap().pending(
+            &offline_fingerprint,
+            std::time::Duration::from_secs(60),
+        );
+        assert_eq!(queued.unwrap().len(), 1);
+    }
+
+    fn handshake_with(
+        handler: &mut ServerHandler,
+        client_key: &RsaPrivateKey,
+        ephemeral_key: Option<X25519PublicKey>,
+        server_challenge: &str,
+        capabilities: Capabilities,
+    ) -> Response {
+        let start = Request::new(rpc_models::START_SERVER_HANDSHAKE.to_string(), serde_json::json!(null));
+        let response = async_std::task::block_on(handler.handle(start));
+        let challenge: String = serde_json::from_value(response.result).unwrap();
+        let (key_type, signiture, signing_key) =
+            pki::sign_handshake(client_key, None, challenge.as_bytes());
+        let mut params = RespondClientChallenge {
+            pub_key: client_key.to_public_key(),
+            signiture,
+            server_challenge: server_challenge.to_string(),
+            key_type,
+            signing_key,
+            signing_key_binding: Vec::new(),
+            ephemeral_key,
+            capabilities,
+            protocol_version: rpc_models::PROTOCOL_VERSION,
+            transcript_signature: Vec::new(),
+        };
+        let transcript = params.transcript(&challenge).unwrap();
+        params.transcript_signature = pki::sign_message(client_key, &transcript);
+        let request = Request::new(rpc_models::CLIENT_CHALLENGE_RESPONSE.to_string(), serde_json::json!(params));
+        async_std::task::block_on(handler.handle(request))
+    }
+
+    /// Starts a handshake and returns the challenge with a signed answer to it, for the
+    /// caller to tamper with before sending.
+    fn challenge_response(
+        handler: &mut ServerHandler,
+        client_key: &RsaPrivateKey,
+    ) -> (String, RespondClientChallenge) {
+        let start = Request::new(rpc_models::START_SERVER_HANDSHAKE.to_string(), serde_json::json!(null));
+        let response = async_std::task::block_on(handler.handle(start));
+        let challenge: String = serde_json::from_value(response.result).unwrap();
+        let mut params = RespondClientChallenge {
+            pub_key: client_key.to_public_key(),
+            signiture: pki::sign_message(client_key, challenge.as_bytes()),
+            server_challenge: Uuid::new_v4().to_string(),
+            key_type: rpc_models::KeyType::Rsa2048,
+            signing_key: None,
+            signing_key_binding: Vec::new(),
+            ephemeral_key: Some(X25519PublicKey::from(&EphemeralSecret::random_from_rng(OsRng))),
+            capabilities: rpc_models::client_capabilities(),
+            protocol_version: rpc_models::PROTOCOL_VERSION,
+            transcript_signature: Vec::new(),
+        };
+        let transcript = params.transcript(&challenge).unwrap();
+        params.transcript_signature = pki::sign_message(client_key, &transcript);
+        (challenge, params)
+    }
+
+    fn send_challenge_response(handler: &mut ServerHandler, params: &RespondClientChallenge) -> Response {
+        let request = Request::new(rpc_models::CLIENT_CHALLENGE_RESPONSE.to_string(), serde_json::json!(params));
+        async_std::task::block_on(handler.handle(request))
+    }
+
+    #[test]
+    fn test_ed25519_key_bound_to_identity() {
+        let victim_key = pki::gen_key().unwrap();
+        let attacker_key = pki::gen_key().unwrap();
+        let server = Server::new(pki::gen_key().unwrap(), vec![victim_key.to_public_key()], None);
+        let server = Arc::new(RwLock::new(server));
+        let ed25519_key = pki::gen_key_ed25519();
+
+        // `holder` vouches for the Ed25519 key, which signs an answer claiming the
+        // victim's identity
+        let answer = |handler: &mut ServerHandler, holder: &RsaPrivateKey| {
+            let (challenge, mut params) = challenge_response(handler, &victim_key);
+            params.key_type = rpc_models::KeyType::Ed25519;
+            params.signing_key = Some(ed25519_key.verifying_key());
+            params.signing_key_binding = pki::bind_signing_key(holder, Some(&ed25519_key));
+            let transcript = params.transcript(&challenge).unwrap();
+            params.transcript_signature = pki::sign_message_ed25519(&ed25519_key, &transcript);
+            send_challenge_response(handler, &params)
+        };
+
+        let mut handler = ServerHandler::new(server.clone());
+        let error = answer(&mut handler, &attacker_key).error.unwrap();
+        assert_eq!(error.message, "Invalid signature");
+        assert!(handler.encryption.is_none());
+
+        let mut handler = ServerHandler::new(server);
+        assert!(answer(&mut handler, &victim_key).error.is_none());
+        assert!(handler.encryption.is_some());
+    }
+
+    #[test]
+    fn test_challenge_answered_once() {
+        let client_key = pki::gen_key().unwrap();
+        let config = ServerConfig {
+            handshake_timeout: Duration::from_millis(100),
+            ..ServerConfig::default()
+        };
+        let server = Server::new(pki::gen_key().unwrap(), vec![client_key.to_public_key()], Some(config));
+        let expiring = Arc::new(RwLock::new(server));
+        let server = Server::new(pki::gen_key().unwrap(), vec![client_key.to_public_key()], None);
+        let server = Arc::new(RwLock::new(server));
+
+        // a challenge left too long expires, and isn't answerable after that
+        let mut handler = ServerHandler::new(expiring);
+        let (_, params) = challenge_response(&mut handler, &client_key);
+        std::thread::sleep(Duration::from_millis(200));
+        let error = send_challenge_response(&mut handler, &params).error.unwrap();
+        assert!(error.message.contains("expired"));
+        let error = send_challenge_response(&mut handler, &params).error.unwrap();
+        assert_eq!(error.message, "No pending challenge");
+        assert!(handler.encryption.is_none());
+
+        // a failed answer uses the challenge up too
+        let forger = pki::gen_key().unwrap();
+        let mut handler = ServerHandler::new(server.clone());
+        let (_, params) = challenge_response(&mut handler, &client_key);
+        let mut forged = params.clone();
+        forged.transcript_signature = pki::sign_message(&forger, b"forged");
+        let error = send_challenge_response(&mut handler, &forged).error.unwrap();
+        assert_eq!(error.message, "Invalid signature");
+        let error = send_challenge_response(&mut handler, &params).error.unwrap();
+        assert_eq!(error.message, "No pending challenge");
+
+        // a replayed answer doesn't replace the session it opened
+        let mut handler = ServerHandler::new(server);
+        let (_, params) = challenge_response(&mut handler, &client_key);
+        assert!(send_challenge_response(&mut handler, &params).error.is_none());
+        let key = handler.encryption.as_ref().unwrap().shared_key.clone();
+        let error = send_challenge_response(&mut handler, &params).error.unwrap();
+        assert_eq!(error.message, "No pending challenge");
+
+        // nor can a new handshake be started within it
+        let start = Request::new(rpc_models::START_SERVER_HANDSHAKE.to_string(), serde_json::json!(null));
+        let error = async_std::task::block_on(handler.handle(start)).error.unwrap();
+        assert!(matches!(error.code, RpcErrorCode::InvalidRequest));
+        assert!(handler.pending_challenge.is_none());
+        assert_eq!(handler.encryption.as_ref().unwrap().shared_key, key);
+    }
+
+    #[test]
+    fn test_handshake_transcript() {
+        let client_key = pki::gen_key().unwrap();
+        let other_key = pki::gen_key().unwrap();
+        let authorized = vec![client_key.to_public_key(), other_key.to_public_key()];
+        let server = Server::new(pki::gen_key().unwrap(), authorized.clone(), None);
+        let server_pub_key = server.private_key.to_public_key();
+        let server = Arc::new(RwLock::new(server));
+
+        // the server signs the whole handshake, tied to the client's transcript
+        let mut handler = ServerHandler::new(server.clone());
+        let (challenge, params) = challenge_response(&mut handler, &client_key);
+        let response = send_challenge_response(&mut handler, &params);
+        assert!(response.error.is_none());
+        let response: RespondServerChallenge = serde_json::from_value(response.result).unwrap();
+        assert_eq!(response.protocol_version, rpc_models::PROTOCOL_VERSION);
+        let client_transcript = params.transcript(&challenge).unwrap();
+        let signature =
+            rsa::pkcs1v15::Signature::try_from(response.transcript_signature.as_slice()).unwrap();
+        let transcript = response.transcript(&client_transcript).unwrap();
+        assert!(pki::verify_signature(&server_pub_key, &transcript, &signature));
+        // a client that saw another public key has another transcript
+        let mut swapped = params.clone();
+        swapped.pub_key = other_key.to_public_key();
+        let transcript = response.transcript(&swapped.transcript(&challenge).unwrap()).unwrap();
+        assert!(!pki::verify_signature(&server_pub_key, &transcript, &signature));
+        // nor does it sign the bare challenge the client picked
+        assert!(response.signiture.is_empty());
+
+        // the client's signature covers its keys, the challenges and its capabilities
+        for tamper in 0..4 {
+            let mut handler = ServerHandler::new(server.clone());
+            let (_, mut params) = challenge_response(&mut handler, &client_key);
+            match tamper {
+                0 => params.pub_key = other_key.to_public_key(),
+                1 => {
+                    let secret = EphemeralSecret::random_from_rng(OsRng);
+                    params.ephemeral_key = Some(X25519PublicKey::from(&secret));
+                }
+                2 => params.server_challenge = Uuid::new_v4().to_string(),
+                _ => params.capabilities = Capabilities::new(),
+            }
+            let error = send_challenge_response(&mut handler, &params).error.unwrap();
+            assert_eq!(error.message, "Invalid signature");
+            assert!(handler.encryption.is_none());
         }
+
+        // clients that only sign the challenge are refused unless the server allows it
+        let mut handler = ServerHandler::new(server.clone());
+        let (_, mut params) = challenge_response(&mut handler, &client_key);
+        params.protocol_version = rpc_models::BATCH_PROTOCOL_VERSION;
+        params.transcript_signature = Vec::new();
+        let error = send_challenge_response(&mut handler, &params).error.unwrap();
+        assert!(error.message.contains("upgrade to protocol version"));
+        assert!(handler.encryption.is_none());
+
+        let config = ServerConfig {
+            allow_legacy_handshake: true,
+            ..ServerConfig::default()
+        };
+        let server = Server::new(pki::gen_key().unwrap(), authorized, Some(config));
+        let mut handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let (_, mut params) = challenge_response(&mut handler, &client_key);
+        params.protocol_version = rpc_models::BATCH_PROTOCOL_VERSION;
+        params.transcript_signature = Vec::new();
+        let response = send_challenge_response(&mut handler, &params);
+        assert!(response.error.is_none());
+        let response: RespondServerChallenge = serde_json::from_value(response.result).unwrap();
+        assert!(response.transcript_signature.is_empty());
+        assert!(!response.signiture.is_empty());
+        assert!(handler.encryption.is_some());
+    }
+
+    fn handshake(handler: &mut ServerHandler, client_key: &RsaPrivateKey) -> Response {
+        let ephemeral_key = X25519PublicKey::from(&EphemeralSecret::random_from_rng(OsRng));
+        handshake_with(
+            handler,
+            client_key,
+            Some(ephemeral_key),
+            &Uuid::new_v4().to_string(),
+            rpc_models::client_capabilities(),
+        )
+    }
+
+    #[test]
+    fn test_handshake_agrees_on_ephemeral_key() {
+        let client_key = pki::gen_key().unwrap();
+        let server = Server::new(pki::gen_key().unwrap(), vec![client_key.to_public_key()], None);
+        let server_pub_key = server.private_key.to_public_key();
+        let server = Arc::new(RwLock::new(server));
+
+        let mut handler = ServerHandler::new(server.clone());
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let client_ephemeral = X25519PublicKey::from(&secret);
+        let challenge = Uuid::new_v4().to_string();
+        let response = handshake_with(
+            &mut handler,
+            &client_key,
+            Some(client_ephemeral),
+            &challenge,
+            rpc_models::client_capabilities(),
+        );
+        let response: RespondServerChallenge = serde_json::from_value(response.result).unwrap();
+        assert_eq!(response.protocol_version, rpc_models::PROTOCOL_VERSION);
+        let server_ephemeral = response.ephemeral_key.unwrap();
+        let signed_data =
+            RespondServerChallenge::ephemeral_signed_data(&challenge, &client_ephemeral, &server_ephemeral);
+        let signature = rsa::pkcs1v15::Signature::try_from(response.ephemeral_signature.as_slice()).unwrap();
+        assert!(pki::verify_signature(&server_pub_key, &signed_data, &signature));
+        // a swapped client key breaks the signature
+        let other = X25519PublicKey::from(&EphemeralSecret::random_from_rng(OsRng));
+        let forged = RespondServerChallenge::ephemeral_signed_data(&challenge, &other, &server_ephemeral);
+        assert!(!pki::verify_signature(&server_pub_key, &forged, &signature));
+
+        let (key, nonce) = ski::derive_session_key(secret.diffie_hellman(&server_ephemeral).as_bytes()).unwrap();
+        assert_eq!(handler.encryption.as_ref().unwrap().shared_key, key);
+        let confirmed = ski::decrypt_gcm(&response.key_confirmation, &key, &nonce).unwrap();
+        assert_eq!(confirmed, challenge.as_bytes());
+
+        // every session gets a key of its own
+        let mut second = ServerHandler::new(server.clone());
+        assert!(handshake(&mut second, &client_key).error.is_none());
+        assert_ne!(second.encryption.as_ref().unwrap().shared_key, key);
+
+        // clients without an ephemeral key get no session
+        let mut legacy = ServerHandler::new(server);
+        let error = handshake_with(&mut legacy, &client_key, None, &challenge, Capabilities::new())
+            .error
+            .unwrap();
+        assert!(error.message.contains("forward secrecy"));
+        assert!(legacy.encryption.is_none());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let client_key = pki::gen_key().unwrap();
+        let mut features = rpc_models::server_capabilities();
+        features.remove(&Capability::UserDirectory);
+        let config = ServerConfig {
+            features,
+            ..ServerConfig::default()
+        };
+        let server = Server::new(pki::gen_key().unwrap(), vec![client_key.to_public_key()], Some(config));
+        let server = Arc::new(RwLock::new(server));
+        // without a database there is no key log or offline delivery either
+        let expected: Capabilities = [Capability::Push, Capability::MsgPack].into_iter().collect();
+
+        let mut handler = ServerHandler::new(server.clone());
+        assert_eq!(handler.codec(), Codec::Json);
+        let request = Request::new(rpc_models::GET_SERVER_INFO.to_string(), serde_json::json!(null));
+        let response = async_std::task::block_on(handler.handle(request));
+        let info: rpc_models::ServerInfo = serde_json::from_value(response.result).unwrap();
+        // probes come from clients of any version
+        let parsed_by_all: Capabilities = [Capability::Push].into_iter().collect();
+        assert_eq!(info.capabilities, parsed_by_all);
+
+        let (outgoing, _) = channel::unbounded();
+        handler.connected(outgoing);
+        let response = handshake(&mut handler, &client_key);
+        let response: RespondServerChallenge = serde_json::from_value(response.result).unwrap();
+        assert_eq!(response.capabilities, expected);
+        assert_eq!(handler.codec(), Codec::MsgPack);
+        let send = |handler: &mut ServerHandler, method: &str| {
+            let encryption = handler.encryption.clone().unwrap();
+            let request = Request::new(method.to_string(), serde_json::json!(null));
+            let request = handler.encrypt_notification(request).unwrap();
+            let response = async_std::task::block_on(handler.handle(request));
+            let ct: Vec<u8> = serde_json::from_value(response.result).unwrap();
+            let data = ski::open_gcm(&ct, &encryption.shared_key).unwrap();
+            serde_json::from_slice::<Response>(&data).unwrap()
+        };
+        assert!(send(&mut handler, rpc_models::SUBSCRIBE).error.is_none());
+        assert!(handler.push_sender().is_some());
+        let error = send(&mut handler, rpc_models::LIST_USERS).error.unwrap();
+        assert!(matches!(error.code, RpcErrorCode::MethodNotFound));
+
+        // clients that don't take pushes get none, their messages are queued
+        let mut handler = ServerHandler::new(server.clone());
+        let (outgoing, _) = channel::unbounded();
+        handler.connected(outgoing);
+        let ephemeral_key = X25519PublicKey::from(&EphemeralSecret::random_from_rng(OsRng));
+        let challenge = Uuid::new_v4().to_string();
+        let response = handshake_with(&mut handler, &client_key, Some(ephemeral_key), &challenge, Capabilities::new());
+        assert!(response.error.is_none());
+        let error = send(&mut handler, rpc_models::SUBSCRIBE).error.unwrap();
+        assert!(matches!(error.code, RpcErrorCode::MethodNotFound));
+        assert!(handler.push_sender().is_none());
+        // MessagePack comes with the protocol version rather than the list
+        assert_eq!(handler.codec(), Codec::MsgPack);
+
+        // a client from before it is neither told about it nor switched to it
+        let mut handler = ServerHandler::new(server.clone());
+        let (challenge, mut params) = challenge_response(&mut handler, &client_key);
+        params.protocol_version = rpc_models::TRANSCRIPT_PROTOCOL_VERSION;
+        let transcript = params.transcript(&challenge).unwrap();
+        params.transcript_signature = pki::sign_message(&client_key, &transcript);
+        let response = send_challenge_response(&mut handler, &params);
+        let response: RespondServerChallenge = serde_json::from_value(response.result).unwrap();
+        assert_eq!(response.capabilities, parsed_by_all);
+        assert_eq!(handler.codec(), Codec::Json);
+
+        // capabilities this build doesn't know are skipped rather than refused
+        let mut answer = serde_json::to_value(&params).unwrap();
+        answer["capabilities"] = serde_json::json!(["Push", "SomethingNew"]);
+        let parsed: RespondClientChallenge = serde_json::from_value(answer).unwrap();
+        assert_eq!(parsed.capabilities, parsed_by_all);
+    }
+
+    #[test]
+    fn test_custom_methods() {
+        let client_key = pki::gen_key().unwrap();
+        let server = Server::new(pki::gen_key().unwrap(), vec![client_key.to_public_key()], None);
+        let mut methods = ServerHandler::builtin_methods();
+        methods.register("WHOAMI", MethodInfo::SESSION, |handler: ServerHandler, request| async move {
+            let key = handler.client_key().ok_or("Session not established")?;
+            let fingerprint = pki::fingerprint(key)?;
+            Ok::<_, Error>(Response::new(serde_json::json!(fingerprint), None, request.id))
+        });
+        let mut handler = ServerHandler::with_methods(Arc::new(RwLock::new(server)), methods);
+
+        // neither a custom method nor the directory is served in the clear
+        let whoami = Request::new(String::from("WHOAMI"), serde_json::json!(null));
+        let response = async_std::task::block_on(handler.handle(whoami.clone()));
+        assert!(matches!(response.error.unwrap().code, RpcErrorCode::SessionNotEstablished));
+        let list = Request::new(rpc_models::LIST_USERS.to_string(), serde_json::json!(null));
+        let response = async_std::task::block_on(handler.handle(list));
+        assert!(matches!(response.error.unwrap().code, RpcErrorCode::Unauthorized));
+
+        assert!(handshake(&mut handler, &client_key).error.is_none());
+        // nor after the handshake, unless sealed under the session key
+        let response = async_std::task::block_on(handler.handle(whoami.clone()));
+        assert!(matches!(response.error.unwrap().code, RpcErrorCode::SessionNotEstablished));
+        let encryption = handler.encryption.clone().unwrap();
+        let request = handler.encrypt_notification(whoami).unwrap();
+        let response = async_std::task::block_on(handler.handle(request));
+        let ct: Vec<u8> = serde_json::from_value(response.result).unwrap();
+        let data = ski::open_gcm(&ct, &encryption.shared_key).unwrap();
+        let response = serde_json::from_slice::<Response>(&data).unwrap();
+        let fingerprint = pki::fingerprint(&client_key.to_public_key()).unwrap();
+        assert_eq!(response.into_result().unwrap(), serde_json::json!(fingerprint));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_handshake_requires_authorized_key() {
+        let known = pki::gen_key().unwrap();
+        let server = Server::new(pki::gen_key().unwrap(), vec![known.to_public_key()], None);
+        let server = Arc::new(RwLock::new(server));
+
+        let mut handler = ServerHandler::new(server.clone());
+        assert!(handshake(&mut handler, &known).error.is_none());
+        assert!(handler.encryption.is_some());
+
+        let mut handler = ServerHandler::new(server.clone());
+        let error = handshake(&mut handler, &pki::gen_key().unwrap()).error.unwrap();
+        assert!(matches!(error.code, RpcErrorCode::Unauthorized));
+        assert!(handler.encryption.is_none());
+        // nor can the refused client go on as if it had a session
+        let params = rpc_models::EncryptedRequestParams {
+            enc_type: rpc_models::EncryptionType::AesGcm,
+            data: vec![0; 32],
+        };
+        let request = Request::new(rpc_models::ENCRYPTED_REQUEST.to_string(), serde_json::json!(params));
+        let response = async_std::task::block_on(handler.handle(request));
+        assert!(matches!(response.error.unwrap().code, RpcErrorCode::SessionNotEstablished));
+        assert_eq!(async_std::task::block_on(server.read()).authorized_keys.len(), 1);
+    }
+
+    #[test]
+    fn test_open_registration_persists_key() {
+        let server_key = pki::gen_key().unwrap();
+        let path = std::env::temp_dir().join(format!("carapace-registration-{}", Uuid::new_v4()));
+        let config = ServerConfig {
+            open_registration: true,
+            ..ServerConfig::default()
+        };
+        let mut server = Server::new(server_key.clone(), Vec::new(), Some(config));
+        server
+            .open_database(&path, &crate::shared::db::DbConfig::default())
+            .unwrap();
+        let server = Arc::new(RwLock::new(server));
+        let client_key = pki::gen_key().unwrap();
+        // a second session with the same key doesn't register it twice
+        for _ in 0..2 {
+            let mut handler = ServerHandler::new(server.clone());
+            assert!(handshake(&mut handler, &client_key).error.is_none());
+        }
+        assert_eq!(async_std::task::block_on(server.read()).authorized_keys.len(), 1);
+        // releases the database lock
+        drop(server);
+
+        // still authorized once registration closes and the server restarts
+        let mut server = Server::new(server_key, Vec::new(), None);
+        server
+            .open_database(&path, &crate::shared::db::DbConfig::default())
+            .unwrap();
+        assert_eq!(server.authorized_keys, vec![client_key.to_public_key()]);
+        let mut handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        assert!(handshake(&mut handler, &client_key).error.is_none());
+    }
+
+    #[test]
+    fn test_key_rotation_needs_old_key_signature() {
+        let old_key = pki::gen_key().unwrap();
+        let new_key = pki::gen_key().unwrap();
+        let server = Server::new(pki::gen_key().unwrap(), vec![old_key.to_public_key()], None);
+        let server = Arc::new(RwLock::new(server));
+        let mut handler = ServerHandler::new(server.clone());
+        assert!(handshake(&mut handler, &old_key).error.is_none());
+        let encryption = handler.encryption.clone().unwrap();
+        let mut rotate = |signer: &RsaPrivateKey| {
+            let message = pki::key_rotation_message(&new_key.to_public_key()).unwrap();
+            let params = rpc_models::KeyRotationParams {
+                new_pub_key: new_key.to_public_key(),
+                signature_over_new_key: pki::sign_message(signer, &message),
+            };
+            let request = Request::new(rpc_models::KEY_ROTATION.to_string(), serde_json::json!(params));
+            let request = handler.encrypt_notification(request).unwrap();
+            let response = async_std::task::block_on(handler.handle(request));
+            let ct: Vec<u8> = serde_json::from_value(response.result).unwrap();
+            let data = ski::open_gcm(&ct, &encryption.shared_key).unwrap();
+            serde_json::from_slice::<Response>(&data).unwrap()
+        };
+
+        // the new key can't vouch for itself
+        assert!(rotate(&new_key).error.is_some());
+        let keys = async_std::task::block_on(server.read()).authorized_keys.clone();
+        assert_eq!(keys, vec![old_key.to_public_key()]);
+        assert!(rotate(&old_key).error.is_none());
+        let keys = async_std::task::block_on(server.read()).authorized_keys.clone();
+        assert_eq!(keys, vec![new_key.to_public_key()]);
+    }
+
+    #[test]
+    fn test_rotated_out_key_stays_revoked() {
+        let server_key = pki::gen_key().unwrap();
+        let old_key = pki::gen_key().unwrap();
+        let new_key = pki::gen_key().unwrap();
+        let path = std::env::temp_dir().join(format!("carapace-revoked-{}", Uuid::new_v4()));
+        let open = || {
+            let mut server = Server::new(server_key.clone(), vec![old_key.to_public_key()], None);
+            server
+                .open_database(&path, &crate::shared::db::DbConfig::default())
+                .unwrap();
+            Arc::new(RwLock::new(server))
+        };
+        let server = open();
+        let mut handler = ServerHandler::new(server.clone());
+        assert!(handshake(&mut handler, &old_key).error.is_none());
+        let encryption = handler.encryption.clone().unwrap();
+        let message = pki::key_rotation_message(&new_key.to_public_key()).unwrap();
+        let params = rpc_models::KeyRotationParams {
+            new_pub_key: new_key.to_public_key(),
+            signature_over_new_key: pki::sign_message(&old_key, &message),
+        };
+        let request = Request::new(rpc_models::KEY_ROTATION.to_string(), serde_json::json!(params));
+        let request = handler.encrypt_notification(request).unwrap();
+        let response = async_std::task::block_on(handler.handle(request));
+        let ct: Vec<u8> = serde_json::from_value(response.result).unwrap();
+        let data = ski::open_gcm(&ct, &encryption.shared_key).unwrap();
+        assert!(serde_json::from_slice::<Response>(&data).unwrap().error.is_none());
+        drop(handler);
+        drop(server);
+
+        // the configuration still lists the old key, but it was handed over
+        let server = open();
+        let keys = async_std::task::block_on(server.read()).authorized_keys.clone();
+        assert_eq!(keys, vec![new_key.to_public_key()]);
+        let mut handler = ServerHandler::new(server.clone());
+        assert!(handshake(&mut handler, &old_key).error.is_some());
+        let mut handler = ServerHandler::new(server);
+        assert!(handshake(&mut handler, &new_key).error.is_none());
+    }
+
+    #[test]
+    fn test_register_user() {
+        let config = ServerConfig {
+            open_registration: true,
+            ..ServerConfig::default()
+        };
+        let mut server = Server::new(pki::gen_key().unwrap(), Vec::new(), Some(config));
+        let path = std::env::temp_dir().join(format!("carapace-users-{}", Uuid::new_v4()));
+        server
+            .open_database(path, &crate::shared::db::DbConfig::default())
+            .unwrap();
+        let server = Arc::new(RwLock::new(server));
+        let session = |client_key: &RsaPrivateKey| {
+            let mut handler = ServerHandler::new(server.clone());
+            assert!(handshake(&mut handler, client_key).error.is_none());
+            handler
+        };
+        let register = |handler: &mut ServerHandler, username: &str| {
+            let encryption = handler.encryption.clone().unwrap();
+            let params = rpc_models::RegisterUserParams {
+                username: username.to_string(),
+            };
+            let request = Request::new(rpc_models::REGISTER_USER.to_string(), serde_json::json!(params));
+            let request = handler.encrypt_notification(request).unwrap();
+            let response = async_std::task::block_on(handler.handle(request));
+            let ct: Vec<u8> = serde_json::from_value(response.result).unwrap();
+            let data = ski::open_gcm(&ct, &encryption.shared_key).unwrap();
+            serde_json::from_slice::<Response>(&data).unwrap().error
+        };
+        let user = |username: &str| {
+            let server = async_std::task::block_on(server.read());
+            server.db.as_ref().unwrap().user(username).unwrap()
+        };
+
+        let alice_key = pki::gen_key().unwrap();
+        let mut alice = session(&alice_key);
+        let mut bob = session(&pki::gen_key().unwrap());
+        assert!(register(&mut alice, "alice").is_none());
+        assert_eq!(user("alice"), Some(alice_key.to_public_key()));
+        // registering again is harmless
+        assert!(register(&mut alice, "alice").is_none());
+
+        let error = register(&mut bob, "alice").unwrap();
+        assert!(matches!(error.code, RpcErrorCode::InvalidParams));
+        assert!(error.message.contains("already taken"));
+        assert_eq!(user("alice"), Some(alice_key.to_public_key()));
+        let error = register(&mut bob, " ").unwrap();
+        assert!(matches!(error.code, RpcErrorCode::InvalidParams));
+
+        // a new name releases the old one
+        assert!(register(&mut alice, "alice2").is_none());
+        assert_eq!(user("alice"), None);
+        assert!(register(&mut bob, "alice").is_none());
+
+        let call = |handler: &mut ServerHandler, method: &str, params: serde_json::Value| {
+            let encryption = handler.encryption.clone().unwrap();
+            let request = handler.encrypt_notification(Request::new(method.to_string(), params)).unwrap();
+            let response = async_std::task::block_on(handler.handle(request));
+            let ct: Vec<u8> = serde_json::from_value(response.result).unwrap();
+            let data = ski::open_gcm(&ct, &encryption.shared_key).unwrap();
+            serde_json::from_slice::<Response>(&data).unwrap()
+        };
+        let users = call(&mut bob, rpc_models::LIST_USERS, serde_json::json!(null)).result;
+        assert_eq!(users, serde_json::json!(["alice", "alice2"]));
+        let params = serde_json::json!(rpc_models::GetUserKeyParams {
+            username: String::from("alice2"),
+        });
+        let response = call(&mut bob, rpc_models::GET_USER_KEY, params);
+        let user_key: rpc_models::UserKey = serde_json::from_value(response.result).unwrap();
+        assert_eq!(pki::pub_key_from_str(&user_key.pub_key).unwrap(), alice_key.to_public_key());
+        let params = serde_json::json!(rpc_models::GetUserKeyParams {
+            username: String::from("carol"),
+        });
+        let error = call(&mut bob, rpc_models::GET_USER_KEY, params).error.unwrap();
+        assert!(matches!(error.code, RpcErrorCode::InvalidParams));
+
+        // never over plaintext
+        let request = Request::new(rpc_models::LIST_USERS.to_string(), serde_json::json!(null));
+        let response = async_std::task::block_on(bob.handle(request));
+        assert!(response.error.is_some());
+        assert!(response.result.is_null());
+    }
+
+    #[test]
+    fn test_session_key_lifetime() {
+        let config = ServerConfig {
+            open_registration: true,
+            session_keys: SessionKeyPolicy {
+                max_bytes: 200,
+                grace: Duration::from_millis(300),
+                ..SessionKeyPolicy::default()
+            },
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(RwLock::new(Server::new(pki::gen_key().unwrap(), Vec::new(), Some(config))));
+        let mut handler = ServerHandler::new(server.clone());
+        let client_key = pki::gen_key().unwrap();
+        assert!(handshake(&mut handler, &client_key).error.is_none());
+        let send = |handler: &mut ServerHandler, request: &Request, key: &[u8]| {
+            let params = rpc_models::EncryptedRequestParams {
+                enc_type: rpc_models::EncryptionType::AesGcm,
+                data: ski::seal_gcm(&serde_json::to_vec(request).unwrap(), key).unwrap(),
+            };
+            let request = Request::new(rpc_models::ENCRYPTED_REQUEST.to_string(), serde_json::json!(params));
+            let response = async_std::task::block_on(handler.handle(request));
+            if response.error.is_some() {
+                return response;
+            }
+            let ct: Vec<u8> = serde_json::from_value(response.result).unwrap();
+            serde_json::from_slice(&ski::open_gcm(&ct, key).unwrap()).unwrap()
+        };
+        let ping = Request::new(rpc_models::PING.to_string(), serde_json::json!(null));
+        let rekey = |secret: &EphemeralSecret| {
+            let params = rpc_models::RekeyParams {
+                ephemeral_key: X25519PublicKey::from(secret),
+            };
+            Request::new(rpc_models::REKEY.to_string(), serde_json::json!(params))
+        };
+
+        // drive the key past its byte budget
+        let old_key = handler.encryption.as_ref().unwrap().shared_key.clone();
+        let mut pings = 0;
+        let error = loop {
+            let response = send(&mut handler, &ping, &old_key);
+            match response.error {
+                Some(error) => break error,
+                None => pings += 1,
+            }
+        };
+        assert!(pings > 0 && pings < 10);
+        assert!(matches!(error.code, RpcErrorCode::RekeyRequired));
+
+        // the rekey itself is still served under the old key, then only the new one works
+        let low_order = rpc_models::RekeyParams {
+            ephemeral_key: X25519PublicKey::from([0; 32]),
+        };
+        let low_order = Request::new(rpc_models::REKEY.to_string(), serde_json::json!(low_order));
+        assert!(send(&mut handler, &low_order, &old_key).error.is_some());
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_key = X25519PublicKey::from(&secret);
+        let response = send(&mut handler, &rekey(&secret), &old_key);
+        let answer: rpc_models::RekeyAnswer =
+            serde_json::from_value(response.into_result().unwrap()).unwrap();
+        // the server's half is signed, and the key isn't one the client picked
+        let signed_data =
+            rpc_models::RekeyAnswer::signed_data(&ephemeral_key, &answer.ephemeral_key);
+        let signature =
+            rsa::pkcs1v15::Signature::try_from(answer.ephemeral_signature.as_slice()).unwrap();
+        let server_key = async_std::task::block_on(server.read()).private_key.to_public_key();
+        assert!(pki::verify_signature(&server_key, &signed_data, &signature));
+        let shared_secret = secret.diffie_hellman(&answer.ephemeral_key);
+        let (new_key, _) = ski::derive_session_key(shared_secret.as_bytes()).unwrap();
+        assert_eq!(handler.encryption.as_ref().unwrap().shared_key, new_key);
+        assert_eq!(async_std::task::block_on(server.read()).metrics().forced_rekeys, 1);
+        assert_eq!(send(&mut handler, &ping, &new_key).result, serde_json::json!("pong"));
+        let response = async_std::task::block_on(handler.handle(Request::new(
+            rpc_models::ENCRYPTED_REQUEST.to_string(),
+            serde_json::json!(rpc_models::EncryptedRequestParams {
+                enc_type: rpc_models::EncryptionType::AesGcm,
+                data: ski::seal_gcm(&serde_json::to_vec(&ping).unwrap(), &old_key).unwrap(),
+            }),
+        )));
+        assert!(response.error.is_some());
+
+        // a key not replaced within the grace window ends the session
+        while send(&mut handler, &ping, &new_key).error.is_none() {}
+        std::thread::sleep(Duration::from_millis(400));
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let error = send(&mut handler, &rekey(&secret), &new_key).error.unwrap();
+        assert!(matches!(error.code, RpcErrorCode::SessionNotEstablished));
+        assert!(handler.encryption.is_none());
+        let metrics = async_std::task::block_on(server.read()).metrics();
+        assert_eq!(metrics.expired_session_keys, 1);
+        assert_eq!(metrics.forced_rekeys, 1);
+    }
+
+    #[test]
+    fn test_dev_plaintext_session_not_served_by_default() {
+        // unknown without the insecure-dev feature, and to servers not started in dev mode
+        let server = Server::new(pki::gen_key().unwrap(), Vec::new(), None);
+        let mut handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        let params = rpc_models::DevPlaintextSessionParams {
+            pub_key: pki::gen_key().unwrap().to_public_key(),
+            capabilities: Capabilities::new(),
+        };
+        let request = Request::new(rpc_models::DEV_PLAINTEXT_SESSION.to_string(), serde_json::json!(params));
+        let response = async_std::task::block_on(handler.handle(request));
+        assert!(matches!(response.error.unwrap().code, RpcErrorCode::MethodNotFound));
+        assert!(!response.insecure);
+        let ping = Request::new(rpc_models::PING.to_string(), serde_json::json!(null));
+        let response = async_std::task::block_on(handler.handle(ping));
+        assert!(response.error.is_some());
+    }
+}
@@ -0,0 +1,34 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+/// Counters for what the server does to sessions on its own initiative.
+#[derive(Default)]
+pub struct ServerMetrics {
+    forced_rekeys: AtomicU64,
+    expired_session_keys: AtomicU64,
+}
+impl ServerMetrics {
+    /// A client replaced a session key after being told it ran out.
+    pub fn forced_rekey(&self) {
+        self.forced_rekeys.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A session key was dropped because it wasn't replaced within the grace window.
+    pub fn expired_session_key(&self) {
+        self.expired_session_keys.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            forced_rekeys: self.forced_rekeys.load(Ordering::Relaxed),
+            expired_session_keys: self.expired_session_keys.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub forced_rekeys: u64,
+    pub expired_session_keys: u64,
+}
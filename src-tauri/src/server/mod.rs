@@ -1,34 +1,170 @@
-use std::error::Error;
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
+use async_std::channel;
 use async_std::net::TcpListener;
+use async_std::task::JoinHandle;
 use async_std::{prelude::*, task};
+use futures::future::{self, Either};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceInfo};
 use rsa::{RsaPrivateKey, RsaPublicKey};
 use serde::{Deserialize, Serialize};
 
 
-use crate::shared::rpc::{self, Handler};
+use crate::shared::db::DbConfig;
+use crate::shared::pki;
+use crate::shared::rpc::{self, FrameWriter, Handler, Request, Response, RpcError, RpcErrorCode};
+use crate::shared::rpc_models::{self, Capabilities, Capability, DEFAULT_MAX_MESSAGE_BYTES};
+use crate::Error;
+use self::db::ServerDatabase;
+use self::metrics::{MetricsSnapshot, ServerMetrics};
+use self::session_key::SessionKeyPolicy;
+pub mod db;
 pub mod handler;
+pub mod metrics;
 pub mod models;
+pub mod rate_limit;
+pub mod session_key;
+
+/// How long notifications for offline recipients are kept unless configured otherwise.
+pub const DEFAULT_PENDING_TTL: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+fn default_pending_ttl() -> Duration {
+    DEFAULT_PENDING_TTL
+}
+
+/// How long a connection may go without a request unless configured otherwise.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+fn default_idle_timeout() -> Duration {
+    DEFAULT_IDLE_TIMEOUT
+}
+
+/// How long a handshake challenge stays answerable unless configured otherwise.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn default_handshake_timeout() -> Duration {
+    DEFAULT_HANDSHAKE_TIMEOUT
+}
+
+fn default_max_frame_bytes() -> usize {
+    rpc::MAX_FRAME_SIZE
+}
+
+/// How many notifications, and how many bytes of them, may wait for one offline
+/// recipient unless configured otherwise.
+pub const DEFAULT_MAX_PENDING: usize = 1000;
+pub const DEFAULT_MAX_PENDING_BYTES: usize = 64 * 1024 * 1024;
+
+fn default_max_pending() -> usize {
+    DEFAULT_MAX_PENDING
+}
+
+fn default_max_pending_bytes() -> usize {
+    DEFAULT_MAX_PENDING_BYTES
+}
+
+/// Results over this many bytes are streamed to clients that ask for it, unless
+/// configured otherwise. Also the default size of each streamed chunk.
+pub const DEFAULT_STREAM_THRESHOLD_BYTES: usize = 64 * 1024;
+
+fn default_stream_bytes() -> usize {
+    DEFAULT_STREAM_THRESHOLD_BYTES
+}
+
+/// How long a connection turned away at the limit gets to take its refusal.
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Serialize, Deserialize)]
 pub struct ServerConfig {
-    open_registration: bool,
-    timeout: Duration,
+    /// Accept clients with unknown keys and add them to the authorized keys.
+    pub open_registration: bool,
+    /// Longest a request may take to handle before it's answered with an error.
+    pub timeout: Duration,
+    /// Connections that send no request for this long are closed. Clients only ping
+    /// a quiet session before using it, so this is kept well past `timeout`.
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: Duration,
+    /// A challenge from `START_SERVER_HANDSHAKE` answered later than this is refused.
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: Duration,
+    /// Largest frame a client may send. Has to fit `max_message_bytes` once encrypted
+    /// and encoded; larger frames are answered with a `ParseError` and the connection
+    /// closed.
+    #[serde(default = "default_max_frame_bytes")]
+    pub max_frame_bytes: usize,
+    pub max_message_bytes: usize,
+    /// Notifications queued for offline recipients are dropped once they are older.
+    #[serde(default = "default_pending_ttl")]
+    pub pending_ttl: Duration,
+    /// Most notifications queued for one offline recipient. A forwarded message past
+    /// this, or past `max_pending_bytes`, is refused for them.
+    #[serde(default = "default_max_pending")]
+    pub max_pending: usize,
+    #[serde(default = "default_max_pending_bytes")]
+    pub max_pending_bytes: usize,
+    #[serde(default)]
+    pub session_keys: SessionKeyPolicy,
+    /// Optional features to serve. Ones that need the database are only advertised
+    /// once it is open, see `Server::capabilities`.
+    #[serde(default = "rpc_models::server_capabilities")]
+    pub features: Capabilities,
+    /// Connections past this many are refused as they come in, so clients can't run
+    /// the server out of file descriptors. `None` for no limit.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Requests each connection may make per second. Ones past it are refused until the
+    /// next second starts. `None` for no limit.
+    #[serde(default)]
+    pub max_rps: Option<u32>,
+    /// Results larger than this are sent as a `StreamInfo` to requests that allow it,
+    /// and fetched in chunks of `stream_chunk_bytes`.
+    #[serde(default = "default_stream_bytes")]
+    pub stream_threshold_bytes: usize,
+    #[serde(default = "default_stream_bytes")]
+    pub stream_chunk_bytes: usize,
+    /// Accept clients older than `TRANSCRIPT_PROTOCOL_VERSION`, whose signature only
+    /// covers the bare challenge and could be replayed into another session. Meant for
+    /// the transition only.
+    #[serde(default)]
+    pub allow_legacy_handshake: bool,
 }
 impl Default for ServerConfig {
     fn default() -> Self {
         ServerConfig {
             open_registration: false,
             timeout: Duration::from_secs(10),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_frame_bytes: rpc::MAX_FRAME_SIZE,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            pending_ttl: DEFAULT_PENDING_TTL,
+            max_pending: DEFAULT_MAX_PENDING,
+            max_pending_bytes: DEFAULT_MAX_PENDING_BYTES,
+            session_keys: SessionKeyPolicy::default(),
+            features: rpc_models::server_capabilities(),
+            max_connections: None,
+            max_rps: None,
+            stream_threshold_bytes: DEFAULT_STREAM_THRESHOLD_BYTES,
+            stream_chunk_bytes: DEFAULT_STREAM_THRESHOLD_BYTES,
+            allow_legacy_handshake: false,
         }
     }
 }
 
 pub struct Server {
     pub private_key: RsaPrivateKey,
+    /// Signs handshake challenges instead of `private_key` when set.
+    pub ed25519_key: Option<ed25519_dalek::SigningKey>,
     authorized_keys: Vec<RsaPublicKey>,
     config: ServerConfig,
+    /// Registered keys are only persisted, and notifications for offline recipients
+    /// only queued, once this is opened.
+    db: Option<ServerDatabase>,
+    metrics: ServerMetrics,
 }
 impl Server {
     pub fn new(
@@ -38,37 +174,354 @@ impl Server {
     ) -> Self {
         Server {
             private_key,
+            ed25519_key: None,
             authorized_keys,
             config: config.unwrap_or_default(),
+            db: None,
+            metrics: ServerMetrics::default(),
+        }
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Opens the server's database, adding the keys registered in earlier runs to the
+    /// authorized keys, dropping the ones rotated away from, and dropping notifications
+    /// that expired while it was down. Usernames missing from the key log are logged.
+    pub fn open_database<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        config: &DbConfig,
+    ) -> Result<(), Error> {
+        let db = ServerDatabase::open(path, &self.private_key, config)?;
+        db.purge_expired(self.config.pending_ttl)?;
+        db.backfill_key_log()?;
+        let revoked = db.revoked_keys()?;
+        self.authorized_keys.retain(|key| !revoked.contains(key));
+        for key in db.authorized_keys()? {
+            if !self.authorized_keys.contains(&key) {
+                self.authorized_keys.push(key);
+            }
+        }
+        self.db = Some(db);
+        Ok(())
+    }
+
+    /// The enabled features the server can actually serve right now.
+    pub fn capabilities(&self) -> Capabilities {
+        self.config
+            .features
+            .iter()
+            .filter(|capability| match capability {
+                Capability::KeyLog | Capability::OfflineDelivery => self.db.is_some(),
+                Capability::Push | Capability::UserDirectory | Capability::MsgPack => true,
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Checks that `pub_key` may open a session. With open registration an unknown key
+    /// is authorized, and persisted if the database is open.
+    pub fn authorize(&mut self, pub_key: &RsaPublicKey) -> Result<(), Error> {
+        if self.authorized_keys.contains(pub_key) {
+            return Ok(());
+        }
+        if !self.config.open_registration {
+            Err(RpcError {
+                message: String::from("Client key is not authorized on this server"),
+                code: RpcErrorCode::Unauthorized,
+            })?;
+        }
+        if let Some(ref db) = self.db {
+            db.add_authorized_key(pub_key)?;
         }
+        self.authorized_keys.push(pub_key.clone());
+        Ok(())
+    }
+
+    /// Advertises the server listening on `addr` to the local network over mDNS, with its
+    /// key's fingerprint so clients can tell it apart from an impostor. A server bound
+    /// to every interface is advertised with their addresses, one bound to loopback
+    /// only to this machine. Stays advertised until the handle is dropped.
+    pub fn advertise_mdns(&self, addr: SocketAddr) -> Result<MdnsHandle, Error> {
+        let fingerprint = pki::fingerprint(&self.private_key.to_public_key())?;
+        let name = format!("carapace-{}", &fingerprint[..16]);
+        let daemon = ServiceDaemon::new()?;
+        let ip = addr.ip();
+        if ip.is_loopback() {
+            daemon.enable_interface(match ip {
+                IpAddr::V4(_) => IfKind::LoopbackV4,
+                IpAddr::V6(_) => IfKind::LoopbackV6,
+            })?;
+        }
+        let properties = [(rpc_models::MDNS_FINGERPRINT_KEY, fingerprint.as_str())];
+        let host = format!("{}.local.", name);
+        let service = if ip.is_unspecified() {
+            ServiceInfo::new(
+                rpc_models::MDNS_SERVICE_TYPE,
+                &name,
+                &host,
+                (),
+                addr.port(),
+                &properties[..],
+            )?
+            .enable_addr_auto()
+        } else {
+            ServiceInfo::new(
+                rpc_models::MDNS_SERVICE_TYPE,
+                &name,
+                &host,
+                ip,
+                addr.port(),
+                &properties[..],
+            )?
+        };
+        let fullname = service.get_fullname().to_string();
+        daemon.register(service)?;
+        Ok(MdnsHandle { daemon, fullname })
+    }
+
+    /// Hands `old`'s place in the authorized keys over to `new`, in the database too if
+    /// one is open, along with the username `old` registered. The caller checks that
+    /// whoever asked holds `old`.
+    pub fn rotate_authorized_key(
+        &mut self,
+        old: &RsaPublicKey,
+        new: &RsaPublicKey,
+    ) -> Result<(), Error> {
+        if !self.authorized_keys.contains(old) {
+            Err("Client key is not authorized on this server")?;
+        }
+        if let Some(ref db) = self.db {
+            db.replace_authorized_key(old, new)?;
+        }
+        self.authorized_keys.retain(|key| key != old && key != new);
+        self.authorized_keys.push(new.clone());
+        Ok(())
     }
 }
-pub async fn start_server<H: Handler + Clone + Send + Sync + 'static>(
+/// A server advertised over mDNS, from `Server::advertise_mdns`. Dropping it withdraws
+/// the advertisement.
+pub struct MdnsHandle {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+impl Drop for MdnsHandle {
+    fn drop(&mut self) {
+        // waits for the goodbye to go out, so browsers forget the server right away
+        if let Ok(unregistered) = self.daemon.unregister(&self.fullname) {
+            let _ = unregistered.recv_timeout(Duration::from_secs(1));
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// A running server, from `start_server_with_handle`. Dropping it shuts the server down
+/// without a grace period.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    // closed to stop accepting
+    stop_accepting: channel::Sender<()>,
+    // closed to drop the connections still open
+    drop_connections: channel::Sender<()>,
+    accepting: JoinHandle<Result<(), Error>>,
+    // every connection task holds a sender, so this closes once they've all ended
+    connections: channel::Receiver<()>,
+    open_connections: Arc<AtomicUsize>,
+}
+impl ServerHandle {
+    /// The address the server is bound to, with the actual port when it was started on
+    /// port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting connections, gives the open ones up to `grace` to finish, then
+    /// drops the rest. Resolves with the accept loop's result.
+    pub async fn shutdown(self, grace: Option<Duration>) -> Result<(), Error> {
+        self.stop_accepting.close();
+        let accepted = self.accepting.await;
+        if let Some(grace) = grace {
+            let _ = async_std::future::timeout(grace, self.connections.recv()).await;
+        }
+        self.drop_connections.close();
+        // cancelled connections end at their next await point
+        let _ = self.connections.recv().await;
+        accepted
+    }
+
+    /// How many connections are being served.
+    pub fn open_connections(&self) -> usize {
+        self.open_connections.load(Ordering::SeqCst)
+    }
+
+    /// Runs until the accept loop fails.
+    pub async fn join(self) -> Result<(), Error> {
+        self.accepting.await
+    }
+}
+
+/// Counts a connection as open for as long as it's held.
+struct OpenConnection(Arc<AtomicUsize>);
+impl OpenConnection {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        OpenConnection(count.clone())
+    }
+}
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Tells a connection past the limit why it's turned away, then closes it.
+async fn refuse_connection(mut stream: async_std::net::TcpStream) {
+    let response = Response::new(
+        serde_json::json!(null),
+        Some(RpcError {
+            message: String::from("max connections reached"),
+            code: RpcErrorCode::ServerError,
+        }),
+        String::new(),
+    );
+    if let Err(e) = response.send(&mut stream, Some(REFUSAL_TIMEOUT)).await {
+        eprintln!("Error: refusing a connection: {}", e);
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// Binds `ip:port` and serves connections from a background task. The listener is
+/// bound by the time this returns, so clients can connect to `local_addr()` right away.
+/// Connections past the handler's `max_connections` are refused without being served.
+pub async fn start_server_with_handle<H: Handler + Clone + Send + Sync + 'static>(
     handler: H,
     ip: String,
     port: u16,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<ServerHandle, Error> {
     let listener = TcpListener::bind(format!("{}:{}", ip, port)).await?;
-    let mut incoming = listener.incoming();
-    while let Some(stream) = incoming.next().await {
-        let mut stream = stream?;
-        let mut handler = handler.clone();
-        task::spawn(async move {
-            if let Err(e) = rpc::listen(&mut stream, &mut handler).await {
-                eprintln!("Error: {}", e);
+    let local_addr = listener.local_addr()?;
+    let (stop_accepting, accept_stopped) = channel::bounded::<()>(1);
+    let (drop_connections, connections_dropped) = channel::bounded::<()>(1);
+    let (alive, connections) = channel::bounded::<()>(1);
+    let max_connections = handler.max_connections().await;
+    let max_frame_size = handler.max_frame_size().await;
+    let open_connections = Arc::new(AtomicUsize::new(0));
+    let counted = open_connections.clone();
+    let accepting = task::spawn(async move {
+        let mut incoming = listener.incoming();
+        loop {
+            let stream = match future::select(incoming.next(), Box::pin(accept_stopped.recv())).await {
+                Either::Left((Some(stream), _)) => stream,
+                _ => break,
+            };
+            let mut stream = stream?;
+            // only this loop adds connections, so the count can't grow past the check
+            if max_connections.is_some_and(|max| counted.load(Ordering::SeqCst) >= max) {
+                refuse_connection(stream).await;
+                continue;
             }
-        });
+            let open = OpenConnection::new(&counted);
+            let mut handler = handler.clone();
+            let writer = FrameWriter::new(stream.clone());
+            let (pushes, pushed) = channel::unbounded::<Request>();
+            handler.connected(pushes);
+            // ends once the handler and every session it registered let go of the sender
+            let push_writer = writer.clone();
+            task::spawn(async move {
+                while let Ok(request) = pushed.recv().await {
+                    // in the session's codec, pushes only go out within one
+                    let frame = match push_writer.codec().encode(&request) {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            eprintln!("Error: {}", e);
+                            continue;
+                        }
+                    };
+                    if push_writer.write(&frame).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            let alive = alive.clone();
+            let dropped = connections_dropped.clone();
+            task::spawn(async move {
+                let listen = async {
+                    match rpc::listen_with_writer(&mut stream, &writer, &mut handler, max_frame_size)
+                        .await
+                    {
+                        Ok(()) => false,
+                        Err(Error::Timeout) => {
+                            eprintln!("Closing an idle connection");
+                            true
+                        }
+                        Err(e) => {
+                            eprintln!("Error: {}", e);
+                            false
+                        }
+                    }
+                };
+                // the push task holds the stream too, so an idle one is shut down here
+                let close = match future::select(Box::pin(listen), Box::pin(dropped.recv())).await {
+                    Either::Left((idle, _)) => idle,
+                    Either::Right(_) => true,
+                };
+                if close {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                handler.disconnected().await;
+                drop(open);
+                drop(alive);
+            });
+        }
+        Ok(())
+    });
+    Ok(ServerHandle {
+        local_addr,
+        stop_accepting,
+        drop_connections,
+        accepting,
+        connections,
+        open_connections,
+    })
+}
+
+pub async fn start_server<H: Handler + Clone + Send + Sync + 'static>(
+    handler: H,
+    ip: String,
+    port: u16,
+) -> Result<(), Error> {
+    start_server_with_handle(handler, ip, port).await?.join().await
+}
+
+/// Starts a server that also accepts `DEV_PLAINTEXT_SESSION`, skipping the handshake
+/// and all encryption. Refuses any address other than loopback.
+#[cfg(feature = "insecure-dev")]
+pub async fn start_insecure_dev_server(
+    mut handler: handler::ServerHandler,
+    ip: std::net::IpAddr,
+    port: u16,
+) -> Result<ServerHandle, Error> {
+    if !ip.is_loopback() {
+        Err(format!("Refusing to serve plaintext sessions on non-loopback address {}", ip))?;
     }
-    Ok(())
+    eprintln!("WARNING: serving unencrypted dev sessions on {}:{}", ip, port);
+    handler.allow_plaintext_sessions();
+    let ip = match ip {
+        std::net::IpAddr::V4(ip) => ip.to_string(),
+        std::net::IpAddr::V6(ip) => format!("[{}]", ip),
+    };
+    start_server_with_handle(handler, ip, port).await
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::{sync::Arc, time::Duration};
 
-    use crate::shared::pki::{decrypt_message, encrypt_message};
-    use crate::shared::rpc_models::{ClientEncryptionPackage, RespondClientChallenge, RespondServerChallenge};
-    use crate::shared::{pki, rpc_models};
+    use crate::shared::rpc_models::{RespondClientChallenge, RespondServerChallenge};
+    use crate::shared::{pki, rpc_models, ski};
     use crate::shared::rpc::{Request, Response};
 
     use self::handler::ServerHandler;
@@ -76,7 +529,9 @@ mod tests {
     use super::*;
     use async_std::net::TcpStream;
     use async_std::{sync::RwLock, task};
+    use rand_core::OsRng;
     use rsa::pkcs1v15::Signature;
+    use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey};
 
     #[test]
     fn test_server() {
@@ -103,19 +558,10 @@ mod tests {
         let handler = TestHandler::new();
         let handler_write = handler.clone();
         let handler_read = handler.clone();
-        let server_private_key = pki::gen_key().unwrap();
-        task::spawn(async {
-            let server = Server::new(
-                server_private_key,
-                Vec::new(),
-                None,
-            );
-
-            start_server(handler_write, String::from("127.0.0.1"), 8888).await.unwrap();
-        });
+        let handle =
+            task::block_on(start_server_with_handle(handler_write, String::from("127.0.0.1"), 0)).unwrap();
         task::block_on(async {
-            task::sleep(Duration::from_secs(1)).await;
-            let mut stream = TcpStream::connect("127.0.0.1:8888").await.unwrap();
+            let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
             assert!(stream.peer_addr().is_ok());
             let request = Request::new("test".to_string(), serde_json::json!("test"));
             let response = request.send(&mut stream, None).await.unwrap();
@@ -127,18 +573,19 @@ mod tests {
     #[test]
     fn test_default_handler() {
         let server_private_key = pki::gen_key().unwrap();
+        let config = ServerConfig {
+            open_registration: true,
+            ..ServerConfig::default()
+        };
         let server = Server::new(
             server_private_key,
             Vec::new(),
-            None,
+            Some(config),
         );
         let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
-        task::spawn(async move {
-            start_server(handler, String::from("127.0.0.1"), 8889).await.unwrap();
-        });
+        let handle = task::block_on(start_server_with_handle(handler, String::from("127.0.0.1"), 0)).unwrap();
         task::block_on(async {
-            task::sleep(Duration::from_secs(1)).await;
-            let mut stream = TcpStream::connect("127.0.0.1:8889").await.unwrap();
+            let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
             assert!(stream.peer_addr().is_ok());
             let request = Request::new(
                 rpc_models::START_SERVER_HANDSHAKE.to_string(),
@@ -148,53 +595,283 @@ mod tests {
             let challenge: String = serde_json::from_value(response.result).unwrap();
             assert!(challenge.len() == 36);
             let private_key = pki::gen_key().unwrap();
-            let challenge = challenge.as_bytes();
-            let sig = pki::sign_message(&private_key, challenge);
+            let sig = pki::sign_message(&private_key, challenge.as_bytes());
             let server_challenge = uuid::Uuid::new_v4().to_string();
-            let response = RespondClientChallenge {
+            let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+            let mut response = RespondClientChallenge {
                 pub_key: private_key.to_public_key(),
                 signiture: sig,
                 server_challenge: server_challenge.clone(),
+                key_type: rpc_models::KeyType::Rsa2048,
+                signing_key: None,
+                signing_key_binding: Vec::new(),
+                ephemeral_key: Some(X25519PublicKey::from(&ephemeral_secret)),
+                capabilities: rpc_models::client_capabilities(),
+                protocol_version: rpc_models::PROTOCOL_VERSION,
+                transcript_signature: Vec::new(),
             };
+            let client_transcript = response.transcript(&challenge).unwrap();
+            response.transcript_signature = pki::sign_message(&private_key, &client_transcript);
             let request = Request::new(
                 rpc_models::CLIENT_CHALLENGE_RESPONSE.to_string(),
                 serde_json::json!(response),
             );
             let response = request.send(&mut stream, None).await.unwrap();
             let response: RespondServerChallenge = serde_json::from_value(response.result).unwrap();
-            let sig = Signature::try_from(response.signiture.as_slice()).unwrap();
-            let server_pub_key = response.pub_key;
+            // the challenge is ours to pick, so only the transcript is signed
+            assert!(response.signiture.is_empty());
+            let sig = Signature::try_from(response.transcript_signature.as_slice()).unwrap();
             assert!(pki::verify_signature(
-                &server_pub_key,
-                server_challenge.as_bytes(),
+                &response.pub_key,
+                &response.transcript(&client_transcript).unwrap(),
                 &sig
             ));
 
-            let request = Request::new(
-                rpc_models::REQUEST_ENCRYPTION_PACKAGE.to_string(),
-                serde_json::json!(null),
-            );
-            let req_id = request.id.clone();
-            let encrypted_request = encrypt_message(
-                &server_pub_key,
-                serde_json::json!(request).to_string().as_bytes(),
-            )
-            .unwrap();
+            let shared_secret = ephemeral_secret.diffie_hellman(&response.ephemeral_key.unwrap());
+            let (shared_key, _) = ski::derive_session_key(shared_secret.as_bytes()).unwrap();
+            let request = Request::new(rpc_models::PING.to_string(), serde_json::json!(null));
             let request_params = rpc_models::EncryptedRequestParams {
-                enc_type: rpc_models::EncryptionType::RsaPkcs1v15,
-                data: encrypted_request,
+                enc_type: rpc_models::EncryptionType::AesGcm,
+                data: ski::seal_gcm(serde_json::json!(request).to_string().as_bytes(), &shared_key).unwrap(),
             };
-            let request = Request::new_with_id(
+            let request = Request::new(
                 rpc_models::ENCRYPTED_REQUEST.to_string(),
                 serde_json::json!(request_params),
-                req_id,
             );
             let response = request.send(&mut stream, None).await.unwrap();
             let ct: Vec<u8> = serde_json::from_value(response.result).unwrap();
-            let response = decrypt_message(&private_key, &ct).unwrap();
+            let response = ski::open_gcm(&ct, &shared_key).unwrap();
             let response: Response = serde_json::from_slice(&response).unwrap();
-            let package: ClientEncryptionPackage = serde_json::from_value(response.result).unwrap();
-            println!("{:?}", package.shared_key());
+            assert_eq!(response.result, serde_json::json!("pong"));
+        });
+    }
+
+    #[test]
+    fn test_shutdown() {
+        #[derive(Clone, Default)]
+        struct SlowHandler {
+            disconnects: Arc<AtomicUsize>,
+        }
+        impl Handler for SlowHandler {
+            async fn handle(&mut self, request: Request) -> Response {
+                task::sleep(Duration::from_millis(300)).await;
+                Response::new(serde_json::json!("done"), None, request.id)
+            }
+            async fn disconnected(&mut self) {
+                self.disconnects.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        let handler = SlowHandler::default();
+        let disconnects = handler.disconnects.clone();
+        task::block_on(async {
+            let handle = start_server_with_handle(handler.clone(), String::from("127.0.0.1"), 0)
+                .await
+                .unwrap();
+            let addr = handle.local_addr();
+            assert_ne!(addr.port(), 0);
+            // in flight when the shutdown starts, and disconnects once answered
+            let in_flight = task::spawn(async move {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                let request = Request::new("slow".to_string(), serde_json::json!(null));
+                request.send(&mut stream, None).await
+            });
+            task::sleep(Duration::from_millis(100)).await;
+            let started = std::time::Instant::now();
+            handle.shutdown(Some(Duration::from_secs(5))).await.unwrap();
+            assert!(started.elapsed() < Duration::from_secs(5));
+            let response = in_flight.await.unwrap();
+            assert_eq!(response.result, serde_json::json!("done"));
+            assert_eq!(disconnects.load(Ordering::SeqCst), 1);
+            assert!(TcpStream::connect(addr).await.is_err());
+
+            // an idle connection is dropped once the grace period runs out
+            let handle = start_server_with_handle(handler, String::from("127.0.0.1"), 0)
+                .await
+                .unwrap();
+            let mut idle = TcpStream::connect(handle.local_addr()).await.unwrap();
+            task::sleep(Duration::from_millis(100)).await;
+            handle.shutdown(Some(Duration::from_millis(100))).await.unwrap();
+            let mut buf = [0; 1];
+            assert!(matches!(idle.read(&mut buf).await, Ok(0) | Err(_)));
+            assert_eq!(disconnects.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let config = ServerConfig {
+            max_connections: Some(3),
+            ..ServerConfig::default()
+        };
+        let server = Server::new(pki::gen_key().unwrap(), Vec::new(), Some(config));
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        task::block_on(async {
+            let handle = start_server_with_handle(handler, String::from("127.0.0.1"), 0)
+                .await
+                .unwrap();
+            let handshake = || {
+                Request::new(rpc_models::START_SERVER_HANDSHAKE.to_string(), serde_json::json!(null))
+            };
+            let mut clients = Vec::new();
+            for _ in 0..3 {
+                let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+                // answered, so the server counts it before the next one connects
+                let response = handshake().send(&mut stream, None).await.unwrap();
+                assert!(response.into_result().is_ok());
+                clients.push(stream);
+            }
+            assert_eq!(handle.open_connections(), 3);
+
+            let mut refused = TcpStream::connect(handle.local_addr()).await.unwrap();
+            let frame = rpc::read_frame(&mut refused, rpc::MAX_FRAME_SIZE)
+                .await
+                .unwrap()
+                .unwrap();
+            let response: Response = serde_json::from_slice(&frame).unwrap();
+            let error = response.into_result().unwrap_err();
+            assert_eq!(error.code, RpcErrorCode::ServerError);
+            assert_eq!(error.message, "max connections reached");
+            assert!(rpc::read_frame(&mut refused, rpc::MAX_FRAME_SIZE).await.unwrap().is_none());
+            assert_eq!(handle.open_connections(), 3);
+
+            drop(clients.pop());
+            let started = std::time::Instant::now();
+            while handle.open_connections() != 2 {
+                assert!(started.elapsed() < Duration::from_secs(5));
+                task::sleep(Duration::from_millis(10)).await;
+            }
+            let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+            let response = handshake().send(&mut stream, None).await.unwrap();
+            assert!(response.into_result().is_ok());
+            handle.shutdown(None).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let config = ServerConfig {
+            idle_timeout: Duration::from_millis(300),
+            ..ServerConfig::default()
+        };
+        let server = Server::new(pki::gen_key().unwrap(), Vec::new(), Some(config));
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        task::block_on(async {
+            let handle = start_server_with_handle(handler, String::from("127.0.0.1"), 0)
+                .await
+                .unwrap();
+            let request = || {
+                Request::new(rpc_models::GET_SERVER_INFO.to_string(), serde_json::json!(null))
+            };
+
+            // a client that never sends anything is let go
+            let mut quiet = TcpStream::connect(handle.local_addr()).await.unwrap();
+            let started = std::time::Instant::now();
+            let closed = async_std::future::timeout(
+                Duration::from_secs(5),
+                rpc::read_frame(&mut quiet, rpc::MAX_FRAME_SIZE),
+            );
+            assert!(closed.await.unwrap().unwrap().is_none());
+            assert!(started.elapsed() >= Duration::from_millis(300));
+
+            // one that keeps asking stays connected past the deadline
+            let mut active = TcpStream::connect(handle.local_addr()).await.unwrap();
+            for _ in 0..10 {
+                let response = request().send(&mut active, Some(Duration::from_secs(1))).await;
+                assert!(response.unwrap().into_result().is_ok());
+                task::sleep(Duration::from_millis(100)).await;
+            }
+            handle.shutdown(None).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_max_frame_bytes() {
+        let config = ServerConfig {
+            max_frame_bytes: 256,
+            ..ServerConfig::default()
+        };
+        let server = Server::new(pki::gen_key().unwrap(), Vec::new(), Some(config));
+        let handler = ServerHandler::new(Arc::new(RwLock::new(server)));
+        task::block_on(async {
+            let handle = start_server_with_handle(handler, String::from("127.0.0.1"), 0)
+                .await
+                .unwrap();
+            // a request padded out to exactly the limit is served
+            let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+            let request = |padding: usize| {
+                let id = "x".repeat(padding);
+                let method = rpc_models::GET_SERVER_INFO.to_string();
+                Request::new_with_id(method, serde_json::json!(null), id)
+            };
+            let base = serde_json::to_vec(&request(0)).unwrap().len();
+            let response = request(256 - base).send(&mut stream, None).await.unwrap();
+            assert!(response.into_result().is_ok());
+
+            // one byte more is refused and the connection closed
+            rpc::write_frame(&mut stream, &serde_json::to_vec(&request(257 - base)).unwrap())
+                .await
+                .unwrap();
+            let frame = rpc::read_frame(&mut stream, rpc::MAX_FRAME_SIZE)
+                .await
+                .unwrap()
+                .unwrap();
+            let response: Response = serde_json::from_slice(&frame).unwrap();
+            assert_eq!(response.into_result().unwrap_err().code, RpcErrorCode::ParseError);
+            // the unread payload may turn the close into a reset
+            let closed = rpc::read_frame(&mut stream, rpc::MAX_FRAME_SIZE).await;
+            assert!(!matches!(closed, Ok(Some(_))));
+            handle.shutdown(None).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_request_deadline() {
+        use std::sync::atomic::AtomicBool;
+
+        #[derive(Clone)]
+        struct SlowHandler {
+            finished: Arc<AtomicBool>,
+        }
+        impl Handler for SlowHandler {
+            async fn handle(&mut self, request: Request) -> Response {
+                if request.method == "slow" {
+                    task::sleep(Duration::from_millis(600)).await;
+                    self.finished.store(true, Ordering::SeqCst);
+                }
+                Response::new(serde_json::json!("done"), None, request.id)
+            }
+            async fn deadlines(&self) -> rpc::Deadlines {
+                rpc::Deadlines {
+                    idle: None,
+                    request: Some(Duration::from_millis(200)),
+                }
+            }
+        }
+
+        let finished = Arc::new(AtomicBool::new(false));
+        let handler = SlowHandler {
+            finished: finished.clone(),
+        };
+        task::block_on(async {
+            let handle = start_server_with_handle(handler, String::from("127.0.0.1"), 0)
+                .await
+                .unwrap();
+            let mut stream = TcpStream::connect(handle.local_addr()).await.unwrap();
+            let slow = Request::new(String::from("slow"), serde_json::json!(null));
+            let started = std::time::Instant::now();
+            let response = slow.send(&mut stream, Some(Duration::from_secs(2))).await.unwrap();
+            assert!(started.elapsed() < Duration::from_millis(500));
+            let error = response.into_result().unwrap_err();
+            assert_eq!(error.code, RpcErrorCode::ServerError);
+            assert_eq!(error.message, "request timed out");
+            assert!(!finished.load(Ordering::SeqCst));
+
+            // the handler wasn't cut off, and the connection is still served once it's done
+            let fast = Request::new(String::from("fast"), serde_json::json!(null));
+            let response = fast.send(&mut stream, Some(Duration::from_secs(2))).await.unwrap();
+            assert!(finished.load(Ordering::SeqCst));
+            assert_eq!(response.into_result().unwrap(), serde_json::json!("done"));
+            handle.shutdown(None).await.unwrap();
         });
     }
 }
@@ -1,22 +1,72 @@
-use std::{collections::HashMap, net::IpAddr};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
 
 use async_std::net::TcpStream;
 use rsa::RsaPublicKey;
 
-use crate::shared::rpc::Request;
+use crate::shared::{rpc::Request, rpc_models::AckStatus};
 
+/// A notification held for recipients that were offline when it was sent.
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct PendingNotification {
     recipients: Vec<String>,
     notification: Request,
+    queued_at: SystemTime,
 }
 impl PendingNotification {
     pub fn new(recipients: Vec<String>, notification: Request) -> Self {
         PendingNotification {
             recipients,
             notification,
+            queued_at: SystemTime::now(),
         }
     }
+    pub fn recipients(&self) -> &[String] {
+        &self.recipients
+    }
+    pub fn notification(&self) -> &Request {
+        &self.notification
+    }
+    pub fn into_notification(self) -> Request {
+        self.notification
+    }
+    /// Whether the notification has been queued for longer than `ttl`.
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        self.queued_at
+            .elapsed()
+            .map_or(false, |age| age > ttl)
+    }
+}
+
+/// Who a forwarded message came from and went to, kept so its recipients'
+/// acknowledgements can be passed back to the sender.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SentMessage {
+    /// Fingerprint of the sender's key.
+    pub sender: String,
+    pub recipients: Vec<String>,
+    /// The furthest each recipient acknowledged, by fingerprint.
+    pub acks: HashMap<String, AckStatus>,
+    pub sent_at: SystemTime,
+}
+impl SentMessage {
+    pub fn new(sender: String, recipients: Vec<String>) -> Self {
+        SentMessage {
+            sender,
+            recipients,
+            acks: HashMap::new(),
+            sent_at: SystemTime::now(),
+        }
+    }
+    /// Whether the message was sent longer than `ttl` ago.
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        self.sent_at
+            .elapsed()
+            .map_or(false, |age| age > ttl)
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -0,0 +1,56 @@
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Counts a connection's requests in one second windows. Past `max_rps` in a window,
+/// requests are refused until the next one starts.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    max_rps: u32,
+    window_start: Instant,
+    count: u32,
+}
+impl RateLimiter {
+    pub fn new(max_rps: u32) -> Self {
+        RateLimiter {
+            max_rps,
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    /// Counts a request made at `now` and says whether it may be served. Refused
+    /// requests don't count against the window.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        if now.saturating_duration_since(self.window_start) >= WINDOW {
+            self.window_start = now;
+            self.count = 0;
+        }
+        if self.count >= self.max_rps {
+            return false;
+        }
+        self.count += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter_window() {
+        let mut limiter = RateLimiter::new(3);
+        let start = limiter.window_start;
+        for _ in 0..3 {
+            assert!(limiter.allow(start));
+        }
+        assert!(!limiter.allow(start + Duration::from_millis(999)));
+        // a second in, the window starts over
+        assert!(limiter.allow(start + WINDOW));
+        assert!(limiter.allow(start + WINDOW));
+        assert!(limiter.allow(start + WINDOW));
+        assert!(!limiter.allow(start + WINDOW));
+        assert!(limiter.allow(start + WINDOW * 2));
+    }
+}
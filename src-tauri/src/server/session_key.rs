@@ -0,0 +1,115 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// How much a session key may protect before the client has to replace it with
+/// `REKEY`. Past any limit the key is only good for rekeying, and only for `grace`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionKeyPolicy {
+    pub max_age: Duration,
+    /// Plaintext bytes sealed or opened under the key, both directions together.
+    pub max_bytes: u64,
+    pub max_messages: u64,
+    pub grace: Duration,
+}
+impl Default for SessionKeyPolicy {
+    fn default() -> Self {
+        SessionKeyPolicy {
+            max_age: Duration::from_secs(60 * 60),
+            max_bytes: 1 << 30,
+            max_messages: 1 << 20,
+            grace: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KeyState {
+    Fresh,
+    /// Over a limit, so everything but `REKEY` is refused.
+    Exhausted,
+    /// Over a limit for longer than the grace window. Nothing is accepted under it.
+    Expired,
+}
+
+/// What the current session key has protected so far.
+pub struct KeyUsage {
+    created: Instant,
+    bytes: u64,
+    messages: u64,
+    exhausted_at: Option<Instant>,
+}
+impl KeyUsage {
+    pub fn new() -> Self {
+        KeyUsage {
+            created: Instant::now(),
+            bytes: 0,
+            messages: 0,
+            exhausted_at: None,
+        }
+    }
+
+    pub fn record(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        self.messages += 1;
+    }
+
+    /// Where the key stands under `policy`. The grace window starts the first time the
+    /// key is found over a limit.
+    pub fn check(&mut self, policy: &SessionKeyPolicy) -> KeyState {
+        if self.exhausted_at.is_none()
+            && (self.created.elapsed() > policy.max_age
+                || self.bytes > policy.max_bytes
+                || self.messages > policy.max_messages)
+        {
+            self.exhausted_at = Some(Instant::now());
+        }
+        match self.exhausted_at {
+            None => KeyState::Fresh,
+            Some(at) if at.elapsed() <= policy.grace => KeyState::Exhausted,
+            Some(_) => KeyState::Expired,
+        }
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted_at.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_usage_limits() {
+        let policy = SessionKeyPolicy {
+            max_bytes: 100,
+            max_messages: 3,
+            grace: Duration::from_millis(100),
+            ..SessionKeyPolicy::default()
+        };
+        let mut usage = KeyUsage::new();
+        usage.record(100);
+        assert_eq!(usage.check(&policy), KeyState::Fresh);
+        usage.record(1);
+        assert_eq!(usage.check(&policy), KeyState::Exhausted);
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(usage.check(&policy), KeyState::Expired);
+
+        let mut usage = KeyUsage::new();
+        for _ in 0..4 {
+            usage.record(0);
+        }
+        assert_eq!(usage.check(&policy), KeyState::Exhausted);
+
+        let policy = SessionKeyPolicy {
+            max_age: Duration::from_millis(50),
+            ..SessionKeyPolicy::default()
+        };
+        let mut usage = KeyUsage::new();
+        assert_eq!(usage.check(&policy), KeyState::Fresh);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(usage.check(&policy), KeyState::Exhausted);
+        assert!(usage.is_exhausted());
+    }
+}
@@ -1,92 +1,546 @@
-use std::error::Error;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use sled::Db;
 use uuid::Uuid;
 
-use super::ski;
+use crate::Error;
+use super::kv::{Batch, KvPair, KvStore, SledStore, DEFAULT_TREE};
+#[cfg(feature = "sqlite")]
+use super::kv::SqliteStore;
+use super::{json, ski};
+
+/// Storage engine a database is opened with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Backend {
+    #[default]
+    Sled,
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+/// Tuning knobs passed through to the backend when a store is opened. SQLite only
+/// uses the cache size.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DbConfig {
+    pub cache_capacity: u64,
+    pub flush_every_ms: Option<u64>,
+    pub use_compression: bool,
+    #[serde(default)]
+    pub backend: Backend,
+}
+impl Default for DbConfig {
+    fn default() -> Self {
+        DbConfig {
+            cache_capacity: 1024 * 1024 * 1024,
+            flush_every_ms: Some(500),
+            use_compression: false,
+            backend: Backend::default(),
+        }
+    }
+}
+impl DbConfig {
+    /// Opens the store at `path`. Sled uses it as a directory, SQLite as a file with
+    /// the extension swapped for `.sqlite`.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<Box<dyn KvStore>, Error> {
+        let path = path.as_ref().to_path_buf();
+        match self.backend {
+            Backend::Sled => Ok(Box::new(SledStore::open(path, self)?)),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite => Ok(Box::new(SqliteStore::open(
+                path.with_extension("sqlite"),
+                self,
+            )?)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StorageUsage {
+    pub entries: usize,
+    pub size_on_disk: u64,
+}
+
+/// Plaintext tree holding the salt the store's key is derived with, or the mark of a
+/// store keyed directly with a data key.
+const KDF_TREE: &str = "kdf";
+const SALT_KEY: &[u8] = b"salt";
+const DATA_KEY_MARK: &[u8] = b"data_key";
 
 pub struct EntryDb {
-    pub db: Db,
+    store: Box<dyn KvStore>,
     key: Vec<u8>,
 }
 impl EntryDb {
-    pub fn new(key: &[u8], db: Db) -> Self {
-        Self {
-            db,
-            key: Vec::from(key),
+    /// Opens an entry database keyed by `passkey` run through Argon2id with the
+    /// store's salt. A store without a salt gets one, and any entries it already holds
+    /// under the raw passkey are re-encrypted.
+    pub fn new(passkey: &[u8], store: Box<dyn KvStore>) -> Result<Self, Error> {
+        if Self::uses_data_key(&*store)? {
+            Err("Store is keyed with a data key, not a passkey")?;
+        }
+        if let Some(salt) = store.get(KDF_TREE, SALT_KEY)? {
+            let key = ski::derive_key(passkey, &salt)?.to_vec();
+            return Ok(Self { store, key });
         }
+        let salt = ski::salt();
+        let db = Self {
+            key: ski::derive_key(passkey, &salt)?.to_vec(),
+            store,
+        };
+        db.upgrade_legacy_entries(passkey, salt)?;
+        Ok(db)
     }
+
+    /// Opens an entry database whose entries are encrypted directly under `key`, which
+    /// has to be random, e.g. derived from a profile's master key. A new store is marked
+    /// as keyed this way; one still keyed by a passkey is refused.
+    pub fn with_key(key: &[u8], store: Box<dyn KvStore>) -> Result<Self, Error> {
+        if !Self::uses_data_key(&*store)? {
+            let mut legacy = store.get(KDF_TREE, SALT_KEY)?.is_some();
+            for tree in store.tree_names()? {
+                if tree != KDF_TREE && !store.page(&tree, 0, 1)?.is_empty() {
+                    legacy = true;
+                }
+            }
+            if legacy {
+                Err("Store is keyed by a passkey, migrate it first")?;
+            }
+            store.insert(KDF_TREE, DATA_KEY_MARK, &[])?;
+        }
+        Ok(Self {
+            store,
+            key: key.to_vec(),
+        })
+    }
+
+    /// Whether `store` is keyed with a data key rather than a passkey.
+    pub fn uses_data_key(store: &dyn KvStore) -> Result<bool, Error> {
+        store.contains(KDF_TREE, DATA_KEY_MARK)
+    }
+
+    fn upgrade_legacy_entries(&self, passkey: &[u8], salt: Vec<u8>) -> Result<(), Error> {
+        let mut batch = Batch::default();
+        for tree in self.store.tree_names()? {
+            if tree == KDF_TREE {
+                continue;
+            }
+            for (key, entry) in self.store.iter(&tree)? {
+                let value = open_entry(passkey, &entry)?;
+                batch.insert(&tree, &key, seal_entry(&self.key, &value, None)?);
+            }
+        }
+        batch.insert(KDF_TREE, SALT_KEY, salt);
+        self.store.apply_batch(batch)
+    }
+
+    /// Re-encrypts every entry under `passkey` with a fresh salt. It's one batch, so
+    /// the store ends up under either the old or the new key and never a mix.
+    pub fn rekey(&mut self, passkey: &[u8]) -> Result<(), Error> {
+        let salt = ski::salt();
+        let key = ski::derive_key(passkey, &salt)?.to_vec();
+        let mut batch = Batch::default();
+        batch.remove(KDF_TREE, DATA_KEY_MARK);
+        batch.insert(KDF_TREE, SALT_KEY, salt);
+        self.reencrypt(key, batch)
+    }
+
+    /// Re-encrypts every entry directly under `key`, like `rekey`, and marks the store
+    /// as keyed with a data key from then on. Also moves a passkey store over.
+    pub fn set_key(&mut self, key: &[u8]) -> Result<(), Error> {
+        let mut batch = Batch::default();
+        batch.remove(KDF_TREE, SALT_KEY);
+        batch.insert(KDF_TREE, DATA_KEY_MARK, vec![]);
+        self.reencrypt(key.to_vec(), batch)
+    }
+
+    /// Applies `batch` along with every entry re-encrypted under `key`, each keeping
+    /// its expiry.
+    fn reencrypt(&mut self, key: Vec<u8>, mut batch: Batch) -> Result<(), Error> {
+        for tree in self.store.tree_names()? {
+            if tree == KDF_TREE {
+                continue;
+            }
+            for (id, entry) in self.store.iter(&tree)? {
+                let entry: Entry = json::from_slice(&entry)?;
+                let value = entry.open(&self.key)?;
+                batch.insert(&tree, &id, seal_entry(&key, &value, entry.expires_at)?);
+            }
+        }
+        self.store.apply_batch(batch)?;
+        self.store.flush()?;
+        self.key = key;
+        Ok(())
+    }
+
+    /// Whether the store's entries open under the passkey it was opened with, judged by
+    /// the first entry found. An empty store opens under any.
+    pub fn unlocks(&self) -> Result<bool, Error> {
+        for tree in self.store.tree_names()? {
+            if tree == KDF_TREE {
+                continue;
+            }
+            if let Some((_, entry)) = self.store.page(&tree, 0, 1)?.pop() {
+                return Ok(open_entry(&self.key, &entry).is_ok());
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn store(&self) -> &dyn KvStore {
+        &*self.store
+    }
+    /// Serializes and encrypts a value into the stored `Entry` format, each with a
+    /// fresh nonce.
+    pub fn encrypt_value<I: Serialize>(&self, value: &I) -> Result<Vec<u8>, Error> {
+        seal_entry(&self.key, &serde_json::to_vec(value)?, None)
+    }
+
+    pub fn decrypt_value<I: DeserializeOwned>(&self, entry: &[u8]) -> Result<I, Error> {
+        let value = open_entry(&self.key, entry)?;
+        json::from_slice(&value)
+    }
+
     pub fn get_entry<I: Serialize + DeserializeOwned>(
         &self,
         id: &str,
-    ) -> Result<I, Box<dyn Error>> {
-        let entry = self.db.get(id)?;
-        let entry = entry.ok_or("Id not found")?;
-        let entry: Entry = serde_json::from_str(std::str::from_utf8(&entry)?)?;
-        let nonce = entry.nonce;
-        let value = ski::decrypt_gcm(&entry.value, &self.key, &nonce)?;
-        let value: I = serde_json::from_str(std::str::from_utf8(&value)?)?;
-        Ok(value)
+    ) -> Result<I, Error> {
+        let entry = self.store.get(DEFAULT_TREE, id.as_bytes())?;
+        let entry: Entry = json::from_slice(&entry.ok_or("Id not found")?)?;
+        if entry.is_expired(SystemTime::now()) {
+            Err(Error::Expired)?;
+        }
+        // the passphrase was checked when the master key was unlocked, so an entry that
+        // doesn't open is damaged or was sealed under some other key
+        let value = entry.open(&self.key).map_err(|_| {
+            Error::crypto(format!("Entry {} doesn't decrypt under the store's key", id))
+        })?;
+        json::from_slice(&value)
     }
 
     pub fn get_all_entries<I: Serialize + DeserializeOwned>(
         &self,
-    ) -> Result<Vec<(String, I)>, Box<dyn Error>> {
+    ) -> Result<Vec<(String, I)>, Error> {
+        self.get_entries_page(0, usize::MAX)
+    }
+
+    /// Decrypts up to `limit` entries, ordered by id, after skipping the first
+    /// `offset`. The rest of the store isn't read, and expired entries are left out.
+    pub fn get_entries_page<I: Serialize + DeserializeOwned>(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(String, I)>, Error> {
+        self.iter_decrypted(offset, limit)?.collect()
+    }
+
+    /// Decrypts every entry and keeps the ones `predicate` accepts.
+    pub fn find_entries<I, F>(&self, predicate: F) -> Result<Vec<(String, I)>, Error>
+    where
+        I: DeserializeOwned + Serialize,
+        F: Fn(&I) -> bool,
+    {
         let mut entries = vec![];
-        for entry in self.db.iter() {
-            let entry = entry?;
-            let id = entry.0;
-            let entry: Entry = serde_json::from_str(std::str::from_utf8(&entry.1)?)?;
-            let nonce = entry.nonce;
-            let value = ski::decrypt_gcm(&entry.value, &self.key, &nonce)?;
-            let value: I = serde_json::from_str(std::str::from_utf8(&value)?)?;
-            entries.push((String::from_utf8(id.to_vec())?, value));
+        for entry in self.iter_decrypted(0, usize::MAX)? {
+            let (id, value) = entry?;
+            if predicate(&value) {
+                entries.push((id, value));
+            }
         }
         Ok(entries)
     }
 
+    /// Like `find_entries`, but only keeps the ids, for callers that fetch the
+    /// entries they need afterwards.
+    pub fn find_entry_ids<I, F>(&self, predicate: F) -> Result<Vec<String>, Error>
+    where
+        I: DeserializeOwned + Serialize,
+        F: Fn(&I) -> bool,
+    {
+        let mut ids = vec![];
+        for entry in self.iter_decrypted::<I>(0, usize::MAX)? {
+            let (id, value) = entry?;
+            if predicate(&value) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// The entries whose ids start with `prefix`, ordered by id and decrypted one at a
+    /// time as they're iterated. Expired entries are skipped.
+    pub fn scan_prefix<'a, I: DeserializeOwned + 'a>(
+        &'a self,
+        prefix: &str,
+    ) -> Result<impl Iterator<Item = Result<(String, I), Error>> + 'a, Error> {
+        let entries = self.store.scan_prefix(DEFAULT_TREE, prefix.as_bytes())?;
+        Ok(self.decrypt_entries(entries))
+    }
+
+    /// Decrypts entries one at a time as they're iterated, ordered by id. Expired
+    /// entries are skipped, so a page can come back short until they're purged.
+    fn iter_decrypted<'a, I: DeserializeOwned + 'a>(
+        &'a self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<impl Iterator<Item = Result<(String, I), Error>> + 'a, Error> {
+        let entries = self.store.page(DEFAULT_TREE, offset, limit)?;
+        Ok(self.decrypt_entries(entries))
+    }
+
+    fn decrypt_entries<'a, I: DeserializeOwned + 'a>(
+        &'a self,
+        entries: Vec<KvPair>,
+    ) -> impl Iterator<Item = Result<(String, I), Error>> + 'a {
+        let now = SystemTime::now();
+        entries.into_iter().filter_map(move |(id, entry)| {
+            let entry: Entry = match json::from_slice(&entry) {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+            if entry.is_expired(now) {
+                return None;
+            }
+            let value = entry
+                .open(&self.key)
+                .and_then(|value| json::from_slice(&value));
+            Some(value.and_then(|value| Ok((String::from_utf8(id)?, value))))
+        })
+    }
+
+    /// When the entry under `id` expires, `None` if it doesn't or isn't there.
+    fn expiry_of(&self, id: &str) -> Result<Option<SystemTime>, Error> {
+        match self.store.get(DEFAULT_TREE, id.as_bytes())? {
+            Some(entry) => Ok(json::from_slice::<Entry>(&entry)?.expires_at),
+            None => Ok(None),
+        }
+    }
+
+    /// Replaces the entry under `id`. An expiring entry keeps its expiry.
     pub fn update_entry<I: Serialize + DeserializeOwned>(
         &self,
         id: &str,
         entry: I,
-    ) -> Result<(), Box<dyn Error>> {
-        let serialized_entry = serde_json::to_string(&entry)?;
-        let nonce = ski::nonce();
-        let serialized_entry = ski::encrypt_gcm(serialized_entry.as_bytes(), &self.key, &nonce)?;
-        let serialized_entry = Entry::new(nonce, serialized_entry);
-        let serialized_entry = serde_json::to_string(&serialized_entry)?;
-        self.db.insert(id, serialized_entry.as_str())?;
+    ) -> Result<(), Error> {
+        let sealed = seal_entry(&self.key, &serde_json::to_vec(&entry)?, self.expiry_of(id)?)?;
+        self.store.insert(DEFAULT_TREE, id.as_bytes(), &sealed)?;
         Ok(())
     }
 
     pub fn save_entry<I: Serialize + DeserializeOwned>(
         &self,
         entry: I,
-    ) -> Result<String, Box<dyn Error>> {
-        let nonce = ski::nonce();
+    ) -> Result<String, Error> {
         let id = Uuid::new_v4().to_string();
-        let serialized_entry = serde_json::to_string(&entry)?;
-        let serialized_entry = ski::encrypt_gcm(serialized_entry.as_bytes(), &self.key, &nonce)?;
-        let serialized_entry = Entry::new(nonce, serialized_entry);
-        let serialized_entry = serde_json::to_string(&serialized_entry)?;
-        self.db.insert(id.clone(), serialized_entry.as_str())?;
+        self.store
+            .insert(DEFAULT_TREE, id.as_bytes(), &self.encrypt_value(&entry)?)?;
         Ok(id)
     }
 
-    pub fn delete_entry(&self, id: &str) -> Result<(), Box<dyn Error>> {
-        self.db.remove(id)?;
-        Ok(())
+    /// Like `save_entry`, but the entry expires `ttl` from now: reading it fails with
+    /// `Error::Expired`, listings skip it and `purge_expired` removes it.
+    pub fn save_entry_with_ttl<I: Serialize + DeserializeOwned>(
+        &self,
+        entry: I,
+        ttl: Duration,
+    ) -> Result<String, Error> {
+        let expires_at = SystemTime::now()
+            .checked_add(ttl)
+            .ok_or("Time to live is too long")?;
+        let id = Uuid::new_v4().to_string();
+        let sealed = seal_entry(&self.key, &serde_json::to_vec(&entry)?, Some(expires_at))?;
+        self.store.insert(DEFAULT_TREE, id.as_bytes(), &sealed)?;
+        Ok(id)
     }
+
+    /// Removes every expired entry in one batch and returns how many there were. Only
+    /// the plaintext expiry is read, nothing is decrypted.
+    pub fn purge_expired(&self) -> Result<usize, Error> {
+        let now = SystemTime::now();
+        let mut batch = Batch::default();
+        let mut purged = 0;
+        for (id, entry) in self.store.iter(DEFAULT_TREE)? {
+            if json::from_slice::<Entry>(&entry)?.is_expired(now) {
+                batch.remove(DEFAULT_TREE, &id);
+                purged += 1;
+            }
+        }
+        if purged > 0 {
+            self.store.apply_batch(batch)?;
+        }
+        Ok(purged)
+    }
+
+    /// Saves all of `entries` in one batch, so either every one of them is stored or
+    /// none is. Ids are generated and values encrypted, each with its own nonce, before
+    /// the store is touched. Returns the ids in the order of `entries`.
+    pub fn save_batch<I: Serialize + DeserializeOwned>(
+        &self,
+        entries: Vec<I>,
+    ) -> Result<Vec<String>, Error> {
+        let mut ids = vec![];
+        let mut batch = Batch::default();
+        for entry in entries {
+            let id = Uuid::new_v4().to_string();
+            batch.insert(DEFAULT_TREE, id.as_bytes(), self.encrypt_value(&entry)?);
+            ids.push(id);
+        }
+        self.store.apply_batch(batch)?;
+        Ok(ids)
+    }
+
+    /// Like `update_entry` for several entries at once, all written or none.
+    pub fn update_batch<I: Serialize + DeserializeOwned>(
+        &self,
+        updates: Vec<(&str, I)>,
+    ) -> Result<(), Error> {
+        let mut batch = Batch::default();
+        for (id, entry) in updates {
+            let sealed = seal_entry(&self.key, &serde_json::to_vec(&entry)?, self.expiry_of(id)?)?;
+            batch.insert(DEFAULT_TREE, id.as_bytes(), sealed);
+        }
+        self.store.apply_batch(batch)
+    }
+
+    /// Every unexpired entry of every tree, opened, e.g. to be carried to another
+    /// profile.
+    pub fn export_entries(&self) -> Result<Vec<ExportedEntry>, Error> {
+        let now = SystemTime::now();
+        let mut exported = vec![];
+        for tree in self.store.tree_names()? {
+            if tree == KDF_TREE {
+                continue;
+            }
+            for (id, entry) in self.store.iter(&tree)? {
+                let entry: Entry = json::from_slice(&entry)?;
+                if entry.is_expired(now) {
+                    continue;
+                }
+                exported.push(ExportedEntry {
+                    tree: tree.clone(),
+                    id,
+                    value: entry.open(&self.key)?,
+                    expires_at: entry.expires_at,
+                });
+            }
+        }
+        Ok(exported)
+    }
+
+    /// Seals `entries` under this database's key and writes them in one batch. Each
+    /// replaces any entry with the same tree and id, others are left alone.
+    pub fn import_entries(&self, entries: Vec<ExportedEntry>) -> Result<(), Error> {
+        let mut batch = Batch::default();
+        for entry in entries {
+            if entry.tree == KDF_TREE {
+                Err("Entries can't be imported into the key derivation tree")?;
+            }
+            let sealed = seal_entry(&self.key, &entry.value, entry.expires_at)?;
+            batch.insert(&entry.tree, &entry.id, sealed);
+        }
+        self.store.apply_batch(batch)
+    }
+
+    pub fn delete_entry(&self, id: &str) -> Result<(), Error> {
+        self.store.remove(DEFAULT_TREE, id.as_bytes())
+    }
+
+    pub fn contains(&self, id: &str) -> Result<bool, Error> {
+        self.store.contains(DEFAULT_TREE, id.as_bytes())
+    }
+
+    pub fn len(&self) -> Result<usize, Error> {
+        self.store.len(DEFAULT_TREE)
+    }
+
+    /// Number of entries, for paging through them with `get_entries_page`. Nothing is
+    /// decrypted.
+    pub fn count_entries(&self) -> Result<usize, Error> {
+        self.len()
+    }
+
+    pub fn clear(&self) -> Result<(), Error> {
+        self.store.clear(DEFAULT_TREE)
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        self.store.flush()
+    }
+
+    pub fn storage_usage(&self) -> Result<StorageUsage, Error> {
+        Ok(StorageUsage {
+            entries: self.len()?,
+            size_on_disk: self.store.size_on_disk()?,
+        })
+    }
+
+    /// Rewrites the store to reclaim space, returning the number of bytes reclaimed on
+    /// disk.
+    pub fn compact(&mut self) -> Result<u64, Error> {
+        self.store.compact()
+    }
+}
+
+/// An entry taken out of its database by `export_entries`, in the clear.
+#[derive(Serialize, Deserialize)]
+pub struct ExportedEntry {
+    pub tree: String,
+    pub id: Vec<u8>,
+    pub value: Vec<u8>,
+    pub expires_at: Option<SystemTime>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct Entry {
     nonce: Vec<u8>,
     value: Vec<u8>,
+    // in the clear so expired entries can be purged without the key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<SystemTime>,
 }
 impl Entry {
-    fn new(nonce: Vec<u8>, value: Vec<u8>) -> Self {
-        Self { nonce, value }
+    fn new(nonce: Vec<u8>, value: Vec<u8>, expires_at: Option<SystemTime>) -> Self {
+        Self {
+            nonce,
+            value,
+            expires_at,
+        }
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+
+    fn open(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        ski::decrypt_gcm_aad(&self.value, key, &self.nonce, &expiry_aad(self.expires_at))
+    }
+}
+
+fn seal_entry(
+    key: &[u8],
+    plaintext: &[u8],
+    expires_at: Option<SystemTime>,
+) -> Result<Vec<u8>, Error> {
+    let nonce = ski::nonce();
+    let value = ski::encrypt_gcm_aad(plaintext, key, &nonce, &expiry_aad(expires_at))?;
+    Ok(serde_json::to_vec(&Entry::new(nonce, value, expires_at))?)
+}
+
+/// The expiry sits next to the ciphertext in the clear, so it's authenticated with it:
+/// an entry can't be kept alive by pushing its expiry back. Entries that never expire
+/// have none, as before expiries existed.
+fn expiry_aad(expires_at: Option<SystemTime>) -> Vec<u8> {
+    match expires_at {
+        Some(expires_at) => {
+            let since_epoch = expires_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let mut aad = b"carapace entry expires at:".to_vec();
+            aad.extend_from_slice(&since_epoch.as_nanos().to_be_bytes());
+            aad
+        }
+        None => vec![],
     }
+}
+
+fn open_entry(key: &[u8], entry: &[u8]) -> Result<Vec<u8>, Error> {
+    json::from_slice::<Entry>(entry)?.open(key)
 }
\ No newline at end of file
@@ -0,0 +1,207 @@
+use std::fmt;
+
+use super::rpc::{RpcError, RpcErrorCode};
+use super::rpc_models::Capability;
+
+/// Every way a crate function can fail. Callers match on the variant instead of
+/// downcasting, e.g. to tell a refused handshake from a dropped connection.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// Something didn't encrypt, decrypt, sign or parse as key material.
+    Crypto(String),
+    /// The peer answered with an error, or sent something the protocol doesn't allow.
+    Rpc { code: RpcErrorCode, message: String },
+    Db(sled::Error),
+    #[cfg(feature = "sqlite")]
+    Sqlite(sqlite::Error),
+    Serialization(serde_json::Error),
+    /// A signature didn't verify, or a key isn't allowed to do what it tried.
+    Auth(String),
+    /// Something sealed under the passphrase didn't open with the one given, so the
+    /// user can be asked again.
+    InvalidPassphrase,
+    /// There's no key file to unlock.
+    KeyNotFound,
+    /// The server refused the handshake or couldn't be trusted with a session.
+    HandshakeFailed(String),
+    /// The server proved it holds a different key than the one pinned for it, both
+    /// given as `pki::display_fingerprint`s.
+    ServerKeyChanged { pinned: String, presented: String },
+    Timeout,
+    MessageTooLarge { actual: usize, limit: usize },
+    /// The server didn't advertise the capability a request needs, so it wasn't sent.
+    UnsupportedByPeer(Capability),
+    /// The entry's time to live ran out. It stays stored until it's purged.
+    Expired,
+    /// Any other failure, described for the user.
+    Other(String),
+}
+impl Error {
+    pub fn crypto(e: impl fmt::Display) -> Self {
+        Error::Crypto(e.to_string())
+    }
+
+    pub fn rpc(code: RpcErrorCode, message: impl ToString) -> Self {
+        Error::Rpc {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    /// The code of an `Rpc` error, `None` for every other variant.
+    pub fn rpc_code(&self) -> Option<RpcErrorCode> {
+        match self {
+            Error::Rpc { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Crypto(message)
+            | Error::Auth(message)
+            | Error::HandshakeFailed(message)
+            | Error::Other(message) => write!(f, "{}", message),
+            Error::InvalidPassphrase => write!(f, "Wrong passphrase"),
+            Error::KeyNotFound => write!(f, "Key file not found"),
+            Error::Rpc { code, message } => write!(f, "{:?}: {}", code, message),
+            Error::Db(e) => write!(f, "{}", e),
+            #[cfg(feature = "sqlite")]
+            Error::Sqlite(e) => write!(f, "{}", e),
+            Error::Serialization(e) => write!(f, "{}", e),
+            Error::Timeout => write!(f, "Timed out"),
+            Error::Expired => write!(f, "Entry expired"),
+            Error::MessageTooLarge { actual, limit } => write!(
+                f,
+                "Message of {} bytes exceeds the limit of {} bytes",
+                actual, limit
+            ),
+            Error::UnsupportedByPeer(capability) => {
+                write!(f, "Server doesn't support {:?}", capability)
+            }
+            Error::ServerKeyChanged { pinned, presented } => write!(
+                f,
+                "The server's key changed from {} to {}. \
+                 Check the new fingerprint with its operator before trusting it",
+                pinned, presented
+            ),
+        }
+    }
+}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Db(e) => Some(e),
+            #[cfg(feature = "sqlite")]
+            Error::Sqlite(e) => Some(e),
+            Error::Serialization(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::Db(e)
+    }
+}
+#[cfg(feature = "sqlite")]
+impl From<sqlite::Error> for Error {
+    fn from(e: sqlite::Error) -> Self {
+        Error::Sqlite(e)
+    }
+}
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Serialization(e)
+    }
+}
+impl From<RpcError> for Error {
+    fn from(e: RpcError) -> Self {
+        Error::Rpc {
+            code: e.code,
+            message: e.message,
+        }
+    }
+}
+impl From<async_std::future::TimeoutError> for Error {
+    fn from(_: async_std::future::TimeoutError) -> Self {
+        Error::Timeout
+    }
+}
+impl From<mdns_sd::Error> for Error {
+    fn from(e: mdns_sd::Error) -> Self {
+        Error::Other(format!("mDNS failed: {}", e))
+    }
+}
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Error::Other(message.to_string())
+    }
+}
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::Other(message)
+    }
+}
+
+// failures of the crypto crates, none of which carry more than a message
+macro_rules! crypto_errors {
+    ($($error:ty),*) => {$(
+        impl From<$error> for Error {
+            fn from(e: $error) -> Self {
+                Error::crypto(e)
+            }
+        }
+    )*};
+}
+crypto_errors!(
+    rsa::Error,
+    rsa::pkcs1::Error,
+    rsa::pkcs8::Error,
+    rsa::pkcs8::spki::Error,
+    rsa::signature::Error,
+    aes_gcm::Error,
+    argon2::Error,
+    base64::DecodeError,
+    hex::FromHexError
+);
+
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        Error::Other(e.to_string())
+    }
+}
+impl From<std::time::SystemTimeError> for Error {
+    fn from(e: std::time::SystemTimeError) -> Self {
+        Error::Other(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rpc_error_conversion() {
+        let error = Error::from(RpcError {
+            message: String::from("Session not established"),
+            code: RpcErrorCode::SessionNotEstablished,
+        });
+        assert_eq!(error.rpc_code(), Some(RpcErrorCode::SessionNotEstablished));
+        assert_eq!(error.to_string(), "SessionNotEstablished: Session not established");
+        assert_eq!(Error::from("closed").rpc_code(), None);
+
+        let error: Error = serde_json::from_str::<u32>("x").unwrap_err().into();
+        assert!(matches!(error, Error::Serialization(_)));
+    }
+}
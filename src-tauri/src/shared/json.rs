@@ -0,0 +1,236 @@
+use std::io::Read;
+
+use serde::de::DeserializeOwned;
+
+use crate::Error;
+use super::rpc::{RpcError, RpcErrorCode, MAX_FRAME_SIZE};
+
+/// Bounds checked on JSON from untrusted sources before serde_json parses it, so
+/// hostile input is rejected without deep recursion or large allocations.
+#[derive(Clone, Copy, Debug)]
+pub struct JsonLimits {
+    pub max_depth: usize,
+    /// Longest string or number literal, in encoded bytes.
+    pub max_string_len: usize,
+    /// Most elements in one array or members in one object. Byte buffers are encoded
+    /// as arrays of numbers, so this has to leave room for a full frame.
+    pub max_array_len: usize,
+}
+impl Default for JsonLimits {
+    fn default() -> Self {
+        JsonLimits {
+            max_depth: 64,
+            max_string_len: 1024 * 1024,
+            max_array_len: MAX_FRAME_SIZE,
+        }
+    }
+}
+
+/// Checks limits over a stream of JSON bytes. It only tracks structure; anything
+/// malformed is left for serde_json to reject.
+struct Scanner {
+    limits: JsonLimits,
+    // element counts of the arrays and objects currently open
+    counts: Vec<usize>,
+    in_string: bool,
+    in_literal: bool,
+    escaped: bool,
+    token_len: usize,
+}
+impl Scanner {
+    fn new(limits: &JsonLimits) -> Self {
+        Scanner {
+            limits: *limits,
+            counts: Vec::new(),
+            in_string: false,
+            in_literal: false,
+            escaped: false,
+            token_len: 0,
+        }
+    }
+
+    fn feed(&mut self, data: &[u8]) -> Result<(), RpcError> {
+        for &byte in data {
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                    continue;
+                }
+                self.grow_token()?;
+                continue;
+            }
+            match byte {
+                b'"' => {
+                    self.start_value();
+                    self.in_string = true;
+                    self.token_len = 0;
+                }
+                b'[' | b'{' => {
+                    self.start_value();
+                    if self.counts.len() >= self.limits.max_depth {
+                        return Err(limit_error(format!(
+                            "JSON nesting exceeds the maximum depth of {}",
+                            self.limits.max_depth
+                        )));
+                    }
+                    self.counts.push(0);
+                }
+                b']' | b'}' => {
+                    self.in_literal = false;
+                    self.counts.pop();
+                }
+                b',' => {
+                    self.in_literal = false;
+                    if let Some(count) = self.counts.last_mut() {
+                        *count += 1;
+                        if *count > self.limits.max_array_len {
+                            return Err(limit_error(format!(
+                                "JSON array or object exceeds the maximum of {} elements",
+                                self.limits.max_array_len
+                            )));
+                        }
+                    }
+                }
+                b' ' | b'\n' | b'\r' | b'\t' | b':' => self.in_literal = false,
+                _ => {
+                    if !self.in_literal {
+                        self.start_value();
+                        self.in_literal = true;
+                        self.token_len = 0;
+                    }
+                    self.grow_token()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn start_value(&mut self) {
+        if let Some(count) = self.counts.last_mut() {
+            if *count == 0 {
+                *count = 1;
+            }
+        }
+    }
+
+    fn grow_token(&mut self) -> Result<(), RpcError> {
+        self.token_len += 1;
+        if self.token_len > self.limits.max_string_len {
+            return Err(limit_error(format!(
+                "JSON string exceeds the maximum length of {} bytes",
+                self.limits.max_string_len
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn limit_error(message: String) -> RpcError {
+    RpcError {
+        message,
+        code: RpcErrorCode::ParseError,
+    }
+}
+
+pub fn validate(data: &[u8], limits: &JsonLimits) -> Result<(), RpcError> {
+    Scanner::new(limits).feed(data)
+}
+
+/// `serde_json::from_slice` for untrusted input, checked against the default limits.
+pub fn from_slice<T: DeserializeOwned>(data: &[u8]) -> Result<T, Error> {
+    validate(data, &JsonLimits::default())?;
+    Ok(serde_json::from_slice(data)?)
+}
+
+/// Reads and parses JSON from `reader`, stopping at the first limit violation so an
+/// oversized file is never read into memory in full.
+pub fn from_reader<R: Read, T: DeserializeOwned>(
+    mut reader: R,
+    limits: &JsonLimits,
+) -> Result<T, Error> {
+    let mut scanner = Scanner::new(limits);
+    let mut data = Vec::new();
+    let mut chunk = [0; 8192];
+    loop {
+        let n = reader.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        scanner.feed(&chunk[..n])?;
+        data.extend_from_slice(&chunk[..n]);
+    }
+    Ok(serde_json::from_slice(&data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Cursor},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let limits = JsonLimits {
+            max_depth: 3,
+            max_string_len: 5,
+            max_array_len: 3,
+        };
+        assert!(validate(br#"{"a": [[1, 2, 3]], "b": "abcde"}"#, &limits).is_ok());
+        // brackets and commas inside strings aren't structure
+        assert!(validate(br#"["[[[[", ",,,,"]"#, &limits).is_ok());
+
+        let err = validate(b"[[[[]]]]", &limits).unwrap_err();
+        assert!(matches!(err.code, RpcErrorCode::ParseError));
+        assert!(err.message.contains("depth of 3"));
+        let err = validate(br#"["abcdef"]"#, &limits).unwrap_err();
+        assert!(err.message.contains("length of 5"));
+        let err = validate(br#"["ab\"\"cd"]"#, &limits).unwrap_err();
+        assert!(err.message.contains("length of 5"));
+        let err = validate(b"[1234567]", &limits).unwrap_err();
+        assert!(err.message.contains("length of 5"));
+        let err = validate(b"[1, 2, 3, 4]", &limits).unwrap_err();
+        assert!(err.message.contains("maximum of 3 elements"));
+        let err = validate(br#"{"a": 1, "b": 2, "c": 3, "d": 4}"#, &limits).unwrap_err();
+        assert!(err.message.contains("maximum of 3 elements"));
+
+        let value: Vec<Vec<u8>> = from_slice(b"[[1, 2], []]").unwrap();
+        assert_eq!(value, vec![vec![1, 2], vec![]]);
+    }
+
+    #[test]
+    fn test_reader_stops_at_oversized_string() {
+        // a 100 MB string, produced lazily so only what is read is ever allocated
+        let file = Cursor::new(br#"{"pem": ""#.to_vec())
+            .chain(io::repeat(b'a').take(100 * 1024 * 1024))
+            .chain(Cursor::new(br#""}"#.to_vec()));
+        let mut read = 0;
+        let counting = CountingReader {
+            inner: file,
+            read: &mut read,
+        };
+        let start = Instant::now();
+        let result = from_reader::<_, serde_json::Value>(counting, &JsonLimits::default());
+        assert!(result.unwrap_err().to_string().contains("maximum length"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(read < 2 * JsonLimits::default().max_string_len);
+    }
+
+    struct CountingReader<'a, R> {
+        inner: R,
+        read: &'a mut usize,
+    }
+    impl<R: Read> Read for CountingReader<'_, R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            *self.read += n;
+            Ok(n)
+        }
+    }
+}
@@ -0,0 +1,146 @@
+mod sled_store;
+#[cfg(feature = "sqlite")]
+mod sqlite_store;
+
+pub use sled_store::SledStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite_store::SqliteStore;
+
+use std::sync::Mutex;
+
+use async_std::channel::{self, Receiver, Sender};
+
+use crate::Error;
+
+/// Tree that `EntryDb` keeps its entries in. For sled this is the database's default
+/// tree, so existing profiles keep working.
+pub const DEFAULT_TREE: &str = "entries";
+
+/// A raw key and value as held by a store.
+pub type KvPair = (Vec<u8>, Vec<u8>);
+
+/// A write seen through `KvStore::watch`: the key and its new value, `None` once removed.
+pub type KvEvent = (Vec<u8>, Option<Vec<u8>>);
+
+/// Subscribers of a store, told about each write once it has landed. A subscriber
+/// whose receiver is gone is dropped on the next write it would see.
+#[derive(Default)]
+struct Watchers {
+    subscribers: Mutex<Vec<(String, Vec<u8>, Sender<KvEvent>)>>,
+}
+impl Watchers {
+    fn watch(&self, tree: &str, prefix: &[u8]) -> Receiver<KvEvent> {
+        let (sender, receiver) = channel::unbounded();
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push((tree.to_string(), prefix.to_vec(), sender));
+        receiver
+    }
+
+    fn watching(&self, tree: &str) -> bool {
+        self.subscribers.lock().unwrap().iter().any(|(t, _, _)| t == tree)
+    }
+
+    fn notify(&self, tree: &str, key: &[u8], value: Option<&[u8]>) {
+        self.subscribers.lock().unwrap().retain(|(t, prefix, sender)| {
+            if t != tree || !key.starts_with(prefix) {
+                return !sender.is_closed();
+            }
+            sender
+                .try_send((key.to_vec(), value.map(|value| value.to_vec())))
+                .is_ok()
+        });
+    }
+
+    fn notify_batch(&self, batch: &Batch) {
+        for (tree, key, op) in batch.ops.iter() {
+            match op {
+                BatchOp::Insert(value) => self.notify(tree, key, Some(value)),
+                BatchOp::Remove => self.notify(tree, key, None),
+            }
+        }
+    }
+}
+
+enum BatchOp {
+    Insert(Vec<u8>),
+    Remove,
+}
+
+/// Writes that a store applies atomically, possibly across several trees.
+#[derive(Default)]
+pub struct Batch {
+    ops: Vec<(String, Vec<u8>, BatchOp)>,
+}
+impl Batch {
+    pub fn insert(&mut self, tree: &str, key: &[u8], value: Vec<u8>) {
+        self.ops
+            .push((tree.to_string(), key.to_vec(), BatchOp::Insert(value)));
+    }
+    pub fn remove(&mut self, tree: &str, key: &[u8]) {
+        self.ops.push((tree.to_string(), key.to_vec(), BatchOp::Remove));
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+    fn trees(&self) -> Vec<&str> {
+        let mut trees: Vec<&str> = self.ops.iter().map(|(tree, _, _)| tree.as_str()).collect();
+        trees.sort_unstable();
+        trees.dedup();
+        trees
+    }
+}
+
+/// Storage engine behind an `EntryDb`. Values are opaque to the store: encryption
+/// happens above it, so a backend only ever holds ciphertext.
+pub trait KvStore: Send + Sync {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> Result<(), Error>;
+    fn remove(&self, tree: &str, key: &[u8]) -> Result<(), Error>;
+    fn contains(&self, tree: &str, key: &[u8]) -> Result<bool, Error> {
+        Ok(self.get(tree, key)?.is_some())
+    }
+    /// Returns every entry of the tree, ordered by key.
+    fn iter(&self, tree: &str) -> Result<Vec<KvPair>, Error> {
+        self.page(tree, 0, usize::MAX)
+    }
+    /// Returns up to `limit` entries of the tree, ordered by key, skipping the first
+    /// `offset`. The rest of the tree isn't collected.
+    fn page(&self, tree: &str, offset: usize, limit: usize) -> Result<Vec<KvPair>, Error>;
+    /// Returns the entries of the tree whose keys start with `prefix`, ordered by key.
+    fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> Result<Vec<KvPair>, Error> {
+        let mut entries = self.iter(tree)?;
+        entries.retain(|(key, _)| key.starts_with(prefix));
+        Ok(entries)
+    }
+    fn len(&self, tree: &str) -> Result<usize, Error>;
+    fn clear(&self, tree: &str) -> Result<(), Error>;
+    fn tree_names(&self) -> Result<Vec<String>, Error>;
+    /// Applies every write of `batch` or none of them. An empty batch is a no-op.
+    fn apply_batch(&self, batch: Batch) -> Result<(), Error>;
+    /// Subscribes to writes on the tree's keys starting with `prefix`, made through this
+    /// store from now on. A batch shows up as its writes in order once it's applied.
+    fn watch(&self, tree: &str, prefix: &[u8]) -> Receiver<KvEvent>;
+    fn flush(&self) -> Result<(), Error>;
+    fn size_on_disk(&self) -> Result<u64, Error>;
+    /// Rewrites the store to reclaim space left by deletions, returning the number of
+    /// bytes reclaimed.
+    fn compact(&mut self) -> Result<u64, Error>;
+}
+
+/// Copies every tree of `from` into `to` without decrypting anything. Returns the
+/// number of entries copied.
+pub fn copy_store(from: &dyn KvStore, to: &dyn KvStore) -> Result<usize, Error> {
+    let mut copied = 0;
+    for tree in from.tree_names()? {
+        let mut batch = Batch::default();
+        for (key, value) in from.iter(&tree)? {
+            batch.insert(&tree, &key, value);
+            copied += 1;
+        }
+        to.apply_batch(batch)?;
+    }
+    to.flush()?;
+    Ok(copied)
+}
@@ -0,0 +1,223 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use sled::{
+    transaction::{TransactionResult, Transactional},
+    Db, Tree,
+};
+
+use async_std::channel::Receiver;
+
+use super::{Batch, BatchOp, KvEvent, KvPair, KvStore, Watchers, DEFAULT_TREE};
+use crate::shared::db::DbConfig;
+use crate::Error;
+
+const SLED_DEFAULT_TREE: &[u8] = b"__sled__default";
+
+pub struct SledStore {
+    db: Db,
+    path: PathBuf,
+    config: DbConfig,
+    watchers: Watchers,
+}
+impl SledStore {
+    pub fn open(path: PathBuf, config: &DbConfig) -> Result<Self, Error> {
+        Self::recover_compaction(&path)?;
+        Ok(SledStore {
+            db: Self::open_db(&path, config)?,
+            path,
+            config: config.clone(),
+            watchers: Watchers::default(),
+        })
+    }
+
+    fn open_db(path: &PathBuf, config: &DbConfig) -> Result<Db, Error> {
+        let db = sled::Config::new()
+            .path(path)
+            .cache_capacity(config.cache_capacity)
+            .flush_every_ms(config.flush_every_ms)
+            .use_compression(config.use_compression)
+            .open()?;
+        Ok(db)
+    }
+
+    /// Where `compact` builds the compacted copy.
+    fn compacted_path(path: &Path) -> PathBuf {
+        path.with_extension("compact")
+    }
+
+    /// Where `compact` moves the live database while the compacted copy takes its place.
+    fn replaced_path(path: &Path) -> PathBuf {
+        path.with_extension("replaced")
+    }
+
+    /// Finishes or rolls back a compaction that was cut short. The live database is
+    /// only ever moved aside once the compacted copy is complete, so whichever of the
+    /// two is left holds everything.
+    fn recover_compaction(path: &Path) -> Result<(), Error> {
+        let compacted = Self::compacted_path(path);
+        let replaced = Self::replaced_path(path);
+        if !path.exists() && replaced.exists() {
+            fs::rename(&replaced, path)?;
+        }
+        if replaced.exists() {
+            fs::remove_dir_all(&replaced)?;
+        }
+        if compacted.exists() {
+            fs::remove_dir_all(&compacted)?;
+        }
+        Ok(())
+    }
+
+    fn tree(&self, name: &str) -> Result<Tree, Error> {
+        if name == DEFAULT_TREE {
+            Ok((*self.db).clone())
+        } else {
+            Ok(self.db.open_tree(name)?)
+        }
+    }
+}
+impl KvStore for SledStore {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.tree(tree)?.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.tree(tree)?.insert(key, value)?;
+        self.watchers.notify(tree, key, Some(value));
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> Result<(), Error> {
+        self.tree(tree)?.remove(key)?;
+        self.watchers.notify(tree, key, None);
+        Ok(())
+    }
+
+    fn contains(&self, tree: &str, key: &[u8]) -> Result<bool, Error> {
+        Ok(self.tree(tree)?.contains_key(key)?)
+    }
+
+    fn page(&self, tree: &str, offset: usize, limit: usize) -> Result<Vec<KvPair>, Error> {
+        let mut entries = vec![];
+        for entry in self.tree(tree)?.iter().skip(offset).take(limit) {
+            let (key, value) = entry?;
+            entries.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> Result<Vec<KvPair>, Error> {
+        let mut entries = vec![];
+        for entry in self.tree(tree)?.scan_prefix(prefix) {
+            let (key, value) = entry?;
+            entries.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn len(&self, tree: &str) -> Result<usize, Error> {
+        Ok(self.tree(tree)?.len())
+    }
+
+    fn clear(&self, tree: &str) -> Result<(), Error> {
+        let removed = if self.watchers.watching(tree) {
+            self.iter(tree)?
+        } else {
+            vec![]
+        };
+        self.tree(tree)?.clear()?;
+        for (key, _) in removed {
+            self.watchers.notify(tree, &key, None);
+        }
+        Ok(())
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>, Error> {
+        let mut names = vec![];
+        for name in self.db.tree_names() {
+            if name == SLED_DEFAULT_TREE {
+                names.push(DEFAULT_TREE.to_string());
+            } else {
+                names.push(String::from_utf8(name.to_vec())?);
+            }
+        }
+        Ok(names)
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), Error> {
+        // sled panics on a transaction over no trees at all
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let names = batch.trees();
+        let trees = names
+            .iter()
+            .map(|name| self.tree(name))
+            .collect::<Result<Vec<Tree>, _>>()?;
+        let result: TransactionResult<()> = trees[..].transaction(|views| {
+            for (tree, key, op) in batch.ops.iter() {
+                let view = &views[names.binary_search(&tree.as_str()).unwrap()];
+                match op {
+                    BatchOp::Insert(value) => view.insert(key.as_slice(), value.as_slice())?,
+                    BatchOp::Remove => view.remove(key.as_slice())?,
+                };
+            }
+            Ok(())
+        });
+        result.map_err(|e| format!("{:?}", e))?;
+        self.watchers.notify_batch(&batch);
+        Ok(())
+    }
+
+    fn watch(&self, tree: &str, prefix: &[u8]) -> Receiver<KvEvent> {
+        self.watchers.watch(tree, prefix)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn size_on_disk(&self) -> Result<u64, Error> {
+        Ok(self.db.size_on_disk()?)
+    }
+
+    /// Rewrites every tree into a fresh sled database and swaps it in. The live one is
+    /// moved aside rather than deleted until the copy is in place, see
+    /// `recover_compaction` for a swap that's cut short.
+    fn compact(&mut self) -> Result<u64, Error> {
+        self.db.flush()?;
+        let before = self.db.size_on_disk()?;
+        let compacted = Self::compacted_path(&self.path);
+        let replaced = Self::replaced_path(&self.path);
+        if compacted.exists() {
+            fs::remove_dir_all(&compacted)?;
+        }
+        {
+            let tmp = Self::open_db(&compacted, &self.config)?;
+            tmp.import(self.db.export());
+            tmp.flush()?;
+        }
+        // sled holds a lock on the directory until the last handle is dropped
+        let old = std::mem::replace(&mut self.db, sled::Config::new().temporary(true).open()?);
+        drop(old);
+        let swapped = fs::rename(&self.path, &replaced)
+            .and_then(|()| fs::rename(&compacted, &self.path));
+        let recovered = match swapped {
+            Ok(()) => Ok(()),
+            // back to the live database, whichever step failed
+            Err(_) => Self::recover_compaction(&self.path),
+        };
+        // never a fresh, empty database in place of one that couldn't be moved back
+        let live = if self.path.exists() { &self.path } else { &replaced };
+        self.db = Self::open_db(live, &self.config)?;
+        swapped?;
+        recovered?;
+        fs::remove_dir_all(&replaced)?;
+        let after = self.db.size_on_disk()?;
+        Ok(before.saturating_sub(after))
+    }
+}
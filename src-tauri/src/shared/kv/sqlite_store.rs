@@ -0,0 +1,249 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+};
+
+use async_std::channel::Receiver;
+use sqlite::{Connection, State};
+
+use super::{Batch, BatchOp, KvEvent, KvPair, KvStore, Watchers};
+use crate::shared::db::DbConfig;
+use crate::Error;
+
+struct Inner {
+    conn: Connection,
+    // trees whose table is known to exist
+    trees: HashSet<String>,
+}
+impl Inner {
+    fn ensure_tree(&mut self, tree: &str) -> Result<(), Error> {
+        if self.trees.contains(tree) {
+            return Ok(());
+        }
+        if tree.is_empty() || !tree.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            Err(format!("Invalid tree name: {}", tree))?;
+        }
+        self.conn.execute(format!(
+            "CREATE TABLE IF NOT EXISTS \"{}\" (key BLOB PRIMARY KEY NOT NULL, value BLOB NOT NULL) WITHOUT ROWID",
+            tree
+        ))?;
+        self.trees.insert(tree.to_string());
+        Ok(())
+    }
+
+    fn insert(&mut self, tree: &str, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.ensure_tree(tree)?;
+        let mut stmt = self
+            .conn
+            .prepare(format!("INSERT OR REPLACE INTO \"{}\" (key, value) VALUES (?, ?)", tree))?;
+        stmt.bind((1, key))?;
+        stmt.bind((2, value))?;
+        while stmt.next()? != State::Done {}
+        Ok(())
+    }
+
+    fn remove(&mut self, tree: &str, key: &[u8]) -> Result<(), Error> {
+        self.ensure_tree(tree)?;
+        let mut stmt = self
+            .conn
+            .prepare(format!("DELETE FROM \"{}\" WHERE key = ?", tree))?;
+        stmt.bind((1, key))?;
+        while stmt.next()? != State::Done {}
+        Ok(())
+    }
+}
+
+/// SQLite backend: one file per store, one table per tree, running in WAL mode.
+pub struct SqliteStore {
+    inner: Mutex<Inner>,
+    path: PathBuf,
+    watchers: Watchers,
+}
+impl SqliteStore {
+    pub fn open(path: PathBuf, config: &DbConfig) -> Result<Self, Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let conn = sqlite::open(&path)?;
+        conn.execute(format!(
+            "PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL; PRAGMA cache_size = -{};",
+            config.cache_capacity / 1024
+        ))?;
+        Ok(SqliteStore {
+            inner: Mutex::new(Inner {
+                conn,
+                trees: HashSet::new(),
+            }),
+            path,
+            watchers: Watchers::default(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+}
+impl KvStore for SqliteStore {
+    fn get(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let mut inner = self.lock();
+        inner.ensure_tree(tree)?;
+        let mut stmt = inner
+            .conn
+            .prepare(format!("SELECT value FROM \"{}\" WHERE key = ?", tree))?;
+        stmt.bind((1, key))?;
+        if stmt.next()? == State::Row {
+            Ok(Some(stmt.read::<Vec<u8>, _>(0)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn insert(&self, tree: &str, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.lock().insert(tree, key, value)?;
+        self.watchers.notify(tree, key, Some(value));
+        Ok(())
+    }
+
+    fn remove(&self, tree: &str, key: &[u8]) -> Result<(), Error> {
+        self.lock().remove(tree, key)?;
+        self.watchers.notify(tree, key, None);
+        Ok(())
+    }
+
+    fn page(&self, tree: &str, offset: usize, limit: usize) -> Result<Vec<KvPair>, Error> {
+        let mut inner = self.lock();
+        inner.ensure_tree(tree)?;
+        let mut stmt = inner.conn.prepare(format!(
+            "SELECT key, value FROM \"{}\" ORDER BY key LIMIT ? OFFSET ?",
+            tree
+        ))?;
+        // a negative limit is no limit to SQLite
+        stmt.bind((1, i64::try_from(limit).unwrap_or(-1)))?;
+        stmt.bind((2, i64::try_from(offset).unwrap_or(i64::MAX)))?;
+        let mut entries = vec![];
+        while stmt.next()? == State::Row {
+            entries.push((stmt.read::<Vec<u8>, _>(0)?, stmt.read::<Vec<u8>, _>(1)?));
+        }
+        Ok(entries)
+    }
+
+    fn scan_prefix(&self, tree: &str, prefix: &[u8]) -> Result<Vec<KvPair>, Error> {
+        let mut inner = self.lock();
+        inner.ensure_tree(tree)?;
+        // keys sort bytewise, so the prefix's entries start at the prefix itself and
+        // follow one another
+        let mut stmt = inner.conn.prepare(format!(
+            "SELECT key, value FROM \"{}\" WHERE key >= ? ORDER BY key",
+            tree
+        ))?;
+        stmt.bind((1, prefix))?;
+        let mut entries = vec![];
+        while stmt.next()? == State::Row {
+            let key = stmt.read::<Vec<u8>, _>(0)?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            entries.push((key, stmt.read::<Vec<u8>, _>(1)?));
+        }
+        Ok(entries)
+    }
+
+    fn len(&self, tree: &str) -> Result<usize, Error> {
+        let mut inner = self.lock();
+        inner.ensure_tree(tree)?;
+        let mut stmt = inner
+            .conn
+            .prepare(format!("SELECT COUNT(*) FROM \"{}\"", tree))?;
+        stmt.next()?;
+        Ok(stmt.read::<i64, _>(0)? as usize)
+    }
+
+    fn clear(&self, tree: &str) -> Result<(), Error> {
+        let removed = if self.watchers.watching(tree) {
+            self.iter(tree)?
+        } else {
+            vec![]
+        };
+        let mut inner = self.lock();
+        inner.ensure_tree(tree)?;
+        inner.conn.execute(format!("DELETE FROM \"{}\"", tree))?;
+        drop(inner);
+        for (key, _) in removed {
+            self.watchers.notify(tree, &key, None);
+        }
+        Ok(())
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>, Error> {
+        let inner = self.lock();
+        let mut stmt = inner
+            .conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")?;
+        let mut names = vec![];
+        while stmt.next()? == State::Row {
+            names.push(stmt.read::<String, _>(0)?);
+        }
+        Ok(names)
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), Error> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut inner = self.lock();
+        // tables can't be created inside the transaction without committing it early
+        for tree in batch.trees() {
+            inner.ensure_tree(tree)?;
+        }
+        inner.conn.execute("BEGIN IMMEDIATE")?;
+        let result = batch.ops.iter().try_for_each(|(tree, key, op)| match op {
+            BatchOp::Insert(value) => inner.insert(tree, key, value),
+            BatchOp::Remove => inner.remove(tree, key),
+        });
+        match result {
+            Ok(()) => inner.conn.execute("COMMIT")?,
+            Err(e) => {
+                inner.conn.execute("ROLLBACK")?;
+                return Err(e);
+            }
+        }
+        drop(inner);
+        self.watchers.notify_batch(&batch);
+        Ok(())
+    }
+
+    fn watch(&self, tree: &str, prefix: &[u8]) -> Receiver<KvEvent> {
+        self.watchers.watch(tree, prefix)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.lock().conn.execute("PRAGMA wal_checkpoint(FULL)")?;
+        Ok(())
+    }
+
+    fn size_on_disk(&self) -> Result<u64, Error> {
+        let mut size = 0;
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.path.clone().into_os_string();
+            path.push(suffix);
+            if let Ok(metadata) = fs::metadata(path) {
+                size += metadata.len();
+            }
+        }
+        Ok(size)
+    }
+
+    fn compact(&mut self) -> Result<u64, Error> {
+        self.flush()?;
+        let before = self.size_on_disk()?;
+        {
+            let inner = self.lock();
+            inner.conn.execute("VACUUM")?;
+            inner.conn.execute("PRAGMA wal_checkpoint(TRUNCATE)")?;
+        }
+        let after = self.size_on_disk()?;
+        Ok(before.saturating_sub(after))
+    }
+}
@@ -3,4 +3,9 @@ pub mod rpc;
 pub mod ski;
 pub mod rpc_models;
 pub mod models;
-pub mod db;
\ No newline at end of file
+pub mod db;
+pub mod kv;
+pub mod json;
+pub mod transparency;
+pub mod error;
+
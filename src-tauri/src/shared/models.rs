@@ -1,15 +1,102 @@
+use std::time::SystemTime;
+
+use rsa::{pkcs1v15::Signature, RsaPrivateKey, RsaPublicKey};
 use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+use crate::shared::{pki, ski::{self, NonceTracker}};
+use crate::Error;
 
+/// Session key agreed during the handshake. Every payload encrypted under it carries
+/// its own nonce; entries saved when the session had a single nonce still load.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct EncryptionConfiguration {
     pub shared_key: Vec<u8>,
-    pub nonce: Vec<u8>,
+    // only kept for the live session, a saved configuration loads without one
+    #[serde(skip)]
+    pub nonce_tracker: Option<NonceTracker>,
 }
 impl EncryptionConfiguration {
-    pub fn new(shared_key: Vec<u8>, nonce: Vec<u8>,) -> Self {
+    pub fn new(shared_key: Vec<u8>) -> Self {
         EncryptionConfiguration {
             shared_key,
-            nonce,
+            nonce_tracker: Some(NonceTracker::new()),
+        }
+    }
+
+    /// `ski::seal_gcm` under the session key, through the nonce tracker if there is one.
+    pub fn seal(&self, pt: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.nonce_tracker {
+            Some(ref tracker) => tracker.seal_gcm(pt, &self.shared_key),
+            None => ski::seal_gcm(pt, &self.shared_key),
+        }
+    }
+}
+impl Drop for EncryptionConfiguration {
+    fn drop(&mut self) {
+        self.shared_key.zeroize();
+    }
+}
+
+/// Accent colors a chat can be given. Anything outside the palette is rejected so a
+/// peer can't make the UI render arbitrary values.
+pub const CHAT_COLORS: [&str; 8] = [
+    "#e53935", "#fb8c00", "#fdd835", "#43a047", "#00acc1", "#1e88e5", "#8e24aa", "#6d4c41",
+];
+pub const CHAT_EMOJIS: [&str; 8] = ["💬", "🔒", "🏠", "💼", "🎮", "🎵", "⭐", "❤️"];
+
+/// Look of a chat as chosen by one of its participants, signed by whoever set it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChatCustomization {
+    pub color: String,
+    pub emoji: String,
+    pub updated_at: SystemTime,
+    pub signature: Vec<u8>,
+}
+impl ChatCustomization {
+    pub fn new_signed(
+        chat_id: &str,
+        color: &str,
+        emoji: &str,
+        sk: &RsaPrivateKey,
+    ) -> Result<Self, Error> {
+        let mut customization = ChatCustomization {
+            color: color.to_string(),
+            emoji: emoji.to_string(),
+            updated_at: SystemTime::now(),
+            signature: vec![],
+        };
+        customization.validate()?;
+        customization.signature = pki::sign_message(sk, &customization.signed_bytes(chat_id)?);
+        Ok(customization)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if !CHAT_COLORS.contains(&self.color.as_str()) {
+            Err(format!("Color {} is not in the chat palette", self.color))?;
+        }
+        if !CHAT_EMOJIS.contains(&self.emoji.as_str()) {
+            Err(format!("Emoji {} is not allowed for chats", self.emoji))?;
         }
+        Ok(())
+    }
+
+    pub fn verify(&self, chat_id: &str, pk: &RsaPublicKey) -> Result<bool, Error> {
+        let sig = match Signature::try_from(self.signature.as_slice()) {
+            Ok(sig) => sig,
+            Err(_) => return Ok(false),
+        };
+        Ok(pki::verify_signature(pk, &self.signed_bytes(chat_id)?, &sig))
+    }
+
+    /// Last writer wins. Equal timestamps are settled on the signature so every
+    /// participant picks the same winner.
+    pub fn supersedes(&self, other: &ChatCustomization) -> bool {
+        (self.updated_at, &self.signature) > (other.updated_at, &other.signature)
+    }
+
+    // the chat id is covered so an update can't be replayed onto another chat
+    fn signed_bytes(&self, chat_id: &str) -> Result<Vec<u8>, Error> {
+        Ok(serde_json::to_vec(&(chat_id, &self.color, &self.emoji, self.updated_at))?)
     }
 }
@@ -1,4 +1,4 @@
-use std::{error::Error, fs};
+use std::fs;
 
 use base64::{prelude::BASE64_STANDARD, Engine};
 use rsa::pkcs1::EncodeRsaPublicKey;
@@ -6,15 +6,18 @@ use rsa::pkcs1v15::{Signature, SigningKey, VerifyingKey};
 use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, LineEnding};
 use rsa::sha2::{Digest, Sha256};
 use rsa::signature::{Keypair, RandomizedSigner, SignatureEncoding, Verifier};
-use rsa::{Pkcs1v15Encrypt, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use rsa::{Oaep, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
 
-use crate::shared::ski::{decrypt_gcm, encrypt_gcm, nonce};
+use crate::shared::json::{self, JsonLimits};
+use crate::shared::rpc_models::KeyType;
+use crate::shared::ski::{decrypt_gcm, derive_key, encrypt_gcm, nonce, salt};
+use crate::Error;
 use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
 
 use rand_core::OsRng;
 
-pub fn gen_key() -> Result<RsaPrivateKey, Box<dyn Error>> {
+pub fn gen_key() -> Result<RsaPrivateKey, Error> {
     let mut csprng = OsRng {};
     let bits = 2048;
     let key = RsaPrivateKey::new(&mut csprng, bits)?;
@@ -35,45 +38,153 @@ pub fn get_line_ending() -> LineEnding {
 struct PEM {
     pem: Vec<u8>,
     nonce: Vec<u8>,
+    // empty for files written before the passkey went through a KDF
+    #[serde(default)]
+    salt: Vec<u8>,
+    // missing from files written before it was added
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    check: Option<KeyCheck>,
 }
 
-pub fn write_key_to_file(
-    sk: &RsaPrivateKey,
-    loc: &str,
-    file_key: &[u8],
-) -> Result<(), Box<dyn Error>> {
+/// `KEY_CHECK` sealed under the key file's key with a nonce of its own, so a wrong key
+/// is told apart from a damaged file.
+#[derive(Serialize, Deserialize)]
+struct KeyCheck {
+    value: Vec<u8>,
+    nonce: Vec<u8>,
+}
+const KEY_CHECK: &[u8] = b"carapace-v1";
+
+const RSA_KEY_FILE: &str = "private_key.pem";
+const ED25519_KEY_FILE: &str = "private_key_ed25519.pem";
+
+fn write_pem(loc: &str, file_name: &str, pem: &str, file_key: &[u8]) -> Result<(), Error> {
     let project_dirs =
         ProjectDirs::from("com", "carapace", loc).ok_or("Could not find project directories")?;
     let config_dir = project_dirs.config_dir();
     fs::create_dir_all(config_dir)?;
-    let key_path = config_dir.join("private_key.pem");
-    let pem = sk.to_pkcs8_pem(get_line_ending())?;
+    let key_path = config_dir.join(file_name);
+    let salt = salt();
+    let key = derive_key(file_key, &salt)?;
+    let check_nonce = nonce();
+    let check = KeyCheck {
+        value: encrypt_gcm(KEY_CHECK, &key, &check_nonce)?,
+        nonce: check_nonce,
+    };
     let nonce = nonce();
-    let pem_enc = encrypt_gcm(pem.as_bytes(), file_key, &nonce)?;
+    let pem_enc = encrypt_gcm(pem.as_bytes(), &key, &nonce)?;
     let pem_struct = PEM {
         pem: pem_enc,
         nonce,
+        salt,
+        check: Some(check),
     };
     let pem_json = serde_json::to_string(&pem_struct)?;
-    fs::write(key_path, pem_json)?;
+    // written aside and renamed over the old file, so a crash never leaves half a key
+    let tmp_path = config_dir.join(format!("{}.tmp", file_name));
+    fs::write(&tmp_path, pem_json)?;
+    fs::rename(tmp_path, key_path)?;
     Ok(())
 }
 
-pub fn read_key_from_file(loc: &str, file_key: &[u8]) -> Result<RsaPrivateKey, Box<dyn Error>> {
+fn read_pem(loc: &str, file_name: &str, file_key: &[u8]) -> Result<String, Error> {
     let project_dirs =
         ProjectDirs::from("com", "carapace", loc).ok_or("Could not find project directories")?;
-    let key_path = project_dirs.config_dir().join("private_key.pem");
-    let pem_json = fs::read_to_string(key_path)?;
-    let pem_struct: PEM = serde_json::from_str(&pem_json)?;
-    let pem = String::from_utf8(decrypt_gcm(&pem_struct.pem, file_key, &pem_struct.nonce)?)?;
+    let key_path = project_dirs.config_dir().join(file_name);
+    let file = match fs::File::open(key_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(Error::KeyNotFound),
+        Err(e) => return Err(e.into()),
+    };
+    let pem_struct: PEM = json::from_reader(file, &JsonLimits::default())?;
+    let key = if pem_struct.salt.is_empty() {
+        file_key.to_vec()
+    } else {
+        derive_key(file_key, &pem_struct.salt)?.to_vec()
+    };
+    let pem = decrypt_gcm(&pem_struct.pem, &key, &pem_struct.nonce);
+    let pem = match pem_struct.check {
+        Some(check) => {
+            let checked = decrypt_gcm(&check.value, &key, &check.nonce);
+            if !matches!(checked, Ok(ref value) if value == KEY_CHECK) {
+                Err(Error::InvalidPassphrase)?;
+            }
+            pem.map_err(|_| Error::crypto("Key file is damaged"))?
+        }
+        // without a check, a file that doesn't open is taken for a wrong key
+        None => pem.map_err(|_| Error::InvalidPassphrase)?,
+    };
+    Ok(String::from_utf8(pem)?)
+}
+
+pub fn write_key_to_file(
+    sk: &RsaPrivateKey,
+    loc: &str,
+    file_key: &[u8],
+) -> Result<(), Error> {
+    let pem = sk.to_pkcs8_pem(get_line_ending())?;
+    write_pem(loc, RSA_KEY_FILE, &pem, file_key)
+}
+
+pub fn read_key_from_file(loc: &str, file_key: &[u8]) -> Result<RsaPrivateKey, Error> {
+    let pem = read_pem(loc, RSA_KEY_FILE, file_key)?;
+    let sk = DecodePrivateKey::from_pkcs8_pem(pem.as_str())?;
+    Ok(sk)
+}
+
+pub fn write_ed25519_key_to_file(
+    sk: &ed25519_dalek::SigningKey,
+    loc: &str,
+    file_key: &[u8],
+) -> Result<(), Error> {
+    let pem = sk.to_pkcs8_pem(get_line_ending())?;
+    write_pem(loc, ED25519_KEY_FILE, &pem, file_key)
+}
+
+pub fn read_ed25519_key_from_file(
+    loc: &str,
+    file_key: &[u8],
+) -> Result<ed25519_dalek::SigningKey, Error> {
+    let pem = read_pem(loc, ED25519_KEY_FILE, file_key)?;
     let sk = DecodePrivateKey::from_pkcs8_pem(pem.as_str())?;
     Ok(sk)
 }
 
-pub fn delete_key_file(loc: &str) -> Result<(), Box<dyn Error>> {
+/// `old_key`'s signature handing its place over to `new_pub_key`, see
+/// `key_rotation_message`.
+pub fn sign_key_rotation(
+    old_key: &RsaPrivateKey,
+    new_pub_key: &RsaPublicKey,
+) -> Result<Vec<u8>, Error> {
+    Ok(sign_message(old_key, &key_rotation_message(new_pub_key)?))
+}
+
+/// What the old key signs to hand its place over to `new_pub_key`.
+pub fn key_rotation_message(new_pub_key: &RsaPublicKey) -> Result<Vec<u8>, Error> {
+    let mut msg = b"carapace key rotation:".to_vec();
+    msg.extend_from_slice(new_pub_key.to_pkcs1_der()?.as_bytes());
+    Ok(msg)
+}
+
+pub fn verify_key_rotation(
+    old_pub_key: &RsaPublicKey,
+    new_pub_key: &RsaPublicKey,
+    signature: &[u8],
+) -> bool {
+    let msg = match key_rotation_message(new_pub_key) {
+        Ok(msg) => msg,
+        Err(_) => return false,
+    };
+    match Signature::try_from(signature) {
+        Ok(sig) => verify_signature(old_pub_key, &msg, &sig),
+        Err(_) => false,
+    }
+}
+
+pub fn delete_key_file(loc: &str) -> Result<(), Error> {
     let project_dirs =
         ProjectDirs::from("com", "carapace", loc).ok_or("Could not find project directories")?;
-    let key_path = project_dirs.config_dir().join("private_key.pem");
+    let key_path = project_dirs.config_dir().join(RSA_KEY_FILE);
     fs::remove_file(key_path)?;
     Ok(())
 }
@@ -83,10 +194,32 @@ pub fn key_exists(loc: &str) -> bool {
         .ok_or("Could not find project directories")
         .unwrap();
     let config_dir = project_dirs.config_dir();
-    let path = config_dir.join("private_key.pem");
+    let path = config_dir.join(RSA_KEY_FILE);
     path.exists()
 }
 
+pub fn ed25519_key_exists(loc: &str) -> bool {
+    ProjectDirs::from("com", "carapace", loc)
+        .map(|dirs| dirs.config_dir().join(ED25519_KEY_FILE).exists())
+        .unwrap_or(false)
+}
+
+/// Whether `file_key` opens the key files at `loc`. False if there are none.
+pub fn key_file_unlocks(loc: &str, file_key: &[u8]) -> Result<bool, Error> {
+    let file_name = if key_exists(loc) {
+        RSA_KEY_FILE
+    } else if ed25519_key_exists(loc) {
+        ED25519_KEY_FILE
+    } else {
+        return Ok(false);
+    };
+    match read_pem(loc, file_name, file_key) {
+        Ok(_) => Ok(true),
+        Err(Error::InvalidPassphrase) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
 pub fn sign_message(sk: &RsaPrivateKey, msg: &[u8]) -> Vec<u8> {
     let mut rng = OsRng {};
     let snk = SigningKey::<Sha256>::from(sk.clone());
@@ -99,26 +232,177 @@ pub fn verify_signature(pk: &RsaPublicKey, msg: &[u8], sig: &Signature) -> bool
     vk.verify(msg, sig).is_ok()
 }
 
-pub fn encrypt_message(pk: &RsaPublicKey, msg: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+pub fn gen_key_ed25519() -> ed25519_dalek::SigningKey {
+    ed25519_dalek::SigningKey::generate(&mut OsRng)
+}
+
+pub fn sign_message_ed25519(sk: &ed25519_dalek::SigningKey, msg: &[u8]) -> Vec<u8> {
+    use ed25519_dalek::Signer;
+    sk.sign(msg).to_bytes().to_vec()
+}
+
+pub fn verify_signature_ed25519(pk: &ed25519_dalek::VerifyingKey, msg: &[u8], sig: &[u8]) -> bool {
+    match ed25519_dalek::Signature::from_slice(sig) {
+        Ok(sig) => pk.verify_strict(msg, &sig).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// The key type and Ed25519 public key `sign_handshake` signs with.
+pub fn handshake_key(
+    ed25519_key: Option<&ed25519_dalek::SigningKey>,
+) -> (KeyType, Option<ed25519_dalek::VerifyingKey>) {
+    match ed25519_key {
+        Some(key) => (KeyType::Ed25519, Some(key.verifying_key())),
+        None => (KeyType::Rsa2048, None),
+    }
+}
+
+/// Signs a handshake challenge with the Ed25519 key when there is one and with RSA
+/// otherwise. Returns what to send along: the key type and the Ed25519 public key.
+pub fn sign_handshake(
+    sk: &RsaPrivateKey,
+    ed25519_key: Option<&ed25519_dalek::SigningKey>,
+    msg: &[u8],
+) -> (KeyType, Vec<u8>, Option<ed25519_dalek::VerifyingKey>) {
+    match ed25519_key {
+        Some(key) => (
+            KeyType::Ed25519,
+            sign_message_ed25519(key, msg),
+            Some(key.verifying_key()),
+        ),
+        None => (KeyType::Rsa2048, sign_message(sk, msg), None),
+    }
+}
+
+fn signing_key_binding_data(ed25519_key: &ed25519_dalek::VerifyingKey) -> Vec<u8> {
+    let mut data = b"carapace signing key:".to_vec();
+    data.extend_from_slice(ed25519_key.as_bytes());
+    data
+}
+
+/// RSA signature over the Ed25519 key handshakes are signed with, so the key can't be
+/// passed off as someone else's. Empty without one.
+pub fn bind_signing_key(
+    sk: &RsaPrivateKey,
+    ed25519_key: Option<&ed25519_dalek::SigningKey>,
+) -> Vec<u8> {
+    match ed25519_key {
+        Some(key) => sign_message(sk, &signing_key_binding_data(&key.verifying_key())),
+        None => Vec::new(),
+    }
+}
+
+/// Verifies a handshake signature with the algorithm the peer says it signed with.
+/// `ed25519_key` is the peer's signing key when `key_type` is `Ed25519`, and only
+/// counts if `key_binding` shows the holder of `pub_key` vouches for it.
+pub fn verify_handshake_signature(
+    key_type: KeyType,
+    pub_key: &RsaPublicKey,
+    ed25519_key: Option<&ed25519_dalek::VerifyingKey>,
+    key_binding: &[u8],
+    msg: &[u8],
+    sig: &[u8],
+) -> bool {
+    match key_type {
+        KeyType::Rsa2048 => match Signature::try_from(sig) {
+            Ok(sig) => verify_signature(pub_key, msg, &sig),
+            Err(_) => false,
+        },
+        KeyType::Ed25519 => match (ed25519_key, Signature::try_from(key_binding)) {
+            (Some(pk), Ok(binding)) => {
+                verify_signature(pub_key, &signing_key_binding_data(pk), &binding)
+                    && verify_signature_ed25519(pk, msg, sig)
+            }
+            _ => false,
+        },
+    }
+}
+
+/// Encrypts with RSA-OAEP over SHA-256.
+pub fn encrypt_message(pk: &RsaPublicKey, msg: &[u8]) -> Result<Vec<u8>, Error> {
     let mut rng = OsRng {};
-    let ct = pk.encrypt(&mut rng, Pkcs1v15Encrypt, msg)?;
+    let ct = pk.encrypt(&mut rng, Oaep::new::<Sha256>(), msg)?;
     Ok(ct)
 }
 
-pub fn decrypt_message(sk: &RsaPrivateKey, ct: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
-    let pt = sk.decrypt(Pkcs1v15Encrypt, ct)?;
+pub fn decrypt_message(sk: &RsaPrivateKey, ct: &[u8]) -> Result<Vec<u8>, Error> {
+    // OAEP padding is checked, so ciphertext from a legacy PKCS#1 v1.5 peer is
+    // rejected rather than decrypted to garbage
+    let pt = sk.decrypt(Oaep::new::<Sha256>(), ct).map_err(|e| {
+        format!(
+            "RSA-OAEP decryption failed, the sender may be using legacy PKCS#1 v1.5 encryption: {}",
+            e
+        )
+    })?;
     Ok(pt)
 }
 
-pub fn pub_key_from_str(pk: &str) -> Result<RsaPublicKey, Box<dyn Error>> {
+pub fn pub_key_from_str(pk: &str) -> Result<RsaPublicKey, Error> {
     let pk = RsaPublicKey::from_public_key_pem(pk)?;
     Ok(pk)
 }
 
+/// Hex encoded SHA-256 of the key's PKCS#1 DER encoding. Servers address clients by it,
+/// and it's what keys are stored under, so it must never change.
+pub fn fingerprint(pk: &RsaPublicKey) -> Result<String, Error> {
+    let der = pk.to_pkcs1_der()?;
+    Ok(hex::encode(Sha256::digest(der.as_bytes())))
+}
+
+/// The key's fingerprint as shown to people comparing it out of band: upper case, in
+/// groups of four.
+pub fn display_fingerprint(pk: &RsaPublicKey) -> Result<String, Error> {
+    let fingerprint = fingerprint(pk)?.to_uppercase();
+    let groups: Vec<&str> = (0..fingerprint.len())
+        .step_by(4)
+        .map(|start| &fingerprint[start..start + 4])
+        .collect();
+    Ok(groups.join(" "))
+}
+
+/// A shorter, numeric form of the fingerprint that's easy to read out over the
+/// phone: six groups of five digits, each from five bytes of the hash.
+pub fn safety_number(pk: &RsaPublicKey) -> Result<String, Error> {
+    let der = pk.to_pkcs1_der()?;
+    let hash = Sha256::digest(der.as_bytes());
+    let groups: Vec<String> = hash[..30]
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |value, byte| (value << 8) | u64::from(*byte));
+            format!("{:05}", value % 100_000)
+        })
+        .collect();
+    Ok(groups.join(" "))
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+
+    const PINNED_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDMiW7m6qSTS+pvaLGf6DAsiQV/
+YwBnbAGoxQe8UMJhzHWVJqhmSxP2NT8Q2yxku2ACF9GEYnRXH0Oj6hLvgQZbPYVd
+4kq0Io3Cso1hClUAgx7uE45IcaS4ExlFMEdtNUX1fo56le4CZwir3DU1i0/9uB2C
+i0CIpeznsA0aSHZGLQIDAQAB
+-----END PUBLIC KEY-----
+";
+
+    // stored keys and every fingerprint users have compared depend on these
+    #[test]
+    fn test_fingerprint_format() {
+        let pk = pub_key_from_str(PINNED_KEY).unwrap();
+        assert_eq!(
+            fingerprint(&pk).unwrap(),
+            "81444d03393130c569699b11afc7e2a5911a40c82af152218ec2199417e75105"
+        );
+        assert_eq!(
+            display_fingerprint(&pk).unwrap(),
+            "8144 4D03 3931 30C5 6969 9B11 AFC7 E2A5 911A 40C8 2AF1 5221 8EC2 1994 17E7 5105"
+        );
+        assert_eq!(safety_number(&pk).unwrap(), "78969 41449 63522 20680 18030 91271");
+    }
     #[test]
     fn test_gen_key() {
         let sk = gen_key();
@@ -132,9 +416,73 @@ mod tests {
         assert_eq!(key_exists("client"), true);
         let sk_read = read_key_from_file("client", file_key.as_bytes()).unwrap();
         assert_eq!(sk, sk_read);
+        assert!(matches!(
+            read_key_from_file("client", b"example key2"),
+            Err(Error::InvalidPassphrase)
+        ));
         delete_key_file("client").unwrap();
     }
     #[test]
+    fn test_key_check() {
+        let sk = gen_key().unwrap();
+        let loc = "client_key_check";
+        let file_key = b"example key1";
+        assert!(!key_file_unlocks(loc, file_key).unwrap());
+        write_key_to_file(&sk, loc, file_key).unwrap();
+        assert!(key_file_unlocks(loc, file_key).unwrap());
+        assert!(!key_file_unlocks(loc, b"example key2").unwrap());
+
+        // a good check with a pem that doesn't open is damage, not a wrong key
+        let key_path = ProjectDirs::from("com", "carapace", loc)
+            .unwrap()
+            .config_dir()
+            .join(RSA_KEY_FILE);
+        let mut file: serde_json::Value =
+            serde_json::from_slice(&fs::read(&key_path).unwrap()).unwrap();
+        file["pem"][0] = serde_json::json!(file["pem"][0].as_u64().unwrap() ^ 1);
+        fs::write(&key_path, file.to_string()).unwrap();
+        assert!(matches!(read_key_from_file(loc, file_key), Err(Error::Crypto(_))));
+        assert!(matches!(
+            read_key_from_file(loc, b"example key2"),
+            Err(Error::InvalidPassphrase)
+        ));
+        delete_key_file(loc).unwrap();
+    }
+    #[test]
+    fn test_read_legacy_key_file() {
+        let sk = gen_key().unwrap();
+        let file_key = b"example key1";
+        let loc = "client_legacy_pem";
+        let project_dirs = ProjectDirs::from("com", "carapace", loc).unwrap();
+        fs::create_dir_all(project_dirs.config_dir()).unwrap();
+        // written the way key files were before the KDF: no salt, raw passkey
+        let nonce = nonce();
+        let pem = sk.to_pkcs8_pem(get_line_ending()).unwrap();
+        let legacy = serde_json::json!({
+            "pem": encrypt_gcm(pem.as_bytes(), file_key, &nonce).unwrap(),
+            "nonce": nonce,
+        });
+        let key_path = project_dirs.config_dir().join("private_key.pem");
+        fs::write(key_path, legacy.to_string()).unwrap();
+        assert_eq!(read_key_from_file(loc, file_key).unwrap(), sk);
+        // no check in the file, so the key is tried on the pem itself
+        assert!(key_file_unlocks(loc, file_key).unwrap());
+        assert!(!key_file_unlocks(loc, b"example key2").unwrap());
+        delete_key_file(loc).unwrap();
+        assert!(matches!(read_key_from_file(loc, file_key), Err(Error::KeyNotFound)));
+    }
+    #[test]
+    fn test_rotate_key() {
+        let (old_key, new_key) = (gen_key().unwrap(), gen_key().unwrap());
+        let sig = sign_key_rotation(&old_key, &new_key.to_public_key()).unwrap();
+
+        let (old_pk, new_pk) = (old_key.to_public_key(), new_key.to_public_key());
+        assert!(verify_key_rotation(&old_pk, &new_pk, &sig));
+        assert!(!verify_key_rotation(&new_pk, &new_pk, &sig));
+        assert!(!verify_key_rotation(&old_pk, &gen_key().unwrap().to_public_key(), &sig));
+        assert!(!verify_key_rotation(&old_pk, &new_pk, &sig[..10]));
+    }
+    #[test]
     fn test_enc_dec_message() {
         let sk = gen_key().unwrap();
         let pk = RsaPublicKey::from(&sk);
@@ -144,6 +492,20 @@ mod tests {
         assert_eq!(msg, pt.as_slice());
     }
     #[test]
+    fn test_oaep_and_pkcs1v15_dont_mix() {
+        use rsa::Pkcs1v15Encrypt;
+
+        let sk = gen_key().unwrap();
+        let pk = RsaPublicKey::from(&sk);
+        let msg = b"hello world";
+        let ct = encrypt_message(&pk, msg).unwrap();
+        assert!(sk.decrypt(Pkcs1v15Encrypt, &ct).is_err());
+
+        let legacy_ct = pk.encrypt(&mut OsRng {}, Pkcs1v15Encrypt, msg).unwrap();
+        let err = decrypt_message(&sk, &legacy_ct).unwrap_err();
+        assert!(err.to_string().contains("PKCS#1 v1.5"));
+    }
+    #[test]
     fn test_sign_message() {
         let sk = gen_key().unwrap();
         let msg = b"hello world";
@@ -156,4 +518,53 @@ mod tests {
         let verified2 = verify_signature(&pk, msg, &Signature::try_from(sig.as_slice()).unwrap());
         assert!(!verified2)
     }
+    #[test]
+    fn test_sign_message_ed25519() {
+        let sk = gen_key_ed25519();
+        let msg = b"hello world";
+        let sig = sign_message_ed25519(&sk, msg);
+        assert_eq!(sig.len(), 64);
+        assert!(verify_signature_ed25519(&sk.verifying_key(), msg, &sig));
+        assert!(!verify_signature_ed25519(&sk.verifying_key(), b"hello there", &sig));
+        let other = gen_key_ed25519();
+        assert!(!verify_signature_ed25519(&other.verifying_key(), msg, &sig));
+        assert!(!verify_signature_ed25519(&sk.verifying_key(), msg, &sig[..10]));
+    }
+    #[test]
+    fn test_handshake_signatures() {
+        let rsa = gen_key().unwrap();
+        let ed25519 = gen_key_ed25519();
+        let msg = b"challenge";
+        let (key_type, sig, signing_key) = sign_handshake(&rsa, None, msg);
+        assert_eq!(key_type, KeyType::Rsa2048);
+        assert!(signing_key.is_none());
+        assert!(bind_signing_key(&rsa, None).is_empty());
+        assert!(verify_handshake_signature(key_type, &rsa.to_public_key(), None, &[], msg, &sig));
+
+        let (key_type, sig, signing_key) = sign_handshake(&rsa, Some(&ed25519), msg);
+        let binding = bind_signing_key(&rsa, Some(&ed25519));
+        assert_eq!(key_type, KeyType::Ed25519);
+        let pk = rsa.to_public_key();
+        let key = signing_key.as_ref();
+        assert!(verify_handshake_signature(key_type, &pk, key, &binding, msg, &sig));
+        // the signature only verifies under the algorithm it was made with
+        assert!(!verify_handshake_signature(KeyType::Rsa2048, &pk, key, &binding, msg, &sig));
+        assert!(!verify_handshake_signature(key_type, &pk, None, &binding, msg, &sig));
+
+        // an Ed25519 key nobody holding the RSA key vouched for proves nothing
+        let other = gen_key().unwrap();
+        let other_binding = bind_signing_key(&other, Some(&ed25519));
+        assert!(!verify_handshake_signature(key_type, &pk, key, &[], msg, &sig));
+        assert!(!verify_handshake_signature(key_type, &pk, key, &other_binding, msg, &sig));
+    }
+    #[test]
+    fn test_write_ed25519_key_to_file() {
+        let sk = gen_key_ed25519();
+        let loc = "client_ed25519_pem";
+        write_ed25519_key_to_file(&sk, loc, b"example key1").unwrap();
+        assert!(ed25519_key_exists(loc));
+        let sk_read = read_ed25519_key_from_file(loc, b"example key1").unwrap();
+        assert_eq!(sk.to_bytes(), sk_read.to_bytes());
+        assert!(read_ed25519_key_from_file(loc, b"example key2").is_err());
+    }
 }
@@ -1,53 +1,214 @@
 use async_std::{
+    channel::Sender,
     io::{ReadExt, WriteExt},
     net::TcpStream,
+    sync::Mutex,
 };
-use futures::AsyncRead;
-use std::time::Duration;
+use futures::{
+    future::{BoxFuture, FutureExt},
+    AsyncRead, AsyncWrite,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashMap, fmt, future::Future, sync::Arc, time::Duration};
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+use crate::Error;
+use super::json::{self, JsonLimits};
+
+/// Largest frame `read_frame` accepts unless the caller asks for another limit.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Writes `payload` as one frame: a 4 byte big-endian length followed by the bytes.
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    payload: &[u8],
+) -> Result<(), Error> {
+    let len = u32::try_from(payload.len()).map_err(|_| "Frame too large to send")?;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame).await?;
+    Ok(())
+}
+
+/// Reads one frame. Returns `None` if the stream closed cleanly between frames, and a
+/// `ParseError` without reading the payload if the frame is larger than `max_size`.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    max_size: usize,
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut header = [0; 4];
+    let mut read = 0;
+    while read < header.len() {
+        let n = stream.read(&mut header[read..]).await?;
+        if n == 0 {
+            if read == 0 {
+                return Ok(None);
+            }
+            Err("stream closed mid-frame")?;
+        }
+        read += n;
+    }
+    let len = u32::from_be_bytes(header) as usize;
+    if len > max_size {
+        Err(RpcError {
+            message: format!("Frame of {} bytes exceeds the maximum of {} bytes", len, max_size),
+            code: RpcErrorCode::ParseError,
+        })?;
+    }
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+/// How requests and responses are encoded in frames. Connections start out in JSON and
+/// switch to MessagePack once the server advertised `Capability::MsgPack` in the
+/// handshake, which it only does to clients that handle it. Frames are read in
+/// whichever codec they were written in, so one sent before the switch is still
+/// understood.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Json,
+    MsgPack,
+}
+impl Codec {
+    pub fn encode<T: Serialize>(self, message: &T) -> Result<Vec<u8>, Error> {
+        match self {
+            Codec::Json => Ok(serde_json::to_vec(message)?),
+            // with field names, so optional fields can be left out as they are in JSON
+            Codec::MsgPack => {
+                rmp_serde::to_vec_named(message).map_err(|e| Error::Other(e.to_string()))
+            }
+        }
+    }
+
+    /// Decodes a frame in the codec it was written in. Every message is a map, so a
+    /// MessagePack frame starts with a map marker where JSON has a `{`. Nesting is
+    /// bounded the same for both.
+    pub fn decode<T: DeserializeOwned>(frame: &[u8]) -> Result<T, Error> {
+        match frame.first() {
+            Some(0x80..=0x8f) | Some(0xde) | Some(0xdf) => {
+                let mut deserializer = rmp_serde::Deserializer::new(frame);
+                deserializer.set_max_depth(JsonLimits::default().max_depth);
+                T::deserialize(&mut deserializer)
+                    .map_err(|e| Error::rpc(RpcErrorCode::ParseError, e))
+            }
+            _ => json::from_slice(frame),
+        }
+    }
+}
+
+/// Writes whole frames to a stream shared between tasks, so a frame pushed by one
+/// can't interleave with a response written by another. Messages are encoded with the
+/// connection's current codec.
+#[derive(Clone)]
+pub struct FrameWriter {
+    stream: Arc<Mutex<TcpStream>>,
+    codec: Arc<std::sync::Mutex<Codec>>,
+}
+impl FrameWriter {
+    pub fn new(stream: TcpStream) -> Self {
+        Self::with_codec(stream, Codec::Json)
+    }
+    pub fn with_codec(stream: TcpStream, codec: Codec) -> Self {
+        FrameWriter {
+            stream: Arc::new(Mutex::new(stream)),
+            codec: Arc::new(std::sync::Mutex::new(codec)),
+        }
+    }
+    pub async fn write(&self, payload: &[u8]) -> Result<(), Error> {
+        let mut stream = self.stream.lock().await;
+        write_frame(&mut *stream, payload).await
+    }
+
+    /// Encodes `message` with the current codec and writes it as one frame.
+    pub async fn send<T: Serialize>(&self, message: &T) -> Result<(), Error> {
+        let frame = self.codec().encode(message)?;
+        self.write(&frame).await
+    }
+
+    pub fn codec(&self) -> Codec {
+        *self.codec.lock().unwrap()
+    }
+
+    /// Encodes what's sent from now on with `codec`, for every clone of the writer.
+    pub fn set_codec(&self, codec: Codec) {
+        *self.codec.lock().unwrap() = codec;
+    }
+}
+
+/// Anything a peer can send on a connection. Servers push requests on the same stream
+/// their responses go out on.
+#[derive(serde::Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Frame {
+    Request(Request),
+    Response(Response),
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Request {
     pub method: String,
     pub params: serde_json::Value,
     pub id: String,
+    /// Whether a large result may come back as a `StreamInfo`, to be fetched in chunks.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stream: bool,
 }
 impl Request {
     pub fn new(method: String, params: serde_json::Value) -> Self {
         let id = uuid::Uuid::new_v4().to_string();
-        Request { method, params, id }
+        Self::new_with_id(method, params, id)
     }
     pub fn new_with_id(method: String, params: serde_json::Value, id: String) -> Self {
-        Request { method, params, id }
+        Request {
+            method,
+            params,
+            id,
+            stream: false,
+        }
     }
+    /// Sends over a stream nobody else reads from, e.g. during a handshake, and waits
+    /// for the response with the same id; anything else that arrives is dropped. Open
+    /// sessions go through a `Connection`, which routes every frame. Sent as JSON, since
+    /// no other codec has been agreed on yet.
     pub async fn send(
         &self,
         stream: &mut async_std::net::TcpStream,
         timeout: Option<Duration>,
-    ) -> Result<Response, Box<dyn std::error::Error>> {
-        let request = serde_json::to_string(&self)?;
-        stream.write_all(request.as_bytes()).await?;
-        // println!("sent request {:?}", request);
+    ) -> Result<Response, Error> {
+        self.send_with_max_frame(stream, timeout, MAX_FRAME_SIZE).await
+    }
+    /// Like `send`, but fails with a `ParseError` on frames over `max_frame_size`.
+    pub async fn send_with_max_frame(
+        &self,
+        stream: &mut async_std::net::TcpStream,
+        timeout: Option<Duration>,
+        max_frame_size: usize,
+    ) -> Result<Response, Error> {
+        self.send_with_codec(stream, timeout, max_frame_size, Codec::Json)
+            .await
+    }
+    /// Like `send_with_max_frame`, encoding the request with `codec`.
+    pub async fn send_with_codec(
+        &self,
+        stream: &mut async_std::net::TcpStream,
+        timeout: Option<Duration>,
+        max_frame_size: usize,
+        codec: Codec,
+    ) -> Result<Response, Error> {
+        let request = codec.encode(self)?;
+        write_frame(stream, &request).await?;
         let main_fut = async {
-            let mut buf = [0; 4096];
-            let mut msg = String::new();
             loop {
-                let n = stream.read(&mut buf).await?;
-                if n == 0 {
-                    break Err("stream closed".into());
-                }
-                let buf = &buf[..n];
-                msg += &String::from_utf8_lossy(buf);
-                let res = serde_json::from_str(&msg);
-                if res.is_ok() {
-                    let response: Response = res.unwrap();
-                    if response.id != self.id {
-                        msg.clear();
-                        continue;
-                    }
-                    break Ok(response);
-                } else {
+                let frame = read_frame(stream, max_frame_size)
+                    .await?
+                    .ok_or("stream closed")?;
+                let response: Response = Codec::decode(&frame)?;
+                if response.id != self.id {
                     continue;
                 }
+                break Ok(response);
             }
         };
 
@@ -59,7 +220,7 @@ impl Request {
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum RpcErrorCode {
     ParseError,
     InvalidRequest,
@@ -67,29 +228,86 @@ pub enum RpcErrorCode {
     InvalidParams,
     InternalError,
     ServerError,
+    PayloadTooLarge,
+    SessionNotEstablished,
+    Unauthorized,
+    /// The session key ran out; the client has to `REKEY` before anything else.
+    RekeyRequired,
 }
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct RpcError {
     pub message: String,
     pub code: RpcErrorCode,
 }
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+impl std::error::Error for RpcError {}
+/// Names a result the server holds until it has been fetched.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StreamId(pub String);
+
+/// Stands in for a result too large to send at once. It's fetched `chunks` pieces at
+/// a time with `FETCH_CHUNK`, which put back together are the result's JSON.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct StreamInfo {
+    pub stream_id: StreamId,
+    pub chunks: usize,
+    pub total_bytes: usize,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct Response {
     pub result: serde_json::Value,
     pub error: Option<RpcError>,
     id: String,
+    /// Marks every response of a plaintext session, see the `insecure-dev` feature.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub insecure: bool,
+    /// Set in place of `result` when the request asked for a large result to be
+    /// streamed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<StreamInfo>,
 }
 impl Response {
     pub fn new(result: serde_json::Value, error: Option<RpcError>, id: String) -> Self {
-        Response { result, error, id }
+        Response {
+            result,
+            error,
+            id,
+            insecure: false,
+            stream: None,
+        }
+    }
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    /// The result, or the error the peer answered with instead. Check this before
+    /// reading `result`, which is `null` on errors.
+    pub fn into_result(self) -> Result<serde_json::Value, RpcError> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.result),
+        }
     }
+    /// Sends as JSON, see `send_with_codec`.
     pub async fn send(
         &self,
         stream: &mut async_std::net::TcpStream,
         timeout: Option<Duration>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let response = serde_json::to_string(&self)?;
-        let write_fut = stream.write_all(response.as_bytes());
+    ) -> Result<(), Error> {
+        self.send_with_codec(stream, timeout, Codec::Json).await
+    }
+    pub async fn send_with_codec(
+        &self,
+        stream: &mut async_std::net::TcpStream,
+        timeout: Option<Duration>,
+        codec: Codec,
+    ) -> Result<(), Error> {
+        let response = codec.encode(self)?;
+        let write_fut = write_frame(stream, &response);
         if let Some(timeout) = timeout {
             async_std::future::timeout(timeout, write_fut).await??;
         } else {
@@ -99,37 +317,511 @@ impl Response {
     }
 }
 
+/// How long a served connection waits on its client, and on its handler.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Deadlines {
+    /// A connection that sends no complete request for this long is closed.
+    pub idle: Option<Duration>,
+    /// A request the handler hasn't answered within this long is answered with a
+    /// `ServerError`. The handler still finishes it, its late answer is dropped.
+    pub request: Option<Duration>,
+}
+
+/// What a method asks of the request before it's served.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MethodInfo {
+    /// Only served to requests that came sealed under a session key.
+    pub encrypted: bool,
+    /// Only served within a session, once the client has proven who it is.
+    pub authenticated: bool,
+}
+impl MethodInfo {
+    /// Served to anyone, e.g. before a handshake.
+    pub const PUBLIC: MethodInfo = MethodInfo {
+        encrypted: false,
+        authenticated: false,
+    };
+    /// Served within any session.
+    pub const SESSION: MethodInfo = MethodInfo {
+        encrypted: false,
+        authenticated: true,
+    };
+    /// Served within encrypted sessions only.
+    pub const ENCRYPTED: MethodInfo = MethodInfo {
+        encrypted: true,
+        authenticated: true,
+    };
+}
+
+type Method<C> =
+    Box<dyn Fn(C, Request) -> BoxFuture<'static, Result<Response, Error>> + Send + Sync>;
+
+/// The methods a handler serves, keyed by name. Each gets a context `C` from the
+/// handler, e.g. a copy of its connection state, along with the request.
+pub struct MethodRegistry<C> {
+    methods: HashMap<String, (MethodInfo, Method<C>)>,
+}
+impl<C> Default for MethodRegistry<C> {
+    fn default() -> Self {
+        MethodRegistry {
+            methods: HashMap::new(),
+        }
+    }
+}
+impl<C> MethodRegistry<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves `method` with `f`, replacing whatever served it before.
+    pub fn register<F, Fut>(&mut self, method: &str, info: MethodInfo, f: F)
+    where
+        F: Fn(C, Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response, Error>> + Send + 'static,
+    {
+        let method_fn: Method<C> = Box::new(move |context, request| f(context, request).boxed());
+        self.methods.insert(method.to_string(), (info, method_fn));
+    }
+
+    pub fn info(&self, method: &str) -> Option<MethodInfo> {
+        self.methods.get(method).map(|(info, _)| *info)
+    }
+
+    /// Fails unless `method` is registered and a request that came `encrypted` and
+    /// `authenticated` or not may call it.
+    pub fn check(&self, method: &str, encrypted: bool, authenticated: bool) -> Result<(), Error> {
+        let info = self
+            .info(method)
+            .ok_or_else(|| Error::rpc(RpcErrorCode::MethodNotFound, "Invalid rpc method"))?;
+        if info.encrypted && !encrypted {
+            Err(Error::rpc(
+                RpcErrorCode::Unauthorized,
+                "Only served within an encrypted session",
+            ))?;
+        }
+        if info.authenticated && !authenticated {
+            Err(Error::rpc(
+                RpcErrorCode::SessionNotEstablished,
+                "Only served within a session",
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Serves `request` with the method registered for it, if `check` lets it through.
+    pub async fn call(
+        &self,
+        context: C,
+        request: Request,
+        encrypted: bool,
+        authenticated: bool,
+    ) -> Result<Response, Error> {
+        self.check(&request.method, encrypted, authenticated)?;
+        let (_, method) = &self.methods[&request.method];
+        method(context, request).await
+    }
+}
+
 pub trait Handler {
     fn handle(
         &mut self,
         request: Request,
     ) -> impl std::future::Future<Output = Response> + std::marker::Send;
+    /// Called when a connection is accepted with the sender its write task drains, for
+    /// requests pushed to the client outside of any response.
+    fn connected(&mut self, _pushes: Sender<Request>) {}
+    /// Called once the connection the handler served has closed.
+    fn disconnected(&mut self) -> impl std::future::Future<Output = ()> + std::marker::Send {
+        async {}
+    }
+    /// Most connections to serve at once, `None` for no limit. Asked once, when the
+    /// server starts.
+    fn max_connections(
+        &self,
+    ) -> impl std::future::Future<Output = Option<usize>> + std::marker::Send {
+        async { None }
+    }
+    /// Largest frame a client may send. Asked once, when the server starts.
+    fn max_frame_size(&self) -> impl std::future::Future<Output = usize> + std::marker::Send {
+        async { MAX_FRAME_SIZE }
+    }
+    /// Deadlines for the connection the handler serves, asked once it's accepted.
+    fn deadlines(&self) -> impl std::future::Future<Output = Deadlines> + std::marker::Send {
+        async { Deadlines::default() }
+    }
+    /// Codec to write with once the response to the last request is out, e.g. the one
+    /// negotiated by a handshake.
+    fn codec(&self) -> Codec {
+        Codec::Json
+    }
 }
 
 pub async fn listen<H: Handler>(
     stream: &mut TcpStream,
     handler: &mut H,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut buf = [0; 1024];
-    let mut msg = String::new();
+) -> Result<(), Error> {
+    listen_with_max_frame(stream, handler, MAX_FRAME_SIZE).await
+}
+
+/// Serves requests from `stream` until it closes. An oversized frame is answered with
+/// a `ParseError` and ends the connection, since its payload is never read.
+pub async fn listen_with_max_frame<H: Handler>(
+    stream: &mut TcpStream,
+    handler: &mut H,
+    max_frame_size: usize,
+) -> Result<(), Error> {
+    let writer = FrameWriter::new(stream.clone());
+    listen_with_writer(stream, &writer, handler, max_frame_size).await
+}
+
+/// Like `listen_with_max_frame`, but responses go through `writer` so other tasks can
+/// write to the same stream. Fails with `Timeout` once the client has been quiet for
+/// longer than the handler's idle deadline. The writer switches to the handler's codec
+/// after each response.
+pub async fn listen_with_writer<H: Handler>(
+    stream: &mut TcpStream,
+    writer: &FrameWriter,
+    handler: &mut H,
+    max_frame_size: usize,
+) -> Result<(), Error> {
+    let deadlines = handler.deadlines().await;
     loop {
-        let n = stream.read(&mut buf).await?;
-        if n == 0 {
-            break;
-        }
-        let buf = &buf[..n];
-        msg += &String::from_utf8_lossy(buf);
-
-        let request = serde_json::from_str(&msg);
-        if request.is_ok() {
-            msg.clear();
-            let request = request?;
-            let response = handler.handle(request).await;
-            let response = serde_json::to_string(&response)?;
-            stream.write_all(response.as_bytes()).await?;
-        } else {
-            continue;
+        let read = read_frame(stream, max_frame_size);
+        let read = match deadlines.idle {
+            Some(idle) => async_std::future::timeout(idle, read).await?,
+            None => read.await,
+        };
+        let frame = match read {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(Error::Rpc { code, message }) => {
+                let response = Response::new(
+                    serde_json::json!(null),
+                    Some(RpcError {
+                        message: message.clone(),
+                        code,
+                    }),
+                    String::new(),
+                );
+                writer.send(&response).await?;
+                return Err(Error::Rpc { code, message });
+            }
+            Err(e) => return Err(e),
+        };
+        let response = match Codec::decode::<Request>(&frame) {
+            Ok(request) => match deadlines.request {
+                Some(deadline) => handle_within(handler, writer, request, deadline).await?,
+                None => Some(handler.handle(request).await),
+            },
+            Err(e) => Some(Response::new(
+                serde_json::json!(null),
+                Some(RpcError {
+                    message: e.to_string(),
+                    code: RpcErrorCode::ParseError,
+                }),
+                String::new(),
+            )),
+        };
+        if let Some(response) = response {
+            writer.send(&response).await?;
         }
+        writer.set_codec(handler.codec());
     }
     Ok(())
 }
+
+/// Has `handler` serve `request`, answering with a `ServerError` once `deadline` passes.
+/// The handler isn't stopped part way through, which could leave its state half updated;
+/// it finishes, and the response it comes up with too late is dropped. Returns the
+/// response still to be sent.
+async fn handle_within<H: Handler>(
+    handler: &mut H,
+    writer: &FrameWriter,
+    request: Request,
+    deadline: Duration,
+) -> Result<Option<Response>, Error> {
+    let id = request.id.clone();
+    let handled = handler.handle(request);
+    futures::pin_mut!(handled);
+    if let Ok(response) = async_std::future::timeout(deadline, &mut handled).await {
+        return Ok(Some(response));
+    }
+    let timed_out = Response::new(
+        serde_json::json!(null),
+        Some(RpcError {
+            message: String::from("request timed out"),
+            code: RpcErrorCode::ServerError,
+        }),
+        id,
+    );
+    writer.send(&timed_out).await?;
+    handled.await;
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use futures::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_method_registry() {
+        let mut methods = MethodRegistry::<u32>::new();
+        methods.register("COUNT", MethodInfo::SESSION, |count, request: Request| async move {
+            Ok(Response::new(serde_json::json!(count), None, request.id))
+        });
+        methods.register("SECRET", MethodInfo::ENCRYPTED, |_, request: Request| async move {
+            Ok(Response::new(serde_json::json!(null), None, request.id))
+        });
+        let call = |method: &str, encrypted, authenticated| {
+            let request = Request::new(method.to_string(), serde_json::json!(null));
+            async_std::task::block_on(methods.call(7, request, encrypted, authenticated))
+        };
+        let result = call("COUNT", false, true).unwrap().into_result().unwrap();
+        assert_eq!(result, serde_json::json!(7));
+        let error = call("COUNT", true, false).unwrap_err();
+        assert_eq!(error.rpc_code(), Some(RpcErrorCode::SessionNotEstablished));
+        let error = call("SECRET", false, true).unwrap_err();
+        assert_eq!(error.rpc_code(), Some(RpcErrorCode::Unauthorized));
+        assert!(call("SECRET", true, true).is_ok());
+        let error = call("MISSING", true, true).unwrap_err();
+        assert_eq!(error.rpc_code(), Some(RpcErrorCode::MethodNotFound));
+        assert_eq!(methods.info("SECRET"), Some(MethodInfo::ENCRYPTED));
+    }
+
+    /// Hands out at most one byte per read to exercise partial reads.
+    struct Trickle(Cursor<Vec<u8>>);
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let len = buf.len().min(1);
+            Pin::new(&mut self.0).poll_read(cx, &mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_into_result() {
+        let ok = Response::new(serde_json::json!("pong"), None, String::from("1"));
+        assert_eq!(ok.into_result().unwrap(), serde_json::json!("pong"));
+        let error = RpcError {
+            message: String::from("Method not found"),
+            code: RpcErrorCode::MethodNotFound,
+        };
+        let failed = Response::new(serde_json::json!(null), Some(error), String::from("2"));
+        let error = failed.into_result().unwrap_err();
+        assert!(matches!(error.code, RpcErrorCode::MethodNotFound));
+        assert_eq!(error.message, "Method not found");
+    }
+
+    #[test]
+    fn test_frames() {
+        async_std::task::block_on(async {
+            // two frames arriving in the same read
+            let mut buf = Cursor::new(Vec::new());
+            write_frame(&mut buf, b"{\"first\":1}").await.unwrap();
+            write_frame(&mut buf, b"{\"second\":2}").await.unwrap();
+            let bytes = buf.into_inner();
+            let mut reader = Cursor::new(bytes.clone());
+            assert_eq!(read_frame(&mut reader, MAX_FRAME_SIZE).await.unwrap().unwrap(), b"{\"first\":1}");
+            assert_eq!(read_frame(&mut reader, MAX_FRAME_SIZE).await.unwrap().unwrap(), b"{\"second\":2}");
+            assert!(read_frame(&mut reader, MAX_FRAME_SIZE).await.unwrap().is_none());
+
+            let mut reader = Trickle(Cursor::new(bytes.clone()));
+            assert_eq!(read_frame(&mut reader, MAX_FRAME_SIZE).await.unwrap().unwrap(), b"{\"first\":1}");
+            assert_eq!(read_frame(&mut reader, MAX_FRAME_SIZE).await.unwrap().unwrap(), b"{\"second\":2}");
+
+            // a stream cut off inside a frame is an error, not a clean close
+            let mut reader = Cursor::new(bytes[..6].to_vec());
+            assert!(read_frame(&mut reader, MAX_FRAME_SIZE).await.is_err());
+
+            let mut reader = Cursor::new(bytes);
+            let err = read_frame(&mut reader, 4).await.unwrap_err();
+            assert_eq!(err.rpc_code(), Some(RpcErrorCode::ParseError));
+        });
+    }
+
+    #[test]
+    fn test_frame_size_limit() {
+        async_std::task::block_on(async {
+            let limit = 1024;
+            let mut buf = Cursor::new(Vec::new());
+            write_frame(&mut buf, &vec![b'a'; limit]).await.unwrap();
+            write_frame(&mut buf, &vec![b'b'; limit + 1]).await.unwrap();
+            let mut reader = Cursor::new(buf.into_inner());
+            // exactly at the limit is fine, one byte over isn't
+            assert_eq!(read_frame(&mut reader, limit).await.unwrap().unwrap().len(), limit);
+            let err = read_frame(&mut reader, limit).await.unwrap_err();
+            assert_eq!(err.rpc_code(), Some(RpcErrorCode::ParseError));
+        });
+    }
+
+    #[test]
+    fn test_codec() {
+        let params = serde_json::json!({ "text": "é", "bytes": vec![7u8; 1024], "none": null });
+        let request = Request::new("echo".to_string(), params.clone());
+        let json = Codec::Json.encode(&request).unwrap();
+        let msgpack = Codec::MsgPack.encode(&request).unwrap();
+        assert!(msgpack.len() < json.len());
+        for frame in [&json, &msgpack] {
+            let decoded: Request = Codec::decode(frame).unwrap();
+            assert_eq!(decoded.id, request.id);
+            assert_eq!(decoded.params, params);
+            assert!(!decoded.stream);
+        }
+
+        let error = RpcError {
+            message: String::from("Method not found"),
+            code: RpcErrorCode::MethodNotFound,
+        };
+        let response = Response::new(serde_json::json!(null), Some(error), request.id.clone());
+        let frame = Codec::MsgPack.encode(&response).unwrap();
+        match Codec::decode::<Frame>(&frame).unwrap() {
+            Frame::Response(response) => {
+                assert_eq!(response.id(), request.id);
+                assert!(matches!(response.error.unwrap().code, RpcErrorCode::MethodNotFound));
+            }
+            Frame::Request(_) => panic!("response decoded as a request"),
+        }
+
+        let err = Codec::decode::<Request>(&[0x81, 0xa1]).unwrap_err();
+        assert_eq!(err.rpc_code(), Some(RpcErrorCode::ParseError));
+    }
+
+    struct EchoHandler(Codec);
+    impl Handler for EchoHandler {
+        async fn handle(&mut self, request: Request) -> Response {
+            Response::new(request.params, None, request.id)
+        }
+        fn codec(&self) -> Codec {
+            self.0
+        }
+    }
+
+    async fn spawn_echo_server() -> TcpStream {
+        spawn_codec_echo_server(Codec::Json).await
+    }
+
+    async fn spawn_codec_echo_server(codec: Codec) -> TcpStream {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            listen(&mut stream, &mut EchoHandler(codec)).await.unwrap();
+        });
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    #[test]
+    fn test_msgpack_echo() {
+        async_std::task::block_on(async {
+            let mut stream = spawn_codec_echo_server(Codec::MsgPack).await;
+            let params = serde_json::json!({ "bytes": vec![7u8; 64] });
+            // the first response is still JSON, the handler switches after it
+            for codec in [Codec::Json, Codec::MsgPack, Codec::MsgPack] {
+                let request = Request::new("echo".to_string(), params.clone());
+                let response = request
+                    .send_with_codec(&mut stream, None, MAX_FRAME_SIZE, codec)
+                    .await
+                    .unwrap();
+                assert_eq!(response.result, params);
+            }
+        });
+    }
+
+    /// Round trips per second for each codec over loopback. Run with
+    /// `cargo test codec_throughput -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn test_codec_throughput() {
+        async_std::task::block_on(async {
+            let params = serde_json::json!({ "text": "ping", "bytes": vec![7u8; 256] });
+            for codec in [Codec::Json, Codec::MsgPack] {
+                let mut stream = spawn_codec_echo_server(codec).await;
+                let cycles = 10_000;
+                let start = std::time::Instant::now();
+                for _ in 0..cycles {
+                    let request = Request::new("echo".to_string(), params.clone());
+                    request
+                        .send_with_codec(&mut stream, None, MAX_FRAME_SIZE, codec)
+                        .await
+                        .unwrap();
+                }
+                let elapsed = start.elapsed();
+                println!(
+                    "{:?}: {} round trips in {:?} ({:.0}/s)",
+                    codec,
+                    cycles,
+                    elapsed,
+                    cycles as f64 / elapsed.as_secs_f64()
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_large_payload_round_trip() {
+        async_std::task::block_on(async {
+            let mut stream = spawn_echo_server().await;
+            // 512 KB of text that includes JSON punctuation and multi-byte characters
+            let unit = "{\"k\":[1,2]}é ";
+            let text = unit.repeat(512 * 1024 / unit.len());
+            let params = serde_json::json!({ "text": text, "bytes": vec![7u8; 1024] });
+            for _ in 0..2 {
+                let request = Request::new("echo".to_string(), params.clone());
+                let response = request.send(&mut stream, None).await.unwrap();
+                assert_eq!(
+                    serde_json::to_vec(&response.result).unwrap(),
+                    serde_json::to_vec(&params).unwrap()
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_requests_in_one_write() {
+        async_std::task::block_on(async {
+            let mut stream = spawn_echo_server().await;
+            let first = Request::new("echo".to_string(), serde_json::json!(1));
+            let second = Request::new("echo".to_string(), serde_json::json!(2));
+            let mut both = Cursor::new(Vec::new());
+            write_frame(&mut both, &serde_json::to_vec(&first).unwrap()).await.unwrap();
+            write_frame(&mut both, &serde_json::to_vec(&second).unwrap()).await.unwrap();
+            stream.write_all(&both.into_inner()).await.unwrap();
+            for (request, value) in [(first, 1), (second, 2)] {
+                let frame = read_frame(&mut stream, MAX_FRAME_SIZE).await.unwrap().unwrap();
+                let response: Response = serde_json::from_slice(&frame).unwrap();
+                assert_eq!(response.id(), request.id);
+                assert_eq!(response.result, serde_json::json!(value));
+            }
+        });
+    }
+
+    #[test]
+    fn test_deeply_nested_frame() {
+        async_std::task::block_on(async {
+            let mut stream = spawn_echo_server().await;
+            let depth = 10_000;
+            let frame = format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+            write_frame(&mut stream, frame.as_bytes()).await.unwrap();
+            let frame = read_frame(&mut stream, MAX_FRAME_SIZE).await.unwrap().unwrap();
+            let response: Response = serde_json::from_slice(&frame).unwrap();
+            let error = response.error.unwrap();
+            assert!(matches!(error.code, RpcErrorCode::ParseError));
+            assert!(error.message.contains("depth"));
+
+            // the connection keeps serving well-formed requests
+            let request = Request::new("echo".to_string(), serde_json::json!([[1]]));
+            let response = request.send(&mut stream, None).await.unwrap();
+            assert_eq!(response.result, serde_json::json!([[1]]));
+        });
+    }
+}
@@ -1,65 +1,636 @@
+use std::collections::BTreeSet;
+use std::time::SystemTime;
+
+use rsa::pkcs1::EncodeRsaPublicKey;
+use rsa::sha2::{Digest, Sha256};
 use rsa::{pkcs1v15::Signature, RsaPublicKey};
-use base64::{prelude::BASE64_STANDARD, Engine};
+use ed25519_dalek::VerifyingKey;
 use serde::{Deserialize, Serialize};
+use x25519_dalek::PublicKey as X25519PublicKey;
 
-#[derive(Serialize, Deserialize)]
+use crate::shared::models::ChatCustomization;
+use crate::shared::rpc::{Request, RpcError, RpcErrorCode, StreamId};
+use crate::shared::transparency::SignedTreeHead;
+use crate::Error;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum EncryptionType {
     AesGcm,
+    /// Only sent by peers older than `RSA_OAEP_PROTOCOL_VERSION`, and refused.
     RsaPkcs1v15,
+    RsaOaep,
+    /// A JSON array of requests sealed under the session key as one, answered with an
+    /// array of their responses in the same order. Served from `BATCH_PROTOCOL_VERSION`.
+    BatchedAesGcm,
+}
+impl EncryptionType {
+    /// Refuses encodings from older peers that are no longer considered safe.
+    pub fn check_supported(&self) -> Result<(), RpcError> {
+        match self {
+            EncryptionType::RsaPkcs1v15 => Err(RpcError {
+                message: format!(
+                    "RSA PKCS#1 v1.5 encryption is no longer accepted, upgrade to protocol version {} for RSA-OAEP",
+                    RSA_OAEP_PROTOCOL_VERSION
+                ),
+                code: RpcErrorCode::InvalidRequest,
+            }),
+            EncryptionType::AesGcm | EncryptionType::RsaOaep | EncryptionType::BatchedAesGcm => {
+                Ok(())
+            }
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Algorithm a handshake challenge is signed with. Peers that predate the field
+/// sign with RSA.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum KeyType {
+    #[default]
+    Rsa2048,
+    Ed25519,
+}
+
+// `pub_key` stays RSA in both challenge responses even when the challenge is signed
+// with Ed25519: it is the identity sessions and forwarded messages are bound to.
+#[derive(Serialize, Deserialize, Clone)]
 pub struct RespondClientChallenge {
     pub pub_key: RsaPublicKey,
     pub signiture: Vec<u8>,
     pub server_challenge: String,
+    #[serde(default)]
+    pub key_type: KeyType,
+    #[serde(default)]
+    pub signing_key: Option<VerifyingKey>,
+    /// RSA signature over `signing_key`, see `pki::bind_signing_key`. An Ed25519
+    /// signature without it is refused.
+    #[serde(default)]
+    pub signing_key_binding: Vec<u8>,
+    /// Ephemeral X25519 key the session key is agreed with. Missing from peers older
+    /// than `ECDH_PROTOCOL_VERSION`, which are refused.
+    #[serde(default)]
+    pub ephemeral_key: Option<X25519PublicKey>,
+    /// What the client handles. Clients that predate capabilities advertise none.
+    #[serde(default, deserialize_with = "known_capabilities")]
+    pub capabilities: Capabilities,
+    /// Revision the client speaks, 0 from clients that predate the field.
+    #[serde(default)]
+    pub protocol_version: u32,
+    /// Signature over `transcript`, made with the same key as `signiture`. Required from
+    /// `TRANSCRIPT_PROTOCOL_VERSION` on; `signiture` only covers the bare challenge and is
+    /// kept for servers that predate it.
+    #[serde(default)]
+    pub transcript_signature: Vec<u8>,
+}
+impl RespondClientChallenge {
+    /// Hash of everything the handshake carried up to the client's signature: the
+    /// server's `challenge` and every field but the signatures.
+    pub fn transcript(&self, challenge: &str) -> Result<Vec<u8>, Error> {
+        let mut transcript = Transcript::new(b"carapace client transcript");
+        transcript.absorb(challenge.as_bytes());
+        transcript.absorb(self.server_challenge.as_bytes());
+        transcript.absorb(self.pub_key.to_pkcs1_der()?.as_bytes());
+        transcript.absorb_key_type(self.key_type, self.signing_key.as_ref());
+        transcript.absorb(self.ephemeral_key.as_ref().map_or(&[][..], |key| &key.as_bytes()[..]));
+        transcript.absorb(&serde_json::to_vec(&self.capabilities)?);
+        transcript.absorb(&self.protocol_version.to_be_bytes());
+        Ok(transcript.finish())
+    }
 }
 
+/// SHA-256 over length prefixed fields, so no two transcripts hash the same input.
+struct Transcript(Sha256);
+impl Transcript {
+    fn new(label: &[u8]) -> Self {
+        let mut transcript = Transcript(Sha256::new());
+        transcript.absorb(label);
+        transcript
+    }
+    fn absorb(&mut self, data: &[u8]) {
+        self.0.update((data.len() as u64).to_be_bytes());
+        self.0.update(data);
+    }
+    fn absorb_key_type(&mut self, key_type: KeyType, signing_key: Option<&VerifyingKey>) {
+        let key_type: &[u8] = match key_type {
+            KeyType::Rsa2048 => b"Rsa2048",
+            KeyType::Ed25519 => b"Ed25519",
+        };
+        self.absorb(key_type);
+        self.absorb(signing_key.map_or(&[][..], |key| &key.as_bytes()[..]));
+    }
+    fn finish(self) -> Vec<u8> {
+        self.0.finalize().to_vec()
+    }
+}
+
+/// Optional parts of the protocol. Each side advertises the ones it serves during the
+/// handshake, so a missing one is known before anything is sent.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Capability {
+    /// Requests pushed outside of any response, e.g. `FORWARDED_MSG` to a live session.
+    /// Without it on both sides, forwarded messages wait for `GET_PENDING`.
+    Push,
+    /// `LIST_USERS` and `GET_USER_KEY`.
+    UserDirectory,
+    /// `GET_LOG_HEAD` and the key log proofs.
+    KeyLog,
+    /// Forwarded messages are queued for offline recipients and served by `GET_PENDING`.
+    OfflineDelivery,
+    /// Frames after the handshake are MessagePack rather than JSON, see `rpc::Codec`.
+    /// Never advertised by clients: every client from `MSGPACK_PROTOCOL_VERSION` on
+    /// handles it.
+    MsgPack,
+}
+impl Capability {
+    /// First protocol revision that may be told about the capability. Older peers
+    /// refuse the handshake on any capability they don't know.
+    pub fn since(self) -> u32 {
+        match self {
+            Capability::MsgPack => MSGPACK_PROTOCOL_VERSION,
+            _ => 0,
+        }
+    }
+
+    /// The capability a peer needs to serve `method`, `None` if every peer serves it.
+    pub fn required_by(method: &str) -> Option<Capability> {
+        match method {
+            LIST_USERS | GET_USER_KEY => Some(Capability::UserDirectory),
+            GET_LOG_HEAD | GET_CONSISTENCY_PROOF | GET_INCLUSION_PROOF => Some(Capability::KeyLog),
+            GET_PENDING => Some(Capability::OfflineDelivery),
+            SUBSCRIBE => Some(Capability::Push),
+            _ => None,
+        }
+    }
+}
+
+pub type Capabilities = BTreeSet<Capability>;
+
+/// Parses a capability list, skipping the ones this build doesn't know, so peers can
+/// advertise what they add later.
+fn known_capabilities<'de, D>(deserializer: D) -> Result<Capabilities, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let capabilities = Vec::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(capabilities
+        .into_iter()
+        .filter_map(|capability| serde_json::from_value(capability).ok())
+        .collect())
+}
+
+/// The part of `capabilities` a peer at `protocol_version` may be told about.
+pub fn capabilities_for(capabilities: &Capabilities, protocol_version: u32) -> Capabilities {
+    capabilities
+        .iter()
+        .filter(|capability| capability.since() <= protocol_version)
+        .copied()
+        .collect()
+}
+
+/// What a client at `protocol_version` handles without advertising it. Clients
+/// advertise before they know which revision the server speaks, so nothing an older
+/// server can't parse goes in their list.
+pub fn implied_capabilities(protocol_version: u32) -> Capabilities {
+    capabilities_for(&[Capability::MsgPack].into_iter().collect(), protocol_version)
+}
+
+/// Everything a server can offer, before its config and storage narrow it down.
+pub fn server_capabilities() -> Capabilities {
+    [
+        Capability::Push,
+        Capability::UserDirectory,
+        Capability::KeyLog,
+        Capability::OfflineDelivery,
+        Capability::MsgPack,
+    ]
+    .into_iter()
+    .collect()
+}
+
+/// What this build's client advertises, see `implied_capabilities` for the rest.
+pub fn client_capabilities() -> Capabilities {
+    [Capability::Push].into_iter().collect()
+}
+
+/// Newest protocol revision this build speaks. Servers that predate versioning report 0.
+pub const PROTOCOL_VERSION: u32 = 6;
+/// First protocol revision that encrypts RSA payloads with OAEP instead of PKCS#1 v1.5.
+pub const RSA_OAEP_PROTOCOL_VERSION: u32 = 2;
+/// First protocol revision that agrees on the session key over ephemeral X25519 keys
+/// instead of sending it under the client's RSA key.
+pub const ECDH_PROTOCOL_VERSION: u32 = 3;
+/// First protocol revision that serves `EncryptionType::BatchedAesGcm`.
+pub const BATCH_PROTOCOL_VERSION: u32 = 4;
+/// First protocol revision whose challenge signatures cover the handshake transcript,
+/// see `RespondClientChallenge::transcript` and `RespondServerChallenge::transcript`.
+pub const TRANSCRIPT_PROTOCOL_VERSION: u32 = 5;
+/// First protocol revision that skips capabilities it doesn't know instead of refusing
+/// the handshake, and that handles `Capability::MsgPack`.
+pub const MSGPACK_PROTOCOL_VERSION: u32 = 6;
+/// Most requests a batch may hold.
+pub const MAX_BATCH_REQUESTS: usize = 16;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RespondServerChallenge{
     pub pub_key: RsaPublicKey,
     pub signiture: Vec<u8>,
+    #[serde(default)]
+    pub key_type: KeyType,
+    #[serde(default)]
+    pub signing_key: Option<VerifyingKey>,
+    /// RSA signature over `signing_key`, see `pki::bind_signing_key`.
+    #[serde(default)]
+    pub signing_key_binding: Vec<u8>,
+    #[serde(default)]
+    pub protocol_version: u32,
+    /// The server's ephemeral X25519 key, thrown away once the session key is derived.
+    #[serde(default)]
+    pub ephemeral_key: Option<X25519PublicKey>,
+    /// RSA signature over `ephemeral_signed_data`, so a man in the middle can't swap
+    /// either ephemeral key.
+    #[serde(default)]
+    pub ephemeral_signature: Vec<u8>,
+    /// The client's `server_challenge` encrypted under the derived session key and
+    /// nonce, proving the server derived the same key.
+    #[serde(default)]
+    pub key_confirmation: Vec<u8>,
+    #[serde(default = "default_max_message_bytes")]
+    pub max_message_bytes: usize,
+    /// What the server serves. Servers that predate capabilities advertise none.
+    #[serde(default, deserialize_with = "known_capabilities")]
+    pub capabilities: Capabilities,
+    /// Signature over `transcript`, sent to clients that signed their own. `signiture`
+    /// only covers the client's challenge and is kept for clients that predate it.
+    #[serde(default)]
+    pub transcript_signature: Vec<u8>,
 }
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ClientEncryptionPackage {
-    nonce: String,
-    shared_key: String,
-}
-impl ClientEncryptionPackage {
-    pub fn new(nonce: Vec<u8>, shared_key: Vec<u8>) -> Self {
-        let nonce = BASE64_STANDARD.encode(nonce);
-        let shared_key = BASE64_STANDARD.encode(shared_key);
-        ClientEncryptionPackage { nonce, shared_key }
-    }
-    pub fn nonce(&self) -> Vec<u8> {
-        BASE64_STANDARD.decode(self.nonce.clone()).unwrap()
+impl RespondServerChallenge {
+    /// Hash of the whole handshake: the client's transcript followed by every field of
+    /// the server's answer but the signatures.
+    pub fn transcript(&self, client_transcript: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut transcript = Transcript::new(b"carapace server transcript");
+        transcript.absorb(client_transcript);
+        transcript.absorb(self.pub_key.to_pkcs1_der()?.as_bytes());
+        transcript.absorb_key_type(self.key_type, self.signing_key.as_ref());
+        transcript.absorb(&self.protocol_version.to_be_bytes());
+        transcript.absorb(self.ephemeral_key.as_ref().map_or(&[][..], |key| &key.as_bytes()[..]));
+        transcript.absorb(&self.key_confirmation);
+        transcript.absorb(&(self.max_message_bytes as u64).to_be_bytes());
+        transcript.absorb(&serde_json::to_vec(&self.capabilities)?);
+        Ok(transcript.finish())
     }
-    pub fn shared_key(&self) -> Vec<u8> {
-        BASE64_STANDARD.decode(self.shared_key.clone()).unwrap()
+
+    pub fn ephemeral_signed_data(
+        server_challenge: &str,
+        client_key: &X25519PublicKey,
+        server_key: &X25519PublicKey,
+    ) -> Vec<u8> {
+        let mut data = b"carapace ephemeral keys:".to_vec();
+        data.extend_from_slice(server_challenge.as_bytes());
+        data.extend_from_slice(client_key.as_bytes());
+        data.extend_from_slice(server_key.as_bytes());
+        data
     }
 }
 
+/// mDNS service type servers advertise themselves under on the local network.
+pub const MDNS_SERVICE_TYPE: &str = "_carapace._tcp.local.";
+/// TXT record key of the advertised server's `pki::fingerprint`.
+pub const MDNS_FINGERPRINT_KEY: &str = "fp";
+
+/// Largest message payload a server relays unless configured otherwise. Bigger content
+/// has to go through attachments.
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+fn default_max_message_bytes() -> usize {
+    DEFAULT_MAX_MESSAGE_BYTES
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct EncryptedRequestParams{
     pub enc_type: EncryptionType,
     pub data: Vec<u8>,
 }
 
+/// `recipients` are the fingerprints of the recipients' public keys, see
+/// `pki::fingerprint`. The server relays `data` without looking at it.
 #[derive(Serialize, Deserialize)]
 pub struct ForwardedMessageParams{
     pub enc_type: EncryptionType,
     pub data: Vec<u8>,
     pub recipients: Vec<String>,
+    #[serde(default)]
+    pub payload_type: PayloadType,
+    /// Set by the server on what it passes on, for the recipients to acknowledge the
+    /// message with `ACK_MESSAGE`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+}
+
+/// The server's answer to a forwarded message, once it has pushed it to the recipients
+/// online and queued it for the others.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ForwardReceipt {
+    /// When the server relayed the message, by its clock.
+    pub relayed_at: SystemTime,
+    pub delivered: usize,
+    pub queued: usize,
+    /// Offline recipients whose queue on the server was full, so they won't get it.
+    #[serde(default)]
+    pub refused: Vec<String>,
+    /// What the server calls the message, from the recipients' acknowledgements on.
+    /// Only servers that keep a database hand one out.
+    #[serde(default)]
+    pub message_id: Option<String>,
+}
+
+/// How far a recipient got with a message.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AckStatus {
+    Delivered,
+    Read,
+}
+
+/// A recipient acknowledging the message the server handed it as `message_id`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AckParams {
+    pub message_id: String,
+    pub status: AckStatus,
+}
+
+/// Pushed to the sender of a message when one of its recipients acknowledges it.
+/// `recipient` is the fingerprint of their key.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MessageAck {
+    pub message_id: String,
+    pub status: AckStatus,
+    pub recipient: String,
+}
+
+/// What the `data` of a forwarded message holds, for its recipients to decode.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum PayloadType {
+    /// Anything the sender chose, carapace doesn't interpret it.
+    #[default]
+    Opaque,
+    ChatInvite,
+    ChatAccept,
+    ChatMessage,
+    /// A `ChatMessagePayload` sealing a chat's new signed `ChatCustomization`.
+    ChatCustomization,
+}
+
+/// Invites the recipient to an end-to-end encrypted chat. `encrypted_key` is the chat
+/// key under the recipient's RSA key, `signature` the inviter's over `signed_data`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChatInvite {
+    pub chat_id: String,
+    pub name: String,
+    /// Username the inviter is registered under on the relaying server.
+    pub from: String,
+    /// SPKI PEM of the inviter's key.
+    pub from_key: String,
+    pub encrypted_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+impl ChatInvite {
+    pub fn signed_data(&self) -> Vec<u8> {
+        let mut data = b"carapace chat invite:".to_vec();
+        for field in [self.chat_id.as_bytes(), self.name.as_bytes(), self.from.as_bytes()] {
+            data.extend_from_slice(field);
+            data.push(0);
+        }
+        data.extend_from_slice(&self.encrypted_key);
+        data
+    }
+}
+
+/// Accepts a `ChatInvite`. `proof` is the chat id sealed under the chat key, showing
+/// the invitee could decrypt it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChatAccept {
+    pub chat_id: String,
+    pub proof: Vec<u8>,
+}
+
+/// A chat message sealed under the chat key. `sender` is the fingerprint of the
+/// sender's key.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChatMessagePayload {
+    pub chat_id: String,
+    pub sender: String,
+    pub data: Vec<u8>,
+    /// The sender's latest head of the relaying server's key log, so recipients can
+    /// check they are shown the same log.
+    #[serde(default)]
+    pub log_head: Option<SignedTreeHead>,
+}
+
+/// Acknowledges notifications returned by an earlier `GET_PENDING`, which the server
+/// then stops handing out. Anything not acknowledged is returned again.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct GetPendingParams {
+    #[serde(default)]
+    pub acknowledged: Vec<String>,
 }
 
+/// A notification that was queued while its recipient was offline.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PendingDelivery {
+    pub id: String,
+    pub notification: Request,
+}
+
+/// Replaces the session key with one agreed over fresh ephemeral X25519 keys, so a
+/// leaked session key doesn't give away the next. Sent under the old key, which is
+/// also used for the response; everything after uses the new one.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RekeyParams {
+    pub ephemeral_key: X25519PublicKey,
+}
+
+/// The server's side of a `REKEY`. `ephemeral_signature` is its RSA signature over
+/// `signed_data`, so whoever holds the old key can't swap in their own.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RekeyAnswer {
+    pub ephemeral_key: X25519PublicKey,
+    pub ephemeral_signature: Vec<u8>,
+}
+impl RekeyAnswer {
+    pub fn signed_data(client_key: &X25519PublicKey, server_key: &X25519PublicKey) -> Vec<u8> {
+        let mut data = b"carapace rekey:".to_vec();
+        data.extend_from_slice(client_key.as_bytes());
+        data.extend_from_slice(server_key.as_bytes());
+        data
+    }
+}
+
+/// Asks for piece `chunk_index` of a streamed result, see `StreamInfo`. The server
+/// lets go of the result once its last piece has been fetched.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FetchChunkParams {
+    pub stream_id: StreamId,
+    pub chunk_index: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FetchChunkResult {
+    pub data: Vec<u8>,
+}
+
+/// Registers `username` for the session's client key. Names are unique per server, and
+/// registering again replaces the key's previous name.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegisterUserParams {
+    pub username: String,
+}
+
+/// Longest username a server accepts, in characters.
+pub const MAX_USERNAME_LEN: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetUserKeyParams {
+    pub username: String,
+}
+
+/// Asks for the key log entries that take the log from `from_size` entries to
+/// `to_size`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConsistencyProofParams {
+    pub from_size: u64,
+    pub to_size: u64,
+}
+
+/// Asks for the key log from the last entry binding `username` to `pub_key` up to the
+/// end of the log's first `tree_size` entries.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InclusionProofParams {
+    pub username: String,
+    pub pub_key: RsaPublicKey,
+    pub tree_size: u64,
+}
+
+/// A registered user's key, as an SPKI PEM.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserKey {
+    pub username: String,
+    pub pub_key: String,
+}
+
+/// Replaces the session's client key with `new_pub_key` in the server's authorized
+/// keys. `signature_over_new_key` is the old key's signature over
+/// `pki::key_rotation_message(new_pub_key)`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeyRotationParams {
+    pub new_pub_key: RsaPublicKey,
+    pub signature_over_new_key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RevocationReason {
+    AdminRevoked,
+    KeyCompromised,
+    SessionExpired,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RevokeSessionParams {
+    pub reason: RevocationReason,
+}
+
+/// Control message sent to the other participants of a chat when its look changes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatCustomizationParams {
+    pub chat_id: String,
+    pub customization: ChatCustomization,
+}
+
+/// Identity a client claims when opening a plaintext dev session. Nothing proves it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DevPlaintextSessionParams {
+    pub pub_key: RsaPublicKey,
+    #[serde(default, deserialize_with = "known_capabilities")]
+    pub capabilities: Capabilities,
+}
+
+/// Public, pre-handshake description of a server, used to probe saved servers
+/// without opening a session.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServerInfo {
+    pub pub_key: RsaPublicKey,
+    pub open_registration: bool,
+    pub max_message_bytes: usize,
+    #[serde(default, deserialize_with = "known_capabilities")]
+    pub capabilities: Capabilities,
+    /// The server's RSA signature over `challenge_signed_data` of the challenge it was
+    /// asked with. Empty when there was none, or from servers that predate it.
+    #[serde(default)]
+    pub challenge_signature: Vec<u8>,
+}
+impl ServerInfo {
+    /// What `challenge_signature` signs, so a probe learns whether the server holds
+    /// its key without a session.
+    pub fn challenge_signed_data(challenge: &str) -> Vec<u8> {
+        let mut data = b"carapace server info:".to_vec();
+        data.extend_from_slice(challenge.as_bytes());
+        data
+    }
+}
+
+/// Optional params of `GET_SERVER_INFO`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServerInfoParams {
+    pub challenge: String,
+}
+
+pub const GET_SERVER_INFO: &str = "get_server_info";
 pub const START_SERVER_HANDSHAKE: &str = "start_server_handshake";
 pub const CLIENT_CHALLENGE_RESPONSE: &str = "client_challenge_response";
 
 
 pub const ENCRYPTED_REQUEST: &str = "encrypted_request";
-pub const REQUEST_ENCRYPTION_PACKAGE: &str = "request_encryption_package";
 
 pub const PING: &str = "ping";
 
 pub const FORWARDED_MSG: &str = "forwarded_message";
+pub const GET_PENDING: &str = "get_pending";
+/// Only answered within an encrypted session. Pushed on to the message's sender.
+pub const ACK_MESSAGE: &str = "ack_message";
+
+pub const KEY_ROTATION: &str = "key_rotation";
+
+pub const REGISTER_USER: &str = "register_user";
+/// Only answered within an encrypted session.
+pub const LIST_USERS: &str = "list_users";
+/// Only answered within an encrypted session.
+pub const GET_USER_KEY: &str = "get_user_key";
+
+pub const GET_LOG_HEAD: &str = "get_log_head";
+pub const GET_CONSISTENCY_PROOF: &str = "get_consistency_proof";
+pub const GET_INCLUSION_PROOF: &str = "get_inclusion_proof";
+
+pub const REKEY: &str = "rekey";
+
+pub const REVOKE_SESSION: &str = "revoke_session";
+
+/// Only answered within an encrypted session, whose pushes it turns on.
+pub const SUBSCRIBE: &str = "subscribe";
+
+/// Only answered within an encrypted session, for the streams it was handed.
+pub const FETCH_CHUNK: &str = "fetch_chunk";
+
+/// Only served with the `insecure-dev` feature, by servers bound to loopback.
+pub const DEV_PLAINTEXT_SESSION: &str = "dev_plaintext_session";
+
+/// Whether `method` only reads, so a request that got no answer can be sent again
+/// without the server acting on it twice.
+pub fn is_idempotent(method: &str) -> bool {
+    matches!(
+        method,
+        PING | GET_SERVER_INFO
+            | LIST_USERS
+            | GET_USER_KEY
+            | GET_LOG_HEAD
+            | GET_CONSISTENCY_PROOF
+            | GET_INCLUSION_PROOF
+    )
+}
@@ -1,33 +1,118 @@
-use std::error::Error;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
 
 use aes_gcm::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
     Aes256Gcm,
     Key, // Or `Aes128Gcm`
     Nonce,
 };
+use hkdf::Hkdf;
+use rsa::sha2::Sha256;
 use sha256::digest;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::Error;
+
+pub fn encrypt_gcm(pt: &[u8], key: &[u8], nonce: &[u8]) -> Result<Vec<u8>, Error> {
+    encrypt_gcm_aad(pt, key, nonce, &[])
+}
 
-pub fn encrypt_gcm(pt: &[u8], key: &[u8], nonce: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+pub fn decrypt_gcm(ct: &[u8], key: &[u8], nonce: &[u8]) -> Result<Vec<u8>, Error> {
+    decrypt_gcm_aad(ct, key, nonce, &[])
+}
+
+/// Like `encrypt_gcm`, also authenticating `aad`, which stays in the clear but can't be
+/// changed without decryption failing. No `aad` is the same as none at all.
+pub fn encrypt_gcm_aad(
+    pt: &[u8],
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, Error> {
     let key = digest(key);
     let key = hex::decode(key)?;
     let key = Key::<Aes256Gcm>::from_slice(&key);
     let cipher = Aes256Gcm::new(&key);
     let nonce = Nonce::from_slice(nonce);
-    let ciphertext = cipher.encrypt(&nonce, pt).map_err(|e| e.to_string())?;
+    let ciphertext = cipher.encrypt(&nonce, Payload { msg: pt, aad })?;
     Ok(ciphertext.to_vec())
 }
 
-pub fn decrypt_gcm(ct: &[u8], key: &[u8], nonce: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+pub fn decrypt_gcm_aad(
+    ct: &[u8],
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, Error> {
     let key = digest(key);
     let key = hex::decode(key)?;
     let key = Key::<Aes256Gcm>::from_slice(&key);
     let cipher = Aes256Gcm::new(&key);
     let nonce = Nonce::from_slice(nonce);
-    let ciphertext = cipher.decrypt(&nonce, ct).map_err(|e| {println!("err {:?}", e); return e.to_string()})?;
+    let ciphertext = cipher
+        .decrypt(&nonce, Payload { msg: ct, aad })
+        .map_err(|e| {println!("err {:?}", e); return e.to_string()})?;
     Ok(ciphertext.to_vec())
 }
 
+const NONCE_LEN: usize = 12;
+
+/// Encrypts under a fresh random nonce and prepends it to the ciphertext, so a key
+/// can be used for any number of messages.
+pub fn seal_gcm(pt: &[u8], key: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut data = nonce();
+    data.extend(encrypt_gcm(pt, key, &data)?);
+    Ok(data)
+}
+
+/// Decrypts a payload produced by `seal_gcm`.
+pub fn open_gcm(data: &[u8], key: &[u8]) -> Result<Vec<u8>, Error> {
+    if data.len() < NONCE_LEN {
+        Err("Ciphertext is too short")?;
+    }
+    let (nonce, ct) = data.split_at(NONCE_LEN);
+    decrypt_gcm(ct, key, nonce)
+}
+
+/// Remembers every nonce used under one key and refuses to encrypt with any of them
+/// twice. Clones share what they've seen, so one tracker covers every holder of the
+/// key. The set lives as long as the key does.
+#[derive(Clone, Default)]
+pub struct NonceTracker {
+    used: Arc<Mutex<HashSet<[u8; NONCE_LEN]>>>,
+}
+impl NonceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Like `encrypt_gcm`, but fails if `nonce` was used before.
+    pub fn encrypt_gcm(&self, pt: &[u8], key: &[u8], nonce: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce_bytes: [u8; NONCE_LEN] = nonce.try_into().map_err(|_| "Nonce has the wrong length")?;
+        if !self.used.lock().unwrap().insert(nonce_bytes) {
+            Err("Nonce was already used with this key")?;
+        }
+        encrypt_gcm(pt, key, nonce)
+    }
+
+    /// Encrypts under a fresh nonce, returning the ciphertext and the nonce.
+    pub fn encrypt_gcm_tracked(&self, pt: &[u8], key: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        let nonce = nonce();
+        let ct = self.encrypt_gcm(pt, key, &nonce)?;
+        Ok((ct, nonce))
+    }
+
+    /// Like `seal_gcm`, with the nonce checked.
+    pub fn seal_gcm(&self, pt: &[u8], key: &[u8]) -> Result<Vec<u8>, Error> {
+        let (ct, mut data) = self.encrypt_gcm_tracked(pt, key)?;
+        data.extend(ct);
+        Ok(data)
+    }
+}
+
 pub fn nonce() -> Vec<u8> {
     let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
     nonce.to_vec()
@@ -38,6 +123,43 @@ pub fn gen_key() -> Vec<u8> {
     key.to_vec()
 }
 
+pub fn salt() -> Vec<u8> {
+    let mut salt = vec![0; 16];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Stretches a user supplied passkey into 32 bytes of key material with Argon2id, so
+/// a weak passkey can't be brute forced offline at the speed of a single hash.
+pub fn derive_key(passkey: &[u8], salt: &[u8]) -> Result<[u8; 32], Error> {
+    let key = argon2::hash_raw(passkey, salt, &argon2::Config::owasp2())?;
+    Ok(key.try_into().map_err(|_| "Derived key has the wrong length")?)
+}
+
+/// Stretches an X25519 shared secret into a 32 byte session key and a 12 byte nonce
+/// with HKDF-SHA256.
+pub fn derive_session_key(shared_secret: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut okm = [0; 32 + NONCE_LEN];
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand(b"carapace session key", &mut okm)
+        .map_err(Error::crypto)?;
+    let (key, nonce) = okm.split_at(32);
+    let derived = (key.to_vec(), nonce.to_vec());
+    okm.zeroize();
+    Ok(derived)
+}
+
+/// Derives a 32 byte subkey of `key` for the purpose named by `info`, with
+/// HKDF-SHA256. Different `info` give unrelated keys, so one leaking says nothing about
+/// the others or about `key`.
+pub fn derive_subkey(key: &[u8], info: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let mut okm = Zeroizing::new(vec![0; 32]);
+    Hkdf::<Sha256>::new(None, key)
+        .expand(info, &mut okm)
+        .map_err(Error::crypto)?;
+    Ok(okm)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -51,4 +173,61 @@ mod test {
         let pt2 = super::decrypt_gcm(&ct, &key, &nonce).unwrap();
         assert_eq!(pt, pt2.as_slice());
     }
+
+    #[test]
+    fn test_seal_open_gcm() {
+        let pt = b"Hello, world!";
+        let key = gen_key();
+        let first = seal_gcm(pt, &key).unwrap();
+        let second = seal_gcm(pt, &key).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(open_gcm(&first, &key).unwrap(), pt);
+        assert_eq!(open_gcm(&second, &key).unwrap(), pt);
+        assert!(open_gcm(&first[..4], &key).is_err());
+    }
+
+    #[test]
+    fn test_nonce_tracker() {
+        let pt = b"Hello, world!";
+        let key = gen_key();
+        let nonce = nonce();
+        // the raw api takes whatever it's given
+        assert_eq!(
+            encrypt_gcm(pt, &key, &nonce).unwrap(),
+            encrypt_gcm(pt, &key, &nonce).unwrap()
+        );
+
+        let tracker = NonceTracker::new();
+        tracker.encrypt_gcm(pt, &key, &nonce).unwrap();
+        let shared = tracker.clone();
+        assert!(shared.encrypt_gcm(pt, &key, &nonce).is_err());
+        assert!(tracker.encrypt_gcm(pt, &key, &nonce[..8]).is_err());
+
+        let (ct, first) = tracker.encrypt_gcm_tracked(pt, &key).unwrap();
+        assert_eq!(decrypt_gcm(&ct, &key, &first).unwrap(), pt);
+        let (_, second) = tracker.encrypt_gcm_tracked(pt, &key).unwrap();
+        assert_ne!(first, second);
+        assert!(tracker.encrypt_gcm(pt, &key, &first).is_err());
+        let sealed = tracker.seal_gcm(pt, &key).unwrap();
+        assert_eq!(open_gcm(&sealed, &key).unwrap(), pt);
+    }
+
+    #[test]
+    fn test_derive_subkey() {
+        let key = gen_key();
+        let subkey = derive_subkey(&key, b"one").unwrap();
+        assert_eq!(subkey.len(), 32);
+        assert_eq!(subkey, derive_subkey(&key, b"one").unwrap());
+        assert_ne!(subkey, derive_subkey(&key, b"two").unwrap());
+        assert_ne!(subkey, derive_subkey(&gen_key(), b"one").unwrap());
+    }
+
+    #[test]
+    fn test_derive_key() {
+        let salt = salt();
+        let key = derive_key(b"password", &salt).unwrap();
+        assert_eq!(key, derive_key(b"password", &salt).unwrap());
+        assert_ne!(key, derive_key(b"passw0rd", &salt).unwrap());
+        assert_ne!(key, derive_key(b"password", &super::salt()).unwrap());
+    }
 }
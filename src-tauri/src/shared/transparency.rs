@@ -0,0 +1,190 @@
+use rsa::pkcs1::EncodeRsaPublicKey;
+use rsa::pkcs1v15::Signature;
+use rsa::sha2::{Digest, Sha256};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+
+use crate::shared::pki;
+use crate::Error;
+
+/// What the chain starts from: the hash of the empty log.
+pub const EMPTY_LOG_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// One (username, key) binding a server handed out. Entries are chained: each commits
+/// to the hash of the one before, so a head's hash commits to the whole log.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LogEntry {
+    pub seq: u64,
+    pub username: String,
+    pub pub_key: RsaPublicKey,
+    /// Hex encoded hash of the previous entry, `EMPTY_LOG_HASH` for the first.
+    pub prev_hash: String,
+}
+impl LogEntry {
+    pub fn hash(&self) -> Result<String, Error> {
+        let mut hasher = Sha256::new();
+        hasher.update(hex::decode(&self.prev_hash)?);
+        hasher.update(self.seq.to_be_bytes());
+        hasher.update(self.username.as_bytes());
+        hasher.update([0]);
+        hasher.update(self.pub_key.to_pkcs1_der()?.as_bytes());
+        Ok(hex::encode(hasher.finalize()))
+    }
+}
+
+/// The size and hash of a server's key log at some point, signed with its RSA key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SignedTreeHead {
+    pub size: u64,
+    pub hash: String,
+    pub signature: Vec<u8>,
+}
+impl SignedTreeHead {
+    fn signed_data(size: u64, hash: &str) -> Vec<u8> {
+        let mut data = b"carapace key log head:".to_vec();
+        data.extend_from_slice(&size.to_be_bytes());
+        data.extend_from_slice(hash.as_bytes());
+        data
+    }
+
+    pub fn sign(size: u64, hash: String, sk: &RsaPrivateKey) -> Self {
+        let signature = pki::sign_message(sk, &Self::signed_data(size, &hash));
+        SignedTreeHead {
+            size,
+            hash,
+            signature,
+        }
+    }
+
+    pub fn verify(&self, pk: &RsaPublicKey) -> bool {
+        match Signature::try_from(self.signature.as_slice()) {
+            Ok(signature) => pki::verify_signature(
+                pk,
+                &Self::signed_data(self.size, &self.hash),
+                &signature,
+            ),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Follows `entries` from `start`, which has to be the hash of the log before
+/// `entries[0]`. Returns the hash they lead to, or `None` if they don't chain.
+fn follow(start: &str, first_seq: u64, entries: &[LogEntry]) -> Option<String> {
+    let mut hash = start.to_string();
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.seq != first_seq + i as u64 || entry.prev_hash != hash {
+            return None;
+        }
+        hash = entry.hash().ok()?;
+    }
+    Some(hash)
+}
+
+/// Checks that `entries` extend the log `old` describes into the one `new` does, i.e.
+/// that `new` only appended to `old`.
+pub fn verify_consistency(old: &SignedTreeHead, new: &SignedTreeHead, entries: &[LogEntry]) -> bool {
+    if old.size > new.size || entries.len() as u64 != new.size - old.size {
+        return false;
+    }
+    follow(&old.hash, old.size, entries).as_deref() == Some(new.hash.as_str())
+}
+
+/// Checks that `entries`, the log from an entry binding `username` to `pub_key` up to
+/// the end of the log `head` describes, lead to `head`, and that none of the later ones
+/// bound `username` to another key.
+pub fn verify_inclusion(
+    head: &SignedTreeHead,
+    username: &str,
+    pub_key: &RsaPublicKey,
+    entries: &[LogEntry],
+) -> bool {
+    let first = match entries.first() {
+        Some(first) => first,
+        None => return false,
+    };
+    if first.username != username
+        || first.pub_key != *pub_key
+        || first.seq + entries.len() as u64 != head.size
+    {
+        return false;
+    }
+    if entries[1..]
+        .iter()
+        .any(|entry| entry.username == username && entry.pub_key != *pub_key)
+    {
+        return false;
+    }
+    follow(&first.prev_hash, first.seq, entries).as_deref() == Some(head.hash.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(keys: &[(&str, &RsaPublicKey)]) -> Vec<LogEntry> {
+        let mut entries: Vec<LogEntry> = vec![];
+        for (seq, (username, pub_key)) in keys.iter().enumerate() {
+            let prev_hash = match entries.last() {
+                Some(last) => last.hash().unwrap(),
+                None => EMPTY_LOG_HASH.to_string(),
+            };
+            entries.push(LogEntry {
+                seq: seq as u64,
+                username: username.to_string(),
+                pub_key: (*pub_key).clone(),
+                prev_hash,
+            });
+        }
+        entries
+    }
+
+    fn head(entries: &[LogEntry], sk: &RsaPrivateKey) -> SignedTreeHead {
+        let hash = match entries.last() {
+            Some(last) => last.hash().unwrap(),
+            None => EMPTY_LOG_HASH.to_string(),
+        };
+        SignedTreeHead::sign(entries.len() as u64, hash, sk)
+    }
+
+    #[test]
+    fn test_inclusion_and_consistency() {
+        let server_key = pki::gen_key().unwrap();
+        let alice = pki::gen_key().unwrap().to_public_key();
+        let bob = pki::gen_key().unwrap().to_public_key();
+        let mallory = pki::gen_key().unwrap().to_public_key();
+        let entries = log(&[("alice", &alice), ("bob", &bob), ("carol", &alice)]);
+        let empty = head(&[], &server_key);
+        let small = head(&entries[..1], &server_key);
+        let large = head(&entries, &server_key);
+        assert!(large.verify(&server_key.to_public_key()));
+        assert!(!large.verify(&bob));
+
+        assert!(verify_inclusion(&large, "bob", &bob, &entries[1..]));
+        assert!(verify_inclusion(&small, "alice", &alice, &entries[..1]));
+        assert!(!verify_inclusion(&large, "bob", &mallory, &entries[1..]));
+        assert!(!verify_inclusion(&large, "bob", &bob, &entries[1..2]));
+        assert!(!verify_inclusion(&large, "bob", &bob, &[]));
+
+        assert!(verify_consistency(&empty, &large, &entries));
+        assert!(verify_consistency(&small, &large, &entries[1..]));
+        assert!(verify_consistency(&large, &large, &[]));
+        assert!(!verify_consistency(&large, &small, &[]));
+        assert!(!verify_consistency(&small, &large, &entries[2..]));
+
+        // a log that swapped bob's key can't be reconciled with the honest one
+        let forked = log(&[("alice", &alice), ("bob", &mallory), ("carol", &alice)]);
+        let forked_head = head(&forked, &server_key);
+        assert!(verify_consistency(&small, &forked_head, &forked[1..]));
+        assert!(!verify_consistency(&large, &forked_head, &[]));
+        assert!(!verify_consistency(&small, &large, &forked[1..]));
+        assert!(!verify_inclusion(&large, "bob", &mallory, &forked[1..]));
+
+        // a binding the name was moved away from no longer proves anything
+        let rebound = log(&[("alice", &alice), ("bob", &bob), ("alice", &mallory)]);
+        let rebound_head = head(&rebound, &server_key);
+        assert!(!verify_inclusion(&rebound_head, "alice", &alice, &rebound));
+        assert!(verify_inclusion(&rebound_head, "alice", &mallory, &rebound[2..]));
+        assert!(verify_inclusion(&rebound_head, "bob", &bob, &rebound[1..]));
+    }
+}